@@ -392,16 +392,28 @@ pub async fn parse_env() -> ProxyResult<ConfigOption> {
             option.after_load_option()?;
             return Ok(option);
         }
-        Command::Check(config) => match read_config_from_path(&config.config) {
-            Ok(_) => {
-                println!("配置文件正确");
+        Command::Check(config) => {
+            let mut check = match read_config_from_path(&config.config) {
+                Ok(check) => check,
+                Err(e) => {
+                    println!("配置文件错误:{:?}", e);
+                    exit(0);
+                }
+            };
+            if let Err(e) = check.after_load_option() {
+                println!("配置文件错误:{:?}", e);
                 exit(0);
             }
-            Err(e) => {
-                println!("配置文件错误:{:?}", e);
+            if let Some(Err(errors)) = check.http.as_ref().map(|h| h.validate()) {
+                println!("配置文件错误:");
+                for e in errors {
+                    println!("  {}", e);
+                }
                 exit(0);
             }
-        },
+            println!("配置文件正确");
+            exit(0);
+        }
         Command::Run(config) => {
             let mut option = read_config_from_path(&config.config)?;
             if shared.verbose {
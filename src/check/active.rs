@@ -11,14 +11,16 @@
 // Created Date: 2023/10/23 09:44:07
 
 use std::{
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     time::{Duration, Instant}, io,
 };
-use tokio::sync::mpsc::{Receiver, error::TryRecvError};
+use tokio::{net::TcpStream, sync::mpsc::{Receiver, error::TryRecvError}};
+use tokio_util::sync::CancellationToken;
 use webparse::{Request, Response};
 use wenmeng::{Client, Body};
 
-use crate::{ProxyResult, HealthCheck};
+use crate::{reverse::ActiveCheckConfig, ProxyResult, HealthCheck, UpstreamConfig};
 
 /// 单项健康检查
 /// TODO HTTP检查应该可以配置请求方法及返回编码是否正确来判定是否为健康
@@ -153,3 +155,179 @@ impl ActiveHealth {
         Ok(())
     }
 }
+
+/// 单个upstream的主动健康检查, 按连续成功或失败的次数标记后端状态
+pub struct UpstreamActiveCheck {
+    /// upstream的名字
+    pub name: String,
+    /// 检查配置
+    pub config: ActiveCheckConfig,
+    /// 后端地址
+    pub servers: Vec<SocketAddr>,
+    /// 每个后端的连续成功次数及连续失败次数
+    counts: HashMap<SocketAddr, (usize, usize)>,
+}
+
+impl UpstreamActiveCheck {
+    pub fn new(name: String, config: ActiveCheckConfig, servers: Vec<SocketAddr>) -> Self {
+        Self {
+            name,
+            config,
+            servers,
+            counts: HashMap::new(),
+        }
+    }
+
+    async fn check_http(&self, addr: &SocketAddr) -> ProxyResult<bool> {
+        let url = format!("http://{}{}", addr, self.config.path);
+        let req = Request::builder()
+            .method("GET")
+            .url(url.clone())
+            .body("")
+            .unwrap();
+        let client = Client::builder().url(url)?.connect().await?;
+        let (mut recv, _sender) = client.send2(req.into_type()).await?;
+        match recv.recv().await {
+            Some(res) => {
+                let status = res?.status();
+                Ok(!status.is_server_error() && !status.is_client_error())
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// 检查一次后端是否可用
+    pub async fn check_one(&self, addr: &SocketAddr) -> bool {
+        let timeout = self.config.timeout.0;
+        if self.config.method.eq_ignore_ascii_case("tcp") {
+            matches!(
+                tokio::time::timeout(timeout, TcpStream::connect(addr)).await,
+                Ok(Ok(_))
+            )
+        } else {
+            matches!(
+                tokio::time::timeout(timeout, self.check_http(addr)).await,
+                Ok(Ok(true))
+            )
+        }
+    }
+
+    /// 记录一次检查结果, 达到阈值时改变后端状态
+    fn record(&mut self, addr: SocketAddr, success: bool) {
+        let count = self.counts.entry(addr).or_insert((0, 0));
+        if success {
+            count.0 += 1;
+            count.1 = 0;
+        } else {
+            count.0 = 0;
+            count.1 += 1;
+        }
+        let failed = if count.1 >= self.config.fall {
+            true
+        } else if count.0 >= self.config.rise {
+            false
+        } else {
+            // 未达到阈值, 保持原有状态
+            HealthCheck::is_fall_down(&addr)
+        };
+        if HealthCheck::set_active_status(addr, failed) {
+            log::info!(
+                "主动健康检查:{}:{}, 状态变更为{}",
+                self.name,
+                addr,
+                if failed { "不可用" } else { "可用" }
+            );
+        }
+    }
+
+    /// 检查所有的后端
+    pub async fn check_all(&mut self) {
+        let servers = self.servers.clone();
+        let checks = servers.iter().map(|addr| self.check_one(addr));
+        let results = futures::future::join_all(checks).await;
+        for (addr, success) in servers.into_iter().zip(results) {
+            self.record(addr, success);
+        }
+    }
+
+    /// 启动所有配置了检查的upstream, 名字及后端相同的upstream只检查一次
+    pub fn start_all<'a>(
+        upstreams: impl Iterator<Item = &'a UpstreamConfig>,
+        cancel: &CancellationToken,
+    ) {
+        let mut already = HashSet::new();
+        for up in upstreams {
+            if let Some(config) = &up.health_check {
+                let servers = up.server.iter().map(|s| s.addr).collect::<Vec<_>>();
+                if !already.insert((up.name.clone(), servers.clone())) {
+                    continue;
+                }
+                UpstreamActiveCheck::new(up.name.clone(), config.clone(), servers)
+                    .do_start(cancel.clone());
+            }
+        }
+    }
+
+    /// 启动检查任务, 直到收到取消信号
+    pub fn do_start(mut self, cancel: CancellationToken) {
+        tokio::spawn(async move {
+            loop {
+                self.check_all().await;
+                tokio::select! {
+                    _ = tokio::time::sleep(self.config.interval.0) => {}
+                    _ = cancel.cancelled() => {
+                        log::trace!("主动健康检查:{}, 收到退出信号", self.name);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{reverse::ActiveCheckConfig, ConfigDuration, HealthCheck, UpstreamConfig};
+
+    use super::UpstreamActiveCheck;
+
+    #[tokio::test]
+    async fn test_active_check() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut config = ActiveCheckConfig::new("tcp".to_string());
+        config.interval = ConfigDuration::new(Duration::from_millis(20));
+        config.timeout = ConfigDuration::new(Duration::from_millis(200));
+        config.fall = 2;
+        config.rise = 2;
+        let upstream = UpstreamConfig::new_single("active".to_string(), addr);
+
+        let cancel = CancellationToken::new();
+        UpstreamActiveCheck::new("active".to_string(), config, vec![addr]).do_start(cancel.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!HealthCheck::is_fall_down(&addr));
+        assert_eq!(upstream.get_server_addr(None), Some(addr));
+
+        // 后端关闭后标记为不可用, 负载均衡时跳过
+        drop(listener);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(HealthCheck::is_fall_down(&addr));
+        let mut upstream = upstream.clone();
+        let other = UpstreamConfig::new_single("other".to_string(), "127.0.0.1:19201".parse().unwrap());
+        upstream.server.extend(other.server);
+        for _ in 0..20 {
+            assert_ne!(upstream.get_server_addr(None), Some(addr));
+        }
+
+        // 后端恢复后重新标记为可用
+        let _listener = TcpListener::bind(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!HealthCheck::is_fall_down(&addr));
+        cancel.cancel();
+    }
+}
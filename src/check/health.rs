@@ -39,6 +39,16 @@ struct HealthRecord {
     rise_times: usize,
     /// 当前的状态
     failed: bool,
+    /// 是否由主动健康检查维护状态
+    active: bool,
+    /// 被动检查中当前统计周期内的失败次数
+    passive_fails: usize,
+    /// 被动检查当前统计周期的开始时间
+    passive_start: Instant,
+    /// 被动检查标记的不可用截止时间
+    down_until: Option<Instant>,
+    /// 不可用时间结束后是否已有试探的请求
+    trial: bool,
 }
 
 impl HealthRecord {
@@ -50,6 +60,20 @@ impl HealthRecord {
             fall_times: 0,
             rise_times: 0,
             failed: false,
+            active: false,
+            passive_fails: 0,
+            passive_start: Instant::now(),
+            down_until: None,
+            trial: false,
+        }
+    }
+
+    /// 被动检查中是否不可用, 不可用时间结束后只允许一个试探的请求
+    fn is_passive_down(&self, now: Instant) -> bool {
+        match self.down_until {
+            Some(until) if now < until => true,
+            Some(_) => self.trial,
+            None => false,
         }
     }
 
@@ -138,6 +162,9 @@ impl HealthCheck {
             if Instant::now().duration_since(value.last_record) > *fail_timeout {
                 return false;
             }
+            if value.active {
+                return value.failed;
+            }
             if &value.fall_times >= fall_times {
                 return true;
             }
@@ -150,6 +177,108 @@ impl HealthCheck {
         }
     }
 
+    /// 主动健康检查设置状态, 返回状态是否发生变化
+    pub fn set_active_status(addr: SocketAddr, failed: bool) -> bool {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            let fail_timeout = h.fail_timeout;
+            let value = h
+                .health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(fail_timeout));
+            let changed = value.failed != failed;
+            value.active = true;
+            value.failed = failed;
+            value.last_record = Instant::now();
+            changed
+        } else {
+            false
+        }
+    }
+
+    /// 被动检查中是否不可用
+    pub fn is_passive_down(addr: &SocketAddr) -> bool {
+        if let Ok(h) = HEALTH_CHECK.read() {
+            h.health_map
+                .get(addr)
+                .map(|v| v.is_passive_down(Instant::now()))
+                .unwrap_or(false)
+        } else {
+            false
+        }
+    }
+
+    /// 请求后端前调用, 不可用时间结束后首个请求将作为试探请求, 返回本次请求是否为试探请求
+    pub fn passive_begin(addr: &SocketAddr) -> bool {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            let now = Instant::now();
+            match h.health_map.get_mut(addr) {
+                Some(value) if value.down_until.is_some() && !value.is_passive_down(now) => {
+                    value.trial = true;
+                    true
+                }
+                _ => false,
+            }
+        } else {
+            false
+        }
+    }
+
+    /// 后端返回失败(连接失败或者5xx)时调用, `fail_timeout`内失败达到`max_fails`次时
+    /// 在`fail_timeout`内不再请求该后端, 试探请求失败时重新标记为不可用
+    pub fn add_passive_fail(addr: SocketAddr, max_fails: usize, fail_timeout: Duration) {
+        if max_fails == 0 {
+            return;
+        }
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            let default_timeout = h.fail_timeout;
+            let value = h
+                .health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(default_timeout));
+            let now = Instant::now();
+            if value.trial {
+                value.trial = false;
+                value.passive_fails = 0;
+                value.down_until = Some(now + fail_timeout);
+                return;
+            }
+            if now.duration_since(value.passive_start) > fail_timeout {
+                value.passive_fails = 0;
+                value.passive_start = now;
+            }
+            value.passive_fails += 1;
+            if value.passive_fails >= max_fails {
+                log::info!("被动健康检查:{}失败{}次, 标记为不可用", addr, max_fails);
+                value.passive_fails = 0;
+                value.down_until = Some(now + fail_timeout);
+            }
+        }
+    }
+
+    /// 后端正常返回时调用, 试探请求成功时恢复为可用
+    pub fn add_passive_success(addr: SocketAddr) {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            if let Some(value) = h.health_map.get_mut(&addr) {
+                // 不可用期间之前发出的请求不改变状态
+                let expired = value.down_until.is_some_and(|u| Instant::now() >= u);
+                if value.trial || expired {
+                    log::info!("被动健康检查:{}请求成功, 恢复为可用", addr);
+                    value.down_until = None;
+                    value.trial = false;
+                }
+            }
+        }
+    }
+
+    /// 试探请求未得到结果(如客户端提前断开)时调用, 由后续的请求重新试探
+    pub fn passive_cancel(addr: &SocketAddr) {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            if let Some(value) = h.health_map.get_mut(addr) {
+                value.trial = false;
+            }
+        }
+    }
+
     /// 失败时调用
     pub fn add_fall_down(addr: SocketAddr) {
         // 需要写入，获取写入锁
@@ -253,7 +382,7 @@ impl HealthCheck {
         } else {
             match tokio::time::timeout(connect.unwrap(), HealthCheck::connect(addr)).await {
                 Ok(s) => s,
-                Err(_) => return Err(io::Error::new(io::ErrorKind::NotConnected, "timeout")),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
             }
         }
     }
@@ -15,4 +15,4 @@ mod health;
 mod active;
 
 pub use health::HealthCheck;
-pub use active::{ActiveHealth, OneHealth};
\ No newline at end of file
+pub use active::{ActiveHealth, OneHealth, UpstreamActiveCheck};
\ No newline at end of file
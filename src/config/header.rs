@@ -90,37 +90,31 @@ impl ConfigHeader {
 impl FromStr for ConfigHeader {
     type Err = io::Error;
 
+    /// 格式为`[proxy] [+|-|?]key [val]`, 操作符可与key相连, 如`+X-Trace abc`
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.len() == 0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, ""));
+        let mut vals = Helper::split_by_whitespace(s);
+        let is_proxy = vals.len() > 1 && vals[0] == "proxy";
+        if is_proxy {
+            vals.remove(0);
         }
-
-        let vals = Helper::split_by_whitespace(s);
         let mut oper = HeaderOper::Replace;
-        let mut is_proxy = false;
-        let (key, val) = {
-            match (vals.len(), vals[0]) {
-                (4, "proxy") => {
-                    is_proxy = true;
-                    oper = HeaderOper::from_str(vals[1])?;
-                    (vals[2].to_string(), vals[3].to_string())
-                }
-                (3, "proxy") => {
-                    is_proxy = true;
-                    (vals[1].to_string(), vals[2].to_string())
-                }
-                (3, _) => {
-                    oper = HeaderOper::from_str(vals[0])?;
-                    (vals[1].to_string(), vals[2].to_string())
-                }
-                (2, "-") => {
-                    oper = HeaderOper::from_str(vals[0])?;
-                    (vals[1].to_string(), String::new())
-                }
-                (2, _) => (vals[0].to_string(), vals[1].to_string()),
-                _ => {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, ""));
-                }
+        if let Some(first) = vals.first().cloned() {
+            if ["+", "-", "?"].contains(&first) {
+                oper = HeaderOper::from_str(first)?;
+                vals.remove(0);
+            } else if first.len() > 1 && first.starts_with(['+', '-', '?']) {
+                oper = HeaderOper::from_str(&first[..1])?;
+                vals[0] = &first[1..];
+            }
+        }
+        let (key, val) = match (&oper, vals.len()) {
+            (HeaderOper::Del, 1) => (vals[0].to_string(), String::new()),
+            (_, 2) => (vals[0].to_string(), vals[1].to_string()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid header config `{}`", s),
+                ));
             }
         };
 
@@ -130,17 +124,21 @@ impl FromStr for ConfigHeader {
 
 impl Display for ConfigHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match (self.is_proxy, &self.oper) {
-            (true, HeaderOper::Default) => {
-                f.write_fmt(format_args!("proxy {} {}", self.key, self.val))
+        if self.is_proxy {
+            f.write_str("proxy ")?;
+        }
+        if self.oper != HeaderOper::Replace {
+            f.write_fmt(format_args!("{} ", self.oper))?;
+        }
+        f.write_str(&self.key)?;
+        if self.oper != HeaderOper::Del {
+            if self.val.is_empty() || self.val.contains(char::is_whitespace) {
+                f.write_fmt(format_args!(" '{}'", self.val))?;
+            } else {
+                f.write_fmt(format_args!(" {}", self.val))?;
             }
-            (true, _) => f.write_fmt(format_args!(
-                "proxy {} {} {}",
-                self.oper, self.key, self.val
-            )),
-            (_, HeaderOper::Default) => f.write_fmt(format_args!("{} {}", self.key, self.val)),
-            (_, _) => f.write_fmt(format_args!("{} {} {}", self.oper, self.key, self.val)),
         }
+        Ok(())
     }
 }
 
@@ -190,5 +188,43 @@ mod tests {
             "key",
             "val 1"
         );
+        // 操作符与key相连
+        header_compare!("proxy +X-Trace abc", true, HeaderOper::Add, "X-Trace", "abc");
+        header_compare!("-Cookie", false, HeaderOper::Del, "Cookie", "");
+        header_compare!("proxy - Authorization", true, HeaderOper::Del, "Authorization", "");
+        header_compare!("?X-Default 1", false, HeaderOper::Default, "X-Default", "1");
+        assert!(crate::ConfigHeader::from_str("").is_err());
+        assert!(crate::ConfigHeader::from_str("+ key").is_err());
+        assert!(crate::ConfigHeader::from_str("key val other").is_err());
+
+        // 输出后可重新解析
+        for raw in ["proxy + key 'val 1'", "? key val", "- key", "proxy Host example.com"] {
+            let config = crate::ConfigHeader::from_str(raw).unwrap();
+            assert_eq!(
+                crate::ConfigHeader::from_str(&config.to_string()).unwrap(),
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_seq() {
+        #[serde_with::serde_as]
+        #[derive(serde::Deserialize)]
+        struct Wrap {
+            #[serde_as(as = "Vec<crate::DisplayFromStrOrSeq>")]
+            headers: Vec<crate::ConfigHeader>,
+        }
+        let wrap: Wrap = toml::from_str(
+            r#"headers = [["proxy", "+X-Trace", "a b"], ["-Cookie"], "proxy Host internal"]"#,
+        )
+        .unwrap();
+        assert_eq!(wrap.headers.len(), 3);
+        assert_eq!(wrap.headers[0].oper, HeaderOper::Add);
+        assert_eq!(wrap.headers[0].val, "a b");
+        assert_eq!(wrap.headers[1].oper, HeaderOper::Del);
+        assert_eq!(wrap.headers[1].key, "Cookie");
+        assert!(wrap.headers[2].is_proxy);
+        assert_eq!(wrap.headers[2].val, "internal");
     }
 }
@@ -0,0 +1,258 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/07 10:05:21
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::{HeaderMap, HeaderName, HeaderValue, Version};
+
+use crate::Helper;
+
+/// 必须唯一的头, 其值的语法中不会出现`,`及`;`, 出现多个值即认为重复
+/// 值完全相同时合并成一个, 不同时拒绝该请求, 防止通过重复的头进行请求走私
+pub const SINGLETON_HEADERS: [&str; 3] = ["content-length", "host", "max-forwards"];
+
+/// 头名称的大小写处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeaderCase {
+    /// 保持原样
+    #[default]
+    Keep,
+    /// 全部转成小写
+    Lower,
+    /// 转成如`Content-Length`的格式, HTTP/2中将转成小写
+    Canonical,
+}
+
+/// 头信息的规范化策略, 格式如`canonical reject_invalid unique`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HeaderPolicy {
+    pub case: HeaderCase,
+    /// 拒绝包含非法字符的头
+    pub reject_invalid: bool,
+    /// 合并或拒绝重复的唯一头, 见[`SINGLETON_HEADERS`]
+    pub unique: bool,
+}
+
+impl HeaderPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 头名称只能由token字符组成
+    pub fn is_valid_name(name: &[u8]) -> bool {
+        !name.is_empty()
+            && name
+                .iter()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(b))
+    }
+
+    /// 头内容中不能包含换行及空字符
+    pub fn is_valid_value(value: &[u8]) -> bool {
+        !value.iter().any(|b| *b == b'\r' || *b == b'\n' || *b == 0)
+    }
+
+    /// 转成首字母及`-`后字母大写的格式
+    pub fn canonical_name(name: &str) -> String {
+        let mut upper = true;
+        name.chars()
+            .map(|c| {
+                let v = if upper {
+                    c.to_ascii_uppercase()
+                } else {
+                    c.to_ascii_lowercase()
+                };
+                upper = c == '-';
+                v
+            })
+            .collect()
+    }
+
+    /// 按策略处理头信息, 遇到非法或者冲突的头时返回错误
+    pub fn apply(&self, headers: &mut HeaderMap, version: Version) -> io::Result<()> {
+        if self.reject_invalid {
+            for (name, value) in headers.iter() {
+                if name.is_spec() {
+                    continue;
+                }
+                if !Self::is_valid_name(name.as_bytes()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("非法的头名称: {:?}", name.name()),
+                    ));
+                }
+                if !Self::is_valid_value(value.as_bytes()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("非法的头内容: {}", name),
+                    ));
+                }
+            }
+        }
+
+        if self.unique {
+            for (name, value) in headers.iter_mut() {
+                if !SINGLETON_HEADERS.iter().any(|s| *name == *s) {
+                    continue;
+                }
+                let val = String::from_utf8_lossy(value.as_bytes()).to_string();
+                let vals = val
+                    .split([',', ';'])
+                    .map(|v| v.trim())
+                    .filter(|v| !v.is_empty())
+                    .collect::<Vec<_>>();
+                if vals.len() <= 1 {
+                    continue;
+                }
+                if vals.iter().any(|v| *v != vals[0]) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("重复的头: {}", name),
+                    ));
+                }
+                *value = HeaderValue::from_bytes(vals[0].as_bytes());
+            }
+        }
+
+        // HTTP/2中头名称必须为小写
+        let case = match (self.case, version) {
+            (HeaderCase::Canonical, Version::Http2) => HeaderCase::Lower,
+            (case, _) => case,
+        };
+        if case == HeaderCase::Keep {
+            return Ok(());
+        }
+        for (name, _) in headers.iter_mut() {
+            if name.is_spec() {
+                continue;
+            }
+            let new = match case {
+                HeaderCase::Canonical => Self::canonical_name(name.name()),
+                _ => name.name().to_ascii_lowercase(),
+            };
+            if new != name.name() {
+                *name = HeaderName::Value(new);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HeaderPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut policy = HeaderPolicy::new();
+        for v in Helper::split_by_whitespace(s) {
+            match v {
+                "keep" => policy.case = HeaderCase::Keep,
+                "lower" => policy.case = HeaderCase::Lower,
+                "canonical" => policy.case = HeaderCase::Canonical,
+                "reject_invalid" => policy.reject_invalid = true,
+                "unique" => policy.unique = true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("未知的header_policy选项: {}", v),
+                    ))
+                }
+            }
+        }
+        Ok(policy)
+    }
+}
+
+impl Display for HeaderPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut vals = vec![match self.case {
+            HeaderCase::Keep => "keep",
+            HeaderCase::Lower => "lower",
+            HeaderCase::Canonical => "canonical",
+        }];
+        if self.reject_invalid {
+            vals.push("reject_invalid");
+        }
+        if self.unique {
+            vals.push("unique");
+        }
+        f.write_str(&vals.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{HeaderMap, Version};
+
+    use super::{HeaderCase, HeaderPolicy};
+
+    #[test]
+    fn test_parse() {
+        let policy = "canonical reject_invalid unique"
+            .parse::<HeaderPolicy>()
+            .unwrap();
+        assert_eq!(policy.case, HeaderCase::Canonical);
+        assert!(policy.reject_invalid);
+        assert!(policy.unique);
+        assert_eq!(format!("{}", policy), "canonical reject_invalid unique");
+        assert!("canonical unknow".parse::<HeaderPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let policy = "reject_invalid".parse::<HeaderPolicy>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ok", "1");
+        assert!(policy.apply(&mut headers, Version::Http11).is_ok());
+        headers.insert("bad name".to_string(), "1");
+        assert!(policy.apply(&mut headers, Version::Http11).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-split", "a\r\nx-inject: b");
+        assert!(policy.apply(&mut headers, Version::Http11).is_err());
+        // 未开启时不做校验
+        assert!(HeaderPolicy::new()
+            .apply(&mut headers, Version::Http11)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_duplicate_singleton() {
+        let policy = "unique".parse::<HeaderPolicy>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.push("Content-Length", "5");
+        headers.push("content-length", "5");
+        headers.push("accept", "a");
+        headers.push("accept", "b");
+        assert!(policy.apply(&mut headers, Version::Http11).is_ok());
+        assert_eq!(headers.get_str_value(&"content-length").unwrap(), "5");
+        // 非唯一头保持原样
+        assert_eq!(headers.get_str_value(&"accept").unwrap(), "a;b");
+
+        let mut headers = HeaderMap::new();
+        headers.insert("content-length", "5, 6");
+        assert!(policy.apply(&mut headers, Version::Http11).is_err());
+        let mut headers = HeaderMap::new();
+        headers.push("host", "a.com");
+        headers.push("host", "b.com");
+        assert!(policy.apply(&mut headers, Version::Http11).is_err());
+    }
+
+    #[test]
+    fn test_case() {
+        let policy = "canonical".parse::<HeaderPolicy>().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-FOR", "1");
+        policy.apply(&mut headers, Version::Http11).unwrap();
+        assert_eq!(headers.iter().next().unwrap().0.name(), "X-Forwarded-For");
+        policy.apply(&mut headers, Version::Http2).unwrap();
+        assert_eq!(headers.iter().next().unwrap().0.name(), "x-forwarded-for");
+    }
+}
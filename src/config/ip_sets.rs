@@ -13,6 +13,7 @@
 use std::{net::IpAddr, str::FromStr, io, fmt::Display};
 
 /// IP单网关,包含子网掩码信息
+/// 未配置掩码时gate为地址的完整位数, 即仅匹配单个IP
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IpGate {
     pub ip: IpAddr,
@@ -20,20 +21,31 @@ pub struct IpGate {
 }
 
 impl IpGate {
-    pub fn contains(&self, ip: &IpAddr) -> bool {
-        if self.gate == 0 {
-            ip == &self.ip
+    fn max_gate(ip: &IpAddr) -> u8 {
+        if ip.is_ipv4() {
+            32
         } else {
-            match (&ip, &self.ip) {
-                (IpAddr::V4(other), IpAddr::V4(my)) => {
-                    let other = u32::from_be_bytes(other.octets()) >> (32u8 - self.gate);
-                    let my = u32::from_be_bytes(my.octets()) >> (32u8 - self.gate);
-                    other == my
-                }
-                _ => {
-                    ip == &self.ip
-                }
+            128
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // IPv4映射的IPv6地址按IPv4处理
+        let ip = ip.to_canonical();
+        match (&ip, &self.ip) {
+            (IpAddr::V4(other), IpAddr::V4(my)) => {
+                let shift = 32 - self.gate.min(32) as u32;
+                let other = u32::from_be_bytes(other.octets()).checked_shr(shift).unwrap_or(0);
+                let my = u32::from_be_bytes(my.octets()).checked_shr(shift).unwrap_or(0);
+                other == my
+            }
+            (IpAddr::V6(other), IpAddr::V6(my)) => {
+                let shift = 128 - self.gate.min(128) as u32;
+                let other = u128::from_be_bytes(other.octets()).checked_shr(shift).unwrap_or(0);
+                let my = u128::from_be_bytes(my.octets()).checked_shr(shift).unwrap_or(0);
+                other == my
             }
+            _ => false,
         }
     }
 }
@@ -44,7 +56,7 @@ impl FromStr for IpGate {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let vals = s.split("/").collect::<Vec<&str>>();
         let ip = vals[0].parse::<IpAddr>().map_err(|_| io::Error::new(io::ErrorKind::Other, "parse ip error"))?;
-        let mut gate = 0;
+        let mut gate = Self::max_gate(&ip);
         if vals.len() > 1 {
             gate = vals[1].parse::<u8>().map_err(|_| io::Error::new(io::ErrorKind::Other, "parse ip error"))?;
             if ip.is_ipv4() && gate > 32 {
@@ -61,7 +73,7 @@ impl FromStr for IpGate {
 
 impl Display for IpGate {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.gate > 0 {
+        if self.gate < Self::max_gate(&self.ip) {
             f.write_fmt(format_args!("{}/{}", self.ip, self.gate))
         } else {
             f.write_fmt(format_args!("{}", self.ip))
@@ -95,6 +107,12 @@ impl FromStr for IpSets {
         let vals = s.split_whitespace().collect::<Vec<&str>>();
         let mut ips = vec![];
         for v in vals {
+            // all表示所有的IPv4及IPv6地址
+            if v == "all" {
+                ips.push("0.0.0.0/0".parse::<IpGate>()?);
+                ips.push("::/0".parse::<IpGate>()?);
+                continue;
+            }
             ips.push(v.parse::<IpGate>()?);
         }
         Ok(IpSets { ips })
@@ -111,11 +129,49 @@ impl Display for IpSets {
     }
 }
 
+/// 按顺序匹配的访问规则, 如`allow 10.0.0.0/8`或`deny all`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRule {
+    pub allow: bool,
+    pub ips: IpSets,
+}
+
+impl AccessRule {
+    /// 规则列表中第一个匹配的规则决定是否允许, 均未匹配时返回None
+    pub fn check(rules: &[AccessRule], ip: &IpAddr) -> Option<bool> {
+        rules.iter().find(|r| r.ips.contains(ip)).map(|r| r.allow)
+    }
+}
+
+impl FromStr for AccessRule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (oper, ips) = s.trim().split_once(char::is_whitespace).unwrap_or((s.trim(), ""));
+        let allow = match oper {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(io::Error::other("unknown access oper")),
+        };
+        let ips = ips.parse::<IpSets>()?;
+        if ips.ips.is_empty() {
+            return Err(io::Error::other("access rule without ip"));
+        }
+        Ok(AccessRule { allow, ips })
+    }
+}
+
+impl Display for AccessRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let oper = if self.allow { "allow" } else { "deny" };
+        f.write_fmt(format_args!("{} {}", oper, self.ips.to_string().trim_end()))
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use std::net::{Ipv4Addr, IpAddr};
-    use crate::IpSets;
+    use crate::{AccessRule, IpSets};
     
     #[test]
     fn do_test() {
@@ -126,7 +182,42 @@ mod tests {
         assert_eq!(ips.ips[1].gate, 24);
         assert!(ips.contains(&ip_local));
         assert!(ips.contains(&IpAddr::V4(Ipv4Addr::new(255, 255, 255, 128))));
+        assert!(!ips.contains(&IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))));
+        assert!(ips.contains(&"::ffff:127.0.0.1".parse().unwrap()));
+        assert_eq!(ips.to_string(), "127.0.0.1 255.255.255.0/24 ");
     }
-}
 
+    #[test]
+    fn test_ipv6() {
+        let ips = "2001:db8::/32 ::1".parse::<IpSets>().unwrap();
+        assert!(ips.contains(&"2001:db8:1::8".parse().unwrap()));
+        assert!(!ips.contains(&"2001:db9::1".parse().unwrap()));
+        assert!(ips.contains(&"::1".parse().unwrap()));
+        assert!(!ips.contains(&"::2".parse().unwrap()));
+        assert!(!ips.contains(&"127.0.0.1".parse().unwrap()));
+
+        let all = "0.0.0.0/0 ::/0".parse::<IpSets>().unwrap();
+        assert!(all.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(all.contains(&"fe80::1".parse().unwrap()));
+        assert!("1.2.3.4/33".parse::<IpSets>().is_err());
+    }
 
+    #[test]
+    fn test_access_rule() {
+        let rules = ["allow 10.0.0.1", "deny 10.0.0.0/8 fd00::/8", "allow all"]
+            .iter()
+            .map(|r| r.parse::<AccessRule>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rules[1].to_string(), "deny 10.0.0.0/8 fd00::/8");
+        // 按顺序匹配, 第一个匹配的规则生效
+        assert_eq!(AccessRule::check(&rules, &"10.0.0.1".parse().unwrap()), Some(true));
+        assert_eq!(AccessRule::check(&rules, &"10.0.0.2".parse().unwrap()), Some(false));
+        assert_eq!(AccessRule::check(&rules, &"fd00::1".parse().unwrap()), Some(false));
+        assert_eq!(AccessRule::check(&rules, &"192.168.0.1".parse().unwrap()), Some(true));
+        assert_eq!(AccessRule::check(&rules[..2], &"192.168.0.1".parse().unwrap()), None);
+
+        assert!("permit 10.0.0.1".parse::<AccessRule>().is_err());
+        assert!("deny".parse::<AccessRule>().is_err());
+        assert!("deny 10.0.0.0/40".parse::<AccessRule>().is_err());
+    }
+}
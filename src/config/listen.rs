@@ -0,0 +1,125 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/27 15:06:42
+
+use std::{fmt::Display, io, net::SocketAddr, path::PathBuf, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use socket2::{Socket, TcpKeepalive};
+
+use super::ConfigDuration;
+
+/// 未配置时监听的等待队列长度
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// 监听的地址, 可为TCP地址如`127.0.0.1:80`, 或者unix socket如`unix:/run/wmproxy.sock`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn is_unix(&self) -> bool {
+        matches!(self, ListenAddr::Unix(_))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if !path.starts_with('/') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unix socket须为绝对路径: {}", s),
+                ));
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse::<SocketAddr>().map(ListenAddr::Tcp).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("无效的监听地址: {}", s),
+            )
+        })
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// 监听的socket选项, 绑定后设置到监听上, 接收的连接继承该设置
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketOptions {
+    /// 是否关闭Nagle算法
+    pub tcp_nodelay: Option<bool>,
+    /// 开启SO_KEEPALIVE, 连接空闲该时间后开始探测
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub keepalive: Option<ConfigDuration>,
+    /// 探测的间隔, 需同时配置keepalive
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub keepalive_interval: Option<ConfigDuration>,
+    /// 等待accept的连接队列长度, 默认128
+    pub backlog: Option<i32>,
+}
+
+impl SocketOptions {
+    pub fn backlog(&self) -> i32 {
+        self.backlog.unwrap_or(DEFAULT_BACKLOG)
+    }
+
+    /// 设置TCP相关的选项, unix socket无需设置
+    pub fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(nodelay) = self.tcp_nodelay {
+            socket.set_nodelay(nodelay)?;
+        }
+        if let Some(time) = &self.keepalive {
+            let mut keepalive = TcpKeepalive::new().with_time(time.0);
+            if let Some(interval) = &self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval.0);
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::PathBuf};
+
+    use super::ListenAddr;
+
+    #[test]
+    fn test_listen_addr() {
+        let addr = "unix:/run/wmproxy.sock".parse::<ListenAddr>().unwrap();
+        assert_eq!(addr, ListenAddr::Unix(PathBuf::from("/run/wmproxy.sock")));
+        assert_eq!(addr.to_string(), "unix:/run/wmproxy.sock");
+        let addr = "127.0.0.1:80".parse::<ListenAddr>().unwrap();
+        assert_eq!(
+            addr,
+            ListenAddr::Tcp("127.0.0.1:80".parse::<SocketAddr>().unwrap())
+        );
+        assert!(!addr.is_unix());
+        assert!("unix:run.sock".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+}
@@ -15,9 +15,13 @@ mod size;
 mod duration;
 mod log;
 mod header;
+mod header_policy;
 mod rate;
 mod ip_sets;
 mod wrap;
+mod response_id;
+mod listen;
+mod error;
 
 use std::{str::FromStr, fmt::{Display, self}, marker::PhantomData};
 
@@ -25,11 +29,15 @@ pub use self::size::ConfigSize;
 pub use self::duration::ConfigDuration;
 pub use self::log::ConfigLog;
 pub use self::header::{ConfigHeader, HeaderOper};
+pub use self::header_policy::{HeaderCase, HeaderPolicy, SINGLETON_HEADERS};
 pub use self::rate::ConfigRate;
 pub use self::ip_sets::*;
 pub use self::wrap::*;
+pub use self::response_id::{ConfigResponseId, UpstreamResponseId};
+pub use self::listen::{ListenAddr, SocketOptions};
+pub use self::error::ConfigError;
 
-use serde::{Serializer, Deserializer, de::{Visitor, Error, self}};
+use serde::{Serializer, Deserialize, Deserializer, de::{Visitor, Error, self}};
 use serde_with::{SerializeAs, DeserializeAs};
 
 pub(crate) struct DisplayFromStrOrNumber;
@@ -84,4 +92,93 @@ where
 
         deserializer.deserialize_any(Helper(PhantomData))
     }
-}
\ No newline at end of file
+}
+
+/// 从字符串或字符串数组中解析, 数组的各项以空格连接, 包含空格的项将加上引号
+pub(crate) struct DisplayFromStrOrSeq;
+
+impl<T> SerializeAs<T> for DisplayFromStrOrSeq
+where
+    T: Display,
+{
+    fn serialize_as<S>(source: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(source)
+    }
+}
+
+impl<'de, T> DeserializeAs<'de, T> for DisplayFromStrOrSeq
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    fn deserialize_as<D>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct Helper<S>(PhantomData<S>);
+        impl<'de, S> Visitor<'de> for Helper<S>
+        where
+            S: FromStr,
+            <S as FromStr>::Err: Display,
+        {
+            type Value = S;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(formatter, "a string or an array of strings")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                value.parse::<Self::Value>().map_err(de::Error::custom)
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                value.to_string().parse::<Self::Value>().map_err(de::Error::custom)
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
+            where
+                E: de::Error,
+            {
+                value.to_string().parse::<Self::Value>().map_err(de::Error::custom)
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: de::SeqAccess<'de>,
+            {
+                /// 数组中的元素可为字符串或数字, 如`[301, "https://example.com"]`
+                #[derive(Deserialize)]
+                #[serde(untagged)]
+                enum Item {
+                    Str(String),
+                    Num(i64),
+                }
+                let mut vals = vec![];
+                while let Some(val) = seq.next_element::<Item>()? {
+                    let val = match val {
+                        Item::Str(val) => val,
+                        Item::Num(val) => val.to_string(),
+                    };
+                    if val.is_empty() || val.contains(char::is_whitespace) {
+                        let quote = if val.contains('"') { '\'' } else { '"' };
+                        vals.push(format!("{quote}{val}{quote}"));
+                    } else {
+                        vals.push(val);
+                    }
+                }
+                vals.join(" ").parse::<Self::Value>().map_err(de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Helper(PhantomData))
+    }
+}
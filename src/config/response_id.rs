@@ -0,0 +1,114 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/10 09:31:17
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::{Response, Serialize};
+
+use crate::Helper;
+
+/// 后端返回的请求ID, 存放在返回的extensions中用于访问日志
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamResponseId(pub String);
+
+/// 将后端返回头中的ID复制到返回给客户端的头中
+/// 格式为`from_header [to_header]`, 不配置`to_header`则与`from_header`相同
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigResponseId {
+    pub from: String,
+    pub to: String,
+}
+
+impl ConfigResponseId {
+    pub fn new(from: String, to: String) -> Self {
+        Self { from, to }
+    }
+
+    /// 复制后端返回的ID, 并记录到返回的extensions中
+    pub fn copy_response<T: Serialize>(&self, res: &mut Response<T>) {
+        let value = match res.headers().get_str_value(&self.from) {
+            Some(value) if !value.is_empty() => value,
+            _ => return,
+        };
+        if !self.from.eq_ignore_ascii_case(&self.to) {
+            res.headers_mut().insert(self.to.clone(), value.clone());
+        }
+        res.extensions_mut().insert(UpstreamResponseId(value));
+    }
+}
+
+impl FromStr for ConfigResponseId {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        match vals.len() {
+            1 => Ok(Self::new(vals[0].to_string(), vals[0].to_string())),
+            2 => Ok(Self::new(vals[0].to_string(), vals[1].to_string())),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "response_id must be `from_header [to_header]`",
+            )),
+        }
+    }
+}
+
+impl Display for ConfigResponseId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.from == self.to {
+            f.write_str(&self.from)
+        } else {
+            f.write_fmt(format_args!("{} {}", self.from, self.to))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use webparse::Response;
+
+    use super::{ConfigResponseId, UpstreamResponseId};
+
+    #[test]
+    fn test_response_id() {
+        let config = ConfigResponseId::from_str("x-trace-id  x-request-id").unwrap();
+        assert_eq!(config.from, "x-trace-id");
+        assert_eq!(config.to, "x-request-id");
+        assert_eq!(config.to_string(), "x-trace-id x-request-id");
+        let same = ConfigResponseId::from_str("x-trace-id").unwrap();
+        assert_eq!(same.to, "x-trace-id");
+        assert!(ConfigResponseId::from_str("").is_err());
+        assert!(ConfigResponseId::from_str("a b c").is_err());
+
+        let mut res = Response::builder()
+            .header("X-Trace-Id", "abc123")
+            .body(())
+            .unwrap();
+        config.copy_response(&mut res);
+        assert_eq!(
+            res.headers().get_str_value(&"x-request-id").unwrap(),
+            "abc123"
+        );
+        assert_eq!(
+            res.extensions().get::<UpstreamResponseId>(),
+            Some(&UpstreamResponseId("abc123".to_string()))
+        );
+
+        // 后端未返回时不添加
+        let mut res = Response::builder().body(()).unwrap();
+        config.copy_response(&mut res);
+        assert!(res.headers().get_str_value(&"x-request-id").is_none());
+        assert!(res.extensions().get::<UpstreamResponseId>().is_none());
+    }
+}
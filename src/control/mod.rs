@@ -11,5 +11,7 @@
 // Created Date: 2023/10/25 03:36:28
 
 mod server;
+mod shutdown;
 
-pub use server::ControlServer;
\ No newline at end of file
+pub use server::ControlServer;
+pub use shutdown::{Shutdown, ShutdownState};
\ No newline at end of file
@@ -12,13 +12,15 @@
 
 use std::sync::Arc;
 
-use crate::{arg, ConfigOption, Helper, ProxyResult, WMCore};
+use crate::{arg, ConfigOption, Helper, MetricsServer, ProxyResult, WMCore};
+
+use super::Shutdown;
 use async_trait::async_trait;
 use tokio::{
     net::TcpListener,
     sync::{
         mpsc::{channel, Receiver, Sender},
-        Mutex,
+        oneshot, Mutex,
     },
 };
 use webparse::{HeaderName, Request, Response};
@@ -65,35 +67,105 @@ impl ControlServer {
 
     pub async fn start_serve(mut self) -> ProxyResult<()> {
         let option = self.option.clone();
+        // 统计端口只绑定一次, 重新加载配置时保留
+        if let Some(addr) = option.metrics {
+            tokio::spawn(async move {
+                if let Err(e) = MetricsServer::bind(addr).await {
+                    log::info!("统计端口绑定失败：{}，原因：{:?}", addr, e);
+                }
+            });
+        }
         self.inner_start_server(option).await?;
-        Self::start_control(Arc::new(Mutex::new(self))).await?;
+        let control = Arc::new(Mutex::new(self));
+        Self::watch_terminate(control.clone());
+        Self::watch_hangup(control.clone());
+        Self::start_control(control).await?;
         Ok(())
     }
 
+    /// 收到SIGTERM时与`/stop`一致, 停止接收新连接并排空已有连接后退出
+    fn watch_terminate(control: Arc<Mutex<ControlServer>>) {
+        tokio::spawn(async move {
+            Shutdown::wait_terminate().await;
+            log::info!("收到SIGTERM信号, 开始关闭进程");
+            let (lame_duck, drain_timeout, sender) = {
+                let value = control.lock().await;
+                (
+                    value.option.lame_duck.0,
+                    value.option.drain_timeout.0,
+                    value.server_sender_close.clone(),
+                )
+            };
+            Shutdown::run(lame_duck, drain_timeout, sender).await;
+        });
+    }
+
+    /// 收到SIGHUP时与`/reload`一致, 重新加载配置, 失败时保留当前的服务
+    fn watch_hangup(control: Arc<Mutex<ControlServer>>) {
+        tokio::spawn(async move {
+            loop {
+                Shutdown::wait_hangup().await;
+                log::info!("收到SIGHUP信号, 开始重新加载配置");
+                let _ = control.lock().await.do_restart_serve().await;
+            }
+        });
+    }
+
+    /// 重新解析配置并启动新的服务, 新服务绑定完毕后旧服务停止接收连接, 已有连接按旧配置处理完毕
+    /// 解析, 检查或绑定失败时旧服务继续运行
     pub async fn do_restart_serve(&mut self) -> ProxyResult<()> {
-        let option = arg::parse_env().await?;
+        let ret = match arg::parse_env().await {
+            Ok(option) => self.reload_option(option).await,
+            Err(e) => Err(e),
+        };
+        match &ret {
+            Ok(_) => log::info!("重新加载配置成功"),
+            Err(e) => log::error!("重新加载配置失败, 继续使用当前的配置: {:?}", e),
+        }
+        ret
+    }
+
+    /// 检查配置及证书通过后才启动新的服务, 不通过时不影响当前的服务
+    async fn reload_option(&mut self, option: ConfigOption) -> ProxyResult<()> {
+        if let Some(http) = &option.http {
+            http.check_start()?;
+        }
         Helper::try_init_log(&option);
-        self.inner_start_server(option).await?;
-        Ok(())
+        self.inner_start_server(option).await
     }
 
     async fn inner_start_server(&mut self, option: ConfigOption) -> ProxyResult<()> {
         let sender = self.control_sender_close.clone();
         let (sender_no_listen, receiver_no_listen) = channel::<()>(1);
-        let sender_close = self.server_sender_close.take();
-        // 每次启动的时候将让控制计数+1
-        self.count += 1;
+        let (sender_ready, receiver_ready) = oneshot::channel::<ProxyResult<()>>();
+        // 新服务准备失败时不影响当前的服务, 因此此处不转移关闭权限
+        let sender_close = self.server_sender_close.clone();
+        let new_option = option.clone();
         tokio::spawn(async move {
             let mut proxy = WMCore::new(option);
-            // 将上一个进程的关闭权限交由下一个服务，只有等下一个服务准备完毕的时候才能关闭上一个服务
-            if let Err(e) = proxy.start_serve(receiver_no_listen, sender_close).await {
+            if let Err(e) = proxy.ready_serve().await {
+                let _ = sender_ready.send(Err(e));
+                return;
+            }
+            let _ = sender_ready.send(Ok(()));
+            // 只有等下一个服务准备完毕的时候才能关闭上一个服务
+            if let Err(e) = proxy.serve(receiver_no_listen, sender_close).await {
                 log::info!("处理失败服务进程失败: {:?}", e);
             }
             // 每次退出的时候将让控制计数-1，减到0则退出
             let _ = sender.send(()).await;
         });
-        self.server_sender_close = Some(sender_no_listen);
-        Ok(())
+        match receiver_ready.await {
+            Ok(Ok(())) => {
+                // 每次启动成功的时候将让控制计数+1
+                self.count += 1;
+                self.option = new_option;
+                self.server_sender_close = Some(sender_no_listen);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(crate::ProxyError::Extension("启动服务失败")),
+        }
     }
 
     async fn inner_operate(
@@ -103,20 +175,30 @@ impl ControlServer {
         let mut value = data.lock().await;
         match &**req.path() {
             "/reload" => {
-                // 将重新启动服务器
-                let _ = value.do_restart_serve().await;
+                // 将重新启动服务器, 失败时保留当前的服务
+                if let Err(e) = value.do_restart_serve().await {
+                    return Ok(Response::text()
+                        .status(500)
+                        .body(format!("重新加载配置失败: {:?}", e))
+                        .unwrap()
+                        .into_type());
+                }
                 return Ok(Response::text()
                     .body("重新加载配置成功")
                     .unwrap()
                     .into_type());
             }
             "/stop" => {
-                // 通知控制端关闭，控制端阻塞主线程，如果控制端退出后进程退出
-                if let Some(sender) = &value.server_sender_close {
-                    let _ = sender.send(()).await;
-                }
+                // 先进入lame duck阶段, 之后再通知服务停止接收连接并排空, 排空完毕后进程退出
+                let lame_duck = value.option.lame_duck.0;
+                let drain_timeout = value.option.drain_timeout.0;
+                let sender = value.server_sender_close.clone();
+                tokio::spawn(Shutdown::run(lame_duck, drain_timeout, sender));
                 return Ok(Response::text().body("关闭进程成功").unwrap().into_type());
             }
+            "/healthz" => {
+                return Ok(Shutdown::readiness());
+            }
             "/now" => {
                 if let Ok(data) = serde_json::to_string_pretty(&value.option) {
                     return Ok(Response::text()
@@ -210,3 +292,156 @@ impl ControlServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::ControlServer;
+    use crate::{ConfigOption, HttpConfig, LocationConfig, ReturnResponse, ServerConfig};
+
+    fn build_option(addr: SocketAddr, text: &str, ssl: bool) -> ConfigOption {
+        let bind = addr.to_string().parse().unwrap();
+        let mut server = if ssl {
+            ServerConfig::new_ssl(bind)
+        } else {
+            ServerConfig::new(bind)
+        };
+        let mut location = LocationConfig::new();
+        location.rule = "/".parse().unwrap();
+        location.return_response = Some(ReturnResponse::new(200, text.to_string()));
+        server.location.push(location);
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        let mut option = ConfigOption::default();
+        option.http = Some(http);
+        option.disable_control = true;
+        option.after_load_option().unwrap();
+        option
+    }
+
+    async fn request(addr: SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = "GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        // 返回的内容为v1或v2, 读取到内容后即结束
+        while !data.ends_with(b"v1") && !data.ends_with(b"v2") {
+            match tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&data).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reload_keep_old() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let option = build_option(addr, "v1", false);
+        let mut control = ControlServer::new(option.clone());
+        control.inner_start_server(option).await.unwrap();
+        assert_eq!(control.count, 1);
+        assert!(request(addr).await.ends_with("v1"));
+
+        // 新配置启动失败时保留当前的服务
+        let bad = build_option(addr, "v2", true);
+        assert!(control.inner_start_server(bad).await.is_err());
+        assert_eq!(control.count, 1);
+        assert!(request(addr).await.ends_with("v1"));
+
+        // 新服务启动后旧服务停止接收连接, 新的请求按新配置处理
+        let option = build_option(addr, "v2", false);
+        control.inner_start_server(option).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for _ in 0..4 {
+            assert!(request(addr).await.ends_with("v2"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_reject_invalid() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let option = build_option(addr, "v1", false);
+        let mut control = ControlServer::new(option.clone());
+        control.reload_option(option).await.unwrap();
+        assert!(request(addr).await.ends_with("v1"));
+
+        // 检查不通过的配置不会启动新的服务, 当前的服务继续按旧配置处理
+        let mut bad = build_option(addr, "v2", false);
+        let http = bad.http.as_mut().unwrap();
+        http.server[0].location[0].return_response = None;
+        assert!(control.reload_option(bad).await.is_err());
+
+        // 证书无法加载的配置同样不会启动
+        let mut bad = build_option(addr, "v2", false);
+        let http = bad.http.as_mut().unwrap();
+        http.server[0].client_ca = Some("not_exist_ca.pem".to_string());
+        bad.after_load_option().unwrap();
+        assert!(control.reload_option(bad).await.is_err());
+        assert_eq!(control.count, 1);
+        assert_eq!(control.option.http.as_ref().unwrap().server[0].client_ca, None);
+        for _ in 0..4 {
+            assert!(request(addr).await.ends_with("v1"));
+        }
+    }
+
+    async fn read_text(stream: &mut TcpStream) -> String {
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.ends_with(b"v1") && !data.ends_with(b"v2") {
+            match tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&data).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reload_listeners() {
+        let bind = || {
+            std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+        let (old_addr, new_addr) = (bind(), bind());
+        let option = build_option(old_addr, "v1", false);
+        let mut control = ControlServer::new(option.clone());
+        control.inner_start_server(option).await.unwrap();
+        let mut keep = TcpStream::connect(old_addr).await.unwrap();
+        let req = b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n";
+        keep.write_all(req).await.unwrap();
+        assert!(read_text(&mut keep).await.ends_with("v1"));
+
+        // 新增的地址开始监听, 移除的地址停止接收连接
+        let option = build_option(new_addr, "v2", false);
+        control.inner_start_server(option).await.unwrap();
+        assert!(request(new_addr).await.ends_with("v2"));
+        // 旧的监听在后台关闭, 等待其不再接收连接
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while TcpStream::connect(old_addr).await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // 已有的连接按旧配置处理完毕
+        keep.write_all(req).await.unwrap();
+        assert!(read_text(&mut keep).await.ends_with("v1"));
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/13 10:12:45
+
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::sync::{mpsc::Sender, watch};
+use webparse::Response;
+use wenmeng::RecvResponse;
+
+use crate::AccessStat;
+
+lazy_static! {
+    // 全局的关闭阶段, 只会向后推进
+    static ref GLOBAL_SHUTDOWN: watch::Sender<ShutdownState> =
+        watch::channel(ShutdownState::Running).0;
+}
+
+/// 关闭的阶段, 按`Running -> LameDuck -> Draining -> Closed`依次推进
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShutdownState {
+    /// 正常服务
+    Running,
+    /// 就绪检查返回503, 使负载均衡停止分配流量, 但仍正常处理所有连接
+    LameDuck,
+    /// 停止接收新连接, 等待已有连接处理完毕
+    Draining,
+    /// 已关闭
+    Closed,
+}
+
+impl ShutdownState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownState::Running => "running",
+            ShutdownState::LameDuck => "lame_duck",
+            ShutdownState::Draining => "draining",
+            ShutdownState::Closed => "closed",
+        }
+    }
+}
+
+/// 进程关闭的状态机
+pub struct Shutdown;
+
+impl Shutdown {
+    /// 排空时检查连接是否结束的间隔
+    const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+    pub fn state() -> ShutdownState {
+        *GLOBAL_SHUTDOWN.borrow()
+    }
+
+    /// 是否可以接收新的流量, 即`/healthz`是否返回200
+    pub fn is_ready() -> bool {
+        Self::state() == ShutdownState::Running
+    }
+
+    /// 推进到指定阶段, 不可回退, 成功推进时返回true
+    fn advance(state: ShutdownState) -> bool {
+        GLOBAL_SHUTDOWN.send_if_modified(|now| {
+            if *now < state {
+                *now = state;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// 就绪检查的返回
+    pub fn readiness() -> RecvResponse {
+        let state = Self::state();
+        let status = if state == ShutdownState::Running {
+            200
+        } else {
+            503
+        };
+        Response::text()
+            .status(status)
+            .body(state.as_str())
+            .unwrap()
+            .into_type()
+    }
+
+    /// 执行关闭流程, `lame_duck`期间照常服务, 之后通知`stop`停止接收新连接,
+    /// 再等待已有连接结束或者超过`drain_timeout`后关闭, 已在关闭流程中时返回false
+    pub async fn run(
+        lame_duck: Duration,
+        drain_timeout: Duration,
+        stop: Option<Sender<()>>,
+    ) -> bool {
+        if !Self::advance(ShutdownState::LameDuck) {
+            return false;
+        }
+        log::info!("进入lame duck阶段, 持续{:?}后开始排空连接", lame_duck);
+        tokio::time::sleep(lame_duck).await;
+
+        Self::advance(ShutdownState::Draining);
+        if let Some(stop) = stop {
+            let _ = stop.send(()).await;
+        }
+        let wait = async {
+            while AccessStat::active() > 0 {
+                tokio::time::sleep(Self::DRAIN_CHECK_INTERVAL).await;
+            }
+        };
+        if tokio::time::timeout(drain_timeout, wait).await.is_err() {
+            log::info!("排空连接超时, 剩余{}个连接将被关闭", AccessStat::active());
+        }
+        Self::advance(ShutdownState::Closed);
+        true
+    }
+
+    /// 等待进程收到SIGTERM信号, 非unix平台不会返回
+    pub async fn wait_terminate() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            if let Ok(mut term) = signal(SignalKind::terminate()) {
+                term.recv().await;
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+
+    /// 等待进程收到SIGHUP信号, 非unix平台不会返回
+    pub async fn wait_hangup() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            if let Ok(mut hangup) = signal(SignalKind::hangup()) {
+                hangup.recv().await;
+                return;
+            }
+        }
+        std::future::pending::<()>().await
+    }
+
+    /// 等待关闭流程结束
+    pub async fn wait_closed() {
+        let mut receiver = GLOBAL_SHUTDOWN.subscribe();
+        let _ = receiver
+            .wait_for(|state| *state == ShutdownState::Closed)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::{Shutdown, ShutdownState};
+
+    #[tokio::test]
+    async fn test_shutdown_state() {
+        assert!(Shutdown::is_ready());
+        assert_eq!(Shutdown::readiness().status().as_u16(), 200);
+
+        let (sender, mut receiver) = channel(1);
+        let run = tokio::spawn(Shutdown::run(
+            Duration::from_millis(200),
+            Duration::from_millis(100),
+            Some(sender),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(Shutdown::state(), ShutdownState::LameDuck);
+        assert_eq!(Shutdown::readiness().status().as_u16(), 503);
+        // lame duck期间尚未通知停止接收连接
+        assert!(receiver.try_recv().is_err());
+
+        assert!(receiver.recv().await.is_some());
+        assert!(Shutdown::state() >= ShutdownState::Draining);
+        Shutdown::wait_closed().await;
+        assert!(run.await.unwrap());
+        assert_eq!(Shutdown::state(), ShutdownState::Closed);
+        // 已关闭后不会重复执行
+        assert!(!Shutdown::run(Duration::ZERO, Duration::ZERO, None).await);
+    }
+}
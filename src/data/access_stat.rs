@@ -0,0 +1,208 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/08 09:41:37
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::ConfigLog;
+
+lazy_static! {
+    // 反向代理的连接及请求统计
+    static ref GLOBAL_ACCESS_STAT: AccessStat = AccessStat::new();
+}
+
+/// 客户端在收到返回前断开连接的请求记录的状态码
+pub const STATUS_CLIENT_CLOSED: u16 = 499;
+
+/// 连接未产生任何完整请求即结束的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnCloseReason {
+    /// TLS握手失败
+    Tls,
+    /// 未收到任何数据即关闭
+    Empty,
+    /// 收到部分请求后关闭或者被重置
+    Incomplete,
+    /// 请求无法解析
+    Parse,
+    /// 等待请求超时
+    Timeout,
+}
+
+impl ConnCloseReason {
+    pub const ALL: [ConnCloseReason; 5] = [
+        ConnCloseReason::Tls,
+        ConnCloseReason::Empty,
+        ConnCloseReason::Incomplete,
+        ConnCloseReason::Parse,
+        ConnCloseReason::Timeout,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnCloseReason::Tls => "tls",
+            ConnCloseReason::Empty => "empty",
+            ConnCloseReason::Incomplete => "incomplete",
+            ConnCloseReason::Parse => "parse_error",
+            ConnCloseReason::Timeout => "timeout",
+        }
+    }
+
+    fn index(&self) -> usize {
+        Self::ALL.iter().position(|r| r == self).unwrap()
+    }
+}
+
+impl Display for ConnCloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 处理该请求的location对应的访问日志, 存放于请求的extensions中
+/// 在返回发送完毕后写入, 以便记录实际发送的字节数
+#[derive(Debug, Clone, Default)]
+pub struct AccessTarget {
+    pub access: Option<ConfigLog>,
+    /// 日志格式, 已按`access.format`从log_format中取出
+    pub format: Option<String>,
+    /// 处理该请求的server名称, 可用`{server_name}`记录
+    pub server_name: Option<String>,
+    /// 处理该请求的location规则, 可用`{location}`记录
+    pub location: Option<String>,
+    /// 按split分流所选的upstream名称, 可用`{up_name}`记录
+    pub upstream: Option<String>,
+}
+
+impl AccessTarget {
+    /// 内置的`combined`格式, 在Apache的combined格式后附加server, location, 后端地址及耗时
+    pub const COMBINED_FORMAT: &'static str = r#"{client_ip} - - [{d(%d/%b/%Y:%H:%M:%S %z)}] "{request}" {status_code} {body_bytes_sent} "{referer}" "{user_agent}" {host} {server_name} {location} {up_addr} {request_time} {up_response_time}"#;
+    /// 内置的`json`格式, 每个请求输出一行JSON
+    pub const JSON_FORMAT: &'static str = r#"{{"time":"{d(%Y-%m-%dT%H:%M:%S%:z)}","client_ip":"{j({client_ip})}","method":"{j({method})}","host":"{j({host})}","url":"{j({url})}","server_name":"{j({server_name})}","location":"{j({location})}","status":{status_code},"body_bytes_sent":{body_bytes_sent},"up_addr":"{j({up_addr})}","request_time":{request_time},"up_response_time":"{up_response_time}","referer":"{j({referer})}","user_agent":"{j({user_agent})}"}}"#;
+
+    pub fn new(log_formats: &HashMap<String, String>, access: &Option<ConfigLog>) -> Self {
+        let format = access
+            .as_ref()
+            .and_then(|a| log_formats.get(&a.format).cloned());
+        Self {
+            access: access.clone(),
+            format,
+            server_name: None,
+            location: None,
+            upstream: None,
+        }
+    }
+
+    pub fn with_route(mut self, server_name: Option<String>, location: Option<String>) -> Self {
+        self.server_name = server_name;
+        self.location = location;
+        self
+    }
+}
+
+/// 连接及请求的统计, 每个连接只会记录为有请求的连接或者某个提前关闭的原因
+/// 即 `accepted = served + sum(closed)`, 而每个请求按返回的状态码记录一次
+pub struct AccessStat {
+    accepted: AtomicU64,
+    served: AtomicU64,
+    closed: [AtomicU64; 5],
+    requests: Mutex<HashMap<u16, u64>>,
+}
+
+/// 统计数据的快照
+#[derive(Debug, Clone, Default)]
+pub struct AccessStatSnapshot {
+    /// 收到的连接数
+    pub accepted: u64,
+    /// 至少处理过一个请求的连接数
+    pub served: u64,
+    /// 未产生请求即关闭的连接数
+    pub closed: HashMap<ConnCloseReason, u64>,
+    /// 各状态码的请求数
+    pub requests: HashMap<u16, u64>,
+}
+
+impl AccessStatSnapshot {
+    pub fn get_closed(&self, reason: ConnCloseReason) -> u64 {
+        self.closed.get(&reason).cloned().unwrap_or(0)
+    }
+
+    pub fn get_requests(&self, status: u16) -> u64 {
+        self.requests.get(&status).cloned().unwrap_or(0)
+    }
+}
+
+impl AccessStat {
+    fn new() -> Self {
+        Self {
+            accepted: AtomicU64::new(0),
+            served: AtomicU64::new(0),
+            closed: Default::default(),
+            requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 收到新的连接
+    pub fn on_accept() {
+        GLOBAL_ACCESS_STAT.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 连接结束, 且处理过请求
+    pub fn on_served() {
+        GLOBAL_ACCESS_STAT.served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 连接结束, 且未产生任何请求
+    pub fn on_close(reason: ConnCloseReason) {
+        GLOBAL_ACCESS_STAT.closed[reason.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 请求结束, 包括生成的错误返回
+    pub fn on_request(status: u16) {
+        if let Ok(mut requests) = GLOBAL_ACCESS_STAT.requests.lock() {
+            *requests.entry(status).or_insert(0) += 1;
+        }
+    }
+
+    /// 当前仍在处理中的连接数
+    pub fn active() -> u64 {
+        let stat = &*GLOBAL_ACCESS_STAT;
+        let finished = stat.served.load(Ordering::Relaxed)
+            + stat
+                .closed
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .sum::<u64>();
+        stat.accepted.load(Ordering::Relaxed).saturating_sub(finished)
+    }
+
+    pub fn snapshot() -> AccessStatSnapshot {
+        let stat = &*GLOBAL_ACCESS_STAT;
+        let mut closed = HashMap::new();
+        for reason in ConnCloseReason::ALL {
+            closed.insert(reason, stat.closed[reason.index()].load(Ordering::Relaxed));
+        }
+        AccessStatSnapshot {
+            accepted: stat.accepted.load(Ordering::Relaxed),
+            served: stat.served.load(Ordering::Relaxed),
+            closed,
+            requests: stat
+                .requests
+                .lock()
+                .map(|r| r.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/17 09:52:18
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::channel;
+use webparse::{Binary, BinaryMut, HeaderName, Request, Response, Version};
+use wenmeng::{Body, Consts, HeaderHelper};
+
+use crate::reverse::BodyLimit;
+
+/// 单个请求收发的字节数及耗时, 存放于请求的extensions中
+/// 返回体按实际发送的内容(压缩后)计算, 不含chunked的分块信息
+#[derive(Debug)]
+pub struct BodyBytes {
+    /// 开始处理该请求的时间
+    start: Instant,
+    /// 收到的请求体大小
+    request: AtomicU64,
+    /// 返回头的大小
+    header: AtomicU64,
+    /// 发送的返回体大小
+    body: AtomicU64,
+}
+
+impl Default for BodyBytes {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            request: AtomicU64::new(0),
+            header: AtomicU64::new(0),
+            body: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BodyBytes {
+    /// 开始统计该请求, 声明了Content-Length的请求体按声明的长度计算
+    pub fn start(req: &mut Request<Body>) -> Arc<BodyBytes> {
+        let bytes = Arc::new(BodyBytes::default());
+        bytes.add_request(req.get_body_len().max(0) as u64);
+        req.extensions_mut().insert(bytes.clone());
+        bytes
+    }
+
+    pub fn get(req: &Request<Body>) -> Option<&Arc<BodyBytes>> {
+        req.extensions().get::<Arc<BodyBytes>>()
+    }
+
+    pub fn add_request(&self, n: u64) {
+        self.request.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn request(&self) -> u64 {
+        self.request.load(Ordering::Relaxed)
+    }
+
+    pub fn header(&self) -> u64 {
+        self.header.load(Ordering::Relaxed)
+    }
+
+    pub fn body(&self) -> u64 {
+        self.body.load(Ordering::Relaxed)
+    }
+
+    /// 从开始处理到当前的耗时, 流式的返回在发送完毕后记录, 即为请求的总耗时
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// 统计已完整的返回, 按发送时的编码计算大小, 未完整的返回不做处理并返回false
+    pub async fn count_complete(&self, res: &mut Response<Body>) -> io::Result<bool> {
+        if !res.body().is_end() {
+            return Ok(false);
+        }
+        let compress = Self::passthrough(res);
+        let mut data = vec![];
+        let mut buf = vec![0u8; 8192];
+        loop {
+            match BodyLimit::read_some(res.body_mut(), &mut buf).await? {
+                0 => break,
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+        // 与服务端发送时一致, 未声明长度时补充Content-Length
+        if !res.headers().is_chunked() && res.get_body_len() <= 0 {
+            res.headers_mut()
+                .insert(HeaderName::CONTENT_LENGTH, data.len());
+        }
+        self.body.store(data.len() as u64, Ordering::Relaxed);
+        self.count_header(res);
+        let mut body = Body::only(Binary::from(data));
+        body.set_origin_compress_method(compress);
+        *res.body_mut() = body;
+        Ok(true)
+    }
+
+    /// 统计流式的返回, 转发时计数, 发送完毕或者客户端断开后调用`done`
+    pub fn count_stream<F>(self: &Arc<Self>, res: &mut Response<Body>, done: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let compress = Self::passthrough(res);
+        if compress != Consts::COMPRESS_METHOD_NONE
+            && res.version() == Version::Http11
+            && !res.headers().is_chunked()
+            && res.get_body_len() <= 0
+        {
+            res.headers_mut()
+                .insert(HeaderName::TRANSFER_ENCODING, "chunked");
+        }
+        self.count_header(res);
+        let mut origin = std::mem::replace(res.body_mut(), Body::empty());
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        let bytes = self.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 8192];
+            loop {
+                let mut data = vec![];
+                // 结束前读取剩余的全部内容, 以便在客户端收到结束前完成统计
+                let ret = loop {
+                    match BodyLimit::read_some(&mut origin, &mut buf).await {
+                        Ok(0) => break Ok(true),
+                        Ok(n) => {
+                            data.extend_from_slice(&buf[..n]);
+                            if !origin.is_end() {
+                                break Ok(false);
+                            }
+                        }
+                        Err(e) => break Err(e),
+                    }
+                };
+                bytes.body.fetch_add(data.len() as u64, Ordering::Relaxed);
+                match ret {
+                    Ok(false) => {
+                        if sender.send((false, Binary::from(data))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(true) => {
+                        done();
+                        let _ = sender.send((true, Binary::from(data))).await;
+                        return;
+                    }
+                    Err(e) => {
+                        log::trace!("读取返回内容失败:{:?}", e);
+                        break;
+                    }
+                }
+            }
+            done();
+        });
+        let mut body = Body::new(receiver, BinaryMut::new(), false);
+        body.set_origin_compress_method(compress);
+        *res.body_mut() = body;
+    }
+
+    /// 原始的返回体按发送时的压缩方式输出, 返回该压缩方式, 转发后的返回体不再重复压缩
+    fn passthrough(res: &mut Response<Body>) -> i8 {
+        let compress = HeaderHelper::get_compress_method(res.headers());
+        res.body_mut().add_compress_method(compress);
+        compress
+    }
+
+    fn count_header(&self, res: &mut Response<Body>) {
+        let mut buf = BinaryMut::new();
+        let size = res.encode_header(&mut buf).unwrap_or(0);
+        self.header.store(size as u64, Ordering::Relaxed);
+    }
+}
@@ -11,8 +11,7 @@
 // Created Date: 2023/11/28 10:14:47
 
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::ops::Sub;
+use std::collections::{BTreeMap, HashMap};
 use std::time::{Duration, Instant};
 use std::{sync::RwLock};
 use wenmeng::{ProtError, ProtResult};
@@ -23,49 +22,66 @@ lazy_static! {
         RwLock::new(HashMap::new());
 }
 
+/// 未配置IP个数时最多记录的IP个数
+pub const DEFAULT_LIMIT_IPS: u64 = 100_000;
+
+/// 清理空闲IP的间隔
+pub const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 按IP的令牌桶限制, 记录的IP个数达到上限时淘汰最久未访问的IP
 pub struct LimitReqData {
     /// 记录所有的ip数据的限制情况
     ips: HashMap<String, InnerLimit>,
+    /// 按访问顺序记录的IP, 用于淘汰最久未访问的IP
+    order: BTreeMap<u64, String>,
+    /// 访问的序号
+    seq: u64,
     /// IP个数
     limit: u64,
     /// 周期内可以通行的数据
     nums: u64,
     /// 每个周期的时间
     per: Duration,
-
-    /// 最后清理IP的时间
-    last_remove: Instant,
+    /// 上次清理空闲IP的时间
+    purged: Instant,
+    /// 清理空闲IP的间隔
+    purge_interval: Duration,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum LimitResult {
     Ok,
-    Refuse,
-    Delay(Duration),
+    /// 拒绝该请求, 并返回可重试的时间
+    Refuse(Duration),
 }
 
 struct InnerLimit {
     last: Instant,
-    nums: u64,
+    /// 桶中剩余的令牌
+    tokens: f64,
+    seq: u64,
 }
 
 impl InnerLimit {
-    pub fn new() -> Self {
+    pub fn new(tokens: f64, seq: u64) -> Self {
         Self {
             last: Instant::now(),
-            nums: 1,
+            tokens,
+            seq,
         }
     }
 
-    pub fn recv_req(&mut self, per: &Duration) -> u64 {
-        let now = Instant::now();
-        if &now.sub(self.last) > per {
-            self.nums = 1;
+    /// 按流逝的时间补充令牌, 并尝试取出一个令牌
+    pub fn recv_req(&mut self, now: Instant, rate: f64, capacity: f64) -> LimitResult {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            LimitResult::Ok
         } else {
-            self.nums += 1;
+            LimitResult::Refuse(Duration::from_secs_f64((1.0 - self.tokens) / rate))
         }
-        self.last = now;
-        self.nums
     }
 }
 
@@ -73,58 +89,74 @@ impl LimitReqData {
     pub fn new(limit: u64, nums: u64, per: Duration) -> Self {
         Self {
             ips: HashMap::new(),
-            limit,
+            order: BTreeMap::new(),
+            seq: 0,
+            limit: if limit == 0 { DEFAULT_LIMIT_IPS } else { limit },
             nums,
             per,
-            last_remove: Instant::now(),
+            purged: Instant::now(),
+            purge_interval: PURGE_INTERVAL,
         }
     }
 
-    pub fn try_remove_unuse(&mut self) {
-        // 未超过限制数
-        if self.ips.len() < self.limit as usize / 10 {
-            return;
-        }
-
-        let now = Instant::now();
-
-        // 未超过当前时间轮回的100倍
-        if now.sub(self.last_remove) < 100 * self.per {
-            return;
-        }
-
-        self.last_remove = now;
-
-        let mut remove_keys = vec![];
-        for (key, value) in &self.ips {
-            if now.sub(value.last) > 50 * self.per {
-                remove_keys.push(key.clone());
+    /// 淘汰令牌已补满的空闲IP, 补满后与新的IP等同, 淘汰后不影响限制的结果
+    fn purge(&mut self, now: Instant, idle: Duration) {
+        self.purged = now;
+        while let Some((_, ip)) = self.order.first_key_value() {
+            let expired = self
+                .ips
+                .get(ip)
+                .map(|inner| now.saturating_duration_since(inner.last) >= idle)
+                .unwrap_or(true);
+            if !expired {
+                break;
+            }
+            if let Some((_, ip)) = self.order.pop_first() {
+                self.ips.remove(&ip);
             }
         }
+    }
 
-        for key in remove_keys {
-            self.ips.remove(&key);
+    /// 每秒补充的令牌数
+    fn rate(&self) -> f64 {
+        let per = self.per.as_secs_f64();
+        if per <= 0.0 {
+            return f64::MAX;
         }
+        self.nums as f64 / per
     }
 
     pub fn inner_recv_new_req(&mut self, ip: &String, burst: u64) -> ProtResult<LimitResult> {
-        self.try_remove_unuse();
-        if self.ips.len() >= self.limit as usize {
-            return Ok(LimitResult::Refuse);
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return Ok(LimitResult::Ok);
+        }
+        let capacity = (self.nums + burst) as f64;
+        let now = Instant::now();
+        if now.saturating_duration_since(self.purged) >= self.purge_interval {
+            self.purge(now, Duration::from_secs_f64(capacity / rate));
+        }
+        self.seq += 1;
+        let seq = self.seq;
+        if let Some(inner) = self.ips.get_mut(ip) {
+            self.order.remove(&inner.seq);
+            self.order.insert(seq, ip.clone());
+            inner.seq = seq;
+            return Ok(inner.recv_req(now, rate, capacity));
         }
-        if self.ips.contains_key(ip) {
-            let nums = self.ips.get_mut(ip).unwrap().recv_req(&self.per);
-            if nums <= self.nums {
-                return Ok(LimitResult::Ok);
-            } else if nums <= self.nums + burst {
-                return Ok(LimitResult::Delay(self.per));
-            } else {
-                return Ok(LimitResult::Refuse);
+        while self.ips.len() as u64 >= self.limit {
+            match self.order.pop_first() {
+                Some((_, old)) => {
+                    self.ips.remove(&old);
+                }
+                None => break,
             }
-        } else {
-            self.ips.insert(ip.clone(), InnerLimit::new());
         }
-        Ok(LimitResult::Ok)
+        self.order.insert(seq, ip.clone());
+        let mut inner = InnerLimit::new(capacity, seq);
+        let ret = inner.recv_req(now, rate, capacity);
+        self.ips.insert(ip.clone(), inner);
+        Ok(ret)
     }
 
     pub fn cache(key: String, limit: u64, nums: u64, per: Duration) -> ProtResult<()> {
@@ -148,3 +180,57 @@ impl LimitReqData {
         write.get_mut(key).unwrap().inner_recv_new_req(ip, burst)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{LimitReqData, LimitResult};
+
+    #[test]
+    fn test_token_bucket() {
+        let mut data = LimitReqData::new(0, 2, Duration::from_secs(1));
+        let ip = "127.0.0.1".to_string();
+        // 容量为速率加上burst
+        for _ in 0..3 {
+            assert_eq!(data.inner_recv_new_req(&ip, 1).unwrap(), LimitResult::Ok);
+        }
+        match data.inner_recv_new_req(&ip, 1).unwrap() {
+            LimitResult::Refuse(retry) => assert!(retry <= Duration::from_millis(500)),
+            LimitResult::Ok => panic!("should refuse"),
+        }
+        // 其它IP不受影响
+        let other = "127.0.0.2".to_string();
+        assert_eq!(data.inner_recv_new_req(&other, 1).unwrap(), LimitResult::Ok);
+    }
+
+    #[test]
+    fn test_lru_bound() {
+        let mut data = LimitReqData::new(2, 1, Duration::from_secs(60));
+        let (a, b, c) = ("a".to_string(), "b".to_string(), "c".to_string());
+        assert_eq!(data.inner_recv_new_req(&a, 0).unwrap(), LimitResult::Ok);
+        assert_eq!(data.inner_recv_new_req(&b, 0).unwrap(), LimitResult::Ok);
+        // 访问a后, b为最久未访问的IP
+        assert!(data.inner_recv_new_req(&a, 0).unwrap() != LimitResult::Ok);
+        assert_eq!(data.inner_recv_new_req(&c, 0).unwrap(), LimitResult::Ok);
+        assert_eq!(data.ips.len(), 2);
+        assert!(data.ips.contains_key(&a) && !data.ips.contains_key(&b));
+        assert_eq!(data.order.len(), 2);
+        // b被淘汰后重新计算
+        assert_eq!(data.inner_recv_new_req(&b, 0).unwrap(), LimitResult::Ok);
+    }
+
+    #[test]
+    fn test_purge_idle() {
+        let mut data = LimitReqData::new(0, 1, Duration::from_millis(1));
+        data.purge_interval = Duration::ZERO;
+        let (a, b) = ("a".to_string(), "b".to_string());
+        assert_eq!(data.inner_recv_new_req(&a, 0).unwrap(), LimitResult::Ok);
+        // 容量为1, 1ms即可补满令牌
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(data.inner_recv_new_req(&b, 0).unwrap(), LimitResult::Ok);
+        assert!(!data.ips.contains_key(&a));
+        assert_eq!(data.ips.len(), 1);
+        assert_eq!(data.order.len(), 1);
+    }
+}
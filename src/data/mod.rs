@@ -11,6 +11,14 @@
 // Created Date: 2023/11/28 10:14:24
 
 
+mod access_stat;
+mod body_bytes;
 mod limit_req_data;
+mod upstream_timing;
 
-pub use limit_req_data::{LimitReqData, LimitResult};
\ No newline at end of file
+pub use access_stat::{
+    AccessStat, AccessTarget, AccessStatSnapshot, ConnCloseReason, STATUS_CLIENT_CLOSED,
+};
+pub use body_bytes::BodyBytes;
+pub use limit_req_data::{LimitReqData, LimitResult};
+pub use upstream_timing::{UpstreamTiming, UpstreamTimingStat, TIMING_BUCKETS};
\ No newline at end of file
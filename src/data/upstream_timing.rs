@@ -0,0 +1,174 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/06 09:21:10
+
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::AtomicHistogram;
+
+lazy_static! {
+    // 每个后端地址的各阶段耗时统计, 仅首次访问该后端时写入
+    static ref GLOBAL_UPSTREAM_TIMING: RwLock<HashMap<SocketAddr, Arc<UpstreamTimingStat>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// 直方图的桶, 单位毫秒
+pub const TIMING_BUCKETS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// 单次访问后端的各阶段耗时, 只在阶段切换时读取单调时钟
+#[derive(Debug, Clone)]
+pub struct UpstreamTiming {
+    /// 后端地址
+    pub addr: Option<SocketAddr>,
+    /// 是否复用的连接, 复用时连接及握手耗时为0
+    pub reused: bool,
+    /// 是否无需解析域名, 如直接配置IP或者由upstream给出地址
+    pub dns_cached: bool,
+    /// 域名解析耗时
+    pub dns: Duration,
+    /// TCP连接耗时
+    pub connect: Duration,
+    /// TLS握手耗时
+    pub tls: Duration,
+    /// 从发送请求到收到返回头的耗时
+    pub header: Duration,
+    /// 从收到返回头到返回体转发完毕的耗时
+    pub body: Duration,
+    last: Instant,
+}
+
+impl UpstreamTiming {
+    pub fn new(reused: bool) -> Self {
+        Self {
+            addr: None,
+            reused,
+            dns_cached: false,
+            dns: Duration::ZERO,
+            connect: Duration::ZERO,
+            tls: Duration::ZERO,
+            header: Duration::ZERO,
+            body: Duration::ZERO,
+            last: Instant::now(),
+        }
+    }
+
+    /// 获取距离上一阶段的耗时, 并开始新的阶段
+    pub fn mark(&mut self) -> Duration {
+        let now = Instant::now();
+        let cost = now.duration_since(self.last);
+        self.last = now;
+        cost
+    }
+
+    /// 访问后端的总耗时
+    pub fn response_time(&self) -> Duration {
+        self.dns + self.connect + self.tls + self.header + self.body
+    }
+
+    /// 获取该后端的统计, 首次访问时创建
+    fn stat_of(addr: SocketAddr) -> Option<Arc<UpstreamTimingStat>> {
+        if let Some(stat) = GLOBAL_UPSTREAM_TIMING.read().ok()?.get(&addr) {
+            return Some(stat.clone());
+        }
+        let mut map = GLOBAL_UPSTREAM_TIMING.write().ok()?;
+        Some(map.entry(addr).or_default().clone())
+    }
+
+    /// 收到返回头后将本次的耗时记录到该后端的统计中
+    pub fn record(&self) {
+        if let Some(stat) = self.addr.and_then(Self::stat_of) {
+            stat.record(self);
+        }
+    }
+
+    /// 返回体转发完毕后记录返回体的传输耗时
+    pub fn record_body(&mut self) {
+        self.body = self.mark();
+        if let Some(stat) = self.addr.and_then(Self::stat_of) {
+            stat.record_body(self.body);
+        }
+    }
+
+    /// 获取该后端最后一次访问的耗时
+    pub fn last_attempt(addr: &SocketAddr) -> Option<UpstreamTiming> {
+        Self::get_stat(addr)?.last()
+    }
+
+    /// 获取该后端的耗时统计
+    pub fn get_stat(addr: &SocketAddr) -> Option<Arc<UpstreamTimingStat>> {
+        GLOBAL_UPSTREAM_TIMING.read().ok()?.get(addr).cloned()
+    }
+
+    /// 获取所有后端的耗时统计
+    pub fn all_stats() -> Vec<(SocketAddr, Arc<UpstreamTimingStat>)> {
+        if let Ok(map) = GLOBAL_UPSTREAM_TIMING.read() {
+            return map.iter().map(|(k, v)| (*k, v.clone())).collect();
+        }
+        vec![]
+    }
+}
+
+/// 单个后端的各阶段统计, 记录时只进行原子操作
+#[derive(Debug, Default)]
+pub struct UpstreamTimingStat {
+    pub dns: AtomicHistogram,
+    pub connect: AtomicHistogram,
+    pub tls: AtomicHistogram,
+    pub header: AtomicHistogram,
+    pub body: AtomicHistogram,
+    /// 复用连接的次数
+    reused: AtomicU64,
+    /// 最后一次的访问耗时
+    last: Mutex<Option<UpstreamTiming>>,
+}
+
+impl UpstreamTimingStat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 复用连接的次数
+    pub fn reused(&self) -> u64 {
+        self.reused.load(Ordering::Relaxed)
+    }
+
+    /// 最后一次的访问耗时
+    pub fn last(&self) -> Option<UpstreamTiming> {
+        self.last.lock().ok()?.clone()
+    }
+
+    fn record(&self, timing: &UpstreamTiming) {
+        if timing.reused {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+        }
+        self.dns.observe(timing.dns);
+        self.connect.observe(timing.connect);
+        self.tls.observe(timing.tls);
+        self.header.observe(timing.header);
+        if let Ok(mut last) = self.last.lock() {
+            *last = Some(timing.clone());
+        }
+    }
+
+    fn record_body(&self, body: Duration) {
+        self.body.observe(body);
+        if let Ok(mut last) = self.last.lock() {
+            if let Some(last) = last.as_mut() {
+                last.body = body;
+            }
+        }
+    }
+}
@@ -24,7 +24,8 @@ use std::{
 use crate::{
     log::{writer::simple::SimpleWriter, Encode, PatternEncoder, ProxyRecord},
     prot::{ProtFrame, ProtFrameHeader},
-    ConfigHeader, ConfigLog, ConfigOption, HeaderOper, ProxyResult,
+    AccessTarget, ConfigHeader, ConfigLog, ConfigOption, ConnCloseReason, HeaderOper, PathCaptures,
+    ProxyError, ProxyResult, RequestId, SocketOptions, TlsConnection,
 };
 use lazy_static::lazy_static;
 use log::{log_enabled, Level, LevelFilter, Record};
@@ -35,7 +36,7 @@ use log4rs::{
 use regex::Regex;
 use socket2::{Domain, Socket, Type};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use webparse::{http2::frame::read_u24, BinaryMut, Buf, Request, Response, Serialize};
+use webparse::{http2::frame::read_u24, BinaryMut, Buf, HeaderMap, Request, Response, Serialize};
 use wenmeng::{Body, HeaderHelper};
 
 thread_local! {
@@ -45,18 +46,32 @@ thread_local! {
 lazy_static! {
     /// 用静态变量存储log4rs的Handle
     static ref LOG4RS_HANDLE: Mutex<Option<log4rs::Handle>> = Mutex::new(None);
+    /// 本进程绑定过的unix socket, 重新加载配置时由新的服务接管
+    static ref UNIX_BOUND: Mutex<HashSet<std::path::PathBuf>> = Mutex::new(HashSet::new());
 }
 /// 帮助类相关
 pub struct Helper;
 
 impl Helper {
     pub fn decode_frame(read: &mut BinaryMut) -> ProxyResult<Option<ProtFrame>> {
+        Self::decode_frame_limit(read, ProtFrameHeader::MAX_FRAME_SIZE as usize)
+    }
+
+    /// 解析消息, 包体长度超过`max_frame_size`时返回错误, 防止对端声明过大的长度使其持续缓存
+    pub fn decode_frame_limit(
+        read: &mut BinaryMut,
+        max_frame_size: usize,
+    ) -> ProxyResult<Option<ProtFrame>> {
         let data_len = read.remaining();
         if data_len < 8 {
             return Ok(None);
         }
         let mut copy = read.clone();
         let length = read_u24(&mut copy);
+        if length as usize > max_frame_size {
+            log::warn!("内网穿透:消息长度{}超出限制{}", length, max_frame_size);
+            return Err(ProxyError::Extension("frame size too large"));
+        }
         let all_len = length as usize + ProtFrameHeader::FRAME_HEADER_BYTES;
         if all_len > data_len {
             return Ok(None);
@@ -87,6 +102,14 @@ impl Helper {
 
     /// 可端口复用的绑定方式，该端口可能被多个进程同时使用
     pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
+        Self::bind_with(addr, &SocketOptions::default()).await
+    }
+
+    /// 可端口复用的绑定方式, 绑定后设置`options`中的socket选项
+    pub async fn bind_with<A: ToSocketAddrs>(
+        addr: A,
+        options: &SocketOptions,
+    ) -> io::Result<TcpListener> {
         let addrs = addr.to_socket_addrs()?;
         let mut last_err = None;
         for addr in addrs {
@@ -104,7 +127,8 @@ impl Helper {
             socket.set_reuse_address(true)?;
             Self::set_reuse_port(&socket, true)?;
             socket.bind(&addr.into())?;
-            match socket.listen(128) {
+            options.apply(&socket)?;
+            match socket.listen(options.backlog()) {
                 Ok(_) => {
                     let listener: std::net::TcpListener = socket.into();
                     return TcpListener::from_std(listener);
@@ -124,6 +148,65 @@ impl Helper {
         }))
     }
 
+    /// 以SO_REUSEPORT在同一地址上绑定多个监听, 由内核将新连接分摊至各监听,
+    /// 仅Linux支持按监听分摊连接, 其它平台只绑定一个监听
+    pub async fn bind_workers(
+        addr: SocketAddr,
+        workers: usize,
+        options: &SocketOptions,
+    ) -> io::Result<Vec<TcpListener>> {
+        let first = Self::bind_with(addr, options).await?;
+        let workers = if cfg!(target_os = "linux") {
+            workers.max(1)
+        } else {
+            if workers > 1 {
+                log::warn!("当前平台不支持SO_REUSEPORT分摊连接, {:?}仅使用一个监听", addr);
+            }
+            1
+        };
+        // 端口为0时其余的监听需使用首个监听实际分配的端口
+        let local = first.local_addr()?;
+        let mut listeners = vec![first];
+        for _ in 1..workers {
+            listeners.push(Self::bind_with(local, options).await?);
+        }
+        Ok(listeners)
+    }
+
+    /// 绑定unix socket的监听, 已存在的socket文件无程序监听时视为残留文件并移除,
+    /// 本进程之前绑定的文件直接替换, 重新加载配置时旧的监听继续处理已建立的连接
+    #[cfg(unix)]
+    pub async fn bind_unix(
+        path: &std::path::Path,
+        options: &SocketOptions,
+    ) -> io::Result<tokio::net::UnixListener> {
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            use std::os::unix::fs::FileTypeExt;
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{}已存在且不是unix socket", path.display()),
+                ));
+            }
+            let owned = UNIX_BOUND.lock().unwrap().contains(path);
+            if !owned && std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("unix socket{}已被其它程序监听", path.display()),
+                ));
+            }
+            log::info!("移除已有的unix socket文件: {}", path.display());
+            remove_file(path)?;
+        }
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&socket2::SockAddr::unix(path)?)?;
+        socket.listen(options.backlog())?;
+        UNIX_BOUND.lock().unwrap().insert(path.to_path_buf());
+        let listener: std::os::unix::net::UnixListener = socket.into();
+        tokio::net::UnixListener::from_std(listener)
+    }
+
     /// 可端口复用的绑定方式，该端口可能被多个进程同时使用
     pub async fn bind_upd<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
         let addrs = addr.to_socket_addrs()?;
@@ -169,6 +252,7 @@ impl Helper {
     /// 尝试初始化, 如果已初始化则重新加载
     pub fn try_init_log(option: &ConfigOption) {
         let log_names = option.get_log_names();
+        let access_names = option.get_access_names();
         let mut log_config = log4rs::config::Config::builder();
         let mut root = Root::builder();
         for (name, path) in log_names {
@@ -183,18 +267,27 @@ impl Helper {
                     )
                 }
             };
-            // 设置默认的匹配类型打印时间信息
-            let parttern =
-                log4rs::encode::pattern::PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}");
-            let appender = FileAppender::builder()
-                .encoder(Box::new(parttern))
-                .build(path)
-                .unwrap();
+            // 设置默认的匹配类型打印时间信息, 访问日志按其配置的格式原样输出
+            let parttern = if access_names.contains(&name) {
+                log4rs::encode::pattern::PatternEncoder::new("{m}{n}")
+            } else {
+                log4rs::encode::pattern::PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {m}{n}")
+            };
+            // 路径为stdout时输出到标准输出
+            let appender: Box<dyn log4rs::append::Append> = if path == "stdout" {
+                Box::new(ConsoleAppender::builder().encoder(Box::new(parttern)).build())
+            } else {
+                Box::new(
+                    FileAppender::builder()
+                        .encoder(Box::new(parttern))
+                        .build(path)
+                        .unwrap(),
+                )
+            };
             if name == "default" {
                 root = root.appender(name.clone());
             }
-            log_config =
-                log_config.appender(Appender::builder().build(name.clone(), Box::new(appender)));
+            log_config = log_config.appender(Appender::builder().build(name.clone(), appender));
             log_config = log_config.logger(
                 Logger::builder()
                     .appender(name.clone())
@@ -228,6 +321,15 @@ impl Helper {
     }
 
     pub fn format_req(req: &Request<Body>, formats: &str) -> String {
+        Self::format_req_res(req, None, formats)
+    }
+
+    /// 格式化请求, 若有返回则同时格式化返回相关的数据
+    pub fn format_req_res(
+        req: &Request<Body>,
+        res: Option<&Response<Body>>,
+        formats: &str,
+    ) -> String {
         let pw = FORMAT_PATTERN_CACHE.with(|m| {
             if !m.borrow().contains_key(&formats) {
                 let p = PatternEncoder::new(formats);
@@ -240,7 +342,8 @@ impl Helper {
         });
 
         // 将其转化成Record然后进行encode
-        let record = ProxyRecord::new_req(Record::builder().level(Level::Info).build(), req);
+        let mut record = ProxyRecord::new_req(Record::builder().level(Level::Info).build(), req);
+        record.res = res;
         let mut buf = vec![];
         pw.encode(&mut SimpleWriter(&mut buf), &record).unwrap();
         String::from_utf8_lossy(&buf[..]).to_string()
@@ -318,6 +421,53 @@ impl Helper {
         Self::format_req(req, formats)
     }
 
+    /// 将nginx风格的日志变量转化成日志格式, 如`$remote_addr "$request" $status`转成
+    /// `{client_ip} "{request}" {status}`, 也可写成`${status}`, 未知的变量保持不变
+    pub fn convert_log_format(format: &str) -> String {
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"\$(?:\{([a-zA-Z_][a-zA-Z0-9_]*)\}|([a-zA-Z_][a-zA-Z0-9_]*))").unwrap();
+        };
+        if !format.contains('$') {
+            return format.to_string();
+        }
+        RE.replace_all(format, |caps: &regex::Captures| {
+            let name = caps.get(1).or(caps.get(2)).map(|m| m.as_str()).unwrap_or_default();
+            let chunk = match name {
+                "remote_addr" => "client_ip",
+                "remote_user" => "client_user",
+                "request" => "request",
+                "request_method" | "method" => "method",
+                "request_uri" => "url",
+                "uri" | "path" => "path",
+                "args" | "query_string" | "query" => "query",
+                "host" => "host",
+                "status" => "status",
+                "body_bytes_sent" | "body_bytes" => "body_bytes_sent",
+                "bytes_sent" => "bytes_sent",
+                "request_length" => "request_length",
+                "http_referer" => "referer",
+                "http_user_agent" => "user_agent",
+                "http_cookie" => "cookie",
+                "ssl_protocol" => "ssl_protocol",
+                "ssl_cipher" => "ssl_cipher",
+                "request_time" => "request_time",
+                "upstream_addr" => "up_addr",
+                "upstream_status" => "up_status",
+                "upstream_response_time" => "up_response_time",
+                "upstream_connect_time" => "up_connect_time",
+                "upstream_header_time" => "up_header_time",
+                "upstream_body_time" => "up_body_time",
+                "server_name" => "server_name",
+                "time_local" => "d(%d/%b/%Y:%H:%M:%S %z)",
+                "time_iso8601" => "d(%Y-%m-%dT%H:%M:%S%:z)",
+                _ => return caps[0].to_string(),
+            };
+            format!("{{{}}}", chunk)
+        })
+        .to_string()
+    }
+
     /// # Examples
     ///
     /// ```
@@ -363,32 +513,171 @@ impl Helper {
         log_formats: &HashMap<String, String>,
         access: &Option<ConfigLog>,
         req: &Request<Body>,
+    ) {
+        Self::inner_log_acess(log_formats, access, req, None)
+    }
+
+    /// 记录HTTP的访问数据及返回数据并将其格式化
+    pub fn log_acess_res(
+        log_formats: &HashMap<String, String>,
+        access: &Option<ConfigLog>,
+        req: &Request<Body>,
+        res: &Response<Body>,
+    ) {
+        Self::inner_log_acess(log_formats, access, req, Some(res))
+    }
+
+    fn inner_log_acess(
+        log_formats: &HashMap<String, String>,
+        access: &Option<ConfigLog>,
+        req: &Request<Body>,
+        res: Option<&Response<Body>>,
     ) {
         if let Some(access) = access {
             if let Some(formats) = log_formats.get(&access.format) {
                 // 需要先判断是否该日志已开启, 如果未开启直接写入将浪费性能
                 if log_enabled!(target: &access.name, access.level) {
                     // 将format转化成pattern会有相当的性能损失, 此处缓存pattern结果
-                    let value = Self::format_req(req, &*formats);
-                    match access.level {
-                        Level::Error => {
-                            log::error!(target: &access.name, "{}", value)
-                        }
-                        Level::Warn => {
-                            log::warn!(target: &access.name, "{}", value)
-                        }
-                        Level::Info => {
-                            log::info!(target: &access.name, "{}", value)
-                        }
-                        Level::Debug => {
-                            log::debug!(target: &access.name, "{}", value)
-                        }
-                        Level::Trace => {
-                            log::trace!(target: &access.name, "{}", value)
-                        }
-                    };
+                    let value = Self::format_req_res(req, res, formats);
+                    Self::write_access(access, &value);
+                }
+            }
+        }
+    }
+
+    /// 按location记录的访问日志写入请求及返回数据
+    pub fn log_access_target(target: &AccessTarget, req: &Request<Body>, res: &Response<Body>) {
+        if let (Some(access), Some(formats)) = (&target.access, &target.format) {
+            if log_enabled!(target: &access.name, access.level) {
+                let value = Self::format_req_res(req, Some(res), formats);
+                Self::write_access(access, &value);
+            }
+        }
+    }
+
+    /// 记录未产生完整请求即关闭的连接
+    pub fn log_conn_close(
+        access: &Option<ConfigLog>,
+        addr: SocketAddr,
+        reason: ConnCloseReason,
+        bytes: u64,
+    ) {
+        if let Some(access) = access {
+            if log_enabled!(target: &access.name, access.level) {
+                let value = format!("{} conn_close reason={} bytes={}", addr, reason, bytes);
+                Self::write_access(access, &value);
+            }
+        }
+    }
+
+    fn write_access(access: &ConfigLog, value: &str) {
+        match access.level {
+            Level::Error => {
+                log::error!(target: &access.name, "{}", value)
+            }
+            Level::Warn => {
+                log::warn!(target: &access.name, "{}", value)
+            }
+            Level::Info => {
+                log::info!(target: &access.name, "{}", value)
+            }
+            Level::Debug => {
+                log::debug!(target: &access.name, "{}", value)
+            }
+            Level::Trace => {
+                log::trace!(target: &access.name, "{}", value)
+            }
+        }
+    }
+
+    /// 将头信息的值中的变量替换成请求中对应的值, 未知的变量保持不变
+    /// 支持`$host`, `$remote_addr`, `$remote_port`, `$scheme`, `$request_uri`, `$uri`(`$path`), `$args`(`$query`)
+    /// `$request_id`, location正则的分组`$1`, `$cookie_<name>`及`$http_<name>`
+    /// 变量可写成`${host}`的形式, 以便与后续的字符相连, 此时值不再按日志格式转化
+    /// 包含`{`的值按日志格式进行转化, 如`{client_ip}`
+    pub fn format_header_value(req: &Request<Body>, value: &str) -> String {
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"\$(?:\{([a-zA-Z_][a-zA-Z0-9_]*)\}|([a-zA-Z_][a-zA-Z0-9_]*|[0-9]))")
+                    .unwrap();
+        };
+        let value = if value.contains('{') && !value.contains("${") {
+            Self::format_req(req, value)
+        } else {
+            value.to_string()
+        };
+        if value.contains('$') {
+            RE.replace_all(&value, |caps: &regex::Captures<'_>| {
+                let client = req.extensions().get::<SocketAddr>();
+                let url = req.url();
+                let name = caps.get(1).or_else(|| caps.get(2)).map_or("", |m| m.as_str());
+                let val = match name {
+                    "host" => req.get_host(),
+                    "remote_addr" => client
+                        .map(|c| c.ip().to_string())
+                        .or_else(|| req.headers().system_get("{client_ip}").cloned()),
+                    "remote_port" => client.map(|c| c.port().to_string()),
+                    "scheme" => Some(if req.extensions().get::<TlsConnection>().is_some() {
+                        "https".to_string()
+                    } else {
+                        "http".to_string()
+                    }),
+                    "uri" | "path" => Some(url.path.clone()),
+                    "args" | "query" => Some(url.query.clone().unwrap_or_default()),
+                    "request_uri" => Some(match &url.query {
+                        Some(query) => format!("{}?{}", url.path, query),
+                        None => url.path.clone(),
+                    }),
+                    "request_id" => req.extensions().get::<RequestId>().map(|id| id.0.clone()),
+                    _ if name.as_bytes()[0].is_ascii_digit() => {
+                        req.extensions().get::<PathCaptures>().map(|caps| {
+                            let index = name.parse::<usize>().unwrap_or(usize::MAX);
+                            caps.0.get(index).cloned().unwrap_or_default()
+                        })
+                    }
+                    _ if name.starts_with("cookie_") => {
+                        Some(Self::get_cookie(req, &name["cookie_".len()..]).unwrap_or_default())
+                    }
+                    _ => name.strip_prefix("http_").map(|header| {
+                        req.headers()
+                            .get_str_value(&header.replace('_', "-"))
+                            .unwrap_or_default()
+                    }),
+                };
+                val.unwrap_or_else(|| caps[0].to_string())
+            })
+            .to_string()
+        } else {
+            value
+        }
+    }
+
+    /// 获取请求中指定名字的cookie
+    pub fn get_cookie(req: &Request<Body>, name: &str) -> Option<String> {
+        let cookie = req.headers().get_str_value(&"Cookie")?;
+        cookie.split(';').find_map(|kv| match kv.trim().split_once('=') {
+            Some((k, v)) if k == name => Some(v.trim().to_string()),
+            _ => None,
+        })
+    }
+
+    /// 按配置修改头信息, `value`为已转化变量后的值
+    pub fn apply_header(headers: &mut HeaderMap, header: &ConfigHeader, value: String) {
+        match header.oper {
+            HeaderOper::Add => {
+                headers.push(header.key.clone(), value);
+            }
+            HeaderOper::Del => {
+                headers.remove(&header.key);
+            }
+            HeaderOper::Default => {
+                if !headers.contains(&header.key) {
+                    headers.push(header.key.clone(), value);
                 }
             }
+            HeaderOper::Replace => {
+                headers.insert(header.key.clone(), value);
+            }
         }
     }
 
@@ -473,12 +762,12 @@ impl Helper {
                     request
                         .unwrap()
                         .headers_mut()
-                        .push(value.key.to_string(), v);
+                        .insert(value.key.to_string(), v);
                 } else {
                     response
                         .unwrap()
                         .headers_mut()
-                        .push(value.key.to_string(), v);
+                        .insert(value.key.to_string(), v);
                 }
             }
         }
@@ -523,8 +812,10 @@ impl Helper {
 
 #[cfg(test)]
 mod tests {
-    use crate::Helper;
-    use webparse::Request;
+    use std::net::SocketAddr;
+
+    use crate::{AccessTarget, BodyBytes, Helper, PathCaptures, RequestId};
+    use webparse::{Request, Response};
     use wenmeng::Body;
 
     fn build_request() -> Request<Body> {
@@ -551,4 +842,129 @@ mod tests {
         let val = Helper::format_req_may_regex(req, format);
         assert_eq!(val, "http://127.0.0.1/formal/st/root?query=1&a=b");
     }
+
+    #[test]
+    fn test_header_value() {
+        let mut req: Request<Body> = Request::builder()
+            .url("http://example.com/tenant/t1/list?a=1")
+            .header("X-Client", "c1")
+            .header("Cookie", "sid=abc; tenant=wm")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut()
+            .insert("10.0.0.1:8080".parse::<SocketAddr>().unwrap());
+        // 未生成ID及分组时保持原样
+        assert_eq!(Helper::format_header_value(&req, "$request_id $1"), "$request_id $1");
+
+        req.extensions_mut().insert(RequestId("id1".to_string()));
+        req.extensions_mut().insert(PathCaptures(vec![
+            "/tenant/t1".to_string(),
+            "t1".to_string(),
+        ]));
+        for (value, expect) in [
+            ("$host", "example.com"),
+            ("$remote_addr:$remote_port", "10.0.0.1:8080"),
+            ("$request_id", "id1"),
+            ("tenant-$1", "tenant-t1"),
+            ("$2", ""),
+            ("$cookie_tenant/$cookie_sid", "wm/abc"),
+            ("$cookie_none", ""),
+            ("$http_x_client", "c1"),
+            ("$unknown", "$unknown"),
+            ("${host}_${remote_addr}", "example.com_10.0.0.1"),
+            ("${unknown}", "${unknown}"),
+        ] {
+            assert_eq!(Helper::format_header_value(&req, value), expect, "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_log_bytes() {
+        let format = "{request_length} {body_bytes_sent} {bytes_sent}";
+        let mut req: Request<Body> = Request::builder()
+            .url("http://example.com/upload")
+            .header("Content-Length", "2")
+            .body(Body::empty())
+            .unwrap();
+        let mut res: Response<Body> = Response::text().body("hello").unwrap().into_type();
+        assert_eq!(Helper::format_req_res(&req, Some(&res), format), "- - -");
+
+        let bytes = BodyBytes::start(&mut req);
+        assert!(bytes.count_complete(&mut res).await.unwrap());
+        assert_eq!(res.headers().get_body_len(), 5);
+        let expect = format!("2 5 {}", 5 + bytes.header());
+        assert_eq!(Helper::format_req_res(&req, Some(&res), format), expect);
+    }
+
+    #[test]
+    fn test_convert_log_format() {
+        let format = Helper::convert_log_format(
+            r#"$remote_addr "$request" $status ${body_bytes}B $request_time $upstream_addr $unknown"#,
+        );
+        assert_eq!(
+            format,
+            r#"{client_ip} "{request}" {status} {body_bytes_sent}B {request_time} {up_addr} $unknown"#
+        );
+        // 已转化的格式保持不变
+        assert_eq!(Helper::convert_log_format(&format), format);
+
+        let mut req: Request<Body> = Request::builder()
+            .method("POST")
+            .url("http://example.com/upload?a=1")
+            .body(Body::empty())
+            .unwrap();
+        let res: Response<Body> = Response::text().body("hello").unwrap().into_type();
+        let format = Helper::convert_log_format("$request_method \"$request\" $request_time $upstream_status");
+        assert_eq!(
+            Helper::format_req_res(&req, Some(&res), &format),
+            "POST \"POST /upload?a=1 HTTP/1.1\" - -"
+        );
+        BodyBytes::start(&mut req);
+        let value = Helper::format_req_res(&req, Some(&res), "{request_time}");
+        assert!(value.parse::<f64>().unwrap() < 1.0, "{}", value);
+    }
+
+    #[tokio::test]
+    async fn test_access_format() {
+        let mut req: Request<Body> = Request::builder()
+            .method("GET")
+            .url("http://example.com/api?a=1")
+            .header("User-Agent", "curl \"7.0\"")
+            .body(Body::empty())
+            .unwrap();
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), "10.0.0.1".to_string());
+        req.extensions_mut().insert(
+            AccessTarget::default().with_route(Some("example.com".to_string()), Some("/api".to_string())),
+        );
+        let mut res: Response<Body> = Response::text().body("hello").unwrap().into_type();
+        BodyBytes::start(&mut req).count_complete(&mut res).await.unwrap();
+
+        let value = Helper::format_req_res(&req, Some(&res), AccessTarget::COMBINED_FORMAT);
+        assert!(value.starts_with("10.0.0.1 - - ["), "{}", value);
+        assert!(
+            value.contains(r#""GET /api?a=1 HTTP/1.1" 200 5 "" "curl "7.0"" example.com example.com /api - "#),
+            "{}",
+            value
+        );
+
+        // 每个请求为一行合法的JSON, 值中的引号被转义
+        let value = Helper::format_req_res(&req, Some(&res), AccessTarget::JSON_FORMAT);
+        let json: serde_json::Value = serde_json::from_str(&value).unwrap();
+        assert_eq!(json["method"], "GET");
+        assert_eq!(json["server_name"], "example.com");
+        assert_eq!(json["location"], "/api");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["body_bytes_sent"], 5);
+        assert_eq!(json["up_addr"], "-");
+        assert_eq!(json["user_agent"], "curl \"7.0\"");
+        assert!(json["request_time"].as_f64().unwrap() < 1.0);
+
+        // 按split分流所选的upstream
+        assert_eq!(Helper::format_req_res(&req, Some(&res), "{up_name}"), "-");
+        let mut target = AccessTarget::default();
+        target.upstream = Some("canary".to_string());
+        req.extensions_mut().insert(target);
+        assert_eq!(Helper::format_req_res(&req, Some(&res), "{up_name}"), "canary");
+    }
 }
@@ -29,6 +29,7 @@ mod config;
 mod plugins;
 pub mod log;
 mod data;
+mod metrics;
 pub mod arg;
 
 pub use error::{ProxyResult, ProxyError};
@@ -39,9 +40,17 @@ pub use proxy::http::ProxyHttp;
 pub use proxy::socks5::ProxySocks5;
 pub use streams::*;
 pub use helper::Helper;
-pub use prot::{ProtFrame, ProtFrameHeader, ProtClose, ProtData, ProtCreate};
+pub use prot::{ProtFrame, ProtFrameHeader, ProtClose, ProtCloseCode, ProtData, ProtCreate, ProtCreateMode, ProtTarget, ProtPing, ProtPong, ProtWindowUpdate};
 pub use mapping::*;
 pub use check::*;
 pub use control::*;
 pub use config::*;
-pub use plugins::*;
\ No newline at end of file
+pub use plugins::*;
+pub use reverse::{
+    AcmeConfig, ActiveCheckConfig, AddHeader, CacheConfig, CacheStore, ClientCert, CorsConfig, HttpConfig, Idempotency, IdempotencyLookup, IdempotencyResponse,
+    IdempotencyStore, ListenInfo, LocationConfig, LocationMatch, PoolStats, ProxyProtocol, ProxyProtocolVersion, RewriteConfig, ServerConfig, SingleStreamConfig, SplitChosen, SplitConfig, SplitKey, SplitUpstream, StickyConfig, TlsConnection, RequestId, PathCaptures, ProxyPeer, ServerHidden,
+    TlsVersion, UpstreamBalance, UpstreamConfig, UpstreamConnGuard, UpstreamPool, VerifyClient, IDEMPOTENCY_KEY,
+};
+pub use data::{AccessStat, AccessStatSnapshot, AccessTarget, BodyBytes, ConnCloseReason, STATUS_CLIENT_CLOSED};
+pub use metrics::{AtomicHistogram, LocationMetrics, Metrics, MetricsServer};
+pub use data::{UpstreamTiming, UpstreamTimingStat, TIMING_BUCKETS};
\ No newline at end of file
@@ -53,6 +53,8 @@
 //!     the default style for all other levels.
 //!     * `{h(the level is {l})}` -
 //!         <code style="color: red; font-weight: bold">the level is ERROR</code>
+//! * `j`, `json` - JSON转义其参数的输出, 不含两侧的引号, 用于JSON格式的访问日志
+//!     * `{{"host":"{j({host})}"}}` - `{"host":"wmproxy.net"}`
 //! * `l`, `level` - The log level.
 //! * `L`, `line` - The line that the log message came from, or `???` if not
 //!     provided.
@@ -129,7 +131,9 @@ use std::{default::Default, io, process, thread};
 //     Color, Encode, Style, NEWLINE,
 // };
 
-use crate::log::{Style, Color, Encode};
+use crate::data::{AccessTarget, BodyBytes, UpstreamTiming};
+use crate::UpstreamResponseId;
+use crate::log::{writer::simple::SimpleWriter, Style, Color, Encode};
 
 use self::parser::{Parameters, Alignment, Piece, Parser};
 
@@ -443,6 +447,23 @@ impl<'a> From<Piece<'a>> for Chunk {
                         params: parameters,
                     }
                 }
+                "j" | "json" => {
+                    if formatter.args.len() != 1 {
+                        return Chunk::Error("expected exactly one argument".to_owned());
+                    }
+
+                    let chunks = formatter
+                        .args
+                        .pop()
+                        .unwrap()
+                        .into_iter()
+                        .map(From::from)
+                        .collect();
+                    Chunk::Formatted {
+                        chunk: FormattedChunk::Json(chunks),
+                        params: parameters,
+                    }
+                }
                 "l" | "level" => no_args(&formatter.args, parameters, FormattedChunk::Level),
                 "m" | "message" => no_args(&formatter.args, parameters, FormattedChunk::Message),
                 "M" | "module" => no_args(&formatter.args, parameters, FormattedChunk::Module),
@@ -499,12 +520,17 @@ impl<'a> From<Piece<'a>> for Chunk {
                 "client_ip" => no_args(&formatter.args, parameters, FormattedChunk::ClientIp),
                 "client_user" => no_args(&formatter.args, parameters, FormattedChunk::ClientUser),
                 "url" => no_args(&formatter.args, parameters, FormattedChunk::Url),
+                "method" => no_args(&formatter.args, parameters, FormattedChunk::Method),
+                "request" => no_args(&formatter.args, parameters, FormattedChunk::Request),
                 "path" => no_args(&formatter.args, parameters, FormattedChunk::Path),
                 "query" => no_args(&formatter.args, parameters, FormattedChunk::Query),
                 "host" => no_args(&formatter.args, parameters, FormattedChunk::Host),
                 "status" => no_args(&formatter.args, parameters, FormattedChunk::Status),
+                "status_code" => no_args(&formatter.args, parameters, FormattedChunk::StatusCode),
                 "up_status" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamStatus),
                 "body_bytes_sent" => no_args(&formatter.args, parameters, FormattedChunk::BodyBytesSent),
+                "bytes_sent" => no_args(&formatter.args, parameters, FormattedChunk::BytesSent),
+                "request_length" => no_args(&formatter.args, parameters, FormattedChunk::RequestLength),
                 "referer" => no_args(&formatter.args, parameters, FormattedChunk::Referer),
                 "user_agent" => no_args(&formatter.args, parameters, FormattedChunk::UserAgent),
                 "cookie" => no_args(&formatter.args, parameters, FormattedChunk::Cookie),
@@ -513,6 +539,16 @@ impl<'a> From<Piece<'a>> for Chunk {
                 "up_addr" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamAddr),
                 "request_time" => no_args(&formatter.args, parameters, FormattedChunk::RequestTime),
                 "up_response_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamResponseTime),
+                "up_dns_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamDnsTime),
+                "up_connect_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamConnectTime),
+                "up_tls_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamTlsTime),
+                "up_header_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamHeaderTime),
+                "up_body_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamBodyTime),
+                "up_reused" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamReused),
+                "up_id" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamId),
+                "server_name" => no_args(&formatter.args, parameters, FormattedChunk::ServerName),
+                "location" => no_args(&formatter.args, parameters, FormattedChunk::Location),
+                "up_name" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamName),
 
                 "" => {
                     if formatter.args.len() != 1 {
@@ -568,16 +604,22 @@ enum FormattedChunk {
     Newline,
     Align(Vec<Chunk>),
     Highlight(Vec<Chunk>),
+    Json(Vec<Chunk>),
     Mdc(String, String),
 
     /// for request or response
     ClientIp,
     ClientUser,
     Url,
+    Method,
+    /// 请求行, 如`GET /index?a=1 HTTP/1.1`
+    Request,
     Path,
     Query,
     Host,
     Status,
+    /// 仅状态码, 如`200`
+    StatusCode,
     Referer,
     UserAgent,
     Cookie,
@@ -585,12 +627,60 @@ enum FormattedChunk {
     SslCipher,
     UpstreamStatus,
     BodyBytesSent,
+    BytesSent,
+    RequestLength,
     UpstreamAddr,
     RequestTime,
     UpstreamResponseTime,
+    UpstreamDnsTime,
+    UpstreamConnectTime,
+    UpstreamTlsTime,
+    UpstreamHeaderTime,
+    UpstreamBodyTime,
+    UpstreamReused,
+    UpstreamId,
+    /// 处理该请求的server名称
+    ServerName,
+    /// 处理该请求的location规则
+    Location,
+    /// 按split分流所选的upstream名称
+    UpstreamName,
 }
 
 impl FormattedChunk {
+    fn get_timing<'a>(record: &'a ProxyRecord<'_>) -> Option<&'a UpstreamTiming> {
+        record.res.and_then(|res| res.extensions().get::<UpstreamTiming>())
+    }
+
+    /// 输出请求中统计的字节数, 未统计时输出`-`
+    fn write_bytes<F>(w: &mut dyn crate::log::Write, record: &ProxyRecord, f: F) -> io::Result<()>
+    where
+        F: Fn(&BodyBytes) -> u64,
+    {
+        match record.req.and_then(BodyBytes::get) {
+            Some(bytes) => w.write_fmt(format_args!("{}", f(bytes)))?,
+            None => w.write_all(b"-")?,
+        }
+        Ok(())
+    }
+
+    /// 以秒为单位输出后端的耗时, 精确到毫秒
+    fn get_target<'a>(record: &'a ProxyRecord<'_>) -> Option<&'a AccessTarget> {
+        record.req.and_then(|req| req.extensions().get::<AccessTarget>())
+    }
+
+    fn write_timing<F>(w: &mut dyn crate::log::Write, record: &ProxyRecord, f: F) -> io::Result<()>
+    where
+        F: Fn(&UpstreamTiming) -> std::time::Duration,
+    {
+        if let Some(timing) = Self::get_timing(record) {
+            w.write_fmt(format_args!("{:.3}", f(timing).as_secs_f64()))?;
+        } else {
+            w.write_all(b"-")?;
+        }
+        Ok(())
+    }
+
     fn encode(&self, w: &mut dyn crate::log::Write, record: &ProxyRecord) -> io::Result<()> {
         match *self {
             FormattedChunk::Time(ref fmt, Timezone::Utc) => write!(w, "{}", Utc::now().format(fmt)),
@@ -644,6 +734,14 @@ impl FormattedChunk {
                 }
                 Ok(())
             }
+            FormattedChunk::Json(ref chunks) => {
+                let mut buf = vec![];
+                for chunk in chunks {
+                    chunk.encode(&mut SimpleWriter(&mut buf), record)?;
+                }
+                let value = serde_json::to_string(&*String::from_utf8_lossy(&buf))?;
+                w.write_all(&value.as_bytes()[1..value.len() - 1])
+            }
             FormattedChunk::Mdc(ref _key, ref _default) => {
                 // log_mdc::get(key, |v| write!(w, "{}", v.unwrap_or(default)))
                 Ok(())
@@ -725,18 +823,101 @@ impl FormattedChunk {
                 }
                 Ok(())
             }
+            FormattedChunk::StatusCode => {
+                if let Some(res) = record.res {
+                    w.write_fmt(format_args!("{}", res.status().as_u16()))?;
+                } else {
+                    w.write_all(b"???")?;
+                }
+                Ok(())
+            }
+            FormattedChunk::Method => {
+                if let Some(req) = record.req {
+                    w.write_all(req.method().as_str().as_bytes())?;
+                } else {
+                    w.write_all(b"???")?;
+                }
+                Ok(())
+            }
+            FormattedChunk::Request => {
+                if let Some(req) = record.req {
+                    let url = req.url();
+                    w.write_fmt(format_args!("{} {}", req.method().as_str(), url.path))?;
+                    if let Some(query) = &url.query {
+                        w.write_fmt(format_args!("?{}", query))?;
+                    }
+                    w.write_fmt(format_args!(" {}", req.version()))?;
+                } else {
+                    w.write_all(b"???")?;
+                }
+                Ok(())
+            }
             FormattedChunk::UpstreamStatus => {
-                // if let Some(res) = record.res {
-                //     w.write_fmt(format_args!("{}", res.status()))?;
-                // }
+                // 经后端处理的请求返回后端的状态码
+                match (record.res, Self::get_timing(record)) {
+                    (Some(res), Some(_)) => w.write_fmt(format_args!("{}", res.status().as_u16()))?,
+                    _ => w.write_all(b"-")?,
+                };
+                Ok(())
+            }
+            FormattedChunk::RequestTime => {
+                match record.req.and_then(BodyBytes::get) {
+                    Some(bytes) => w.write_fmt(format_args!("{:.3}", bytes.elapsed().as_secs_f64()))?,
+                    None => w.write_all(b"-")?,
+                }
+                Ok(())
+            }
+            FormattedChunk::BodyBytesSent => Self::write_bytes(w, record, |b| b.body()),
+            FormattedChunk::BytesSent => Self::write_bytes(w, record, |b| b.header() + b.body()),
+            FormattedChunk::RequestLength => Self::write_bytes(w, record, |b| b.request()),
+            FormattedChunk::UpstreamAddr => {
+                if let Some(addr) = Self::get_timing(record).and_then(|t| t.addr) {
+                    w.write_fmt(format_args!("{}", addr))?;
+                } else {
+                    w.write_all(b"-")?;
+                }
                 Ok(())
             }
-            FormattedChunk::BodyBytesSent => {
-                // if let Some(res) = record.res {
-                //     w.write_fmt(format_args!("{}", res.status()))?;
-                // }
+            FormattedChunk::UpstreamResponseTime => {
+                Self::write_timing(w, record, |t| t.response_time())
+            }
+            FormattedChunk::UpstreamDnsTime => Self::write_timing(w, record, |t| t.dns),
+            FormattedChunk::UpstreamConnectTime => Self::write_timing(w, record, |t| t.connect),
+            FormattedChunk::UpstreamTlsTime => Self::write_timing(w, record, |t| t.tls),
+            FormattedChunk::UpstreamHeaderTime => Self::write_timing(w, record, |t| t.header),
+            FormattedChunk::UpstreamBodyTime => Self::write_timing(w, record, |t| t.body),
+            FormattedChunk::UpstreamReused => {
+                if let Some(timing) = Self::get_timing(record) {
+                    w.write_all(if timing.reused { "1" } else { "0" }.as_bytes())?;
+                } else {
+                    w.write_all(b"-")?;
+                }
                 Ok(())
             }
+            FormattedChunk::UpstreamId => {
+                match record.res.and_then(|res| res.extensions().get::<UpstreamResponseId>()) {
+                    Some(id) => w.write_all(id.0.as_bytes()),
+                    None => w.write_all("-".as_bytes()),
+                }
+            }
+            FormattedChunk::ServerName => {
+                match Self::get_target(record).and_then(|t| t.server_name.as_ref()) {
+                    Some(name) => w.write_all(name.as_bytes()),
+                    None => w.write_all("-".as_bytes()),
+                }
+            }
+            FormattedChunk::Location => {
+                match Self::get_target(record).and_then(|t| t.location.as_ref()) {
+                    Some(location) => w.write_all(location.as_bytes()),
+                    None => w.write_all("-".as_bytes()),
+                }
+            }
+            FormattedChunk::UpstreamName => {
+                match Self::get_target(record).and_then(|t| t.upstream.as_ref()) {
+                    Some(name) => w.write_all(name.as_bytes()),
+                    None => w.write_all("-".as_bytes()),
+                }
+            }
             _ => {
                 Ok(())
             }
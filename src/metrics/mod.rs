@@ -0,0 +1,366 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/11 14:06:52
+
+mod server;
+
+pub use server::MetricsServer;
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use webparse::{Request, Response};
+use wenmeng::Body;
+
+use crate::{
+    data::{BodyBytes, UpstreamTiming},
+    AccessStat, ConnCloseReason, TIMING_BUCKETS,
+};
+
+lazy_static! {
+    // 所有注册的统计, 只在加载配置时写入
+    static ref GLOBAL_METRICS: RwLock<Vec<Arc<LocationMetrics>>> = RwLock::new(vec![]);
+    // 未匹配到location的请求
+    static ref UNMATCHED_METRICS: Arc<LocationMetrics> = Metrics::location("-", "-");
+}
+
+/// 返回状态的分类, 依次为1xx到5xx
+const STATUS_CLASS: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+
+/// 无锁的耗时直方图, 桶与`TIMING_BUCKETS`一致
+#[derive(Debug, Default)]
+pub struct AtomicHistogram {
+    /// 落在各个桶的次数, 最后一个为超出所有桶的次数
+    buckets: [AtomicU64; TIMING_BUCKETS.len() + 1],
+    /// 总耗时, 单位微秒
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub fn observe(&self, cost: Duration) {
+        let ms = cost.as_millis() as u64;
+        let idx = TIMING_BUCKETS
+            .iter()
+            .position(|b| ms <= *b)
+            .unwrap_or(TIMING_BUCKETS.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum
+            .fetch_add(cost.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut total = 0;
+        for (idx, bucket) in TIMING_BUCKETS.iter().enumerate() {
+            total += self.buckets[idx].load(Ordering::Relaxed);
+            let le = *bucket as f64 / 1000.0;
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {total}");
+        }
+        total += self.buckets[TIMING_BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {total}");
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {total}");
+    }
+}
+
+/// 单个server及location的统计, 请求时只进行原子操作
+#[derive(Debug, Default)]
+pub struct LocationMetrics {
+    server: String,
+    location: String,
+    requests: AtomicU64,
+    status: [AtomicU64; 5],
+    bytes_in: AtomicU64,
+    header_out: AtomicU64,
+    bytes_out: AtomicU64,
+    /// 与后端建立连接(含TLS握手)的耗时, 复用的连接不记录
+    pub connect: AtomicHistogram,
+    /// 访问后端直到收到返回头的总耗时
+    pub response: AtomicHistogram,
+}
+
+impl LocationMetrics {
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// 该状态码分类的返回数, 如200对应2xx
+    pub fn responses(&self, status: u16) -> u64 {
+        Self::status_index(status)
+            .map(|i| self.status[i].load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn header_out(&self) -> u64 {
+        self.header_out.load(Ordering::Relaxed)
+    }
+
+    fn status_index(status: u16) -> Option<usize> {
+        match status / 100 {
+            1..=5 => Some((status / 100 - 1) as usize),
+            _ => None,
+        }
+    }
+
+    /// 记录一次请求, 流量按请求中统计的`BodyBytes`计算, 未统计时按Content-Length
+    pub fn record(&self, req: &Request<Body>, res: &Response<Body>) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(i) = Self::status_index(res.status().as_u16()) {
+            self.status[i].fetch_add(1, Ordering::Relaxed);
+        }
+        let (bytes_in, header_out, bytes_out) = match BodyBytes::get(req) {
+            Some(bytes) => (bytes.request(), bytes.header(), bytes.body()),
+            None => (
+                req.headers().get_body_len().max(0) as u64,
+                0,
+                res.headers().get_body_len().max(0) as u64,
+            ),
+        };
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.header_out.fetch_add(header_out, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+        if let Some(timing) = res.extensions().get::<UpstreamTiming>() {
+            if !timing.reused {
+                self.connect.observe(timing.connect + timing.tls);
+            }
+            self.response.observe(timing.response_time());
+        }
+    }
+
+    fn labels(&self) -> String {
+        format!(
+            "server=\"{}\",location=\"{}\"",
+            escape_label(&self.server),
+            escape_label(&self.location)
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+type CounterGetter = fn(&LocationMetrics) -> u64;
+type HistogramGetter = fn(&LocationMetrics) -> &AtomicHistogram;
+
+/// 反向代理的Prometheus统计
+pub struct Metrics;
+
+impl Metrics {
+    /// 获取server及location对应的统计, 不存在时注册, 重新加载配置后保留原有的计数
+    pub fn location(server: &str, location: &str) -> Arc<LocationMetrics> {
+        if let Ok(all) = GLOBAL_METRICS.read() {
+            if let Some(m) = all
+                .iter()
+                .find(|m| m.server == server && m.location == location)
+            {
+                return m.clone();
+            }
+        }
+        let mut all = GLOBAL_METRICS.write().unwrap();
+        if let Some(m) = all
+            .iter()
+            .find(|m| m.server == server && m.location == location)
+        {
+            return m.clone();
+        }
+        let metrics = Arc::new(LocationMetrics {
+            server: server.to_string(),
+            location: location.to_string(),
+            ..Default::default()
+        });
+        all.push(metrics.clone());
+        metrics
+    }
+
+    /// 记录请求, 未匹配到location的请求记录为`server="-",location="-"`
+    pub fn record(req: &Request<Body>, res: &Response<Body>) {
+        match req.extensions().get::<Arc<LocationMetrics>>() {
+            Some(metrics) => metrics.record(req, res),
+            None => UNMATCHED_METRICS.record(req, res),
+        }
+    }
+
+    /// 以Prometheus的文本格式输出所有统计
+    pub fn render() -> String {
+        let all = GLOBAL_METRICS.read().map(|m| m.clone()).unwrap_or_default();
+        let mut out = String::new();
+        let counters: [(&str, &str, CounterGetter); 4] = [
+            (
+                "wmproxy_http_requests_total",
+                "Total HTTP requests.",
+                LocationMetrics::requests,
+            ),
+            (
+                "wmproxy_http_request_bytes_total",
+                "Request body bytes.",
+                LocationMetrics::bytes_in,
+            ),
+            (
+                "wmproxy_http_response_header_bytes_total",
+                "Response header bytes.",
+                LocationMetrics::header_out,
+            ),
+            (
+                "wmproxy_http_response_bytes_total",
+                "Response body bytes.",
+                LocationMetrics::bytes_out,
+            ),
+        ];
+        for (name, help, get) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for m in &all {
+                let _ = writeln!(out, "{name}{{{}}} {}", m.labels(), get(m));
+            }
+        }
+
+        let name = "wmproxy_http_responses_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} HTTP responses by status class.\n# TYPE {name} counter"
+        );
+        for m in &all {
+            for (i, class) in STATUS_CLASS.iter().enumerate() {
+                let value = m.status[i].load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{{{},class=\"{class}\"}} {value}", m.labels());
+            }
+        }
+
+        let histograms: [(&str, &str, HistogramGetter); 2] = [
+            (
+                "wmproxy_upstream_connect_seconds",
+                "Upstream connect time.",
+                |m| &m.connect,
+            ),
+            (
+                "wmproxy_upstream_response_seconds",
+                "Upstream response header time.",
+                |m| &m.response,
+            ),
+        ];
+        for (name, help, get) in histograms {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+            for m in &all {
+                get(m).render(&mut out, name, &m.labels());
+            }
+        }
+
+        let stat = AccessStat::snapshot();
+        let name = "wmproxy_connections_accepted_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Accepted client connections.\n# TYPE {name} counter"
+        );
+        let _ = writeln!(out, "{name} {}", stat.accepted);
+        let name = "wmproxy_connections_closed_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Connections closed before a complete request.\n# TYPE {name} counter"
+        );
+        for reason in ConnCloseReason::ALL {
+            let _ = writeln!(
+                out,
+                "{name}{{reason=\"{reason}\"}} {}",
+                stat.get_closed(reason)
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use webparse::{Request, Response};
+    use wenmeng::Body;
+
+    use super::Metrics;
+    use crate::data::UpstreamTiming;
+
+    #[test]
+    fn test_record_render() {
+        let metrics = Metrics::location("metrics.test", "/api");
+        assert!(std::sync::Arc::ptr_eq(
+            &metrics,
+            &Metrics::location("metrics.test", "/api")
+        ));
+        let mut req = Request::builder()
+            .url("http://metrics.test/api")
+            .header("Content-Length", "10")
+            .body(Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(metrics.clone());
+        let mut timing = UpstreamTiming::new(false);
+        timing.connect = Duration::from_millis(3);
+        timing.header = Duration::from_millis(20);
+        let mut res = Response::builder()
+            .status(503)
+            .header("Content-Length", "5")
+            .body(Body::empty())
+            .unwrap();
+        res.extensions_mut().insert(timing);
+        Metrics::record(&req, &res);
+        assert_eq!(metrics.requests(), 1);
+        assert_eq!(metrics.responses(500), 1);
+        assert_eq!(metrics.responses(200), 0);
+        assert_eq!((metrics.bytes_in(), metrics.bytes_out()), (10, 5));
+        assert_eq!(metrics.connect.count(), 1);
+
+        let text = Metrics::render();
+        let labels = "server=\"metrics.test\",location=\"/api\"";
+        assert!(text.contains(&format!("wmproxy_http_requests_total{{{labels}}} 1\n")));
+        assert!(text.contains(&format!(
+            "wmproxy_http_responses_total{{{labels},class=\"5xx\"}} 1\n"
+        )));
+        assert!(text.contains(&format!(
+            "wmproxy_upstream_connect_seconds_bucket{{{labels},le=\"0.005\"}} 1\n"
+        )));
+        assert!(text.contains(&format!(
+            "wmproxy_upstream_response_seconds_bucket{{{labels},le=\"0.01\"}} 0\n"
+        )));
+        assert!(text.contains(&format!(
+            "wmproxy_upstream_response_seconds_count{{{labels}}} 1\n"
+        )));
+    }
+}
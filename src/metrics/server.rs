@@ -0,0 +1,65 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/11 15:20:36
+
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use webparse::{HeaderName, Response};
+use wenmeng::{HttpTrait, ProtResult, RecvRequest, RecvResponse, Server};
+
+use crate::{ProxyResult, Shutdown};
+
+use super::Metrics;
+
+struct Operate;
+
+#[async_trait]
+impl HttpTrait for Operate {
+    async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
+        if req.path() == "/healthz" {
+            return Ok(Shutdown::readiness());
+        }
+        if req.path() != "/metrics" {
+            return Ok(Response::status404().body("not found")?.into_type());
+        }
+        Ok(Response::text()
+            .header(HeaderName::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Metrics::render())?
+            .into_type())
+    }
+}
+
+/// 统计数据的服务, 与代理的监听端口分开, 提供`/metrics`及就绪检查`/healthz`
+pub struct MetricsServer;
+
+impl MetricsServer {
+    /// 绑定地址并开始提供服务
+    pub async fn bind(addr: SocketAddr) -> ProxyResult<()> {
+        let listener = TcpListener::bind(addr).await?;
+        log::info!("统计端口绑定：{:?}，提供Prometheus统计。", addr);
+        Self::serve(listener).await
+    }
+
+    pub async fn serve(listener: TcpListener) -> ProxyResult<()> {
+        loop {
+            let (conn, addr) = listener.accept().await?;
+            tokio::spawn(async move {
+                let mut server = Server::new(conn, Some(addr));
+                server.set_callback_http(Box::new(Operate));
+                if let Err(e) = server.incoming().await {
+                    log::trace!("统计端口：处理信息时发生错误：{:?}", e);
+                }
+            });
+        }
+    }
+}
@@ -31,7 +31,8 @@ use tokio_rustls::{rustls, TlsAcceptor};
 
 use crate::{
     reverse::{HttpConfig, StreamConfig, UpstreamConfig},
-    CenterClient, Flag, Helper, MappingConfig, OneHealth, ProxyError, ProxyResult, WrapAddr,
+    CenterClient, ConfigDuration, ConfigSize, Flag, Helper, MappingConfig, OneHealth, ProxyError, ProxyResult,
+    WrapAddr,
 };
 
 pub struct Builder {
@@ -95,6 +96,13 @@ impl Builder {
         })
     }
 
+    pub fn compress(self, compress: bool) -> Builder {
+        self.and_then(|mut proxy| {
+            proxy.compress = compress;
+            Ok(proxy)
+        })
+    }
+
     pub fn tc(self, is_tls: bool) -> Builder {
         self.and_then(|mut proxy| {
             proxy.tc = is_tls;
@@ -193,6 +201,18 @@ impl Builder {
     }
 }
 
+fn default_ping_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30))
+}
+
+fn default_ping_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(10))
+}
+
+fn default_max_frame_size() -> ConfigSize {
+    ConfigSize::new(1024 * 1024)
+}
+
 fn default_bind_addr() -> SocketAddr {
     "127.0.0.1:8090".parse().unwrap()
 }
@@ -255,6 +275,24 @@ pub struct ProxyConfig {
     /// 双向认证是否启用
     #[serde(default)]
     pub(crate) two_way_tls: bool,
+    /// 内网穿透时是否压缩数据消息, 控制类消息不压缩
+    #[serde(default)]
+    pub(crate) compress: bool,
+    /// 内网穿透时发送心跳的间隔, 为0时不发送
+    #[bpaf(fallback(default_ping_interval()), display_fallback, long)]
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_ping_interval")]
+    pub(crate) ping_interval: ConfigDuration,
+    /// 发送心跳后等待回应的时长, 超时未回应则断开连接
+    #[bpaf(fallback(default_ping_timeout()), display_fallback, long)]
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_ping_timeout")]
+    pub(crate) ping_timeout: ConfigDuration,
+    /// 内网穿透时接收的单个消息的最大长度, 超出则断开连接, 需不小于流量控制的窗口(256k)
+    #[bpaf(fallback(default_max_frame_size()), display_fallback, long)]
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_max_frame_size")]
+    pub(crate) max_frame_size: ConfigSize,
     /// tls证书所用的域名
     pub(crate) domain: Option<String>,
     /// 公开的证书公钥文件
@@ -272,6 +310,14 @@ pub fn default_control_port() -> SocketAddr {
 pub fn default_pidfile() -> String {
     "wmproxy.pid".to_string()
 }
+
+pub fn default_lame_duck() -> ConfigDuration {
+    ConfigDuration::new(Duration::ZERO)
+}
+
+pub fn default_drain_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30))
+}
 #[serde_as]
 /// 代理类, 一个代理类启动一种类型的代理
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -290,10 +336,21 @@ pub struct ConfigOption {
     pub(crate) disable_stdout: bool,
     #[serde(default)]
     pub(crate) disable_control: bool,
+    /// Prometheus统计的监听地址, 与代理端口分开, 不配置则不开启
+    #[serde(default)]
+    pub(crate) metrics: Option<SocketAddr>,
     #[serde(default="default_pidfile")]
     pub pidfile: String,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub(crate) default_level: Option<LevelFilter>,
+    /// 关闭前`/healthz`返回503但仍正常服务的时间, 使负载均衡先摘除流量
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_lame_duck")]
+    pub lame_duck: ConfigDuration,
+    /// 停止接收新连接后等待已有连接结束的最长时间
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: ConfigDuration,
 }
 
 impl Default for ConfigOption {
@@ -305,8 +362,11 @@ impl Default for ConfigOption {
             control: default_control_port(),
             disable_stdout: Default::default(),
             disable_control: Default::default(),
+            metrics: None,
             default_level: None,
             pidfile: default_pidfile(),
+            lame_duck: default_lame_duck(),
+            drain_timeout: default_drain_timeout(),
         }
     }
 }
@@ -333,6 +393,10 @@ impl Default for ProxyConfig {
             ts: false,
             tc: false,
             two_way_tls: false,
+            compress: false,
+            ping_interval: default_ping_interval(),
+            ping_timeout: default_ping_timeout(),
+            max_frame_size: default_max_frame_size(),
             domain: None,
             cert: None,
             key: None,
@@ -763,4 +827,13 @@ impl ConfigOption {
         }
         names
     }
+
+    /// 访问日志使用的日志名称, 其格式中已包含时间等信息, 输出时不再添加前缀
+    pub fn get_access_names(&self) -> HashSet<String> {
+        let mut names = HashSet::new();
+        if let Some(http) = &self.http {
+            http.get_access_names(&mut names);
+        }
+        names
+    }
 }
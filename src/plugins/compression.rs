@@ -0,0 +1,348 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/12 10:25:18
+
+use webparse::{Binary, HeaderName, Method, Request, Response};
+use wenmeng::Body;
+
+use crate::Idempotency;
+
+/// 动态压缩的限制, 存放于请求的extensions中, 返回时判断是否压缩
+/// 满足条件时交由服务端按`Accept-Encoding`压缩, 否则返回原始内容
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Compression {
+    /// 小于该大小的返回不压缩
+    pub min_length: u64,
+    /// 允许压缩的Content-Type, 如`text/*`, 为空时不限制
+    pub types: Vec<String>,
+    /// 关闭压缩, 所有返回均按原始内容返回
+    pub disabled: bool,
+    /// 允许的压缩方式, 按优先级排列, 为空时按`DEFAULT_METHODS`
+    pub methods: Vec<String>,
+}
+
+impl Compression {
+    /// 开启`gzip`时未配置的最小压缩大小
+    pub const DEFAULT_MIN_LENGTH: u64 = 1024;
+    /// 开启`gzip`时未配置的压缩类型
+    pub const DEFAULT_TYPES: [&'static str; 7] = [
+        "text/html",
+        "text/plain",
+        "text/css",
+        "text/xml",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+    ];
+
+    /// 支持的压缩方式, 未配置时的优先级
+    pub const DEFAULT_METHODS: [&'static str; 3] = ["gzip", "br", "deflate"];
+
+    pub fn new(min_length: u64, types: Vec<String>) -> Self {
+        Self {
+            min_length,
+            types,
+            disabled: false,
+            methods: vec![],
+        }
+    }
+
+    /// 设置允许的压缩方式, 如`["br", "gzip"]`
+    pub fn with_methods(mut self, methods: Vec<String>) -> Self {
+        self.methods = methods;
+        self
+    }
+
+    /// 关闭压缩
+    pub fn off() -> Self {
+        Self {
+            disabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// 判断该content-type是否允许压缩
+    pub fn is_match_type(&self, content_type: Option<&str>) -> bool {
+        if self.types.is_empty() {
+            return true;
+        }
+        let content_type = match content_type {
+            Some(c) => c
+                .split(';')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_ascii_lowercase(),
+            None => return false,
+        };
+        self.types.iter().any(|t| {
+            if t == "*" || t.eq_ignore_ascii_case(&content_type) {
+                return true;
+            }
+            match t.strip_suffix("/*") {
+                Some(prefix) => content_type
+                    .split_once('/')
+                    .map(|(main, _)| main.eq_ignore_ascii_case(prefix))
+                    .unwrap_or(false),
+                None => false,
+            }
+        })
+    }
+
+    /// 按配置的优先级选择客户端支持的压缩方式, 未知的方式忽略
+    fn accept_encoding(&self, req: &Request<Body>) -> Option<&'static str> {
+        let accept = req.headers().get_str_value(&HeaderName::ACCEPT_ENCODING)?;
+        let allow = |m: &&'static str| {
+            self.methods.is_empty() || self.methods.iter().any(|v| v.eq_ignore_ascii_case(m))
+        };
+        let mut methods: Vec<&'static str> =
+            Self::DEFAULT_METHODS.into_iter().filter(allow).collect();
+        methods.sort_by_key(|m| {
+            self.methods
+                .iter()
+                .position(|v| v.eq_ignore_ascii_case(m))
+                .unwrap_or(0)
+        });
+        methods.into_iter().find(|m| accept.contains(m))
+    }
+
+    /// 禁止服务端对该返回进行压缩
+    fn disable(res: &mut Response<Body>) {
+        res.headers_mut().insert(HeaderName::CONTENT_ENCODING, "");
+    }
+
+    /// 返回内容随`Accept-Encoding`变化, 告知缓存
+    fn vary(res: &mut Response<Body>) {
+        let value = match res.headers().get_str_value(&HeaderName::VARY) {
+            Some(v) if v.to_ascii_lowercase().contains("accept-encoding") || v.trim() == "*" => {
+                return
+            }
+            Some(v) if !v.trim().is_empty() => format!("{}, Accept-Encoding", v.trim()),
+            _ => "Accept-Encoding".to_string(),
+        };
+        res.headers_mut().insert(HeaderName::VARY, value);
+    }
+
+    /// 标记压缩方式, 发送时由服务端进行压缩
+    fn enable(res: &mut Response<Body>, method: &'static str) {
+        res.headers_mut().remove(&HeaderName::CONTENT_LENGTH);
+        res.headers_mut()
+            .insert(HeaderName::CONTENT_ENCODING, method);
+    }
+
+    /// 按大小及类型决定是否压缩该返回
+    /// 未知大小时先读取至阈值, 在阈值前结束的则按原始内容返回
+    pub async fn process_response(
+        &self,
+        req: &Request<Body>,
+        res: &mut Response<Body>,
+    ) -> std::io::Result<()> {
+        let status = res.status().as_u16();
+        if req.method() == &Method::HEAD
+            || status < 200
+            || status == 204
+            || status == 304
+            || res.headers().contains(&HeaderName::CONTENT_ENCODING)
+        {
+            return Ok(());
+        }
+        let method = self.accept_encoding(req);
+        let content_type = res.headers().get_str_value(&HeaderName::CONTENT_TYPE);
+        if self.disabled || !self.is_match_type(content_type.as_deref()) {
+            if method.is_some() {
+                Self::disable(res);
+            }
+            return Ok(());
+        }
+        Self::vary(res);
+        let method = match method {
+            Some(method) => method,
+            None => return Ok(()),
+        };
+
+        let len = res.get_body_len();
+        if len > 0 {
+            if (len as u64) >= self.min_length {
+                Self::enable(res, method);
+            }
+            return Ok(());
+        }
+        if self.min_length == 0 {
+            Self::enable(res, method);
+            return Ok(());
+        }
+        let limit = self.min_length as usize - 1;
+        let (data, complete) = Idempotency::read_body(res.body_mut(), limit).await?;
+        if complete {
+            res.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+            res.headers_mut()
+                .insert(HeaderName::CONTENT_LENGTH, format!("{}", data.len()));
+            *res.body_mut() = Body::only(Binary::from(data));
+        } else {
+            let origin = std::mem::replace(res.body_mut(), Body::empty());
+            *res.body_mut() = Idempotency::chain_body(data, origin);
+            Self::enable(res, method);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+    use webparse::{Binary, BinaryMut, Request, Response};
+    use wenmeng::Body;
+
+    use super::Compression;
+    use crate::Idempotency;
+
+    fn build_req() -> Request<Body> {
+        Request::builder()
+            .url("http://localhost/")
+            .header("Accept-Encoding", "gzip, br")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_match_type() {
+        let compression = Compression::new(
+            0,
+            vec!["text/*".to_string(), "application/json".to_string()],
+        );
+        assert!(compression.is_match_type(Some("text/html; charset=utf-8")));
+        assert!(compression.is_match_type(Some("Application/JSON")));
+        assert!(!compression.is_match_type(Some("image/png")));
+        assert!(!compression.is_match_type(None));
+        assert!(Compression::default().is_match_type(None));
+    }
+
+    #[tokio::test]
+    async fn test_process_response() {
+        let compression = Compression::new(1024, vec!["text/*".to_string()]);
+        let req = build_req();
+
+        let mut res: Response<Body> = Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Content-Length", "10")
+            .body(Body::only("0123456789".into()))
+            .unwrap();
+        compression.process_response(&req, &mut res).await.unwrap();
+        assert_eq!(res.get_body_len(), 10);
+
+        let mut res: Response<Body> = Response::builder()
+            .header("Content-Type", "text/plain")
+            .header("Content-Length", "2048")
+            .body(Body::only(vec![b'a'; 2048].into()))
+            .unwrap();
+        compression.process_response(&req, &mut res).await.unwrap();
+        assert_eq!(res.get_body_len(), 0);
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Encoding").unwrap(),
+            "gzip"
+        );
+
+        let mut res: Response<Body> = Response::builder()
+            .header("Content-Type", "image/png")
+            .body(Body::only(vec![b'a'; 2048].into()))
+            .unwrap();
+        compression.process_response(&req, &mut res).await.unwrap();
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Encoding").unwrap(),
+            ""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_vary_and_off() {
+        let compression = Compression::new(
+            0,
+            Compression::DEFAULT_TYPES.iter().map(|t| t.to_string()).collect(),
+        );
+        let req = build_req();
+        let mut res: Response<Body> = Response::builder()
+            .header("Content-Type", "text/html")
+            .header("Vary", "Origin")
+            .body(Body::only(vec![b'a'; 2048].into()))
+            .unwrap();
+        compression.process_response(&req, &mut res).await.unwrap();
+        assert_eq!(
+            res.headers().get_str_value(&"Vary").unwrap(),
+            "Origin, Accept-Encoding"
+        );
+        compression.process_response(&req, &mut res).await.unwrap();
+        assert_eq!(
+            res.headers().get_str_value(&"Vary").unwrap(),
+            "Origin, Accept-Encoding"
+        );
+
+        let mut res: Response<Body> = Response::builder()
+            .header("Content-Type", "text/html")
+            .body(Body::only(vec![b'a'; 2048].into()))
+            .unwrap();
+        Compression::off()
+            .process_response(&req, &mut res)
+            .await
+            .unwrap();
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Encoding").unwrap(),
+            ""
+        );
+        assert!(!res.headers().contains(&"Vary"));
+    }
+
+    /// 未知大小的返回, 按读取到的数据是否达到阈值决定
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_process_stream() {
+        let compression = Compression::new(1024, vec![]);
+        let req = build_req();
+        for (size, compress) in [(100usize, false), (4096, true)] {
+            let (sender, receiver) = channel::<(bool, Binary)>(10);
+            for chunk in vec![b'a'; size].chunks(512) {
+                sender
+                    .send((false, Binary::from(chunk.to_vec())))
+                    .await
+                    .unwrap();
+            }
+            sender.send((true, Binary::new())).await.unwrap();
+            let mut res: Response<Body> = Response::builder()
+                .header("Transfer-Encoding", "chunked")
+                .body(Body::new(receiver, BinaryMut::new(), false))
+                .unwrap();
+            compression.process_response(&req, &mut res).await.unwrap();
+            if compress {
+                assert_eq!(res.get_body_len(), 0);
+                assert_eq!(
+                    res.headers().get_str_value(&"Content-Encoding").unwrap(),
+                    "gzip"
+                );
+            } else {
+                assert_eq!(res.get_body_len(), size as isize);
+                assert!(!res.headers().contains(&"Transfer-Encoding"));
+            }
+            let (data, complete) = Idempotency::read_body(res.body_mut(), usize::MAX)
+                .await
+                .unwrap();
+            assert!(complete);
+            assert_eq!(data.len(), size);
+        }
+    }
+
+    #[test]
+    fn test_methods() {
+        let req = build_req();
+        assert_eq!(Compression::default().accept_encoding(&req), Some("gzip"));
+        let compression = Compression::default().with_methods(vec!["br".to_string()]);
+        assert_eq!(compression.accept_encoding(&req), Some("br"));
+        let compression = Compression::default().with_methods(vec!["deflate".to_string()]);
+        assert_eq!(compression.accept_encoding(&req), None);
+    }
+}
@@ -17,7 +17,7 @@ use wenmeng::{Body, ProtResult, RecvRequest, RecvResponse};
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::fs::Metadata;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::SystemTime;
 use std::{collections::HashMap, io};
 use tokio::fs::File;
@@ -143,6 +143,9 @@ pub struct FileServer {
     pub disable_compress: bool,
     #[serde(default)]
     pub browse: bool,
+    /// 浏览目录时是否展示以`.`开头的隐藏文件
+    #[serde(default)]
+    pub show_hidden: bool,
     /// 通过"Access-Control-Allow-Origin"标头启用 CORS
     #[serde(default)]
     pub cors: bool,
@@ -163,6 +166,7 @@ table tr { white-space: nowrap; }
 td.perms {}
 td.file-size { text-align: right; padding-left: 1em; }
 td.display-name { padding-left: 1em; }
+td.mtime { padding-left: 1em; }
 i.icon-_blank {
   background-image: url("data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAABAAAAAQCAYAAAAf8/9hAAAAGXRFWHRTb2Z0d2FyZQBBZG9iZSBJbWFnZVJlYWR5ccllPAAAAWBJREFUeNqEUj1LxEAQnd1MVA4lyIEWx6UIKEGUExGsbC3tLfwJ/hT/g7VlCnubqxXBwg/Q4hQP/LhKL5nZuBsvuGfW5MGyuzM7jzdvVuR5DgYnZ+f99ai7Vt5t9K9unu4HLweI3qWYxI6PDosdy0fhcntxO44CcOBzPA7mfEyuHwf7ntQk4jcnywOxIlfxOCNYaLVgb6cXbkTdhJXq2SIlNMC0xIqhHczDbi8OVzpLSUa0WebRfmigLHqj1EcPZnwf7gbDIrYVRyEinurj6jTBHyI7pqVrFQqEbt6TEmZ9v1NRAJNC1xTYxIQh/MmRUlmFQE3qWOW1nqB2TWk1/3tgJV0waVvkFIEeZbHq4ElyKzAmEXOx6gnEVJuWBzmkRJBRPYGZBDsVaOlpSgVJE2yVaAe/0kx/3azBRO0VsbMFZE3CDSZKweZfYIVg+DZ6v7h9GDVOwZPw/PoxKu/fAgwALbDAXf7DdQkAAAAASUVORK5CYII=");
 }
@@ -195,6 +199,7 @@ impl FileServer {
             precompressed: vec![],
             disable_compress: false,
             browse: true,
+            show_hidden: false,
             cors: false,
             comm: CommonConfig::new(),
         };
@@ -250,6 +255,41 @@ impl FileServer {
         .into_type()
     }
 
+    /// 对链接中的路径进行百分号编码, 保留`/`
+    pub fn encode_path(path: &str) -> String {
+        let mut ret = String::with_capacity(path.len());
+        for b in path.bytes() {
+            if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+                ret.push(b as char);
+            } else {
+                ret.push_str(&format!("%{:02X}", b));
+            }
+        }
+        ret
+    }
+
+    pub fn escape_html(val: &str) -> String {
+        val.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+
+    /// 拒绝访问, 如访问上级目录或不允许浏览的目录
+    fn ret_forbidden(&self, req: &mut RecvRequest, msg: &'static str) -> Response<Body> {
+        Response::builder()
+            .version(req.version())
+            .status(403)
+            .body(msg)
+            .unwrap()
+            .into_type()
+    }
+
+    /// 按扩展名获取内置的mimetype
+    pub fn get_default_mimetype(extension: &str) -> Option<&'static str> {
+        DEFAULT_MIMETYPE.get(extension).copied()
+    }
+
     pub fn get_mimetype(&self, extension: &String) -> String {
         if let Some(s) = DEFAULT_MIMETYPE.get(&**extension) {
             s.to_string()
@@ -397,6 +437,8 @@ impl FileServer {
         None
     }
 
+    /// 解析Range, 返回包含首尾的字节位置, 如`bytes=0-499`, `bytes=9500-`, `bytes=-500`(最后500字节)
+    /// 超出文件大小的结束位置截断到文件末尾, 起始位置超出文件大小时无法满足
     pub fn calc_bytes_range(val: &str, len: u64) -> Option<(u64, u64)> {
         let vals = val.split("=").collect::<Vec<&str>>();
         if vals.len() != 2 || vals[0].trim() != "bytes" {
@@ -406,32 +448,27 @@ impl FileServer {
         // 存在多个range, 暂时只取一个range, 只支持单一的
         let val = vals[0].trim();
         // 取前缀及后缀
-        let vals = val.split("-").collect::<Vec<&str>>();
-        // 格式不合法, 无法解析'-500', '9500-', '500-600'
-        if vals.len() != 2 {
+        let (first, last) = val.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        if len == 0 {
             return None;
         }
-        let (start, end);
-        if vals[0].len() == 0 {
-            start = 0;
-        } else {
-            if let Ok(v) = vals[0].parse() {
-                start = v;
-            } else {
+        let (start, end) = if first.is_empty() {
+            let suffix: u64 = last.parse().ok()?;
+            if suffix == 0 {
                 return None;
             }
-        }
-        if vals[1].len() == 0 {
-            end = len;
+            (len - suffix.min(len), len - 1)
         } else {
-            if let Ok(v) = vals[1].parse() {
-                end = v;
+            let start: u64 = first.parse().ok()?;
+            let end = if last.is_empty() {
+                len - 1
             } else {
-                return None;
-            }
-        }
-
-        if end < start {
+                last.parse::<u64>().ok()?.min(len - 1)
+            };
+            (start, end)
+        };
+        if start >= len || end < start {
             return None;
         }
         Some((start, end))
@@ -497,11 +534,14 @@ impl FileServer {
                 if let Some(bytes) = req.headers().get_str_value(&HeaderName::RANGE) {
                     match Self::calc_bytes_range(&bytes, data.len()) {
                         Some((start, end)) => {
-                            res.body_mut().set_start_end(start, end).await?;
+                            res.body_mut().set_start_end(start, end + 1).await?;
                             res.headers_mut().insert(
                                 HeaderName::CONTENT_RANGE,
                                 format!("bytes {start}-{end}/{}", data.len()),
                             );
+                            res.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+                            res.headers_mut()
+                                .insert(HeaderName::CONTENT_LENGTH, format!("{}", end - start + 1));
                             *res.status_mut() = StatusCode::PARTIAL_CONTENT;
                         }
                         None => {
@@ -518,6 +558,7 @@ impl FileServer {
 
             if req.method() == &Method::Head {
                 res.replace_body(Body::empty());
+                res.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
                 res.headers_mut().insert(HeaderName::ACCEPT_RANGES, "bytes");
                 res.headers_mut()
                     .insert(HeaderName::CONTENT_LENGTH, format!("{}", data.len()));
@@ -528,6 +569,10 @@ impl FileServer {
 
     pub async fn deal_request(&self, req: &mut RecvRequest) -> ProtResult<Response<Body>> {
         let mut path = req.path().clone();
+        // 查询参数不参与文件的映射
+        if let Some(index) = path.find('?') {
+            path.truncate(index);
+        }
         if path == "/robots.txt" && self.robots.is_some() {
             let robots = self.robots.clone().unwrap();
             let builder = Response::builder()
@@ -561,11 +606,14 @@ impl FileServer {
         if !href.starts_with("/") {
             href = "/".to_string() + &href;
         }
+        // 必须保证不会跑出root设置的目录之外，如故意访问`../`之类的
+        if Path::new(&href).components().any(|c| c == Component::ParentDir) {
+            return Ok(self.ret_forbidden(req, "can't view parent file"));
+        }
         let real_path = root.clone() + &href;
         let mut real_path = Path::new(&real_path).to_owned();
-        // 必须保证不会跑出root设置的目录之外，如故意访问`../`之类的
         if !real_path.starts_with(root_path) || self.is_hide_path(root_path.as_ref()) {
-            return Ok(self.ret_error_msg(req, "can't view parent file").await);
+            return Ok(self.ret_forbidden(req, "can't view parent file"));
         }
 
         // 访问路径是目录，尝试是否有index的文件，如果有还是以文件访问
@@ -579,64 +627,69 @@ impl FileServer {
             }
         }
 
+        // 符号链接等可能指向root之外, 按真实路径再次判断
+        if let (Ok(real), Ok(base)) = (real_path.canonicalize(), root_path.canonicalize()) {
+            if !real.starts_with(base) {
+                return Ok(self.ret_forbidden(req, "can't view parent file"));
+            }
+        }
+
         // 访问为目录，如果启用目录访问，则返回当前的文件夹的内容
         if real_path.is_dir() {
             if !self.browse {
-                return Ok(self.ret_error_msg(req, "can't view parent file").await);
+                return Ok(self.ret_forbidden(req, "can't view dir"));
             }
             let mut binary = BinaryMut::new();
             binary.put_slice(HEAD_HTML_PRE.as_bytes());
-            binary.put_slice(href.as_bytes());
+            let title = Self::escape_html(&href);
+            binary.put_slice(title.as_bytes());
             binary.put_slice(HEAD_HTML_AFTER.as_bytes());
-            binary.put_slice(format!("<body><h1>Index Of {}</h1>", href).as_bytes());
+            binary.put_slice(format!("<body><h1>Index Of {}</h1>", title).as_bytes());
             binary.put_slice("<table>\r\n<tbody>".as_bytes());
 
             let mut folder_binary = BinaryMut::new();
             let mut file_binary = BinaryMut::new();
-            for entry in real_path.read_dir()? {
-                if let Ok(entry) = entry {
-                    let path = entry.path();
-                    if self.is_hide_path(path.as_ref()) {
-                        continue;
-                    }
-                    let new = path.strip_prefix(root_path).unwrap();
-                    let value = "/".to_string() + new.to_str().unwrap();
-                    let value = value.replace("\\", "/");
-                    let op_ref = if path.is_dir() {
-                        &mut folder_binary
-                    } else {
-                        &mut file_binary
-                    };
-                    op_ref.put_slice("<tr>".as_bytes());
-                    let filename = path.file_name().unwrap().to_str().unwrap();
-                    if path.is_dir() {
-                        op_ref.put_slice("<td><i class=\"icon icon-_blank\"></i></td>".as_bytes());
-                        op_ref.put_slice("<td class=\"file-size\"><code></code></td>".as_bytes());
-                        op_ref.put_slice(
-                            format!("<td><a href=\"{}{}\">{}</td>", self.prefix, value, filename)
-                                .as_bytes(),
-                        );
-                    } else {
-                        op_ref.put_slice("<td><i class=\"icon icon-_page\"></i></td>".as_bytes());
-                        if let Ok(meta) = path.metadata() {
-                            op_ref.put_slice(
-                                format!(
-                                    "<td class=\"file-size\"><code>{}</code></td>",
-                                    calc_file_size(meta.len())
-                                )
-                                .as_bytes(),
-                            );
-                        } else {
-                            op_ref
-                                .put_slice("<td class=\"file-size\"><code></code></td>".as_bytes());
-                        }
-                        op_ref.put_slice(
-                            format!("<td><a href=\"{}{}\">{}</td>", self.prefix, value, filename)
-                                .as_bytes(),
-                        );
-                    }
-                    op_ref.put_slice("</tr>".as_bytes());
+            let mut entries = real_path.read_dir()?.flatten().collect::<Vec<_>>();
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                let path = entry.path();
+                let filename = entry.file_name().to_string_lossy().to_string();
+                // 默认不展示以`.`开头的隐藏文件
+                if self.is_hide_path(path.as_ref()) || (!self.show_hidden && filename.starts_with('.')) {
+                    continue;
                 }
+                let new = match path.strip_prefix(root_path) {
+                    Ok(new) => new,
+                    Err(_) => continue,
+                };
+                let value = "/".to_string() + &new.to_string_lossy().replace("\\", "/");
+                let meta = entry.metadata().ok();
+                let is_dir = meta.as_ref().map(|m| m.is_dir()).unwrap_or(false);
+                let (op_ref, icon, size) = if is_dir {
+                    (&mut folder_binary, "icon-_blank", String::new())
+                } else {
+                    let size = meta.as_ref().map(|m| calc_file_size(m.len())).unwrap_or_default();
+                    (&mut file_binary, "icon-_page", size)
+                };
+                let mtime = meta
+                    .as_ref()
+                    .and_then(|m| m.modified().ok())
+                    .map(|t| DateTime::<Utc>::from(t).format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                op_ref.put_slice(
+                    format!(
+                        "<tr><td><i class=\"icon {}\"></i></td><td class=\"file-size\"><code>{}</code></td><td class=\"mtime\"><code>{}</code></td><td><a href=\"{}{}{}\">{}{}</a></td></tr>",
+                        icon,
+                        size,
+                        mtime,
+                        Self::encode_path(&self.prefix),
+                        Self::encode_path(&value),
+                        if is_dir { "/" } else { "" },
+                        Self::escape_html(&filename),
+                        if is_dir { "/" } else { "" },
+                    )
+                    .as_bytes(),
+                );
             }
             binary.put_slice(folder_binary.chunk());
             binary.put_slice(file_binary.chunk());
@@ -677,3 +730,30 @@ impl FileServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FileServer;
+
+    #[test]
+    fn test_bytes_range() {
+        let cases = [
+            ("bytes=0-499", Some((0, 499))),
+            ("bytes=2-5", Some((2, 5))),
+            ("bytes=9500-", Some((9500, 9999))),
+            ("bytes=-500", Some((9500, 9999))),
+            ("bytes=-20000", Some((0, 9999))),
+            ("bytes=9000-20000", Some((9000, 9999))),
+            ("bytes=0-0, 5-6", Some((0, 0))),
+            ("bytes=10000-", None),
+            ("bytes=5-4", None),
+            ("bytes=-0", None),
+            ("items=0-1", None),
+            ("bytes=a-1", None),
+        ];
+        for (range, expect) in cases {
+            assert_eq!(FileServer::calc_bytes_range(range, 10000), expect, "{}", range);
+        }
+        assert_eq!(FileServer::calc_bytes_range("bytes=0-", 0), None);
+    }
+}
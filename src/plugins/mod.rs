@@ -10,11 +10,17 @@
 // -----
 // Created Date: 2023/11/10 02:21:22
 
+mod compression;
 mod file_server;
+mod return_response;
 mod static_response;
+mod sub_filter;
 
+pub use compression::Compression;
 pub use file_server::FileServer;
+pub use return_response::ReturnResponse;
 pub use static_response::StaticResponse;
+pub use sub_filter::{SubFilter, SubFilterRule};
 
 fn calc_file_size(len: u64) -> String {
     if len < 1024 {
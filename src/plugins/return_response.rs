@@ -0,0 +1,130 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/12 16:40:05
+
+use std::{fmt::Display, str::FromStr};
+
+use webparse::{HeaderName, Response};
+use wenmeng::{ProtResult, RecvRequest, RecvResponse};
+
+use crate::{Helper, ProxyError};
+
+/// 直接返回指定状态码, 格式为`status [text]`, 如`[301, "https://example.com$path"]`
+/// 重定向的状态码时text为`Location`, 其它为返回的内容, 均可使用`$path`, `$host`, `$query`等变量
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReturnResponse {
+    pub status: u16,
+    pub text: String,
+}
+
+impl ReturnResponse {
+    pub fn new(status: u16, text: String) -> Self {
+        Self { status, text }
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.status, 301 | 302 | 303 | 307 | 308)
+    }
+
+    pub fn deal_request(&self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+        let text = Helper::format_header_value(req, &self.text);
+        let builder = Response::text().status(self.status);
+        if self.is_redirect() {
+            Ok(builder
+                .header(HeaderName::LOCATION, text)
+                .body("")?
+                .into_type())
+        } else {
+            Ok(builder.body(text)?.into_type())
+        }
+    }
+}
+
+impl FromStr for ReturnResponse {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        if vals.is_empty() {
+            return Err(ProxyError::Extension("return must be `status [text]`"));
+        }
+        let status = match vals[0].parse::<u16>() {
+            Ok(status) if (100..600).contains(&status) => status,
+            _ => return Err(ProxyError::Extension("return status error")),
+        };
+        let text = vals[1..].join(" ");
+        let ret = Self::new(status, text);
+        if ret.is_redirect() && ret.text.is_empty() {
+            return Err(ProxyError::Extension("return redirect need location"));
+        }
+        Ok(ret)
+    }
+}
+
+impl Display for ReturnResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.text.is_empty() {
+            f.write_fmt(format_args!("{}", self.status))
+        } else if self.text.contains(char::is_whitespace) {
+            let quote = if self.text.contains('"') { '\'' } else { '"' };
+            f.write_fmt(format_args!("{} {quote}{}{quote}", self.status, self.text))
+        } else {
+            f.write_fmt(format_args!("{} {}", self.status, self.text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use super::ReturnResponse;
+
+    #[test]
+    fn test_return_parse() {
+        let ret = ReturnResponse::from_str("301 https://www.example.com$path").unwrap();
+        assert_eq!(ret.status, 301);
+        assert!(ret.is_redirect());
+        assert_eq!(ret.to_string(), "301 https://www.example.com$path");
+        let ret = ReturnResponse::from_str("403 'access denied'").unwrap();
+        assert_eq!(ret.text, "access denied");
+        assert_eq!(ret.to_string(), "403 \"access denied\"");
+        assert_eq!(ReturnResponse::from_str("204").unwrap().text, "");
+        assert!(ReturnResponse::from_str("302").is_err());
+        assert!(ReturnResponse::from_str("abc").is_err());
+        assert!(ReturnResponse::from_str("700 x").is_err());
+    }
+
+    #[test]
+    fn test_return_response() {
+        let req = Request::builder()
+            .url("http://example.com/old/a?x=1")
+            .header("Host", "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let ret = ReturnResponse::from_str("307 https://www.$host$path?$query").unwrap();
+        let res = ret.deal_request(&req).unwrap();
+        assert_eq!(res.status().as_u16(), 307);
+        assert_eq!(
+            res.headers().get_str_value(&"Location").unwrap(),
+            "https://www.example.com/old/a?x=1"
+        );
+
+        let ret = ReturnResponse::from_str("403 forbidden").unwrap();
+        let mut res = ret.deal_request(&req).unwrap();
+        assert_eq!(res.status().as_u16(), 403);
+        assert!(res.headers().get_str_value(&"Location").is_none());
+        assert_eq!(&res.body_mut().read_now()[..], b"forbidden");
+    }
+}
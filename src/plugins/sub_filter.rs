@@ -0,0 +1,335 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/05 10:12:36
+
+use std::{fmt::Display, io, str::FromStr};
+
+use tokio::sync::mpsc::channel;
+use webparse::{Binary, BinaryMut, HeaderName, Response};
+use wenmeng::Body;
+
+use crate::{reverse::BodyLimit, Helper};
+
+fn default_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+/// 返回内容的替换规则, 格式如`from to [once|all] [content-type ...]`
+/// 如 `'http://127.0.0.1:8080' 'https://soft.wm-proxy.com' all text/html application/javascript`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubFilterRule {
+    /// 待替换的内容
+    pub from: String,
+    /// 替换后的内容
+    pub to: String,
+    /// 是否只替换第一个匹配项
+    pub once: bool,
+    /// 生效的content-type, `*`表示所有文本类型
+    pub types: Vec<String>,
+}
+
+impl SubFilterRule {
+    pub fn new(from: String, to: String, once: bool, types: Vec<String>) -> Self {
+        Self {
+            from,
+            to,
+            once,
+            types,
+        }
+    }
+
+    /// 判断该规则是否对该content-type生效
+    pub fn is_match_type(&self, content_type: &str) -> bool {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        for t in &self.types {
+            if t == "*" || t.eq_ignore_ascii_case(&content_type) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl FromStr for SubFilterRule {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        if vals.len() < 2 || vals[0].is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sub_filter格式为: from to [once|all] [content-type ...]",
+            ));
+        }
+        let mut once = false;
+        let mut types = vec![];
+        for (idx, v) in vals[2..].iter().enumerate() {
+            match (idx, *v) {
+                (0, "once") => once = true,
+                (0, "all") => once = false,
+                _ => types.push(v.to_ascii_lowercase()),
+            }
+        }
+        if types.is_empty() {
+            types = default_types();
+        }
+        Ok(SubFilterRule::new(
+            vals[0].to_string(),
+            vals[1].to_string(),
+            once,
+            types,
+        ))
+    }
+}
+
+impl Display for SubFilterRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "'{}' '{}' {} {}",
+            self.from,
+            self.to,
+            if self.once { "once" } else { "all" },
+            self.types.join(" ")
+        ))
+    }
+}
+
+/// 单条规则的替换状态, 保留未能确定是否匹配的尾部数据
+struct SubFilterStage {
+    from: Vec<u8>,
+    to: Vec<u8>,
+    once: bool,
+    done: bool,
+    cache: Vec<u8>,
+}
+
+impl SubFilterStage {
+    fn new(rule: &SubFilterRule) -> Self {
+        Self {
+            from: rule.from.as_bytes().to_vec(),
+            to: rule.to.as_bytes().to_vec(),
+            once: rule.once,
+            done: false,
+            cache: vec![],
+        }
+    }
+
+    fn feed(&mut self, data: &[u8], is_end: bool) -> Vec<u8> {
+        self.cache.extend_from_slice(data);
+        let buf = std::mem::take(&mut self.cache);
+        if self.done {
+            return buf;
+        }
+        let mut out = Vec::with_capacity(buf.len());
+        let mut pos = 0;
+        while pos + self.from.len() <= buf.len() {
+            if buf[pos..].starts_with(&self.from) {
+                out.extend_from_slice(&self.to);
+                pos += self.from.len();
+                if self.once {
+                    self.done = true;
+                    out.extend_from_slice(&buf[pos..]);
+                    return out;
+                }
+            } else {
+                out.push(buf[pos]);
+                pos += 1;
+            }
+        }
+        if is_end {
+            out.extend_from_slice(&buf[pos..]);
+        } else {
+            // 剩余数据不足一个完整匹配, 可能跨越数据块, 留待下次处理
+            self.cache.extend_from_slice(&buf[pos..]);
+        }
+        out
+    }
+}
+
+/// 按顺序应用多条替换规则, 支持跨数据块的匹配
+pub struct SubFilter {
+    stages: Vec<SubFilterStage>,
+}
+
+impl SubFilter {
+    pub fn new(rules: &[SubFilterRule]) -> Self {
+        Self {
+            stages: rules.iter().map(SubFilterStage::new).collect(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        self.inner_feed(data, false)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.inner_feed(&[], true)
+    }
+
+    fn inner_feed(&mut self, data: &[u8], is_end: bool) -> Vec<u8> {
+        let mut value = data.to_vec();
+        for stage in &mut self.stages {
+            value = stage.feed(&value, is_end);
+        }
+        value
+    }
+
+    /// 选出对该返回生效的规则, 压缩或者二进制的返回将不做处理
+    pub fn match_rules(rules: &[SubFilterRule], res: &Response<Body>) -> Vec<SubFilterRule> {
+        if let Some(encoding) = res.headers().get_str_value(&HeaderName::CONTENT_ENCODING) {
+            if !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity") {
+                return vec![];
+            }
+        }
+        let content_type = match res.headers().get_str_value(&HeaderName::CONTENT_TYPE) {
+            Some(c) => c,
+            None => return vec![],
+        };
+        rules
+            .iter()
+            .filter(|r| r.is_match_type(&content_type))
+            .cloned()
+            .collect()
+    }
+
+    /// 将返回的body替换成经过规则替换的数据流
+    pub fn rewrite_response(rules: &[SubFilterRule], res: &mut Response<Body>) {
+        let rules = Self::match_rules(rules, res);
+        if rules.is_empty() {
+            return;
+        }
+        let mut filter = SubFilter::new(&rules);
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        let mut body = std::mem::replace(
+            res.body_mut(),
+            Body::new(receiver, BinaryMut::new(), false),
+        );
+        res.headers_mut().remove(&HeaderName::CONTENT_LENGTH);
+        res.headers_mut()
+            .insert(HeaderName::TRANSFER_ENCODING, "chunked");
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                match BodyLimit::read_some(&mut body, &mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        let data = filter.feed(&buf[..n]);
+                        if !data.is_empty()
+                            && sender.send((false, Binary::from(data))).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        log::trace!("替换返回内容时读取数据失败:{:?}", e);
+                        break;
+                    }
+                }
+            }
+            let _ = sender.send((true, Binary::from(filter.finish()))).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::channel;
+    use webparse::{Binary, BinaryMut, Buf, Response};
+    use wenmeng::Body;
+
+    use super::{SubFilter, SubFilterRule};
+
+    fn do_filter(rules: &[&str], chunks: &[&str]) -> String {
+        let rules = rules
+            .iter()
+            .map(|r| r.parse::<SubFilterRule>().unwrap())
+            .collect::<Vec<_>>();
+        let mut filter = SubFilter::new(&rules);
+        let mut out = vec![];
+        for c in chunks {
+            out.extend(filter.feed(c.as_bytes()));
+        }
+        out.extend(filter.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let rule = "'http://a' 'https://b' once text/html application/javascript"
+            .parse::<SubFilterRule>()
+            .unwrap();
+        assert_eq!(rule.from, "http://a");
+        assert_eq!(rule.to, "https://b");
+        assert!(rule.once);
+        assert!(rule.is_match_type("text/html; charset=utf-8"));
+        assert!(rule.is_match_type("application/javascript"));
+        assert!(!rule.is_match_type("image/png"));
+
+        let rule = "a b".parse::<SubFilterRule>().unwrap();
+        assert!(!rule.once);
+        assert_eq!(rule.types, vec!["text/html".to_string()]);
+        assert!("a".parse::<SubFilterRule>().is_err());
+    }
+
+    #[test]
+    fn test_multi_rules() {
+        let val = do_filter(
+            &["http://inner:8080 https://out", "inner2 out2 once"],
+            &["<a href='http://inner:8080/a'>inner2 inner2</a> http://inner:8080"],
+        );
+        assert_eq!(val, "<a href='https://out/a'>out2 inner2</a> https://out");
+
+        // 规则按顺序执行, 前一条的结果会被后一条处理
+        let val = do_filter(&["aa bb", "bb cc"], &["aa bb"]);
+        assert_eq!(val, "cc cc");
+    }
+
+    #[test]
+    fn test_chunk_boundary() {
+        let val = do_filter(
+            &["http://inner:8080 https://out"],
+            &["<a href='http://in", "ner:80", "80/a'>", "http://inner:8080"],
+        );
+        assert_eq!(val, "<a href='https://out/a'>https://out");
+
+        let val = do_filter(&["abc x once"], &["ab", "cab", "c"]);
+        assert_eq!(val, "xabc");
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_slow_body() {
+        let rules = vec!["inner out".parse::<SubFilterRule>().unwrap()];
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        let mut res = Response::builder()
+            .header("Content-Type", "text/html")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        SubFilter::rewrite_response(&rules, &mut res);
+        // 数据间隔到达, 等待期间不应空转
+        tokio::spawn(async move {
+            for chunk in ["in", "ner ", "inner"] {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                let _ = sender.send((false, Binary::from(chunk.as_bytes().to_vec()))).await;
+            }
+            let _ = sender.send((true, Binary::new())).await;
+        });
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(data.chunk(), b"out out");
+    }
+}
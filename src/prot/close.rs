@@ -19,36 +19,86 @@ use crate::{
 
 use super::{ProtFrameHeader, read_short_string, write_short_string};
 
+/// 连接关闭的原因码, 未知的原因码按Normal处理
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProtCloseCode {
+    /// 正常关闭
+    Normal = 0,
+    /// 连接后端或内网地址失败
+    UpstreamError = 1,
+    /// 超时
+    Timeout = 2,
+    /// 验证失败或无可用的映射等策略拒绝
+    PolicyDenied = 3,
+}
+
+impl ProtCloseCode {
+    pub fn new(byte: u8) -> ProtCloseCode {
+        match byte {
+            1 => ProtCloseCode::UpstreamError,
+            2 => ProtCloseCode::Timeout,
+            3 => ProtCloseCode::PolicyDenied,
+            _ => ProtCloseCode::Normal,
+        }
+    }
+
+    pub fn encode(&self) -> u8 {
+        *self as u8
+    }
+}
+
 /// 旧的Socket连接关闭, 接收到则关闭掉当前的连接
+/// 包体为原因描述及一个字节的原因码, 旧版本的空包体或无原因码时均为Normal
 #[derive(Debug)]
 pub struct ProtClose {
     sock_map: u64,
+    code: ProtCloseCode,
     reason: String,
 }
 
 impl ProtClose {
+    /// 验证失败时关闭控制流的原因
+    pub const REASON_AUTH_FAILED: &'static str = "auth failed";
+
     pub fn new(sock_map: u64) -> ProtClose {
-        ProtClose { sock_map, reason: String::new() }
+        Self::new_by_code(sock_map, ProtCloseCode::Normal, String::new())
     }
 
     pub fn new_by_reason(sock_map: u64, reason: String) -> ProtClose {
-        ProtClose { sock_map, reason }
+        Self::new_by_code(sock_map, ProtCloseCode::Normal, reason)
+    }
+
+    pub fn new_by_code(sock_map: u64, code: ProtCloseCode, reason: String) -> ProtClose {
+        ProtClose { sock_map, code, reason }
     }
 
     pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtClose> {
+        if header.length == 0 {
+            return Ok(Self::new(header.sock_map()));
+        }
+        let remaining = buf.remaining();
         let reason = read_short_string(&mut buf)?;
+        let used = remaining - buf.remaining();
+        let code = if header.length as usize > used && buf.has_remaining() {
+            ProtCloseCode::new(buf.get_u8())
+        } else {
+            ProtCloseCode::Normal
+        };
         Ok(ProtClose {
             sock_map: header.sock_map(),
+            code,
             reason,
         })
     }
 
     pub fn encode<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
         let mut head = ProtFrameHeader::new(ProtKind::Close, ProtFlag::zero(), self.sock_map);
-        head.length = self.reason.as_bytes().len() as u32 + 1;
+        head.length = self.reason.len() as u32 + 2;
         let mut size = 0;
         size += head.encode(buf)?;
         size += write_short_string(buf, &self.reason)?;
+        size += buf.put_u8(self.code.encode());
         Ok(size)
     }
 
@@ -56,7 +106,15 @@ impl ProtClose {
         self.sock_map
     }
 
+    pub fn code(&self) -> ProtCloseCode {
+        self.code
+    }
+
     pub fn reason(&self) -> &String {
         &self.reason
     }
+
+    pub fn is_auth_failed(&self) -> bool {
+        self.reason == Self::REASON_AUTH_FAILED
+    }
 }
@@ -10,48 +10,240 @@
 // -----
 // Created Date: 2023/09/22 10:28:28
 
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
 use webparse::{Buf, BufMut};
 
 use crate::{
     prot::{ProtFlag, ProtKind},
-    ProxyResult,
+    ProxyError, ProxyResult,
 };
 
-use super::ProtFrameHeader;
+use super::{read_short_string, write_short_string, ProtFrameHeader};
+
+/// 新连接的协议提示, 未知的值按Unknown处理
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProtCreateMode {
+    Unknown = 0,
+    Tcp = 1,
+    Http = 2,
+    Https = 3,
+}
+
+impl ProtCreateMode {
+    pub fn new(byte: u8) -> ProtCreateMode {
+        match byte {
+            1 => ProtCreateMode::Tcp,
+            2 => ProtCreateMode::Http,
+            3 => ProtCreateMode::Https,
+            _ => ProtCreateMode::Unknown,
+        }
+    }
+
+    pub fn encode(&self) -> u8 {
+        *self as u8
+    }
+}
+
+/// 新连接需要连接的目标地址, 为IP或者域名加端口
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ProtTarget {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl ProtTarget {
+    const ATYP_IPV4: u8 = 1;
+    const ATYP_DOMAIN: u8 = 3;
+    const ATYP_IPV6: u8 = 4;
+
+    pub fn port(&self) -> u16 {
+        match self {
+            ProtTarget::Ip(addr) => addr.port(),
+            ProtTarget::Domain(_, port) => *port,
+        }
+    }
+
+    fn is_valid_domain(domain: &str) -> bool {
+        !domain.is_empty()
+            && domain.len() <= 255
+            && domain
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+    }
+
+    fn parse<T: Buf>(buf: &mut T) -> ProxyResult<ProtTarget> {
+        if !buf.has_remaining() {
+            return Err(ProxyError::TooShort);
+        }
+        let target = match buf.get_u8() {
+            Self::ATYP_IPV4 => {
+                if buf.remaining() < 4 {
+                    return Err(ProxyError::TooShort);
+                }
+                let mut ip = [0u8; 4];
+                ip.copy_from_slice(&buf.chunk()[..4]);
+                buf.advance(4);
+                ProtTarget::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), 0))
+            }
+            Self::ATYP_IPV6 => {
+                if buf.remaining() < 16 {
+                    return Err(ProxyError::TooShort);
+                }
+                let mut ip = [0u8; 16];
+                ip.copy_from_slice(&buf.chunk()[..16]);
+                buf.advance(16);
+                ProtTarget::Ip(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), 0))
+            }
+            Self::ATYP_DOMAIN => {
+                let domain = read_short_string(buf)?;
+                if !Self::is_valid_domain(&domain) {
+                    return Err(ProxyError::Extension("invalid create target"));
+                }
+                ProtTarget::Domain(domain, 0)
+            }
+            _ => return Err(ProxyError::Extension("invalid create target")),
+        };
+        if buf.remaining() < 2 {
+            return Err(ProxyError::TooShort);
+        }
+        let port = buf.get_u16();
+        if port == 0 {
+            return Err(ProxyError::Extension("invalid create target"));
+        }
+        Ok(match target {
+            ProtTarget::Ip(addr) => ProtTarget::Ip(SocketAddr::new(addr.ip(), port)),
+            ProtTarget::Domain(domain, _) => ProtTarget::Domain(domain, port),
+        })
+    }
+
+    fn encode_len(&self) -> u32 {
+        let addr = match self {
+            ProtTarget::Ip(SocketAddr::V4(_)) => 4,
+            ProtTarget::Ip(SocketAddr::V6(_)) => 16,
+            ProtTarget::Domain(domain, _) => 1 + domain.len() as u32,
+        };
+        1 + addr + 2
+    }
+
+    fn encode<B: Buf + BufMut>(&self, buf: &mut B) -> ProxyResult<usize> {
+        let mut size = 0;
+        match self {
+            ProtTarget::Ip(SocketAddr::V4(addr)) => {
+                size += buf.put_u8(Self::ATYP_IPV4);
+                size += buf.put_slice(&addr.ip().octets());
+            }
+            ProtTarget::Ip(SocketAddr::V6(addr)) => {
+                size += buf.put_u8(Self::ATYP_IPV6);
+                size += buf.put_slice(&addr.ip().octets());
+            }
+            ProtTarget::Domain(domain, _) => {
+                size += buf.put_u8(Self::ATYP_DOMAIN);
+                size += write_short_string(buf, domain)?;
+            }
+        }
+        size += buf.put_u16(self.port());
+        Ok(size)
+    }
+}
+
+impl FromStr for ProtTarget {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            if addr.port() == 0 {
+                return Err(ProxyError::Extension("invalid create target"));
+            }
+            return Ok(ProtTarget::Ip(addr));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or(ProxyError::Extension("invalid create target"))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| ProxyError::Extension("invalid create target"))?;
+        if port == 0 || !Self::is_valid_domain(host) {
+            return Err(ProxyError::Extension("invalid create target"));
+        }
+        Ok(ProtTarget::Domain(host.to_string(), port))
+    }
+}
+
+impl fmt::Display for ProtTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtTarget::Ip(addr) => write!(f, "{}", addr),
+            ProtTarget::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
 
 /// 新的Socket连接请求,
 /// 接收方创建一个虚拟链接来对应该Socket的读取写入
+/// 包体为映射的域名, 之后为一个字节的协议提示及可选的目标地址, 旧版本只有域名
 #[derive(Debug)]
-#[allow(dead_code)]
 pub struct ProtCreate {
     sock_map: u64,
-    mode: u8,
+    mode: ProtCreateMode,
     domain: Option<String>,
+    target: Option<ProtTarget>,
 }
 
 impl ProtCreate {
     pub fn new(sock_map: u64, domain: Option<String>) -> Self {
         Self {
             sock_map,
-            mode: 0,
+            mode: ProtCreateMode::Unknown,
             domain,
+            target: None,
         }
     }
 
-    pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtCreate> {
-        let length = buf.get_u8() as usize;
-        let mut domain = None;
-        if length > buf.remaining() {
-            return Err(crate::ProxyError::TooShort);
+    pub fn new_target(
+        sock_map: u64,
+        domain: Option<String>,
+        mode: ProtCreateMode,
+        target: Option<ProtTarget>,
+    ) -> Self {
+        Self {
+            sock_map,
+            mode,
+            domain,
+            target,
+        }
+    }
+
+    pub fn parse<T: Buf>(header: ProtFrameHeader, buf: T) -> ProxyResult<ProtCreate> {
+        // 长度以帧头为准, 不读取后续帧的数据
+        let length = header.length as usize;
+        if length == 0 || buf.remaining() < length {
+            return Err(ProxyError::TooShort);
         }
-        if length > 0 {
-            let data = &buf.chunk()[..length];
-            domain = Some(String::from_utf8_lossy(data).to_string());
+        let mut buf = &buf.chunk()[..length];
+        let domain = read_short_string(&mut buf)?;
+        let domain = if domain.is_empty() { None } else { Some(domain) };
+        let mut mode = ProtCreateMode::Unknown;
+        let mut target = None;
+        if buf.has_remaining() {
+            mode = ProtCreateMode::new(buf.get_u8());
+            if buf.has_remaining() {
+                target = Some(ProtTarget::parse(&mut buf)?);
+                if buf.has_remaining() {
+                    return Err(ProxyError::Extension("invalid create target"));
+                }
+            }
         }
         Ok(ProtCreate {
             sock_map: header.sock_map(),
-            mode: 0,
+            mode,
             domain,
+            target,
         })
     }
 
@@ -61,17 +253,21 @@ impl ProtCreate {
             ProtFlag::zero(),
             self.sock_map,
         );
-        let domain_len = self
-            .domain
-            .as_ref()
-            .map(|s| s.as_bytes().len() as u32)
-            .unwrap_or(0);
-        head.length = 1 + domain_len;
+        let domain = self.domain.as_deref().unwrap_or("");
+        head.length = 1 + domain.len() as u32;
+        // 无附加信息时保持旧版本的格式
+        let extend = self.mode != ProtCreateMode::Unknown || self.target.is_some();
+        if extend {
+            head.length += 1 + self.target.as_ref().map(|t| t.encode_len()).unwrap_or(0);
+        }
         let mut size = 0;
         size += head.encode(buf)?;
-        size += buf.put_u8(domain_len as u8);
-        if let Some(d) = &self.domain {
-            size += buf.put_slice(d.as_bytes());
+        size += write_short_string(buf, domain)?;
+        if extend {
+            size += buf.put_u8(self.mode.encode());
+            if let Some(target) = &self.target {
+                size += target.encode(buf)?;
+            }
         }
         Ok(size)
     }
@@ -83,4 +279,92 @@ impl ProtCreate {
     pub fn domain(&self) -> &Option<String> {
         &self.domain
     }
+
+    pub fn mode(&self) -> ProtCreateMode {
+        self.mode
+    }
+
+    pub fn target(&self) -> Option<&ProtTarget> {
+        self.target.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Buf};
+
+    use crate::prot::{ProtFlag, ProtFrame, ProtFrameHeader, ProtKind};
+
+    use super::{ProtCreate, ProtCreateMode, ProtTarget};
+
+    fn round_trip(create: ProtCreate) -> ProtCreate {
+        let mut buf = BinaryMut::new();
+        create.encode(&mut buf).unwrap();
+        let header = ProtFrameHeader::parse(&mut buf).unwrap();
+        match ProtFrame::parse(header, buf.chunk()).unwrap() {
+            ProtFrame::Create(p) => p,
+            p => panic!("unexpected frame {:?}", p),
+        }
+    }
+
+    #[test]
+    fn test_create_target() {
+        for target in ["example.com:443", "127.0.0.1:8080", "[::1]:80"] {
+            let target = target.parse::<ProtTarget>().unwrap();
+            let create = ProtCreate::new_target(
+                7,
+                Some("web".to_string()),
+                ProtCreateMode::Https,
+                Some(target.clone()),
+            );
+            let p = round_trip(create);
+            assert_eq!(p.sock_map(), 7);
+            assert_eq!(p.domain(), &Some("web".to_string()));
+            assert_eq!(p.mode(), ProtCreateMode::Https);
+            assert_eq!(p.target(), Some(&target));
+        }
+        let target = "example.com:443".parse::<ProtTarget>().unwrap();
+        assert_eq!(target, ProtTarget::Domain("example.com".to_string(), 443));
+        assert_eq!(target.to_string(), "example.com:443");
+        assert_eq!("[::1]:80".parse::<ProtTarget>().unwrap().to_string(), "[::1]:80");
+
+        // 无附加信息时与旧版本格式一致
+        let p = round_trip(ProtCreate::new(3, Some("tcp".to_string())));
+        assert_eq!(p.domain(), &Some("tcp".to_string()));
+        assert_eq!(p.mode(), ProtCreateMode::Unknown);
+        assert_eq!(p.target(), None);
+        let p = round_trip(ProtCreate::new(3, None));
+        assert_eq!(p.domain(), &None);
+
+        for target in ["example.com", "example.com:0", "exa mple.com:80", ":80", "a:b"] {
+            assert!(target.parse::<ProtTarget>().is_err(), "{}", target);
+        }
+    }
+
+    #[test]
+    fn test_create_malformed() {
+        let parse = |body: &[u8]| {
+            let mut buf = BinaryMut::new();
+            let mut header = ProtFrameHeader::new(ProtKind::Create, ProtFlag::zero(), 1);
+            header.length = body.len() as u32;
+            header.encode(&mut buf).unwrap();
+            buf.put_slice(body);
+            let header = ProtFrameHeader::parse(&mut buf).unwrap();
+            ProtFrame::parse(header, buf.chunk())
+        };
+        assert!(parse(b"\x03web\x01\x01\x7f\x00\x00\x01\x00\x50").is_ok());
+        // 长度不足
+        assert!(parse(b"").is_err());
+        assert!(parse(b"\x05web").is_err());
+        assert!(parse(b"\x00\x01\x01\x7f\x00").is_err());
+        assert!(parse(b"\x00\x01\x01\x7f\x00\x00\x01\x00").is_err());
+        assert!(parse(b"\x00\x01\x04\x00\x00").is_err());
+        assert!(parse(b"\x00\x01\x03\x05ab").is_err());
+        // 错误的地址类型, 端口或域名
+        assert!(parse(b"\x00\x01\x02\x7f\x00\x00\x01\x00\x50").is_err());
+        assert!(parse(b"\x00\x01\x01\x7f\x00\x00\x01\x00\x00").is_err());
+        assert!(parse(b"\x00\x01\x03\x00\x00\x50").is_err());
+        assert!(parse(b"\x00\x01\x03\x03a/b\x00\x50").is_err());
+        assert!(parse(b"\x00\x01\x01\x7f\x00\x00\x01\x00\x50\x00").is_err());
+    }
 }
@@ -10,11 +10,14 @@
 // -----
 // Created Date: 2023/09/22 10:28:41
 
+use std::io::{Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use webparse::{Buf, BufMut, Serialize};
 
 use crate::{
     prot::{ProtFlag, ProtKind},
-    ProxyResult,
+    ProxyError, ProxyResult,
 };
 
 use super::ProtFrameHeader;
@@ -27,18 +30,56 @@ pub struct ProtData {
 }
 
 impl ProtData {
+    /// 小于该长度的数据不做压缩
+    pub const MIN_COMPRESS_LEN: usize = 128;
+    /// 解压后的最大长度, 与包体的最大长度一致
+    pub const MAX_DECOMPRESS_LEN: u64 = 0xFFFFFF;
+
     pub fn new(sock_map: u64, data: Vec<u8>) -> ProtData {
         Self { sock_map, data }
     }
 
     pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtData> {
         log::trace!("代理中心: 解码Data数据长度={}", header.length);
+        let chunk = buf.advance_chunk(header.length as usize);
+        let data = if header.flag().is_compress() {
+            let mut data = vec![];
+            DeflateDecoder::new(chunk)
+                .take(Self::MAX_DECOMPRESS_LEN + 1)
+                .read_to_end(&mut data)?;
+            if data.len() as u64 > Self::MAX_DECOMPRESS_LEN {
+                return Err(ProxyError::Extension("decompress data too large"));
+            }
+            data
+        } else {
+            chunk.to_vec()
+        };
         Ok(Self {
             sock_map: header.sock_map(),
-            data: buf.advance_chunk(header.length as usize).to_vec(),
+            data,
         })
     }
 
+    /// 压缩数据后编码, 压缩后未变小的按原始数据编码
+    pub fn encode_compress<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
+        if self.data.len() < Self::MIN_COMPRESS_LEN {
+            return self.encode(buf);
+        }
+        let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+        encoder.write_all(&self.data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() >= self.data.len() {
+            return self.encode(buf);
+        }
+        log::trace!("代理中心: 编码压缩Data数据长度={}->{}", self.data.len(), compressed.len());
+        let mut head = ProtFrameHeader::new(ProtKind::Data, ProtFlag::compress(), self.sock_map);
+        head.length = compressed.len() as u32;
+        let mut size = 0;
+        size += head.encode(buf)?;
+        size += buf.put_slice(&compressed);
+        Ok(size)
+    }
+
     pub fn encode<B: Buf + BufMut>(mut self, buf: &mut B) -> ProxyResult<usize> {
         log::trace!("代理中心: 编码Data数据长度={}", self.data.len());
         let mut head = ProtFrameHeader::new(ProtKind::Data, ProtFlag::zero(), self.sock_map);
@@ -57,3 +98,51 @@ impl ProtData {
         self.sock_map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use webparse::{BinaryMut, Buf};
+
+    use super::ProtData;
+    use crate::{
+        prot::{ProtFrameHeader, ProtKind},
+        Helper, ProtFrame,
+    };
+
+    #[test]
+    fn test_data_round_trip() {
+        for data in [vec![], b"wmproxy".to_vec(), vec![7u8; 4096]] {
+            let mut buf = BinaryMut::new();
+            let size = ProtData::new(5, data.clone()).encode(&mut buf).unwrap();
+            assert_eq!(size, ProtFrameHeader::FRAME_HEADER_BYTES + data.len());
+            let header = ProtFrameHeader::parse(&mut buf).unwrap();
+            assert_eq!(header.kind(), ProtKind::Data);
+            assert_eq!(header.length as usize, data.len());
+            assert_eq!(header.sock_map(), 5);
+            assert_eq!(buf.remaining(), data.len());
+            let frame = ProtData::parse(header, buf.chunk()).unwrap();
+            assert_eq!(frame.sock_map(), 5);
+            assert_eq!(frame.data(), &data);
+        }
+    }
+
+    #[test]
+    fn test_data_before_close() {
+        // 同一sock_map的数据及关闭消息按发送顺序解析, 关闭前的数据不丢失
+        let mut buf = BinaryMut::new();
+        ProtFrame::new_data(3, b"last".to_vec()).encode(&mut buf).unwrap();
+        ProtFrame::new_close(3).encode(&mut buf).unwrap();
+        match Helper::decode_frame(&mut buf).unwrap() {
+            Some(ProtFrame::Data(d)) => {
+                assert_eq!(d.sock_map(), 3);
+                assert_eq!(d.data(), b"last");
+            }
+            p => panic!("unexpected frame {:?}", p),
+        }
+        match Helper::decode_frame(&mut buf).unwrap() {
+            Some(ProtFrame::Close(c)) => assert_eq!(c.sock_map(), 3),
+            p => panic!("unexpected frame {:?}", p),
+        }
+        assert!(Helper::decode_frame(&mut buf).unwrap().is_none());
+    }
+}
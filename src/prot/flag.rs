@@ -23,6 +23,8 @@ bitflags! {
         const CLOSE = 0x4;
         /// 数据消息
         const DATA = 0x8;
+        /// 数据消息的内容已压缩, 仅用于Data
+        const COMPRESS = 0x10;
     }
 }
 
@@ -74,6 +76,14 @@ impl ProtFlag {
         self.contains(ProtFlag::DATA)
     }
 
+    pub fn compress() -> ProtFlag {
+        ProtFlag::COMPRESS
+    }
+
+    pub fn is_compress(&self) -> bool {
+        self.contains(ProtFlag::COMPRESS)
+    }
+
     pub fn kind(&self) -> Self {
         let mut new = self.clone();
         new.set(ProtFlag::ACK, false);
@@ -15,7 +15,10 @@ use webparse::{Buf, http2::frame::{read_u24, encode_u24}, BufMut};
 
 use crate::{Helper, MappingConfig, ProxyResult};
 
-use super::{ProtCreate, ProtClose, ProtData, ProtFlag, ProtKind, ProtMapping, ProtToken};
+use super::{
+    ProtClose, ProtCloseCode, ProtCreate, ProtCreateMode, ProtData, ProtFlag, ProtKind, ProtMapping, ProtPing,
+    ProtPong, ProtTarget, ProtToken, ProtWindowUpdate,
+};
 
 /// 协议相关头信息
 #[derive(Debug)]
@@ -43,10 +46,18 @@ pub enum ProtFrame {
     Token(ProtToken),
     /// 收到内网映射的相关消息
     Mapping(ProtMapping),
+    /// 收到心跳检测
+    Ping(ProtPing),
+    /// 收到心跳回应
+    Pong(ProtPong),
+    /// 收到流量控制的窗口更新
+    WindowUpdate(ProtWindowUpdate),
 }
 
 impl ProtFrameHeader {
     pub const FRAME_HEADER_BYTES: usize = 12;
+    /// 3个字节可表示的最大包体长度
+    pub const MAX_FRAME_SIZE: u32 = 0xFF_FFFF;
 
     pub fn new(kind: ProtKind, flag: ProtFlag, sock_map: u64) -> ProtFrameHeader {
         ProtFrameHeader {
@@ -61,6 +72,10 @@ impl ProtFrameHeader {
         self.sock_map
     }
 
+    pub fn kind(&self) -> ProtKind {
+        self.kind
+    }
+
     pub fn flag(&self) -> ProtFlag {
         self.flag
     }
@@ -105,18 +120,27 @@ impl ProtFrameHeader {
 }
 
 impl ProtFrame {
-    /// 把字节流转化成数据对象
+    /// 把字节流转化成数据对象, 仅解析头信息中声明长度的包体, 不足该长度时返回错误
     pub fn parse<T: Buf>(
         header: ProtFrameHeader,
-        buf: T,
+        mut buf: T,
     ) -> ProxyResult<ProtFrame> {
+        if buf.remaining() < header.length as usize {
+            return Err(crate::ProxyError::TooShort);
+        }
+        let buf = buf.advance_chunk(header.length as usize);
         let v = match header.kind {
             ProtKind::Data => ProtFrame::Data(ProtData::parse(header, buf)?) ,
             ProtKind::Create => ProtFrame::Create(ProtCreate::parse(header, buf)?),
             ProtKind::Close => ProtFrame::Close(ProtClose::parse(header, buf)?),
             ProtKind::Mapping => ProtFrame::Mapping(ProtMapping::parse(header, buf)?),
             ProtKind::Token => ProtFrame::Token(ProtToken::parse(header, buf)?),
-            ProtKind::Unregistered => todo!(),
+            ProtKind::Ping => ProtFrame::Ping(ProtPing::parse(header, buf)?),
+            ProtKind::Pong => ProtFrame::Pong(ProtPong::parse(header, buf)?),
+            ProtKind::WindowUpdate => {
+                ProtFrame::WindowUpdate(ProtWindowUpdate::parse(header, buf)?)
+            }
+            ProtKind::Unregistered => return Err(crate::ProxyError::ProtNoSupport),
         };
         Ok(v)
     }
@@ -132,14 +156,34 @@ impl ProtFrame {
             ProtFrame::Close(s) => s.encode(buf)?,
             ProtFrame::Mapping(s) => s.encode(buf)?,
             ProtFrame::Token(s) => s.encode(buf)?,
+            ProtFrame::Ping(s) => s.encode(buf)?,
+            ProtFrame::Pong(s) => s.encode(buf)?,
+            ProtFrame::WindowUpdate(s) => s.encode(buf)?,
         };
         Ok(size)
     }
 
+    /// 按是否开启压缩转化成字节流, 仅压缩Data的内容, 控制类消息均不压缩
+    pub fn encode_by<B: Buf + BufMut>(self, buf: &mut B, compress: bool) -> ProxyResult<usize> {
+        match self {
+            ProtFrame::Data(s) if compress => s.encode_compress(buf),
+            _ => self.encode(buf),
+        }
+    }
+
     pub fn new_create(sock_map: u64, domain: Option<String>) -> Self {
         Self::Create(ProtCreate::new(sock_map, domain))
     }
 
+    pub fn new_create_target(
+        sock_map: u64,
+        domain: Option<String>,
+        mode: ProtCreateMode,
+        target: Option<ProtTarget>,
+    ) -> Self {
+        Self::Create(ProtCreate::new_target(sock_map, domain, mode, target))
+    }
+
     pub fn new_close(sock_map: u64) -> Self {
         Self::Close(ProtClose::new(sock_map))
     }
@@ -148,6 +192,10 @@ impl ProtFrame {
         Self::Close(ProtClose::new_by_reason(sock_map, reason))
     }
 
+    pub fn new_close_code(sock_map: u64, code: ProtCloseCode, reason: String) -> Self {
+        Self::Close(ProtClose::new_by_code(sock_map, code, reason))
+    }
+
     pub fn new_data(sock_map: u64, data: Vec<u8>) -> Self {
         Self::Data(ProtData::new(sock_map, data))
     }
@@ -160,6 +208,18 @@ impl ProtFrame {
         Self::Token(ProtToken::new(username, password))
     }
 
+    pub fn new_ping(sock_map: u64, data: [u8; 8]) -> Self {
+        Self::Ping(ProtPing::new(sock_map, data))
+    }
+
+    pub fn new_pong(sock_map: u64, data: [u8; 8]) -> Self {
+        Self::Pong(ProtPong::new(sock_map, data))
+    }
+
+    pub fn new_window_update(sock_map: u64, size: u32) -> Self {
+        Self::WindowUpdate(ProtWindowUpdate::new(sock_map, size))
+    }
+
     pub fn is_create(&self) -> bool {
         match self {
             ProtFrame::Create(_) => true,
@@ -195,7 +255,137 @@ impl ProtFrame {
             ProtFrame::Close(s) => s.sock_map(),
             ProtFrame::Mapping(s) => s.sock_map(),
             ProtFrame::Token(s) => s.sock_map(),
+            ProtFrame::Ping(s) => s.sock_map(),
+            ProtFrame::Pong(s) => s.sock_map(),
+            ProtFrame::WindowUpdate(s) => s.sock_map(),
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use webparse::{http2::frame::encode_u24, BinaryMut, Buf};
+
+    use super::{ProtCloseCode, ProtFlag, ProtFrame, ProtFrameHeader, ProtKind};
+    use crate::Helper;
+
+    fn encode_header(frame: ProtFrame, compress: bool) -> (ProtFrameHeader, BinaryMut) {
+        let mut buf = BinaryMut::new();
+        frame.encode_by(&mut buf, compress).unwrap();
+        let mut copy = buf.clone();
+        (ProtFrameHeader::parse(&mut copy).unwrap(), buf)
+    }
+
+    #[test]
+    fn test_encode_compress() {
+        // 控制类消息即使开启压缩也按原样发送
+        let reason = "x".repeat(200);
+        let (header, mut buf) = encode_header(ProtFrame::new_close_reason(3, reason.clone()), true);
+        assert!(!header.flag().is_compress());
+        assert_eq!(header.length as usize, reason.len() + 2);
+        match Helper::decode_frame(&mut buf).unwrap() {
+            Some(ProtFrame::Close(p)) => assert_eq!(p.reason(), &reason),
+            p => panic!("unexpected frame {:?}", p),
+        }
+        let (header, _) = encode_header(ProtFrame::new_create(3, Some("a".repeat(200))), true);
+        assert!(!header.flag().is_compress());
+
+        // 数据消息压缩后解码还原
+        let data = b"wmproxy ".repeat(100);
+        let (header, mut buf) = encode_header(ProtFrame::new_data(3, data.clone()), true);
+        assert!(header.flag().is_compress());
+        assert!((header.length as usize) < data.len());
+        match Helper::decode_frame(&mut buf).unwrap() {
+            Some(ProtFrame::Data(p)) => assert_eq!(p.data(), &data),
+            p => panic!("unexpected frame {:?}", p),
+        }
+
+        // 未开启压缩或数据过小时不压缩
+        let (header, _) = encode_header(ProtFrame::new_data(3, data.clone()), false);
+        assert!(!header.flag().is_compress());
+        assert_eq!(header.length as usize, data.len());
+        let (header, _) = encode_header(ProtFrame::new_data(3, b"small".to_vec()), true);
+        assert!(!header.flag().is_compress());
+    }
+
+    #[test]
+    fn test_close_code() {
+        let (_, mut buf) = encode_header(
+            ProtFrame::new_close_code(3, ProtCloseCode::Timeout, "ping".to_string()),
+            false,
+        );
+        // 后续的消息不影响原因码的解析
+        ProtFrame::new_data(5, b"data".to_vec()).encode(&mut buf).unwrap();
+        match Helper::decode_frame(&mut buf).unwrap() {
+            Some(ProtFrame::Close(p)) => {
+                assert_eq!(p.code(), ProtCloseCode::Timeout);
+                assert_eq!(p.reason(), "ping");
+            }
+            p => panic!("unexpected frame {:?}", p),
+        }
+        assert!(Helper::decode_frame(&mut buf).unwrap().unwrap().is_data());
+
+        // 旧版本的空包体及仅有原因描述的包体均为Normal
+        let mut buf = BinaryMut::new();
+        ProtFrameHeader::new(ProtKind::Close, ProtFlag::zero(), 3)
+            .encode(&mut buf)
+            .unwrap();
+        let mut head = ProtFrameHeader::new(ProtKind::Close, ProtFlag::zero(), 5);
+        head.length = 3;
+        head.encode(&mut buf).unwrap();
+        buf.put_slice(&[2, b'o', b'k']);
+        ProtFrame::new_data(5, vec![2]).encode(&mut buf).unwrap();
+        for reason in ["", "ok"] {
+            match Helper::decode_frame(&mut buf).unwrap() {
+                Some(ProtFrame::Close(p)) => {
+                    assert_eq!(p.code(), ProtCloseCode::Normal);
+                    assert_eq!(p.reason(), reason);
+                }
+                p => panic!("unexpected frame {:?}", p),
+            }
+        }
+        assert!(Helper::decode_frame(&mut buf).unwrap().unwrap().is_data());
+    }
+
+    #[test]
+    fn test_frame_length() {
+        // 声明的长度不足包体时返回错误, 不读取后续消息的内容
+        let mut buf = BinaryMut::new();
+        let mut head = ProtFrameHeader::new(ProtKind::Create, ProtFlag::zero(), 3);
+        head.encode(&mut buf).unwrap();
+        ProtFrame::new_data(5, b"data".to_vec()).encode(&mut buf).unwrap();
+        assert!(Helper::decode_frame(&mut buf).is_err());
+        let mut buf = BinaryMut::new();
+        head.length = 2;
+        head.encode(&mut buf).unwrap();
+        buf.put_slice(&[5, b'a']);
+        assert!(Helper::decode_frame(&mut buf).is_err());
+
+        // 包体不完整时等待后续数据
+        let (_, mut buf) = encode_header(ProtFrame::new_data(3, b"data".to_vec()), false);
+        let mut part = BinaryMut::new();
+        part.put_slice(&buf.chunk()[..buf.remaining() - 1]);
+        assert!(Helper::decode_frame(&mut part).unwrap().is_none());
+        assert!(Helper::decode_frame(&mut buf).unwrap().is_some());
+
+        // 直接解析时数据不足声明的长度返回错误
+        let (header, buf) = encode_header(ProtFrame::new_data(3, b"data".to_vec()), false);
+        let mut copy = buf.clone();
+        ProtFrameHeader::parse(&mut copy).unwrap();
+        assert!(ProtFrame::parse(header, &copy.chunk()[..2]).is_err());
+
+        // 超出最大长度时返回错误, 未识别的类型不再panic
+        let (_, mut buf) = encode_header(ProtFrame::new_data(3, vec![1u8; 100]), false);
+        assert!(Helper::decode_frame_limit(&mut buf.clone(), 99).is_err());
+        assert!(Helper::decode_frame_limit(&mut buf, 100).unwrap().is_some());
+        let mut buf = BinaryMut::new();
+        encode_u24(&mut buf, 0xFF_FFFF);
+        buf.put_slice(&[0u8; 9]);
+        assert!(Helper::decode_frame_limit(&mut buf, 1024 * 1024).is_err());
+        let mut buf = BinaryMut::new();
+        ProtFrameHeader::new(ProtKind::Unregistered, ProtFlag::zero(), 3)
+            .encode(&mut buf)
+            .unwrap();
+        assert!(Helper::decode_frame(&mut buf).is_err());
+    }
+}
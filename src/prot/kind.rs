@@ -18,6 +18,9 @@ pub enum ProtKind {
     Close = 2,
     Mapping = 3,
     Token = 4,
+    Ping = 5,
+    Pong = 6,
+    WindowUpdate = 7,
     Unregistered
 }
 
@@ -29,6 +32,9 @@ impl ProtKind {
             2 => ProtKind::Close,
             3 => ProtKind::Mapping,
             4 => ProtKind::Token,
+            5 => ProtKind::Ping,
+            6 => ProtKind::Pong,
+            7 => ProtKind::WindowUpdate,
             _ => ProtKind::Unregistered
         }
     }
@@ -40,6 +46,9 @@ impl ProtKind {
             ProtKind::Close => 2,
             ProtKind::Mapping => 3,
             ProtKind::Token => 4,
+            ProtKind::Ping => 5,
+            ProtKind::Pong => 6,
+            ProtKind::WindowUpdate => 7,
             ProtKind::Unregistered => 255
         }
     }
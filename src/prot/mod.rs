@@ -18,15 +18,21 @@ mod data;
 mod frame;
 mod kind;
 mod mapping;
+mod ping;
+mod pong;
 mod token;
+mod window_update;
 
 pub use flag::ProtFlag;
 pub use kind::ProtKind;
-pub use create::ProtCreate;
-pub use close::ProtClose;
+pub use create::{ProtCreate, ProtCreateMode, ProtTarget};
+pub use close::{ProtClose, ProtCloseCode};
 pub use data::ProtData;
 pub use mapping::ProtMapping;
+pub use ping::ProtPing;
+pub use pong::ProtPong;
 pub use token::ProtToken;
+pub use window_update::ProtWindowUpdate;
 pub use frame::{ProtFrame, ProtFrameHeader};
 
 use webparse::{Buf, BufMut};
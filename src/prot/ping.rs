@@ -0,0 +1,66 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/20 10:21:05
+
+use webparse::{Buf, BufMut};
+
+use crate::{
+    prot::{ProtFlag, ProtKind},
+    ProxyResult,
+};
+
+use super::ProtFrameHeader;
+
+/// 心跳检测, 接收到则回应携带相同内容的Pong
+#[derive(Debug)]
+pub struct ProtPing {
+    sock_map: u64,
+    data: [u8; 8],
+}
+
+impl ProtPing {
+    /// 携带内容的长度
+    pub const DATA_BYTES: usize = 8;
+
+    pub fn new(sock_map: u64, data: [u8; 8]) -> ProtPing {
+        ProtPing { sock_map, data }
+    }
+
+    pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtPing> {
+        if header.length as usize != Self::DATA_BYTES || buf.remaining() < Self::DATA_BYTES {
+            return Err(crate::ProxyError::TooShort);
+        }
+        let mut data = [0u8; 8];
+        data.copy_from_slice(&buf.chunk()[..Self::DATA_BYTES]);
+        buf.advance(Self::DATA_BYTES);
+        Ok(ProtPing {
+            sock_map: header.sock_map(),
+            data,
+        })
+    }
+
+    pub fn encode<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
+        let mut head = ProtFrameHeader::new(ProtKind::Ping, ProtFlag::zero(), self.sock_map);
+        head.length = Self::DATA_BYTES as u32;
+        let mut size = 0;
+        size += head.encode(buf)?;
+        size += buf.put_slice(&self.data);
+        Ok(size)
+    }
+
+    pub fn sock_map(&self) -> u64 {
+        self.sock_map
+    }
+
+    pub fn data(&self) -> &[u8; 8] {
+        &self.data
+    }
+}
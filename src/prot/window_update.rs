@@ -0,0 +1,64 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/21 09:42:18
+
+use webparse::{Buf, BufMut};
+
+use crate::{
+    prot::{ProtFlag, ProtKind},
+    ProxyResult,
+};
+
+use super::ProtFrameHeader;
+
+/// 流量控制的窗口更新, 接收端处理完数据后通知发送端可继续发送的字节数
+#[derive(Debug)]
+pub struct ProtWindowUpdate {
+    sock_map: u64,
+    size: u32,
+}
+
+impl ProtWindowUpdate {
+    /// 每个sock_map初始的发送窗口大小
+    pub const INIT_WINDOW: u32 = 256 * 1024;
+
+    pub fn new(sock_map: u64, size: u32) -> ProtWindowUpdate {
+        ProtWindowUpdate { sock_map, size }
+    }
+
+    pub fn parse<T: Buf>(header: ProtFrameHeader, mut buf: T) -> ProxyResult<ProtWindowUpdate> {
+        if header.length != 4 || buf.remaining() < 4 {
+            return Err(crate::ProxyError::TooShort);
+        }
+        Ok(ProtWindowUpdate {
+            sock_map: header.sock_map(),
+            size: buf.get_u32(),
+        })
+    }
+
+    pub fn encode<B: Buf + BufMut>(self, buf: &mut B) -> ProxyResult<usize> {
+        let mut head =
+            ProtFrameHeader::new(ProtKind::WindowUpdate, ProtFlag::zero(), self.sock_map);
+        head.length = 4;
+        let mut size = 0;
+        size += head.encode(buf)?;
+        size += buf.put_u32(self.size);
+        Ok(size)
+    }
+
+    pub fn sock_map(&self) -> u64 {
+        self.sock_map
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+}
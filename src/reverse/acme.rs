@@ -0,0 +1,570 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/23 11:06:20
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader},
+    path::Path,
+    sync::RwLock,
+    time::Duration,
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{
+        EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio_util::sync::CancellationToken;
+use webparse::{BinaryMut, Buf, Request};
+use wenmeng::Client;
+
+use super::{der::write_tlv, HttpConfig};
+use crate::{ConfigDuration, ProxyResult};
+
+/// HTTP-01校验时访问的路径前缀
+pub const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+lazy_static! {
+    /// 正在校验的token及其对应的内容
+    static ref CHALLENGES: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+fn default_directory() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_data_dir() -> String {
+    "acme".to_string()
+}
+
+fn default_renew_before() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30 * 24 * 3600))
+}
+
+fn default_check_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(12 * 3600))
+}
+
+/// 通过ACME(如Let's Encrypt)自动申请及续期证书, 使用HTTP-01校验, 需在80端口提供HTTP服务
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcmeConfig {
+    /// 联系邮箱, 证书即将过期时由CA通知
+    pub email: Option<String>,
+    /// ACME服务的目录地址
+    #[serde(default = "default_directory")]
+    pub directory: String,
+    /// 保存账号及证书的目录
+    #[serde(default = "default_data_dir")]
+    pub data_dir: String,
+    /// 证书剩余有效期小于该值时重新申请
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_renew_before")]
+    pub renew_before: ConfigDuration,
+    /// 检查证书是否需要续期的间隔
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_check_interval")]
+    pub check_interval: ConfigDuration,
+}
+
+impl AcmeConfig {
+    pub fn new(directory: String, data_dir: String) -> Self {
+        AcmeConfig {
+            email: None,
+            directory,
+            data_dir,
+            renew_before: default_renew_before(),
+            check_interval: default_check_interval(),
+        }
+    }
+
+    pub fn cert_path(&self, domain: &str) -> String {
+        format!("{}/{}.pem", self.data_dir, domain)
+    }
+
+    pub fn key_path(&self, domain: &str) -> String {
+        format!("{}/{}.key", self.data_dir, domain)
+    }
+
+    /// 未缓存证书, 证书无法解析或剩余有效期不足时需要申请
+    pub fn need_issue(&self, domain: &str) -> bool {
+        if !Path::new(&self.key_path(domain)).exists() {
+            return true;
+        }
+        let cert = File::open(self.cert_path(domain)).ok().and_then(|file| {
+            rustls_pemfile::certs(&mut BufReader::new(file))
+                .next()?
+                .ok()
+        });
+        let not_after = match cert.as_ref().and_then(super::der::not_after) {
+            Some(not_after) => not_after,
+            None => return true,
+        };
+        match (not_after - Utc::now()).to_std() {
+            Ok(remain) => remain < self.renew_before.0,
+            Err(_) => true,
+        }
+    }
+}
+
+/// ACME的接口地址
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// 与ACME服务交互的账号, 请求以JWS(ES256)签名
+struct AcmeClient {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    directory: Directory,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+/// ACME服务返回的状态码, Location头及内容
+struct AcmeResponse {
+    status: u16,
+    location: Option<String>,
+    nonce: Option<String>,
+    body: String,
+}
+
+impl AcmeResponse {
+    fn json(&self) -> ProxyResult<Value> {
+        serde_json::from_str(&self.body).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("ACME返回内容无法解析:{:?}", e),
+            )
+            .into()
+        })
+    }
+
+    fn check(self) -> ProxyResult<Self> {
+        if self.status >= 400 {
+            return Err(io::Error::other(format!(
+                "ACME请求失败, 状态码:{}, 内容:{}",
+                self.status, self.body
+            ))
+            .into());
+        }
+        Ok(self)
+    }
+}
+
+fn to_pem(label: &str, der: &[u8]) -> String {
+    let data = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in data.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+fn json_str(value: &Value, key: &str) -> ProxyResult<String> {
+    match value[key].as_str() {
+        Some(v) => Ok(v.to_string()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("ACME返回内容缺少{}", key),
+        )
+        .into()),
+    }
+}
+
+/// 生成证书签名请求(CSR), 主题及SAN均为该域名
+fn build_csr(key: &EcdsaKeyPair, rng: &SystemRandom, domain: &str) -> ProxyResult<Vec<u8>> {
+    let oid = |v: &[u8]| write_tlv(0x06, v);
+    let cn = [oid(&[0x55, 0x04, 0x03]), write_tlv(0x0C, domain.as_bytes())].concat();
+    let subject = write_tlv(0x30, &write_tlv(0x31, &write_tlv(0x30, &cn)));
+    let alg = [
+        oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01]),
+        oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07]),
+    ]
+    .concat();
+    let public = [&[0u8][..], key.public_key().as_ref()].concat();
+    let spki = write_tlv(
+        0x30,
+        &[write_tlv(0x30, &alg), write_tlv(0x03, &public)].concat(),
+    );
+    let san = write_tlv(0x30, &write_tlv(0x82, domain.as_bytes()));
+    let ext = [oid(&[0x55, 0x1D, 0x11]), write_tlv(0x04, &san)].concat();
+    let ext_req = [
+        oid(&[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x09, 0x0E]),
+        write_tlv(0x31, &write_tlv(0x30, &write_tlv(0x30, &ext))),
+    ]
+    .concat();
+    let info = write_tlv(
+        0x30,
+        &[
+            write_tlv(0x02, &[0]),
+            subject,
+            spki,
+            write_tlv(0xA0, &write_tlv(0x30, &ext_req)),
+        ]
+        .concat(),
+    );
+    let sign = key
+        .sign(rng, &info)
+        .map_err(|_| io::Error::other("生成CSR签名失败"))?;
+    let sign = [&[0u8][..], sign.as_ref()].concat();
+    let sign_alg = write_tlv(
+        0x30,
+        &oid(&[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02]),
+    );
+    Ok(write_tlv(
+        0x30,
+        &[info, sign_alg, write_tlv(0x03, &sign)].concat(),
+    ))
+}
+
+impl AcmeClient {
+    /// 加载或生成账号密钥, 并获取ACME的接口地址
+    async fn connect(config: &AcmeConfig) -> ProxyResult<Self> {
+        let rng = SystemRandom::new();
+        let path = format!("{}/account.key", config.data_dir);
+        let pkcs8 = match File::open(&path) {
+            Ok(file) => match rustls_pemfile::private_key(&mut BufReader::new(file))? {
+                Some(key) => key.secret_der().to_vec(),
+                None => return Err(io::Error::other(format!("账号密钥{}无效", path)).into()),
+            },
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| io::Error::other("生成ACME账号密钥失败"))?;
+                fs::write(&path, to_pem("PRIVATE KEY", pkcs8.as_ref()))?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| io::Error::other(format!("账号密钥{}无效", path)))?;
+        let res = Self::request("GET", &config.directory, None)
+            .await?
+            .check()?;
+        let value = res.json()?;
+        let directory = Directory {
+            new_nonce: json_str(&value, "newNonce")?,
+            new_account: json_str(&value, "newAccount")?,
+            new_order: json_str(&value, "newOrder")?,
+        };
+        Ok(AcmeClient {
+            key,
+            rng,
+            directory,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    async fn request(method: &str, url: &str, body: Option<String>) -> ProxyResult<AcmeResponse> {
+        let req = Request::builder()
+            .method(method)
+            .url(url)
+            .header("Content-Type", "application/jose+json")
+            .body(body.unwrap_or_default())
+            .unwrap();
+        let client = Client::builder().url(url)?.connect().await?;
+        let (mut recv, _sender) = client.send2(req.into_type()).await?;
+        let mut res = match recv.recv().await {
+            Some(res) => res?,
+            None => return Err(io::Error::other(format!("ACME请求{}未返回", url)).into()),
+        };
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        Ok(AcmeResponse {
+            status: res.status().as_u16(),
+            location: res.headers().get_str_value(&"Location"),
+            nonce: res.headers().get_str_value(&"Replay-Nonce"),
+            body: String::from_utf8_lossy(data.chunk()).to_string(),
+        })
+    }
+
+    fn jwk(&self) -> Value {
+        let public = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        })
+    }
+
+    /// 账号公钥的指纹, 用于生成校验的内容
+    fn thumbprint(&self) -> String {
+        // 按字典序排列的jwk
+        let jwk = self.jwk().to_string();
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()))
+    }
+
+    async fn nonce(&mut self) -> ProxyResult<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let url = self.directory.new_nonce.clone();
+        match Self::request("GET", &url, None).await?.check()?.nonce {
+            Some(nonce) => Ok(nonce),
+            None => Err(io::Error::other("ACME未返回nonce").into()),
+        }
+    }
+
+    /// 发送签名的请求, payload为None时为POST-as-GET, nonce失效时重试一次
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> ProxyResult<AcmeResponse> {
+        let mut retry = true;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload = payload
+                .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+                .unwrap_or_default();
+            let sign = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| io::Error::other("ACME请求签名失败"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(sign.as_ref()),
+            });
+            let res = Self::request("POST", url, Some(body.to_string())).await?;
+            self.nonce = res.nonce.clone();
+            if res.status == 400 && retry && res.body.contains("badNonce") {
+                retry = false;
+                continue;
+            }
+            return res.check();
+        }
+    }
+}
+
+impl AcmeClient {
+    async fn new_account(&mut self, config: &AcmeConfig) -> ProxyResult<()> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = &config.email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = self.directory.new_account.clone();
+        let res = self.post(&url, Some(&payload)).await?;
+        match res.location {
+            Some(kid) => {
+                self.kid = Some(kid);
+                Ok(())
+            }
+            None => Err(io::Error::other("ACME创建账号未返回地址").into()),
+        }
+    }
+
+    /// 轮询直到状态不为pending或processing
+    async fn poll(&mut self, url: &str) -> ProxyResult<Value> {
+        for _ in 0..30 {
+            let value = self.post(url, None).await?.json()?;
+            match value["status"].as_str() {
+                Some("pending") | Some("processing") => {
+                    tokio::time::sleep(Duration::from_secs(1)).await
+                }
+                _ => return Ok(value),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, format!("ACME等待{}超时", url)).into())
+    }
+
+    /// 完成HTTP-01校验, 校验期间由HTTP服务返回对应的内容
+    async fn authorize(&mut self, url: &str) -> ProxyResult<()> {
+        let authz = self.post(url, None).await?.json()?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == "http-01"))
+            .cloned();
+        let challenge = match challenge {
+            Some(challenge) => challenge,
+            None => return Err(io::Error::other("ACME未提供http-01校验").into()),
+        };
+        let token = json_str(&challenge, "token")?;
+        let chall_url = json_str(&challenge, "url")?;
+        let key_auth = format!("{}.{}", token, self.thumbprint());
+        if let Ok(mut challenges) = CHALLENGES.write() {
+            challenges.insert(token.clone(), key_auth);
+        }
+        let ret = async {
+            self.post(&chall_url, Some(&json!({}))).await?;
+            self.poll(url).await
+        }
+        .await;
+        if let Ok(mut challenges) = CHALLENGES.write() {
+            challenges.remove(&token);
+        }
+        let authz = ret?;
+        if authz["status"] != "valid" {
+            return Err(io::Error::other(format!("ACME校验失败:{}", authz)).into());
+        }
+        Ok(())
+    }
+
+    /// 申请该域名的证书, 成功后将证书及私钥保存至`data_dir`
+    async fn issue(config: &AcmeConfig, domain: &str) -> ProxyResult<()> {
+        fs::create_dir_all(&config.data_dir)?;
+        let mut client = Self::connect(config).await?;
+        client.new_account(config).await?;
+        let url = client.directory.new_order.clone();
+        let payload = json!({ "identifiers": [{ "type": "dns", "value": domain }] });
+        let res = client.post(&url, Some(&payload)).await?;
+        let order_url = match &res.location {
+            Some(location) => location.clone(),
+            None => return Err(io::Error::other("ACME创建订单未返回地址").into()),
+        };
+        let order = res.json()?;
+        let authzs = order["authorizations"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        for authz in authzs {
+            if let Some(authz) = authz.as_str() {
+                client.authorize(authz).await?;
+            }
+        }
+
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &client.rng)
+            .map_err(|_| io::Error::other("生成证书私钥失败"))?;
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &client.rng)
+                .map_err(|_| io::Error::other("生成证书私钥失败"))?;
+        let csr = build_csr(&key, &client.rng, domain)?;
+        let finalize = json_str(&order, "finalize")?;
+        let payload = json!({ "csr": URL_SAFE_NO_PAD.encode(csr) });
+        client.post(&finalize, Some(&payload)).await?;
+        let order = client.poll(&order_url).await?;
+        if order["status"] != "valid" {
+            return Err(io::Error::other(format!("ACME订单未完成:{}", order)).into());
+        }
+        let cert_url = json_str(&order, "certificate")?;
+        let cert = client.post(&cert_url, None).await?;
+        if !cert.body.contains("-----BEGIN CERTIFICATE-----") {
+            return Err(io::Error::other("ACME返回的证书无效").into());
+        }
+        fs::write(
+            config.key_path(domain),
+            to_pem("PRIVATE KEY", pkcs8.as_ref()),
+        )?;
+        fs::write(config.cert_path(domain), cert.body)?;
+        Ok(())
+    }
+}
+
+/// 自动申请及续期证书
+pub struct Acme;
+
+impl Acme {
+    /// 返回HTTP-01校验路径对应的内容, 无正在校验的token时返回None
+    pub fn challenge(path: &str) -> Option<String> {
+        let token = path.strip_prefix(ACME_CHALLENGE_PREFIX)?;
+        CHALLENGES.read().ok()?.get(token).cloned()
+    }
+
+    /// 为配置acme的server启动申请及续期的任务, 申请成功后重新加载证书, 收到取消信号后退出
+    /// 申请失败时仅记录错误, 不影响其它server
+    pub fn start(http: &HttpConfig, cancel: &CancellationToken) {
+        for server in &http.server {
+            let config = match &server.acme {
+                Some(config) => config.clone(),
+                None => continue,
+            };
+            let domain = server.comm.domain.clone().unwrap_or(server.up_name.clone());
+            if domain.is_empty() {
+                log::error!("ACME: 配置acme的server需配置up_name");
+                continue;
+            }
+            let http = http.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                loop {
+                    if config.need_issue(&domain) {
+                        log::info!("ACME: 开始申请{}的证书", domain);
+                        match AcmeClient::issue(&config, &domain).await {
+                            Ok(()) => match http.reload_certs() {
+                                Ok(()) => log::info!("ACME: {}的证书申请成功", domain),
+                                Err(e) => log::error!("ACME: 加载{}的证书失败:{:?}", domain, e),
+                            },
+                            Err(e) => log::error!("ACME: 申请{}的证书失败:{:?}", domain, e),
+                        }
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(config.check_interval.0) => {}
+                        _ = cancel.cancelled() => {
+                            log::trace!("ACME: {}, 收到退出信号", domain);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{build_csr, AcmeConfig};
+    use crate::reverse::der::read_tlv;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    #[test]
+    fn test_acme_config() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_acme_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = AcmeConfig::new(String::new(), dir.to_string_lossy().to_string());
+        assert!(config.need_issue("localhost"));
+        fs::copy("tests/certs/client.pem", config.cert_path("localhost")).unwrap();
+        fs::copy("tests/certs/client.key", config.key_path("localhost")).unwrap();
+        assert!(!config.need_issue("localhost"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let csr = build_csr(&key, &rng, "soft.wm-proxy.com").unwrap();
+        let (tag, content, rest) = read_tlv(&csr).unwrap();
+        assert_eq!((tag, rest.len()), (0x30, 0));
+        assert!(content
+            .windows("soft.wm-proxy.com".len())
+            .any(|w| w == b"soft.wm-proxy.com"));
+    }
+}
@@ -0,0 +1,82 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/23 15:32:08
+
+use std::{fmt::Display, io, str::FromStr};
+
+use webparse::Response;
+use wenmeng::Body;
+
+/// HSTS的返回头
+pub const HSTS_HEADER: &str = "Strict-Transport-Security";
+
+/// 返回时添加的头, 格式为`X-Content-Type-Options: nosniff`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl AddHeader {
+    /// 添加到返回中, 后端已设置同名的头时仅在`force`时覆盖
+    pub fn apply(res: &mut Response<Body>, name: &str, value: &str, force: bool) {
+        if force || !res.headers().contains(&name) {
+            res.headers_mut().insert(name.to_string(), value.to_string());
+        }
+    }
+}
+
+impl FromStr for AddHeader {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => ("", ""),
+        };
+        if name.is_empty() || name.contains(char::is_whitespace) || value.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("add_headers格式应为`名称: 值`, 当前为{}", s),
+            ));
+        }
+        Ok(AddHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Display for AddHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name, self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AddHeader;
+
+    #[test]
+    fn test_add_header() {
+        let header = "X-Content-Type-Options: nosniff".parse::<AddHeader>().unwrap();
+        assert_eq!(header.name, "X-Content-Type-Options");
+        assert_eq!(header.value, "nosniff");
+        assert_eq!(header.to_string(), "X-Content-Type-Options: nosniff");
+        let header = "Permissions-Policy:geolocation=(), camera=()"
+            .parse::<AddHeader>()
+            .unwrap();
+        assert_eq!(header.value, "geolocation=(), camera=()");
+        assert!("X-Frame-Options".parse::<AddHeader>().is_err());
+        assert!("X Frame: DENY".parse::<AddHeader>().is_err());
+        assert!("X-Frame-Options:".parse::<AddHeader>().is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/15 09:41:27
+
+use std::{
+    future::poll_fn,
+    io,
+    net::Shutdown,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    net::TcpStream,
+    sync::{mpsc::channel, watch},
+    time::Instant,
+};
+use webparse::{Binary, BinaryMut, HeaderName, Request, Response};
+use wenmeng::Body;
+
+use crate::data::BodyBytes;
+
+/// 请求体大小及接收时间的限制, 未知长度的请求体转发时计数, 超出或超时时中断发往后端的连接
+#[derive(Debug)]
+pub struct BodyLimit {
+    over: watch::Sender<bool>,
+    /// 是否因接收超时而中断
+    timed_out: AtomicBool,
+    /// 当前发往后端的连接, 超出时关闭该连接, 后端不会收到完整的请求
+    upstream: Mutex<Option<std::net::TcpStream>>,
+}
+
+impl BodyLimit {
+    fn new() -> Self {
+        Self {
+            over: watch::channel(false).0,
+            timed_out: AtomicBool::new(false),
+            upstream: Mutex::new(None),
+        }
+    }
+
+    /// 声明的Content-Length是否超出限制, `max`为0时不限制
+    pub fn is_declared_over(req: &Request<Body>, max: u64) -> bool {
+        max > 0 && req.get_body_len() > 0 && req.get_body_len() as u64 > max
+    }
+
+    /// 超出限制时的返回, 剩余的请求体不再读取, 关闭客户端连接
+    pub fn too_large() -> Response<Body> {
+        Response::text()
+            .status(413)
+            .header(HeaderName::CONNECTION, "close")
+            .body("request body too large")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 接收请求体超时时的返回, 关闭客户端连接
+    pub fn request_timeout() -> Response<Body> {
+        Response::text()
+            .status(408)
+            .header(HeaderName::CONNECTION, "close")
+            .body("request timeout")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 中断时的返回, 超时为408, 超出大小为413
+    pub fn reject(&self) -> Response<Body> {
+        if self.timed_out.load(Ordering::Relaxed) {
+            Self::request_timeout()
+        } else {
+            Self::too_large()
+        }
+    }
+
+    /// 将未知长度的请求体(如chunked)替换为计数转发的请求体, 返回检查是否超出的句柄
+    /// 收到的字节数同时计入请求的`BodyBytes`, `max`为0时只计数不限制,
+    /// 配置了`timeout`时未接收完的请求体也替换, 超过该时间未接收完即中断
+    pub fn wrap(
+        req: &mut Request<Body>,
+        max: u64,
+        timeout: Option<Duration>,
+    ) -> Option<Arc<BodyLimit>> {
+        let unknown = req.get_body_len() <= 0 && !req.body().is_end();
+        let pending = timeout.is_some() && !req.body().is_end();
+        if !(unknown || pending || req.headers().is_chunked()) {
+            return None;
+        }
+        let chunked = unknown || req.headers().is_chunked();
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let bytes = BodyBytes::get(req).cloned();
+        let limit = Arc::new(BodyLimit::new());
+        let mut body = std::mem::replace(req.body_mut(), Body::empty());
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        let pump = limit.clone();
+        tokio::spawn(async move {
+            let mut total = 0u64;
+            let mut buf = vec![0u8; 4096];
+            let complete = loop {
+                let read = Self::read_some(&mut body, &mut buf);
+                let read = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, read).await {
+                        Ok(read) => read,
+                        Err(_) => {
+                            log::trace!("接收请求内容超时");
+                            pump.timed_out.store(true, Ordering::Relaxed);
+                            pump.abort(true);
+                            break false;
+                        }
+                    },
+                    None => read.await,
+                };
+                match read {
+                    Ok(0) => break true,
+                    Ok(n) => {
+                        total += n as u64;
+                        if let Some(bytes) = &bytes {
+                            bytes.add_request(n as u64);
+                        }
+                        if max > 0 && total > max {
+                            pump.abort(true);
+                            break false;
+                        }
+                        let data = Binary::from(buf[..n].to_vec());
+                        if sender.send((false, data)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        log::trace!("读取请求内容失败:{:?}", e);
+                        pump.abort(false);
+                        break false;
+                    }
+                }
+            };
+            if !complete {
+                // 不结束请求体, 避免后端将截断的内容当作完整的请求
+                sender.closed().await;
+                return;
+            }
+            let _ = sender.send((true, Binary::new())).await;
+        });
+        let mut counted = Body::new(receiver, BinaryMut::new(), false);
+        if chunked {
+            // 转发时长度未知, 始终以chunked发送
+            counted.set_chunked(true);
+            req.headers_mut()
+                .insert(HeaderName::TRANSFER_ENCODING, "chunked");
+        }
+        *req.body_mut() = counted;
+        req.extensions_mut().insert(limit.clone());
+        Some(limit)
+    }
+
+    /// 读取请求体的数据, 暂无数据时等待, 结束时返回0
+    pub(crate) async fn read_some(body: &mut Body, buf: &mut [u8]) -> io::Result<usize> {
+        poll_fn(|cx| {
+            let mut read = ReadBuf::new(buf);
+            ready!(Pin::new(&mut *body).poll_read(cx, &mut read))?;
+            let n = read.filled().len();
+            if n == 0 && !body.is_end() {
+                // 读取时已注册唤醒, 有新数据时再继续
+                Poll::Pending
+            } else {
+                Poll::Ready(Ok(n))
+            }
+        })
+        .await
+    }
+
+    /// 记录发往后端的连接, 以便超出时关闭
+    pub fn watch_upstream(&self, stream: TcpStream) -> io::Result<TcpStream> {
+        let stream = stream.into_std()?;
+        let watch = stream.try_clone()?;
+        if self.is_over() {
+            let _ = watch.shutdown(Shutdown::Both);
+        } else {
+            *self.upstream.lock().unwrap() = Some(watch);
+        }
+        TcpStream::from_std(stream)
+    }
+
+    /// 中断发往后端的连接, `over`表示因超出限制而中断
+    fn abort(&self, over: bool) {
+        if over {
+            self.over.send_replace(true);
+        }
+        if let Some(stream) = self.upstream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        *self.over.borrow()
+    }
+
+    /// 等待请求体超出限制或接收超时
+    pub async fn wait_over(&self) {
+        let mut receiver = self.over.subscribe();
+        let _ = receiver.wait_for(|v| *v).await;
+    }
+}
@@ -0,0 +1,270 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/18 10:12:36
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use webparse::{Method, Request, Response};
+use wenmeng::Body;
+
+use crate::{ConfigDuration, ConfigSize, IdempotencyResponse};
+
+lazy_static! {
+    // 每个location独立的返回缓存
+    static ref CACHE_STORES: Mutex<HashMap<String, CacheStore>> = Mutex::new(HashMap::new());
+}
+
+/// 标记缓存是否命中的返回头
+pub const CACHE_STATUS_HEADER: &str = "X-Cache";
+
+fn default_cache_size() -> ConfigSize {
+    ConfigSize::new(16 * 1024 * 1024)
+}
+
+fn default_cache_ttl() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(60))
+}
+
+fn default_cache_status() -> Vec<u16> {
+    vec![200]
+}
+
+/// location的返回缓存配置, 只缓存GET请求
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// 该location缓存的总大小, 超出时淘汰最久未访问的返回
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_cache_size")]
+    pub max_size: ConfigSize,
+    /// 返回的缓存时间
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_cache_ttl")]
+    pub ttl: ConfigDuration,
+    /// 可缓存的状态码
+    #[serde(default = "default_cache_status")]
+    pub status: Vec<u16>,
+}
+
+impl CacheConfig {
+    pub fn new() -> Self {
+        Self {
+            max_size: default_cache_size(),
+            ttl: default_cache_ttl(),
+            status: default_cache_status(),
+        }
+    }
+
+    /// 请求的缓存key, 非GET请求不缓存
+    pub fn get_key(req: &Request<Body>) -> Option<String> {
+        if req.method() != &Method::Get {
+            return None;
+        }
+        let path = match &req.url().query {
+            Some(query) if !req.path().contains('?') => format!("{}?{}", req.path(), query),
+            _ => req.path().clone(),
+        };
+        Some(format!(
+            "{} {}{}",
+            req.method().as_str(),
+            req.get_host().unwrap_or_default(),
+            path
+        ))
+    }
+
+    /// 返回是否可缓存, 声明了`no-store`或`private`以及设置了cookie的返回不缓存
+    pub fn is_cacheable(&self, res: &Response<Body>) -> bool {
+        if !self.status.contains(&res.status().as_u16()) {
+            return false;
+        }
+        if res.headers().contains(&"Set-Cookie") {
+            return false;
+        }
+        match res.headers().get_str_value(&"Cache-Control") {
+            Some(v) => !v.split(',').any(|v| {
+                let v = v.trim();
+                v.eq_ignore_ascii_case("no-store") || v.eq_ignore_ascii_case("private")
+            }),
+            None => true,
+        }
+    }
+
+    pub fn lookup(store: &str, key: &str) -> Option<IdempotencyResponse> {
+        let mut stores = CACHE_STORES.lock().unwrap();
+        stores.get_mut(store)?.get(key)
+    }
+
+    pub fn insert(&self, store: &str, key: &str, res: IdempotencyResponse) {
+        let mut stores = CACHE_STORES.lock().unwrap();
+        stores.entry(store.to_string()).or_default().insert(
+            key,
+            res,
+            self.ttl.0,
+            self.max_size.0 as usize,
+        );
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct CacheEntry {
+    res: IdempotencyResponse,
+    expire: Instant,
+    size: usize,
+    /// 最近访问的序号, 用于淘汰最久未访问的返回
+    visit: u64,
+}
+
+/// 单个location的返回缓存, 超出大小时淘汰最久未访问的返回
+#[derive(Default)]
+pub struct CacheStore {
+    entries: HashMap<String, CacheEntry>,
+    size: usize,
+    visit: u64,
+}
+
+impl CacheStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(e) = self.entries.remove(key) {
+            self.size -= e.size;
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<IdempotencyResponse> {
+        self.visit += 1;
+        let visit = self.visit;
+        let expired = match self.entries.get_mut(key) {
+            Some(e) if e.expire > Instant::now() => {
+                e.visit = visit;
+                return Some(e.res.clone());
+            }
+            Some(_) => true,
+            None => false,
+        };
+        if expired {
+            self.remove(key);
+        }
+        None
+    }
+
+    pub fn insert(&mut self, key: &str, res: IdempotencyResponse, ttl: Duration, max_size: usize) {
+        self.remove(key);
+        let size = key.len() + res.body.len();
+        if size > max_size {
+            return;
+        }
+        let now = Instant::now();
+        let size_ref = &mut self.size;
+        self.entries.retain(|_, e| {
+            if e.expire > now {
+                true
+            } else {
+                *size_ref -= e.size;
+                false
+            }
+        });
+        while self.size + size > max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.visit)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(k) => self.remove(&k),
+                None => break,
+            }
+        }
+        self.visit += 1;
+        self.size += size;
+        self.entries.insert(
+            key.to_string(),
+            CacheEntry {
+                res,
+                expire: now + ttl,
+                size,
+                visit: self.visit,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use webparse::{HeaderMap, Response};
+    use wenmeng::Body;
+
+    use super::{CacheConfig, CacheStore};
+    use crate::IdempotencyResponse;
+
+    fn build_res(body: &str) -> IdempotencyResponse {
+        IdempotencyResponse {
+            status: 200,
+            headers: HeaderMap::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_cache_lru() {
+        let ttl = Duration::from_secs(60);
+        let mut store = CacheStore::new();
+        for key in ["a", "b"] {
+            store.insert(key, build_res("0123456789"), ttl, 25);
+        }
+        // 访问a后再插入c, 淘汰最久未访问的b
+        assert!(store.get("a").is_some());
+        store.insert("c", build_res("0123456789"), ttl, 25);
+        assert!(store.get("b").is_none());
+        assert!(store.get("a").is_some());
+        assert!(store.get("c").is_some());
+        assert!(store.size <= 25);
+
+        // 超出总大小的返回不缓存, 过期后不再命中
+        store.insert("big", build_res(&"x".repeat(30)), ttl, 25);
+        assert!(store.get("big").is_none());
+        store.insert("ttl", build_res("1"), Duration::ZERO, 25);
+        assert!(store.get("ttl").is_none());
+    }
+
+    #[test]
+    fn test_cacheable() {
+        let config = CacheConfig::new();
+        let build = |status: u16, header: Option<(&'static str, &'static str)>| {
+            let mut builder = Response::builder().status(status);
+            if let Some((k, v)) = header {
+                builder = builder.header(k, v);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        assert!(config.is_cacheable(&build(200, None)));
+        assert!(config.is_cacheable(&build(200, Some(("Cache-Control", "max-age=60")))));
+        assert!(!config.is_cacheable(&build(404, None)));
+        assert!(!config.is_cacheable(&build(200, Some(("Cache-Control", "public, no-store")))));
+        assert!(!config.is_cacheable(&build(200, Some(("Set-Cookie", "sid=1")))));
+    }
+}
@@ -0,0 +1,150 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/22 09:41:26
+
+use std::{fmt::Display, io, str::FromStr};
+
+use rustls::pki_types::CertificateDer;
+use webparse::Request;
+use wenmeng::Body;
+
+use super::der::{read_tlv, tbs_fields};
+
+/// 向后端传递客户端证书的主题
+pub const CLIENT_CERT_SUBJECT: &str = "X-Client-Cert-Subject";
+/// 向后端传递客户端证书的序列号
+pub const CLIENT_CERT_SERIAL: &str = "X-Client-Cert-Serial";
+
+/// 是否校验客户端证书, `on`必须提供有效证书, `optional`提供时校验, `off`不请求证书
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyClient {
+    On,
+    Optional,
+    #[default]
+    Off,
+}
+
+impl FromStr for VerifyClient {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_ascii_lowercase() {
+            "on" => Ok(VerifyClient::On),
+            "optional" => Ok(VerifyClient::Optional),
+            "off" => Ok(VerifyClient::Off),
+            _ => Err(io::Error::other(
+                "verify_client must be on, optional or off",
+            )),
+        }
+    }
+}
+
+impl Display for VerifyClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VerifyClient::On => "on",
+            VerifyClient::Optional => "optional",
+            VerifyClient::Off => "off",
+        })
+    }
+}
+
+/// 握手时校验通过的客户端证书信息, 主题如`O=wmproxy,CN=client`, 序列号为大写的十六进制,
+/// 存放于请求的extensions中, 可按主题对请求授权
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCert {
+    pub subject: String,
+    pub serial: String,
+}
+
+impl ClientCert {
+    /// 证书信息仅由握手结果生成, 移除客户端自行传入的内容后写入校验通过的证书
+    pub fn apply_request(req: &mut Request<Body>, cert: Option<&ClientCert>) {
+        req.headers_mut().remove(&CLIENT_CERT_SUBJECT);
+        req.headers_mut().remove(&CLIENT_CERT_SERIAL);
+        if let Some(cert) = cert {
+            req.headers_mut().insert(CLIENT_CERT_SUBJECT, cert.subject.clone());
+            req.headers_mut().insert(CLIENT_CERT_SERIAL, cert.serial.clone());
+            req.extensions_mut().insert(cert.clone());
+        }
+    }
+
+    /// 解析DER格式证书中的序列号及主题, 主题仅保留常用的属性并按证书中的顺序排列
+    pub fn parse(cert: &CertificateDer<'_>) -> Option<Self> {
+        let tbs = tbs_fields(cert)?;
+        let (tag, serial, tbs) = read_tlv(tbs)?;
+        if tag != 0x02 {
+            return None;
+        }
+        // 跳过签名算法, 颁发者及有效期
+        let (_, _, tbs) = read_tlv(tbs)?;
+        let (_, _, tbs) = read_tlv(tbs)?;
+        let (_, _, tbs) = read_tlv(tbs)?;
+        let (_, mut name, _) = read_tlv(tbs)?;
+
+        let mut attrs = vec![];
+        while !name.is_empty() {
+            let (_, mut set, rest) = read_tlv(name)?;
+            name = rest;
+            while !set.is_empty() {
+                let (_, attr, rest) = read_tlv(set)?;
+                set = rest;
+                let (_, oid, attr) = read_tlv(attr)?;
+                let (_, value, _) = read_tlv(attr)?;
+                let key = match oid {
+                    [0x55, 0x04, 0x03] => "CN",
+                    [0x55, 0x04, 0x06] => "C",
+                    [0x55, 0x04, 0x07] => "L",
+                    [0x55, 0x04, 0x08] => "ST",
+                    [0x55, 0x04, 0x0A] => "O",
+                    [0x55, 0x04, 0x0B] => "OU",
+                    _ => continue,
+                };
+                attrs.push(format!("{}={}", key, String::from_utf8_lossy(value)));
+            }
+        }
+        // 正数的最高位为1时会补充0
+        let serial = match serial {
+            [0, rest @ ..] if !rest.is_empty() => rest,
+            _ => serial,
+        };
+        Some(ClientCert {
+            subject: attrs.join(","),
+            serial: serial.iter().map(|b| format!("{:02X}", b)).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use super::{ClientCert, VerifyClient};
+
+    #[test]
+    fn test_client_cert() {
+        let file = File::open("tests/certs/client.pem").unwrap();
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let cert = ClientCert::parse(&certs[0]).unwrap();
+        assert_eq!(cert.subject, "O=wmproxy,CN=client");
+        assert_eq!(cert.serial, "1A2B3C");
+        assert!(ClientCert::parse(&certs[0][..20].to_vec().into()).is_none());
+
+        assert_eq!(
+            "Optional".parse::<VerifyClient>().unwrap(),
+            VerifyClient::Optional
+        );
+        assert_eq!(VerifyClient::On.to_string(), "on");
+        assert!("yes".parse::<VerifyClient>().is_err());
+    }
+}
@@ -10,17 +10,58 @@
 // -----
 // Created Date: 2023/11/03 05:01:37
 
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, net::IpAddr, time::Duration};
 
-use crate::{ConfigDuration, ConfigLog, ConfigRate, IpSets};
+use crate::{AccessRule, Compression, ConfigDuration, ConfigLog, ConfigRate, ConfigSize, HeaderPolicy, Helper, IpSets};
 use crate::{DisplayFromStrOrNumber};
-use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{serde_as, DeserializeAs, DisplayFromStr, SerializeAs};
 use webparse::Url;
 use wenmeng::RateLimitLayer;
 use wenmeng::TimeoutLayer;
 
-use super::{LimitReq, Matcher};
+use super::{LimitReq, Matcher, ReverseHelper, UNIX_PREFIX};
+
+/// 未配置时连接后端的超时时间
+const DEFAULT_PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// 未配置时等待后端返回的超时时间
+const DEFAULT_PROXY_READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// 未配置时向后端发送数据的超时时间
+const DEFAULT_PROXY_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+/// 未配置时请求体的最大大小
+const DEFAULT_MAX_BODY_SIZE: u64 = 10 * 1024 * 1024;
+
+/// 解析proxy_url, `unix:/run/app.sock`形式的unix socket地址转为`unix:///run/app.sock`
+pub(crate) struct ProxyUrl;
+
+impl SerializeAs<Url> for ProxyUrl {
+    fn serialize_as<S>(source: &Url, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match ReverseHelper::unix_url_path(source) {
+            Some(path) => serializer.collect_str(&format_args!("{}{}", UNIX_PREFIX, path)),
+            None => serializer.collect_str(source),
+        }
+    }
+}
+
+impl<'de> DeserializeAs<'de, Url> for ProxyUrl {
+    fn deserialize_as<D>(deserializer: D) -> Result<Url, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        let value = match value.strip_prefix(UNIX_PREFIX) {
+            Some(path) if !path.starts_with('/') => {
+                return Err(serde::de::Error::custom("unix socket须为绝对路径"));
+            }
+            Some(path) if !path.starts_with("//") => format!("{}//{}", UNIX_PREFIX, path),
+            _ => value,
+        };
+        value.parse::<Url>().map_err(serde::de::Error::custom)
+    }
+}
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -37,12 +78,24 @@ pub struct CommonConfig {
     pub client_timeout: Option<ConfigDuration>,
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
     pub client_ka_timeout: Option<ConfigDuration>,
+    /// 接收完整请求头的超时时间, 从连接建立或收到请求的首个字节开始计算, 超时返回408并关闭连接
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    pub client_header_timeout: Option<ConfigDuration>,
+    /// 接收完整请求体的超时时间, 从收到请求头开始计算, 超时返回408并关闭连接
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    pub client_body_timeout: Option<ConfigDuration>,
 
+    /// 连接后端的超时时间, 默认10s
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(alias = "connect_timeout")]
     pub proxy_connect_timeout: Option<ConfigDuration>,
+    /// 等待后端返回的超时时间, 默认60s, 超时返回504
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(alias = "read_timeout")]
     pub proxy_read_timeout: Option<ConfigDuration>,
+    /// 向后端发送数据的超时时间, 默认60s
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(alias = "write_timeout")]
     pub proxy_write_timeout: Option<ConfigDuration>,
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
     pub proxy_timeout: Option<ConfigDuration>,
@@ -57,14 +110,47 @@ pub struct CommonConfig {
     pub error_log: Option<ConfigLog>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub limit_req: Option<LimitReq>,
+    /// 允许访问的IP列表, 支持IPv4及IPv6的CIDR, 如`192.168.0.0/24 ::1`
     #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(alias = "allow")]
     pub allow_ip: Option<IpSets>,
+    /// 禁止访问的IP列表, 优先于允许列表
     #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(alias = "deny")]
     pub deny_ip: Option<IpSets>,
+    /// 按顺序匹配的访问规则, 如`["allow 10.0.0.1", "deny 10.0.0.0/8", "allow all"]`,
+    /// 第一个匹配的规则生效, 均未匹配时再按allow_ip及deny_ip判断
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub access: Vec<AccessRule>,
+    /// 拒绝访问时返回的状态码, 默认403
+    pub deny_status: Option<u16>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub domain: Option<String>,
-    #[serde_as(as = "Option<DisplayFromStr>")]
+    /// 代理的后端地址, 如`http://server/`, 也可为unix socket, 如`unix:/run/app.sock`
+    #[serde_as(as = "Option<ProxyUrl>")]
     pub proxy_url: Option<Url>,
+    /// 头信息的规范化策略
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub header_policy: Option<HeaderPolicy>,
+    /// 是否向后端添加X-Forwarded-For, X-Real-IP, X-Forwarded-Proto及X-Forwarded-Port头, 默认添加
+    #[serde(alias = "real_ip")]
+    pub forwarded_headers: Option<bool>,
+    /// 是否压缩返回内容, 开启时未配置的大小及类型使用默认值, 关闭时不做任何压缩
+    pub gzip: Option<bool>,
+    /// 动态压缩的最小返回大小, 小于该值的返回不压缩
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub compression_min_length: Option<ConfigSize>,
+    /// 允许动态压缩的Content-Type, 如`text/*`, 为空时不限制
+    #[serde(default = "Vec::new")]
+    pub compression_types: Vec<String>,
+    /// 允许的压缩方式, 按优先级排列, 如`["br", "gzip"]`, 为空时依次为gzip, br, deflate
+    #[serde(default = "Vec::new")]
+    pub compression_methods: Vec<String>,
+    /// 请求体的最大大小, 超出时返回413, 默认10m, 0表示不限制
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(alias = "client_max_body_size")]
+    pub max_body_size: Option<ConfigSize>,
     
     #[serde(default = "HashMap::new")]
     #[serde_as(as = "HashMap<_, DisplayFromStr>")]
@@ -81,6 +167,8 @@ impl CommonConfig {
             client_write_timeout: None,
             client_timeout: None,
             client_ka_timeout: None,
+            client_header_timeout: None,
+            client_body_timeout: None,
 
             proxy_connect_timeout: None,
             proxy_timeout: None,
@@ -96,9 +184,18 @@ impl CommonConfig {
             limit_req: None,
             allow_ip: None,
             deny_ip: None,
+            access: vec![],
+            deny_status: None,
 
             domain: None,
             proxy_url: None,
+            header_policy: None,
+            forwarded_headers: None,
+            gzip: None,
+            compression_min_length: None,
+            compression_types: vec![],
+            compression_methods: vec![],
+            max_body_size: None,
             
             match_names: HashMap::new(),
         }
@@ -147,6 +244,50 @@ impl CommonConfig {
         if self.deny_ip.is_none() {
             self.deny_ip = parent.deny_ip.clone();
         }
+
+        if self.access.is_empty() {
+            self.access = parent.access.clone();
+        }
+
+        if self.deny_status.is_none() {
+            self.deny_status = parent.deny_status;
+        }
+
+        if self.header_policy.is_none() {
+            self.header_policy = parent.header_policy.clone();
+        }
+
+        if self.forwarded_headers.is_none() {
+            self.forwarded_headers = parent.forwarded_headers;
+        }
+
+        if self.gzip.is_none() {
+            self.gzip = parent.gzip;
+        }
+
+        if self.compression_min_length.is_none() {
+            self.compression_min_length = parent.compression_min_length.clone();
+        }
+
+        if self.compression_types.is_empty() {
+            self.compression_types = parent.compression_types.clone();
+        }
+
+        if self.compression_methods.is_empty() {
+            self.compression_methods = parent.compression_methods.clone();
+        }
+
+        if self.max_body_size.is_none() {
+            self.max_body_size = parent.max_body_size.clone();
+        }
+
+        if self.client_header_timeout.is_none() {
+            self.client_header_timeout = parent.client_header_timeout.clone();
+        }
+
+        if self.client_body_timeout.is_none() {
+            self.client_body_timeout = parent.client_body_timeout.clone();
+        }
         
         for p in &parent.match_names {
             if !self.match_names.contains_key(p.0) {
@@ -159,7 +300,10 @@ impl CommonConfig {
         if let Some(err) = &mut self.error_log {
             err.as_error();
         }
-        
+        for format in self.log_format.values_mut() {
+            *format = Helper::convert_log_format(format);
+        }
+
     }
 
     pub fn get_rate_limit(&self) -> Option<RateLimitLayer> {
@@ -170,6 +314,90 @@ impl CommonConfig {
         }
     }
     
+    /// 配置了压缩的限制时返回, 否则按服务端默认的方式压缩
+    pub fn get_compression(&self) -> Option<Compression> {
+        match self.gzip {
+            Some(false) => return Some(Compression::off()),
+            Some(true) => {
+                let min_length = self
+                    .compression_min_length
+                    .as_ref()
+                    .map(|s| s.0)
+                    .unwrap_or(Compression::DEFAULT_MIN_LENGTH);
+                let types = if self.compression_types.is_empty() {
+                    Compression::DEFAULT_TYPES.iter().map(|t| t.to_string()).collect()
+                } else {
+                    self.compression_types.clone()
+                };
+                let compression = Compression::new(min_length, types);
+                return Some(compression.with_methods(self.compression_methods.clone()));
+            }
+            None => {}
+        }
+        if self.compression_min_length.is_none()
+            && self.compression_types.is_empty()
+            && self.compression_methods.is_empty()
+        {
+            return None;
+        }
+        let min_length = self.compression_min_length.as_ref().map(|s| s.0).unwrap_or(0);
+        let compression = Compression::new(min_length, self.compression_types.clone());
+        Some(compression.with_methods(self.compression_methods.clone()))
+    }
+    
+    /// 判断该IP是否允许访问, 拒绝时返回相应的状态码
+    pub fn check_ip(&self, ip: &IpAddr) -> Result<(), u16> {
+        let status = self.deny_status.unwrap_or(403);
+        match AccessRule::check(&self.access, ip) {
+            Some(true) => return Ok(()),
+            Some(false) => return Err(status),
+            None => {}
+        }
+        if let Some(deny) = &self.deny_ip {
+            if deny.contains(ip) {
+                return Err(status);
+            }
+        }
+        if let Some(allow) = &self.allow_ip {
+            if !allow.contains(ip) {
+                return Err(status);
+            }
+        }
+        Ok(())
+    }
+
+    /// 连接后端(含TLS握手)的超时时间, 未配置时使用默认值
+    pub fn get_proxy_connect_timeout(&self) -> Duration {
+        self.proxy_connect_timeout
+            .as_ref()
+            .map(|t| t.0)
+            .unwrap_or(DEFAULT_PROXY_CONNECT_TIMEOUT)
+    }
+
+    /// 发出请求后等待后端返回头的超时时间, 未配置时使用默认值
+    pub fn get_proxy_read_timeout(&self) -> Duration {
+        self.proxy_read_timeout
+            .as_ref()
+            .map(|t| t.0)
+            .unwrap_or(DEFAULT_PROXY_READ_TIMEOUT)
+    }
+
+    /// 向后端发送请求的超时时间, 未配置时使用默认值
+    pub fn get_proxy_write_timeout(&self) -> Duration {
+        self.proxy_write_timeout
+            .as_ref()
+            .map(|t| t.0)
+            .unwrap_or(DEFAULT_PROXY_WRITE_TIMEOUT)
+    }
+
+    /// 请求体的最大大小, 未配置时使用默认值, 0表示不限制
+    pub fn get_max_body_size(&self) -> u64 {
+        self.max_body_size
+            .as_ref()
+            .map(|s| s.0)
+            .unwrap_or(DEFAULT_MAX_BODY_SIZE)
+    }
+
     pub fn build_proxy_timeout(&self) -> Option<TimeoutLayer> {
         let mut timeout = TimeoutLayer::new();
         let mut has_data = false;
@@ -240,4 +468,11 @@ impl CommonConfig {
         }
     }
 
+    /// 访问日志使用的日志名称
+    pub fn get_access_names(&self, names: &mut HashSet<String>) {
+        if let Some(access) = &self.access_log {
+            names.insert(access.name.clone());
+        }
+    }
+
 }
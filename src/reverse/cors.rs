@@ -0,0 +1,324 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/28 10:21:43
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use webparse::{HeaderName, Method, Request, Response};
+use wenmeng::Body;
+
+use crate::{ConfigDuration, Helper};
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()]
+}
+
+/// location的跨域配置, 预检请求直接返回204, 不再访问后端
+/// 仅对允许列表中的Origin返回跨域头, 其它Origin不返回任何跨域头
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// 允许的Origin, 如`https://example.com`, `https://*.example.com`, `*`为允许所有
+    #[serde(default = "Vec::new")]
+    pub allow_origins: Vec<String>,
+    /// 允许的请求方法, 默认为`GET HEAD POST`
+    #[serde(default = "default_cors_methods")]
+    pub allow_methods: Vec<String>,
+    /// 允许的请求头, 为空时允许预检请求中声明的请求头
+    #[serde(default = "Vec::new")]
+    pub allow_headers: Vec<String>,
+    /// 允许浏览器读取的返回头
+    #[serde(default = "Vec::new")]
+    pub expose_headers: Vec<String>,
+    /// 是否允许携带cookie等凭证
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// 预检结果的缓存时间
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_age: Option<ConfigDuration>,
+}
+
+impl CorsConfig {
+    pub fn new() -> Self {
+        Self {
+            allow_origins: vec![],
+            allow_methods: default_cors_methods(),
+            allow_headers: vec![],
+            expose_headers: vec![],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Origin是否在允许列表中, `*`可匹配一级或多级子域名
+    pub fn is_allow_origin(&self, origin: &str) -> bool {
+        let origin = origin.trim().to_ascii_lowercase();
+        if origin.is_empty() {
+            return false;
+        }
+        self.allow_origins.iter().any(|pattern| {
+            let pattern = pattern.trim().to_ascii_lowercase();
+            if pattern == "*" || pattern == origin {
+                return true;
+            }
+            if !pattern.contains('*') {
+                return false;
+            }
+            let re = format!(
+                "^{}$",
+                regex::escape(&pattern).replace(r"\*", "[a-z0-9-]+(\\.[a-z0-9-]+)*")
+            );
+            Helper::try_cache_regex(&re)
+                .map(|re| re.is_match(&origin))
+                .unwrap_or(false)
+        })
+    }
+
+    fn allowed_origin(&self, req: &Request<Body>) -> Option<String> {
+        let origin = req.headers().get_str_value(&HeaderName::ORIGIN)?;
+        if self.is_allow_origin(&origin) {
+            Some(origin.trim().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// 是否为跨域的预检请求
+    pub fn is_preflight(req: &Request<Body>) -> bool {
+        req.method() == &Method::Options
+            && req.headers().contains(&HeaderName::ORIGIN)
+            && req
+                .headers()
+                .contains(&HeaderName::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    /// 生成预检请求的返回, Origin或请求方法不被允许时不返回跨域头
+    pub fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let mut res = Response::builder().status(204).body(Body::empty()).unwrap();
+        Self::vary(&mut res);
+        let origin = match self.allowed_origin(req) {
+            Some(origin) => origin,
+            None => return res,
+        };
+        let method = req
+            .headers()
+            .get_str_value(&HeaderName::ACCESS_CONTROL_REQUEST_METHOD)
+            .unwrap_or_default();
+        if !self
+            .allow_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.trim()))
+        {
+            return res;
+        }
+        let headers = if self.allow_headers.is_empty() {
+            req.headers()
+                .get_str_value(&HeaderName::ACCESS_CONTROL_REQUEST_HEADERS)
+                .map(|v| v.trim().to_string())
+                .unwrap_or_default()
+        } else {
+            self.allow_headers.join(", ")
+        };
+        self.set_origin(&mut res, origin);
+        let methods = self.allow_methods.join(", ");
+        let headers_mut = res.headers_mut();
+        headers_mut.insert(HeaderName::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        if !headers.is_empty() {
+            headers_mut.insert(HeaderName::ACCESS_CONTROL_ALLOW_HEADERS, headers);
+        }
+        if let Some(max_age) = &self.max_age {
+            headers_mut.insert(
+                HeaderName::ACCESS_CONTROL_MAX_AGE,
+                max_age.0.as_secs().to_string(),
+            );
+        }
+        res
+    }
+
+    /// 普通请求的返回中按Origin添加跨域头, 并替换后端返回的跨域头
+    pub fn apply_response(&self, req: &Request<Body>, res: &mut Response<Body>) {
+        let headers = res.headers_mut();
+        headers.remove(&HeaderName::ACCESS_CONTROL_ALLOW_ORIGIN);
+        headers.remove(&HeaderName::ACCESS_CONTROL_ALLOW_CREDENTIALS);
+        headers.remove(&HeaderName::ACCESS_CONTROL_EXPOSE_HEADERS);
+        Self::vary(res);
+        let origin = match self.allowed_origin(req) {
+            Some(origin) => origin,
+            None => return,
+        };
+        self.set_origin(res, origin);
+        if !self.expose_headers.is_empty() {
+            res.headers_mut().insert(
+                HeaderName::ACCESS_CONTROL_EXPOSE_HEADERS,
+                self.expose_headers.join(", "),
+            );
+        }
+    }
+
+    fn set_origin(&self, res: &mut Response<Body>, origin: String) {
+        res.headers_mut()
+            .insert(HeaderName::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.allow_credentials {
+            res.headers_mut()
+                .insert(HeaderName::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+    }
+
+    /// 返回内容随`Origin`变化, 告知缓存
+    fn vary(res: &mut Response<Body>) {
+        let value = match res.headers().get_str_value(&HeaderName::VARY) {
+            Some(v) if v.to_ascii_lowercase().contains("origin") || v.trim() == "*" => return,
+            Some(v) if !v.trim().is_empty() => format!("{}, Origin", v.trim()),
+            _ => "Origin".to_string(),
+        };
+        res.headers_mut().insert(HeaderName::VARY, value);
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{Request, Response};
+    use wenmeng::Body;
+
+    use super::CorsConfig;
+
+    fn build_req(method: &str, headers: &[(&'static str, &'static str)]) -> Request<Body> {
+        let mut builder = Request::builder()
+            .method(method)
+            .url("http://localhost/api");
+        for (k, v) in headers {
+            builder = builder.header(*k, *v);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn header(res: &Response<Body>, name: &'static str) -> Option<String> {
+        res.headers().get_str_value(&name)
+    }
+
+    #[test]
+    fn test_cors_origin() {
+        let mut cors = CorsConfig::new();
+        cors.allow_origins = vec![
+            "https://example.com".to_string(),
+            "https://*.example.org".to_string(),
+        ];
+        assert!(cors.is_allow_origin("https://example.com"));
+        assert!(cors.is_allow_origin("HTTPS://Example.com"));
+        assert!(cors.is_allow_origin("https://a.example.org"));
+        assert!(cors.is_allow_origin("https://a.b.example.org"));
+        assert!(!cors.is_allow_origin("https://example.org"));
+        assert!(!cors.is_allow_origin("https://evil.com/.example.org"));
+        assert!(!cors.is_allow_origin("https://example.com.evil.com"));
+        assert!(!cors.is_allow_origin("http://example.com"));
+        assert!(!cors.is_allow_origin(""));
+        cors.allow_origins = vec!["*".to_string()];
+        assert!(cors.is_allow_origin("https://any.com"));
+    }
+
+    #[test]
+    fn test_cors_response() {
+        let mut cors = CorsConfig::new();
+        cors.allow_origins = vec!["https://example.com".to_string()];
+        cors.allow_credentials = true;
+        cors.max_age = Some("10min".parse().unwrap());
+
+        let req = build_req(
+            "OPTIONS",
+            &[
+                ("Origin", "https://example.com"),
+                ("Access-Control-Request-Method", "POST"),
+                ("Access-Control-Request-Headers", "content-type, x-token"),
+            ],
+        );
+        assert!(CorsConfig::is_preflight(&req));
+        let res = cors.preflight(&req);
+        assert_eq!(res.status().as_u16(), 204);
+        assert_eq!(
+            header(&res, "Access-Control-Allow-Origin").as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            header(&res, "Access-Control-Allow-Credentials").as_deref(),
+            Some("true")
+        );
+        assert_eq!(
+            header(&res, "Access-Control-Allow-Methods").as_deref(),
+            Some("GET, HEAD, POST")
+        );
+        assert_eq!(
+            header(&res, "Access-Control-Allow-Headers").as_deref(),
+            Some("content-type, x-token")
+        );
+        assert_eq!(
+            header(&res, "Access-Control-Max-Age").as_deref(),
+            Some("600")
+        );
+        assert_eq!(header(&res, "Vary").as_deref(), Some("Origin"));
+
+        // 方法及Origin不被允许时不返回跨域头
+        let req = build_req(
+            "OPTIONS",
+            &[
+                ("Origin", "https://example.com"),
+                ("Access-Control-Request-Method", "DELETE"),
+            ],
+        );
+        assert_eq!(
+            header(&cors.preflight(&req), "Access-Control-Allow-Origin"),
+            None
+        );
+        let req = build_req(
+            "OPTIONS",
+            &[
+                ("Origin", "https://evil.com"),
+                ("Access-Control-Request-Method", "GET"),
+            ],
+        );
+        let res = cors.preflight(&req);
+        assert_eq!(res.status().as_u16(), 204);
+        assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&res, "Access-Control-Allow-Methods"), None);
+        assert!(!CorsConfig::is_preflight(&build_req("OPTIONS", &[])));
+
+        // 普通请求替换后端返回的跨域头
+        let build_res = || {
+            Response::builder()
+                .header("Access-Control-Allow-Origin", "*")
+                .header("Vary", "Accept-Encoding")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let req = build_req("GET", &[("Origin", "https://example.com")]);
+        let mut res = build_res();
+        cors.apply_response(&req, &mut res);
+        assert_eq!(
+            header(&res, "Access-Control-Allow-Origin").as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            header(&res, "Vary").as_deref(),
+            Some("Accept-Encoding, Origin")
+        );
+        let req = build_req("GET", &[("Origin", "https://evil.com")]);
+        let mut res = build_res();
+        cors.apply_response(&req, &mut res);
+        assert_eq!(header(&res, "Access-Control-Allow-Origin"), None);
+        assert_eq!(header(&res, "Access-Control-Allow-Credentials"), None);
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/23 10:12:47
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustls::pki_types::CertificateDer;
+
+/// 读取一个DER元素, 返回标签, 内容及剩余的数据
+pub fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let num = first & 0x7F;
+        if num == 0 || num > 4 {
+            return None;
+        }
+        let bytes = data.get(2..2 + num)?;
+        (
+            bytes.iter().fold(0usize, |l, b| (l << 8) | *b as usize),
+            2 + num,
+        )
+    };
+    let content = data.get(start..start.checked_add(len)?)?;
+    Some((tag, content, &data[start + len..]))
+}
+
+/// 编码一个DER元素
+pub fn write_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut data = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        data.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        data.push(0x80 | (bytes.len() - skip) as u8);
+        data.extend_from_slice(&bytes[skip..]);
+    }
+    data.extend_from_slice(content);
+    data
+}
+
+/// 证书中的tbsCertificate, 跳过可选的版本号, 从序列号开始
+pub fn tbs_fields<'a>(cert: &'a CertificateDer<'_>) -> Option<&'a [u8]> {
+    let (_, cert, _) = read_tlv(cert)?;
+    let (_, mut tbs, _) = read_tlv(cert)?;
+    // 可选的版本号为[0]
+    if tbs.first() == Some(&0xA0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    Some(tbs)
+}
+
+/// 证书的过期时间
+pub fn not_after(cert: &CertificateDer<'_>) -> Option<DateTime<Utc>> {
+    let tbs = tbs_fields(cert)?;
+    // 跳过序列号, 签名算法及颁发者
+    let (_, _, tbs) = read_tlv(tbs)?;
+    let (_, _, tbs) = read_tlv(tbs)?;
+    let (_, _, tbs) = read_tlv(tbs)?;
+    let (_, validity, _) = read_tlv(tbs)?;
+    let (_, _, validity) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let time = match tag {
+        // UTCTime, 年份小于50时为20xx年
+        0x17 => {
+            let year = time.get(..2)?.parse::<u32>().ok()?;
+            let century = if year < 50 { "20" } else { "19" };
+            format!("{}{}", century, time)
+        }
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    let time = NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok()?;
+    Some(time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufReader};
+
+    use super::{not_after, read_tlv, write_tlv};
+
+    #[test]
+    fn test_der() {
+        let data = write_tlv(0x04, &[1u8; 300]);
+        assert_eq!(&data[..4], &[0x04, 0x82, 0x01, 0x2C]);
+        let (tag, content, rest) = read_tlv(&data).unwrap();
+        assert_eq!((tag, content.len(), rest.len()), (0x04, 300, 0));
+        assert!(read_tlv(&data[..100]).is_none());
+
+        let file = File::open("tests/certs/client.pem").unwrap();
+        let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let time = not_after(&certs[0]).unwrap();
+        assert!(time.format("%Y").to_string().parse::<u32>().unwrap() > 2100);
+    }
+}
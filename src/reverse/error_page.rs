@@ -0,0 +1,155 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/20 14:06:42
+
+use std::{fmt::Display, io, path::Path, str::FromStr};
+
+use webparse::{HeaderName, Response, Version};
+use wenmeng::Body;
+
+use crate::FileServer;
+
+/// 错误页面, 如`404 /404.html`, `500 502 503 504 html/50x.html`或`502 <h1>维护中</h1>`
+/// 以`/`开头的为内部跳转的路径, 重新匹配location处理, 以`<`开头的为内嵌的HTML, 否则为本地的文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPage {
+    pub status: Vec<u16>,
+    pub page: String,
+}
+
+impl ErrorPage {
+    /// 查找该状态码对应的错误页面
+    pub fn find(pages: &[ErrorPage], status: u16) -> Option<&ErrorPage> {
+        pages.iter().find(|p| p.status.contains(&status))
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        self.page.starts_with('/')
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.page.starts_with('<')
+    }
+
+    /// 读取本地或内嵌的错误页面并保留原状态码, 文件不存在时返回None
+    pub async fn load_file(&self, status: u16, version: Version) -> Option<Response<Body>> {
+        let (data, extension) = if self.is_inline() {
+            (self.page.clone().into_bytes(), "html".to_string())
+        } else {
+            let data = match tokio::fs::read(&self.page).await {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("读取错误页面{}失败:{:?}", self.page, e);
+                    return None;
+                }
+            };
+            let extension = Path::new(&self.page)
+                .extension()
+                .map(|e| e.to_string_lossy().to_string())
+                .unwrap_or_default();
+            (data, extension)
+        };
+        let content_type = match FileServer::get_default_mimetype(&extension) {
+            Some(t) if !t.starts_with("text/") => t.to_string(),
+            t => format!("{}; charset=utf-8", t.unwrap_or("text/html")),
+        };
+        Response::builder()
+            .version(version)
+            .status(status)
+            .header(HeaderName::CONTENT_TYPE, content_type)
+            .body(data)
+            .ok()
+            .map(|r| r.into_type())
+    }
+}
+
+impl FromStr for ErrorPage {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // 开头的数字均为状态码, 剩余的部分为页面, 内嵌的HTML可包含空格
+        let mut status = vec![];
+        let mut page = s.trim();
+        while let Some((v, rest)) = page.split_once(char::is_whitespace) {
+            let v = match v.parse::<u16>() {
+                Ok(v) => v,
+                Err(_) => break,
+            };
+            if !(300..600).contains(&v) {
+                return Err(io::Error::other("invalid error page status"));
+            }
+            status.push(v);
+            page = rest.trim_start();
+        }
+        let is_single = page.split_whitespace().count() == 1;
+        if status.is_empty() || page.is_empty() || (!is_single && !page.starts_with('<')) {
+            return Err(io::Error::other("error page need status and page"));
+        }
+        Ok(ErrorPage {
+            status,
+            page: page.to_string(),
+        })
+    }
+}
+
+impl Display for ErrorPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for s in &self.status {
+            f.write_fmt(format_args!("{} ", s))?;
+        }
+        f.write_str(&self.page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::Version;
+
+    use super::ErrorPage;
+
+    #[tokio::test]
+    async fn test_error_page() {
+        let pages = vec![
+            "404 /404.html".parse::<ErrorPage>().unwrap(),
+            "502 503 target/error_page_test.html".parse::<ErrorPage>().unwrap(),
+        ];
+        assert_eq!(pages[1].to_string(), "502 503 target/error_page_test.html");
+        assert!(ErrorPage::find(&pages, 404).unwrap().is_redirect());
+        assert!(ErrorPage::find(&pages, 500).is_none());
+        assert!("/404.html".parse::<ErrorPage>().is_err());
+        assert!("200 /404.html".parse::<ErrorPage>().is_err());
+        assert!("404 a.html b.html".parse::<ErrorPage>().is_err());
+
+        // 内嵌的HTML可包含空格
+        let page = "502 504 <h1>Service busy</h1>".parse::<ErrorPage>().unwrap();
+        assert_eq!(page.status, vec![502, 504]);
+        assert!(page.is_inline());
+        let res = page.load_file(502, Version::Http11).await.unwrap();
+        assert_eq!(res.status().as_u16(), 502);
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Type"),
+            Some("text/html; charset=utf-8".to_string())
+        );
+
+        // 文件不存在时返回None, 存在时保留原状态码
+        let page = ErrorPage::find(&pages, 503).unwrap();
+        let _ = std::fs::remove_file(&page.page);
+        assert!(page.load_file(503, Version::Http11).await.is_none());
+        std::fs::write(&page.page, "<h1>busy</h1>").unwrap();
+        let res = page.load_file(503, Version::Http11).await.unwrap();
+        assert_eq!(res.status().as_u16(), 503);
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Type"),
+            Some("text/html; charset=utf-8".to_string())
+        );
+        let _ = std::fs::remove_file(&page.page);
+    }
+}
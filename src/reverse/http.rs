@@ -12,37 +12,55 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    fs::File,
+    fs::{self, File},
     io::{self, BufReader},
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::{data::LimitReqData, Helper, ProxyResult};
+use crate::{
+    data::{
+        AccessStat, AccessTarget, BodyBytes, ConnCloseReason, LimitReqData, UpstreamTiming,
+        STATUS_CLIENT_CLOSED,
+    },
+    Compression, ConfigDuration, ConfigError, CountStream, DeadlineStream, FlowSlot, FlowStream, FlowWindow, H2Ping, Helper, IpSets, ListenAddr, Metrics, ProxyError, ProxyResult, ReadDeadline, ReadRecord, Shutdown,
+    ShutdownState, SocketOptions, UpstreamActiveCheck,
+};
 use async_trait::async_trait;
 use console::Style;
 use rustls::{
     crypto::ring::sign::any_supported_type,
     pki_types::{CertificateDer, PrivateKeyDer},
-    server::ResolvesServerCertUsingSni,
-    sign::CertifiedKey,
+    server::{
+        Acceptor, ClientHello, ResolvesServerCert, ResolvesServerCertUsingSni, WantsServerCert,
+        WebPkiClientVerifier,
+    },
+    sign::{CertifiedKey, SigningKey},
+    ConfigBuilder, RootCertStore, SignatureAlgorithm, SignatureScheme, WantsVerifier,
 };
+use rustls_pemfile::Item;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
-    sync::mpsc::{Receiver, Sender},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
 };
-use tokio_rustls::TlsAcceptor;
-use webparse::{Request, Response};
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
+use tokio_util::sync::CancellationToken;
+use webparse::{BinaryMut, HeaderName, Request, Response, Version};
 use wenmeng::{
     Body, HttpTrait, Middleware, ProtError, ProtResult, RecvRequest, RecvResponse, Server,
 };
 
 use super::{
-    common::CommonConfig, limit_req::LimitReqZone, ws::ServerWsOperate, LimitReqMiddleware,
-    LocationConfig, ServerConfig, UpstreamConfig,
+    der::not_after, pool::{CacheClient, PoolReturn, ReusedClient}, Acme, ClientCert, CorsConfig, ACME_CHALLENGE_PREFIX, TlsOption, TlsVersion, VerifyClient, DEFAULT_ALPN,
+    common::CommonConfig, limit_req::{LimitConnGuard, LimitReqZone}, ws::{ServerWsOperate, UpgradeSlot}, ErrorPage, LimitReqMiddleware,
+    LimitConcurrency, LimitConn, ListenInfo, LocationConfig, LocationMatch, PathCaptures, ProxyPeer, ProxyProtocol, RequestId, ReverseHelper, ServerConfig, ServerHidden, SplitConfig, SplitUpstream, TlsConnection, UpstreamConfig,
 };
 use async_recursion::async_recursion;
 
@@ -53,7 +71,30 @@ struct Operate {
 #[async_trait]
 impl HttpTrait for Operate {
     async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
-        HttpConfig::operate(req, &mut self.inner).await
+        let deadline = self.inner.header_deadline.clone();
+        if let Some(deadline) = &deadline {
+            // HTTP/2的连接中多个请求并行处理, 只限制连接建立后的首个请求
+            if req.version() == Version::Http2 {
+                deadline.disable();
+            } else {
+                deadline.pause();
+            }
+        }
+        // HTTP/2的连接中多个请求共用连接, 不按窗口限制
+        let flow = self.inner.flow.clone().filter(|_| req.version() != Version::Http2);
+        if let Some(flow) = &flow {
+            // 处理期间返回体可能需读取到本地, 如缓存及压缩, 发送时再开始限制
+            flow.release();
+            req.extensions_mut().insert(flow.clone());
+        }
+        let ret = HttpConfig::operate(req, &mut self.inner).await;
+        if let Some(deadline) = &deadline {
+            deadline.resume();
+        }
+        if let Some(flow) = &flow {
+            flow.arm();
+        }
+        ret
     }
 
     async fn middle_operate(
@@ -66,26 +107,192 @@ impl HttpTrait for Operate {
         Ok(())
     }
 
+    /// 排空连接时处理完当前请求即关闭, 同时释放缓存的后端连接
     fn is_continue_next(&self) -> bool {
-        true
+        Shutdown::state() < ShutdownState::Draining
+    }
+}
+
+/// 按SNI选择证书, 完全匹配优先于`*.example.com`形式的通配名, 未携带SNI或者未匹配时使用默认证书
+/// 同一域名有多个证书时, 按客户端支持的签名算法选择, 优先选择ECDSA证书
+#[derive(Debug, Default)]
+struct DefaultCertResolver {
+    sni: HashMap<String, Vec<Arc<CertifiedKey>>>,
+    default: Vec<Arc<CertifiedKey>>,
+    /// 各证书及私钥文件对应加载的证书
+    loaded: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl DefaultCertResolver {
+    /// 添加域名的证书, 并校验证书是否对该域名有效
+    fn add(&mut self, name: &str, ck: Arc<CertifiedKey>) -> Result<(), rustls::Error> {
+        // 通配名以其中的一个子域名校验证书
+        let check = match name.strip_prefix("*.") {
+            Some(suffix) => format!("wildcard.{}", suffix),
+            None => name.to_string(),
+        };
+        ResolvesServerCertUsingSni::new().add(&check, (*ck).clone())?;
+        self.sni
+            .entry(name.to_ascii_lowercase())
+            .or_default()
+            .push(ck);
+        Ok(())
+    }
+
+    /// 选出支持客户端签名算法的证书, 均不支持时返回第一个证书
+    fn choose(
+        keys: &[Arc<CertifiedKey>],
+        schemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        let supported = keys
+            .iter()
+            .filter(|ck| ck.key.choose_scheme(schemes).is_some());
+        supported
+            .clone()
+            .find(|ck| ck.key.algorithm() != SignatureAlgorithm::RSA)
+            .or_else(|| supported.clone().next())
+            .or_else(|| keys.first())
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for DefaultCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let schemes = client_hello.signature_schemes();
+        if let Some(name) = client_hello.server_name() {
+            let name = name.to_ascii_lowercase();
+            if let Some(keys) = self.sni.get(&name) {
+                return Self::choose(keys, schemes);
+            }
+            // 未完全匹配时使用通配名的证书, 仅匹配一级子域名
+            let wildcard = name.split_once('.').map(|(_, rest)| format!("*.{}", rest));
+            if let Some(keys) = wildcard.and_then(|w| self.sni.get(&w)) {
+                return Self::choose(keys, schemes);
+            }
+        }
+        Self::choose(&self.default, schemes)
+    }
+}
+
+/// 可在运行时替换的证书选择, 用于重新加载证书
+#[derive(Debug)]
+struct ReloadCertResolver {
+    inner: RwLock<Arc<DefaultCertResolver>>,
+}
+
+impl ResolvesServerCert for ReloadCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let inner = match self.inner.read() {
+            Ok(inner) => inner.clone(),
+            Err(_) => return None,
+        };
+        inner.resolve(client_hello)
     }
 }
 
-struct InnerHttpOper {
+#[derive(Clone)]
+pub(crate) struct InnerHttpOper {
     pub servers: Vec<Arc<ServerConfig>>,
-    pub cache_sender:
-        HashMap<LocationConfig, (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>)>,
+    /// 客户端地址, 写入请求的extensions供负载均衡使用
+    pub addr: SocketAddr,
+    /// 该连接处理的请求数, 连接结束时判断是否需要记录为提前关闭
+    pub req_num: Arc<AtomicUsize>,
+    /// 是否经由TLS接入
+    pub is_tls: bool,
+    /// 接入的监听地址, 未知时为None
+    pub listen_addr: Option<SocketAddr>,
+    /// 握手时校验通过的客户端证书
+    pub client_cert: Option<ClientCert>,
+    /// 接收请求头的截止时间, 处理请求时暂停
+    pub header_deadline: Option<Arc<ReadDeadline>>,
+    /// 转发返回体的窗口, 客户端接收缓慢时暂停读取后端
+    pub flow: Option<Arc<FlowWindow>>,
 }
 
 impl InnerHttpOper {
-    pub fn new(http: Vec<Arc<ServerConfig>>) -> Self {
+    pub fn new(http: Vec<Arc<ServerConfig>>, addr: SocketAddr, is_tls: bool) -> Self {
         Self {
             servers: http,
-            cache_sender: HashMap::new(),
+            addr,
+            req_num: Arc::new(AtomicUsize::new(0)),
+            is_tls,
+            listen_addr: None,
+            client_cert: None,
+            header_deadline: None,
+            flow: None,
         }
     }
 }
 
+/// 客户端连接的计数, 按端口首个server的`max_connections`及`max_connections_per_ip`限制
+struct ServerConnGuard {
+    guard: Option<LimitConnGuard>,
+    limit: Arc<LimitConn>,
+    addr: SocketAddr,
+}
+
+impl ServerConnGuard {
+    /// 接入连接时计数, 超出限制时返回None
+    fn acquire(servers: &[Arc<ServerConfig>], addr: SocketAddr) -> Option<Self> {
+        let server = &servers[0];
+        let fair = LimitConcurrency::new(
+            server.max_connections.unwrap_or(usize::MAX),
+            server.max_connections_per_ip.unwrap_or(usize::MAX),
+        );
+        let limit = server.connections.clone();
+        let guard = LimitConn::try_acquire_fair(&limit, addr.ip(), fair);
+        log::debug!(
+            "反向代理：{}连接{}, 当前连接数{}, 该IP连接数{}",
+            if guard.is_some() { "接入" } else { "超出限制拒绝" },
+            addr,
+            limit.get_total(),
+            limit.get_conns(&addr.ip())
+        );
+        Some(Self {
+            guard: Some(guard?),
+            limit,
+            addr,
+        })
+    }
+}
+
+impl Drop for ServerConnGuard {
+    fn drop(&mut self) {
+        self.guard.take();
+        log::debug!(
+            "反向代理：关闭连接{}, 当前连接数{}, 该IP连接数{}",
+            self.addr,
+            self.limit.get_total(),
+            self.limit.get_conns(&self.addr.ip())
+        );
+    }
+}
+
+/// 请求处理被中断(如客户端断开)时记录为客户端关闭
+struct RequestGuard {
+    done: bool,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        if !self.done {
+            AccessStat::on_request(STATUS_CLIENT_CLOSED);
+        }
+    }
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_cert_watch_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30))
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpConfig {
@@ -98,6 +305,50 @@ pub struct HttpConfig {
     #[serde(default = "HashMap::new")]
     pub limit_req_zone: HashMap<String, LimitReqZone>,
 
+    /// 默认证书, 客户端未发送SNI或者未匹配到域名时使用
+    pub default_cert: Option<String>,
+    pub default_key: Option<String>,
+
+    /// 可信的代理(如CDN及负载均衡), 如`10.0.0.0/8 192.168.1.1`
+    /// 直连的地址为可信的代理时, 按X-Forwarded-For解析客户端的地址, 用于限流及访问控制
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub trusted_proxies: Option<IpSets>,
+
+    /// 检查证书文件是否变更的间隔, 变更后重新加载证书, 为0时不检查
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_cert_watch_interval")]
+    pub cert_watch_interval: ConfigDuration,
+
+    /// 握手时默认协商的ALPN协议, 按顺序优先, server未配置alpn时使用, 默认为`["h2", "http/1.1"]`
+    pub alpn: Option<Vec<String>>,
+    /// 默认允许的最低TLS版本, 可选`1.2`, `1.3`, server未配置时使用, 默认不限制
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub min_tls_version: Option<TlsVersion>,
+    /// 默认允许的最高TLS版本
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_tls_version: Option<TlsVersion>,
+    /// 默认允许的加密套件, server未配置时使用, 为空时使用默认的套件
+    #[serde(default)]
+    pub ciphers: Vec<String>,
+    /// 是否协商HTTP/2, 为false时即使alpn中配置或客户端提供了h2也不协商
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+
+    /// 每个监听地址以SO_REUSEPORT绑定的监听数, 由内核分摊新连接, 仅Linux生效
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+
+    /// 用于取消主动健康检查的任务
+    #[serde(skip)]
+    pub health_cancel: Option<CancellationToken>,
+
+    /// 当前使用的证书, 重新加载证书时替换
+    #[serde(skip)]
+    cert_resolver: Option<Arc<ReloadCertResolver>>,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
@@ -109,6 +360,18 @@ impl HttpConfig {
             server: vec![],
             upstream: vec![],
             limit_req_zone: HashMap::new(),
+            default_cert: None,
+            default_key: None,
+            trusted_proxies: None,
+            cert_watch_interval: default_cert_watch_interval(),
+            min_tls_version: None,
+            max_tls_version: None,
+            ciphers: vec![],
+            alpn: None,
+            http2: default_http2(),
+            workers: default_workers(),
+            health_cancel: None,
+            cert_resolver: None,
             comm: CommonConfig::new(),
         }
     }
@@ -117,31 +380,278 @@ impl HttpConfig {
         if !self.comm.log_format.contains_key(&"main".to_string()) {
             self.comm.log_format.insert("main".to_string(), "{d(%Y-%m-%d %H:%M:%S)} {client_ip} {l} {url} path:{path} query:{query} host:{host} status: {status} {up_status} referer: {referer} user_agent: {user_agent} cookie: {cookie}".to_string());
         }
+        for (name, format) in [
+            ("combined", AccessTarget::COMBINED_FORMAT),
+            ("json", AccessTarget::JSON_FORMAT),
+        ] {
+            if !self.comm.log_format.contains_key(name) {
+                self.comm.log_format.insert(name.to_string(), format.to_string());
+            }
+        }
         self.copy_to_child();
+        if self.alpn.as_ref().is_some_and(|alpn| alpn.iter().any(|p| p.is_empty())) {
+            return Err(ProtError::Extension("http的alpn不能为空"));
+        }
+        for server in &self.server {
+            server.check_tls()?;
+            for up in &server.upstream {
+                up.check_unix()?;
+            }
+            for split in server.location.iter().filter_map(|l| l.split.as_ref()) {
+                split.check()?;
+            }
+            let unix_url = server
+                .location
+                .iter()
+                .filter_map(|l| l.comm.proxy_url.as_ref())
+                .any(|url| ReverseHelper::unix_url_path(url).is_some());
+            if cfg!(not(unix)) && unix_url {
+                return Err(ProtError::Extension("proxy_url配置了unix socket, 当前平台不支持"));
+            }
+            if cfg!(not(unix)) && server.listen.iter().any(|l| l.is_unix()) {
+                return Err(ProtError::Extension("listen配置了unix socket, 当前平台不支持"));
+            }
+        }
+        self.check_default_server()?;
         for (k, zone) in &self.limit_req_zone {
             LimitReqData::cache(k.to_string(), zone.limit, zone.rate.nums, zone.rate.per)?;
         }
         Ok(())
     }
 
+    /// 同一端口最多只能有一个`default_server`, 否则未匹配的请求的处理者取决于配置顺序
+    fn check_default_server(&self) -> io::Result<()> {
+        match self.default_server_conflicts().into_iter().next() {
+            Some(err) => Err(io::Error::new(io::ErrorKind::InvalidInput, err)),
+            None => Ok(()),
+        }
+    }
+
+    /// 所有配置了多个`default_server`的端口
+    fn default_server_conflicts(&self) -> Vec<String> {
+        let mut ports = HashMap::new();
+        let mut conflicts = vec![];
+        for server in self.server.iter().filter(|s| s.default_server) {
+            let addrs = server.bind_addr.0.iter().chain(server.bind_ssl.0.iter());
+            for port in addrs.map(|v| v.port()).collect::<HashSet<_>>() {
+                if let Some(other) = ports.insert(port, &server.up_name) {
+                    conflicts.push(format!(
+                        "端口{}配置了多个default_server: {}, {}",
+                        port, other, server.up_name
+                    ));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// 错误的可读内容, IO错误不显示类型
+    fn error_message(err: ProxyError<TcpStream>) -> String {
+        match err {
+            ProxyError::IoError(e) => e.to_string(),
+            e => e.to_string(),
+        }
+    }
+
+    /// 启动前检查配置, 返回所有发现的错误以便一次修改, 需在`after_load_option`之后调用
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = vec![];
+        let mut names = HashSet::new();
+        for up in &self.upstream {
+            if !names.insert(&up.name) {
+                errors.push(ConfigError::new(format!("upstream[{}]", up.name), "名称重复"));
+            }
+        }
+        if self.default_cert.is_some() || self.default_key.is_some() {
+            if let Err(e) = Self::load_certified_key(&self.default_cert, &self.default_key) {
+                errors.push(ConfigError::new("default_cert", Self::error_message(e)));
+            }
+        }
+        let mut checked_up = HashSet::new();
+        let mut checked_ip = HashSet::new();
+        let mut hosts = HashMap::new();
+        for (idx, server) in self.server.iter().enumerate() {
+            let path = if server.up_name.is_empty() {
+                format!("server[{}]", idx)
+            } else {
+                format!("server[{}]", server.up_name)
+            };
+            if let Err(e) = server.check_tls() {
+                errors.push(ConfigError::new(&path, e.to_string()));
+            }
+            // acme的证书在启动后申请, 此时可能还不存在
+            if server.acme.is_none() {
+                let pairs = [(&server.cert, &server.key), (&server.alt_cert, &server.alt_key)];
+                for (cert, key) in pairs {
+                    if cert.is_none() && key.is_none() {
+                        continue;
+                    }
+                    if let Err(e) = Self::load_certified_key(cert, key) {
+                        let file = cert.clone().unwrap_or_default();
+                        let msg = format!("证书{}加载失败: {}", file, Self::error_message(e));
+                        errors.push(ConfigError::new(&path, msg));
+                    }
+                }
+            }
+            let listen = server.listen.iter().filter_map(|l| match l {
+                ListenAddr::Tcp(addr) => Some(addr),
+                ListenAddr::Unix(_) => None,
+            });
+            let addrs = server.bind_addr.0.iter().chain(server.bind_ssl.0.iter());
+            let mut ports = HashSet::new();
+            for addr in addrs.chain(listen) {
+                ports.insert(addr.port());
+                // 仅检查地址是否可绑定, 端口可能被重载前的进程占用
+                if checked_ip.insert(addr.ip()) {
+                    if let Err(e) = std::net::TcpListener::bind(SocketAddr::new(addr.ip(), 0)) {
+                        let msg = format!("监听地址{}不可用: {}", addr, e);
+                        errors.push(ConfigError::new(&path, msg));
+                    }
+                }
+            }
+            for port in ports {
+                let key = (port, server.up_name.to_ascii_lowercase());
+                if let Some(other) = hosts.insert(key, path.clone()) {
+                    let msg = format!("端口{}已有相同server_name的{}", port, other);
+                    errors.push(ConfigError::new(&path, msg));
+                }
+            }
+            for up in &server.upstream {
+                if !checked_up.insert(&up.name) {
+                    continue;
+                }
+                let up_path = format!("upstream[{}]", up.name);
+                if let Err(e) = up.check_unix() {
+                    errors.push(ConfigError::new(&up_path, e.to_string()));
+                }
+                if up.server.is_empty() && up.hosts.is_empty() {
+                    errors.push(ConfigError::new(&up_path, "未配置后端地址"));
+                }
+            }
+            for l in &server.location {
+                let l_path = format!("{}.location[{}]", path, l.rule);
+                if !l.has_handler() {
+                    errors.push(ConfigError::new(&l_path, "未配置proxy_url, root等处理方式"));
+                }
+                let split = match &l.split {
+                    Some(split) => split,
+                    None => continue,
+                };
+                if let Err(e) = split.check() {
+                    errors.push(ConfigError::new(&l_path, e.to_string()));
+                }
+                for up in &split.upstreams {
+                    if !server.upstream.iter().any(|u| u.name == up.name) {
+                        let msg = format!("split的upstream{}不存在", up.name);
+                        errors.push(ConfigError::new(&l_path, msg));
+                    }
+                }
+            }
+        }
+        for conflict in self.default_server_conflicts() {
+            errors.push(ConfigError::new("default_server", conflict));
+        }
+        let has_default = self.default_cert.is_some() || self.default_key.is_some();
+        if let Err(e) = self.group_bind_addrs(has_default) {
+            errors.push(ConfigError::new("bind", Self::error_message(e)));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// 启动或重新加载前检查配置, 并加载证书及各server的TLS配置, 不绑定地址,
+    /// 失败时不应启动新的服务, 需在`after_load_option`之后调用
+    pub fn check_start(&self) -> ProxyResult<()> {
+        if let Err(errors) = self.validate() {
+            for e in &errors {
+                log::error!("配置错误: {}", e);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("配置检查失败:\n{}", ConfigError::join(&errors)),
+            )
+            .into());
+        }
+        self.build_cert_resolver(None)?;
+        Self::tls_builder(
+            self.min_tls_version,
+            self.max_tls_version,
+            &self.ciphers,
+            "http",
+        )?;
+        for value in self.server.iter().filter(|s| s.is_custom_tls()) {
+            self.server_tls_builder(value)?;
+        }
+        Ok(())
+    }
+
     /// 将配置参数提前共享给子级
     pub fn copy_to_child(&mut self) {
         self.comm.pre_deal();
         for server in &mut self.server {
             server.upstream.append(&mut self.upstream.clone());
+            server.trusted_proxies = self.trusted_proxies.clone();
             server.comm.copy_from_parent(&self.comm);
             server.comm.pre_deal();
             server.copy_to_child();
         }
     }
 
+    /// 启动所有upstream的主动健康检查
+    pub fn start_health_check(&mut self) {
+        self.stop_health_check();
+        let cancel = CancellationToken::new();
+        let upstreams = self
+            .upstream
+            .iter()
+            .chain(self.server.iter().flat_map(|s| s.upstream.iter()));
+        UpstreamActiveCheck::start_all(upstreams, &cancel);
+        self.health_cancel = Some(cancel);
+    }
+
+    /// 解析所有upstream中的域名后端, 之后定时重新解析, 与健康检查一同停止
+    pub async fn start_resolve(&self) {
+        if let Some(cancel) = &self.health_cancel {
+            let upstreams = self
+                .upstream
+                .iter()
+                .chain(self.server.iter().flat_map(|s| s.upstream.iter()));
+            UpstreamConfig::start_resolve_all(upstreams, cancel).await;
+        }
+    }
+
+    /// 停止所有的主动健康检查
+    pub fn stop_health_check(&self) {
+        if let Some(cancel) = &self.health_cancel {
+            cancel.cancel();
+        }
+    }
+
+    /// 停止接收新连接, 已有连接处理完当前请求后关闭, 全部结束或者超过`timeout`后返回,
+    /// 剩余的连接将被关闭, 已在关闭流程中时等待其结束
+    pub async fn shutdown(timeout: Duration) {
+        if !Shutdown::run(Duration::ZERO, timeout, None).await {
+            Shutdown::wait_closed().await;
+        }
+    }
+
     fn load_certs(path: &Option<String>) -> io::Result<Vec<CertificateDer<'static>>> {
         if let Some(path) = path {
             match File::open(&path) {
                 Ok(file) => {
                     let mut reader = BufReader::new(file);
                     let certs = rustls_pemfile::certs(&mut reader);
-                    Ok(certs.into_iter().collect::<Result<Vec<_>, _>>()?)
+                    let certs = certs.into_iter().collect::<Result<Vec<_>, _>>()?;
+                    if certs.is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("No certificate found in {}", path),
+                        ));
+                    }
+                    Ok(certs)
                 }
                 Err(e) => {
                     log::warn!("加载公钥{}出错，错误内容:{:?}", path, e);
@@ -158,7 +668,16 @@ impl HttpConfig {
             match File::open(&path) {
                 Ok(file) => {
                     let mut reader = BufReader::new(file);
-                    rustls_pemfile::rsa_private_keys(&mut reader).collect::<Result<Vec<_>, _>>()?
+                    let mut keys = vec![];
+                    for item in rustls_pemfile::read_all(&mut reader) {
+                        match item? {
+                            Item::Pkcs1Key(key) => keys.push(PrivateKeyDer::from(key)),
+                            Item::Pkcs8Key(key) => keys.push(PrivateKeyDer::from(key)),
+                            Item::Sec1Key(key) => keys.push(PrivateKeyDer::from(key)),
+                            _ => {}
+                        }
+                    }
+                    keys
                 }
                 Err(e) => {
                     log::warn!("加载私钥{}出错，错误内容:{:?}", path, e);
@@ -172,101 +691,522 @@ impl HttpConfig {
         match keys.len() {
             0 => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!("No RSA private key found"),
+                "No private key found".to_string(),
             )),
-            1 => Ok(PrivateKeyDer::from(keys.remove(0))),
+            1 => Ok(keys.remove(0)),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                format!("More than one RSA private key found"),
+                "More than one private key found".to_string(),
             )),
         }
     }
 
-    pub async fn bind(
-        &mut self,
-    ) -> ProxyResult<(Option<TlsAcceptor>, Vec<bool>, Vec<TcpListener>)> {
-        let mut listeners = vec![];
-        let mut tlss = vec![];
-        let mut bind_addr_set = HashSet::new();
-        let config = rustls::ServerConfig::builder();
-        let mut resolve = ResolvesServerCertUsingSni::new();
-        let mut one_key = None;
-        let mut one_cert = None;
-        let is_single = self.server.len() == 1;
-        for value in &self.server.clone() {
-            let mut is_ssl = false;
-            if value.cert.is_some() && value.key.is_some() {
-                let key = Self::load_keys(&value.key)?;
-                let cert = Self::load_certs(&value.cert)?;
-                if is_single {
-                    one_key = Some(key.clone_key());
-                    one_cert = Some(cert.clone());
-                } else {
-                    let singed_key = any_supported_type(&key)
-                        .map_err(|_| ProtError::Extension("unvaild key"))?;
-                    let ck = CertifiedKey::new(cert, singed_key);
-                    let name = value.comm.domain.clone().unwrap_or(value.up_name.clone());
-                    resolve.add(&name, ck).map_err(|e| {
-                        log::warn!("添加证书时失败:{:?}", e);
-                        ProtError::Extension("key error")
-                    })?;
-                }
-                is_ssl = true;
-            }
-            for v in &value.bind_addr.0 {
-                if bind_addr_set.contains(&v) {
+    fn load_certified_key(
+        cert: &Option<String>,
+        key: &Option<String>,
+    ) -> ProxyResult<CertifiedKey> {
+        let key = Self::load_keys(key)?;
+        let certs = Self::load_certs(cert)?;
+        let singed_key =
+            any_supported_type(&key).map_err(|_| ProtError::Extension("unvaild key"))?;
+        Self::check_chain(cert, &certs, &*singed_key)?;
+        // 证书文件中的叶子证书及中间证书均在握手时发送给客户端
+        Ok(CertifiedKey::new(certs, singed_key))
+    }
+
+    /// 证书文件需按叶子证书, 中间证书的顺序排列, 且叶子证书与私钥匹配
+    fn check_chain(
+        path: &Option<String>,
+        certs: &[CertificateDer<'static>],
+        key: &dyn SigningKey,
+    ) -> io::Result<()> {
+        let path = path.clone().unwrap_or_default();
+        let matches = certs
+            .iter()
+            .map(|c| Self::is_key_match(c, key))
+            .collect::<Vec<_>>();
+        match matches.iter().position(|m| m == &Some(true)) {
+            Some(0) => Ok(()),
+            Some(i) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "证书{}中与私钥匹配的叶子证书位于第{}个, 需位于中间证书之前",
+                    path,
+                    i + 1
+                ),
+            )),
+            // 无法解析的证书不做校验
+            None if matches[0].is_none() => Ok(()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("证书{}与私钥不匹配", path),
+            )),
+        }
+    }
+
+    /// 以私钥签名并用证书中的公钥校验, 判断证书与私钥是否匹配, 无法校验时返回None
+    fn is_key_match(cert: &CertificateDer<'_>, key: &dyn SigningKey) -> Option<bool> {
+        let algs: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+            (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+            (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+            (SignatureScheme::ED25519, &webpki::ED25519),
+            (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+        ];
+        let schemes = algs.iter().map(|(s, _)| *s).collect::<Vec<_>>();
+        let signer = key.choose_scheme(&schemes)?;
+        let (_, alg) = algs.iter().find(|(s, _)| *s == signer.scheme())?;
+        let cert = webpki::EndEntityCert::try_from(cert.as_ref()).ok()?;
+        let msg = b"wmproxy check certified key";
+        let signature = signer.sign(msg).ok()?;
+        Some(cert.verify_signature(alg, msg, &signature).is_ok())
+    }
+
+    /// 加载证书, 失败时若`fallback`中有该文件上次加载成功的证书则继续使用并记录错误
+    fn load_or_keep(
+        cert: &Option<String>,
+        key: &Option<String>,
+        fallback: Option<&HashMap<String, Arc<CertifiedKey>>>,
+        loaded: &mut HashMap<String, Arc<CertifiedKey>>,
+    ) -> Result<Arc<CertifiedKey>, String> {
+        let file = format!(
+            "{}/{}",
+            cert.clone().unwrap_or_default(),
+            key.clone().unwrap_or_default()
+        );
+        let ck = match Self::load_certified_key(cert, key) {
+            Ok(ck) => Arc::new(ck),
+            Err(e) => match fallback.and_then(|f| f.get(&file)) {
+                Some(old) => {
+                    log::error!("加载证书{}失败, 继续使用原证书, 错误内容:{:?}", file, e);
+                    old.clone()
+                }
+                None => return Err(format!("{}({})", file, Self::error_message(e))),
+            },
+        };
+        loaded.insert(file, ck.clone());
+        Ok(ck)
+    }
+
+    /// 根据配置加载所有证书, 任一证书加载失败则返回所有失败的文件
+    /// `fallback`为上次加载的证书, 加载失败的文件继续使用原证书
+    fn build_cert_resolver(
+        &self,
+        fallback: Option<&HashMap<String, Arc<CertifiedKey>>>,
+    ) -> ProxyResult<DefaultCertResolver> {
+        let mut errors = vec![];
+        let mut resolve = DefaultCertResolver::default();
+        let has_default = self.default_cert.is_some() || self.default_key.is_some();
+        if has_default {
+            match Self::load_or_keep(
+                &self.default_cert,
+                &self.default_key,
+                fallback,
+                &mut resolve.loaded,
+            ) {
+                Ok(ck) => resolve.default.push(ck),
+                Err(err) => errors.push(err),
+            }
+        }
+        // 只有一个Server时, 不区分SNI均使用该证书
+        let is_single = self.server.len() == 1 && !has_default;
+        for value in &self.server {
+            let pairs = [(&value.cert, &value.key), (&value.alt_cert, &value.alt_key)];
+            for (cert, key) in pairs {
+                if cert.is_none() || key.is_none() {
                     continue;
                 }
-                bind_addr_set.insert(v);
-                let url = format!("http://{}", v);
-                log::info!("HTTP服务：{}，提供http处理及转发功能。", Style::new().blink().green().apply_to(url));
-                let listener = Helper::bind(v).await?;
-                listeners.push(listener);
-                tlss.push(false);
+                match Self::load_or_keep(cert, key, fallback, &mut resolve.loaded) {
+                    Ok(ck) => {
+                        if is_single {
+                            resolve.default.push(ck);
+                        } else {
+                            let name = value.comm.domain.clone().unwrap_or(value.up_name.clone());
+                            if let Err(e) = resolve.add(&name, ck) {
+                                log::warn!("添加证书时失败:{:?}", e);
+                                errors.push(format!(
+                                    "{}/{}",
+                                    cert.as_ref().unwrap(),
+                                    key.as_ref().unwrap()
+                                ));
+                            }
+                        }
+                    }
+                    // ACME的证书尚未申请成功时不影响其它server
+                    Err(file) if value.acme.is_some() => {
+                        log::warn!("ACME证书{}尚未申请成功", file);
+                    }
+                    Err(err) => errors.push(err),
+                }
             }
+        }
+        if !errors.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("加载证书失败: {}", errors.join(", ")),
+            )
+            .into());
+        }
+        Ok(resolve)
+    }
 
-            for v in &value.bind_ssl.0 {
-                if bind_addr_set.contains(&v) {
+    /// 重新加载证书, 新的握手将使用新证书, 已建立的连接不受影响
+    /// 任一证书加载失败时不做任何变更
+    pub fn reload_certs(&self) -> ProxyResult<()> {
+        let resolver = match &self.cert_resolver {
+            Some(resolver) => resolver,
+            None => return Err(crate::ProxyError::Extension("未绑定HTTPS服务, 无法重载证书")),
+        };
+        let new = self.build_cert_resolver(None)?;
+        if let Ok(mut inner) = resolver.inner.write() {
+            *inner = Arc::new(new);
+        }
+        log::info!("HTTPs服务证书已重新加载");
+        Ok(())
+    }
+
+    /// 所有证书及私钥文件的修改时间, 文件不存在时为None
+    fn cert_mtimes(&self) -> HashMap<String, Option<SystemTime>> {
+        let mut files = vec![&self.default_cert, &self.default_key];
+        for s in &self.server {
+            files.extend([&s.cert, &s.key, &s.alt_cert, &s.alt_key]);
+        }
+        files
+            .into_iter()
+            .flatten()
+            .map(|f| (f.clone(), fs::metadata(f).and_then(|m| m.modified()).ok()))
+            .collect()
+    }
+
+    /// 证书文件变更后重新加载, 新的文件无法加载时继续使用原证书, 并输出新证书的过期时间
+    fn reload_changed_certs(&self) -> ProxyResult<()> {
+        let resolver = match &self.cert_resolver {
+            Some(resolver) => resolver,
+            None => return Ok(()),
+        };
+        let old = match resolver.inner.read() {
+            Ok(inner) => inner.clone(),
+            Err(_) => return Ok(()),
+        };
+        let new = self.build_cert_resolver(Some(&old.loaded))?;
+        for (file, ck) in &new.loaded {
+            if old.loaded.get(file).is_some_and(|old| Arc::ptr_eq(old, ck)) {
+                continue;
+            }
+            match ck.cert.first().and_then(not_after) {
+                Some(time) => log::info!(
+                    "证书{}已重新加载, 有效期至{}",
+                    file,
+                    time.format("%Y-%m-%d %H:%M:%S")
+                ),
+                None => log::info!("证书{}已重新加载", file),
+            }
+        }
+        if let Ok(mut inner) = resolver.inner.write() {
+            *inner = Arc::new(new);
+        }
+        Ok(())
+    }
+
+    /// 定时检查证书文件的修改时间, 变更后重新加载, 收到取消信号后退出
+    pub fn start_cert_watch(&self, cancel: &CancellationToken) {
+        let interval = self.cert_watch_interval.0;
+        if interval.is_zero() || self.cert_resolver.is_none() {
+            return;
+        }
+        let http = self.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            let mut last = http.cert_mtimes();
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {}
+                    _ = cancel.cancelled() => {
+                        log::trace!("证书文件检查, 收到退出信号");
+                        break;
+                    }
+                }
+                let now = http.cert_mtimes();
+                if now == last {
                     continue;
                 }
-                bind_addr_set.insert(v);
-                if !is_ssl {
-                    return Err(crate::ProxyError::Extension("配置SSL端口但未配置证书"));
+                last = now;
+                if let Err(e) = http.reload_changed_certs() {
+                    log::error!("证书文件变更后重新加载失败, 继续使用原证书:{:?}", e);
+                }
+            }
+        });
+    }
+
+    /// 按监听地址对server分组, 返回各地址, 是否为TLS及首个server的socket选项, 同一地址的server共享监听及SNI证书
+    /// 同一地址不可同时配置为HTTP及HTTPS, HTTPS的地址需至少有一个server配置证书或配置默认证书
+    fn group_bind_addrs(
+        &self,
+        has_default: bool,
+    ) -> ProxyResult<Vec<(SocketAddr, bool, SocketOptions)>> {
+        // 地址, 是否为TLS, 是否有证书, socket选项
+        let mut groups: Vec<(SocketAddr, bool, bool, SocketOptions)> = vec![];
+        for value in &self.server {
+            let has_cert = value.cert.is_some() && value.key.is_some();
+            let listen = value.listen.iter().filter_map(|l| match l {
+                ListenAddr::Tcp(addr) => Some((addr, false)),
+                ListenAddr::Unix(_) => None,
+            });
+            let addrs = value.bind_addr.0.iter().map(|v| (v, false)).chain(listen);
+            for (v, is_tls) in addrs.chain(value.bind_ssl.0.iter().map(|v| (v, true))) {
+                match groups.iter_mut().find(|g| &g.0 == v) {
+                    Some(group) => {
+                        if group.1 != is_tls {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("监听地址{}不能同时配置为HTTP及HTTPS", v),
+                            )
+                            .into());
+                        }
+                        group.2 = group.2 || has_cert;
+                    }
+                    None => groups.push((*v, is_tls, has_cert, value.socket.clone())),
+                }
+            }
+        }
+        for (v, is_tls, has_cert, _) in &groups {
+            if *is_tls && !has_cert && !has_default {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("配置SSL端口{}但未配置证书", v),
+                )
+                .into());
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .map(|(v, is_tls, _, options)| (v, is_tls, options))
+            .collect())
+    }
+
+    /// 绑定server中配置的unix socket, 同一路径的server共享监听
+    #[cfg(unix)]
+    pub async fn bind_unix(&self) -> ProxyResult<Vec<(PathBuf, tokio::net::UnixListener)>> {
+        let mut listeners: Vec<(PathBuf, tokio::net::UnixListener)> = vec![];
+        for value in &self.server {
+            for l in &value.listen {
+                let path = match l {
+                    ListenAddr::Unix(path) => path,
+                    ListenAddr::Tcp(_) => continue,
+                };
+                if listeners.iter().any(|(p, _)| p == path) {
+                    continue;
                 }
+                log::info!("HTTP服务：unix:{}，提供http处理及转发功能。", path.display());
+                let listener = Helper::bind_unix(path, &value.socket).await?;
+                listeners.push((path.clone(), listener));
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// 处理unix socket上的连接, 由监听该路径的server处理, 客户端地址记为`127.0.0.1:0`,
+    /// 可将其配置为可信的代理以使用X-Forwarded-For中的地址, 取消后停止接收,
+    /// socket文件未被新的服务接管时将其移除
+    #[cfg(unix)]
+    pub async fn serve_unix(
+        servers: Vec<Arc<ServerConfig>>,
+        path: PathBuf,
+        listener: tokio::net::UnixListener,
+        cancel: CancellationToken,
+    ) {
+        use std::os::unix::fs::MetadataExt;
+        let unix = ListenAddr::Unix(path.clone());
+        let servers: Vec<_> = servers
+            .into_iter()
+            .filter(|s| s.listen.contains(&unix))
+            .collect();
+        let ino = fs::metadata(&path).map(|m| m.ino()).ok();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        loop {
+            let stream = tokio::select! {
+                ret = listener.accept() => match ret {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::info!("反向代理：unix socket接收连接失败：{:?}", e);
+                        continue;
+                    }
+                },
+                _ = cancel.cancelled() => break,
+            };
+            log::trace!("反向代理:unix收到客户端连接: {}", unix);
+            let _ = Self::process(servers.clone(), stream, addr).await;
+        }
+        if ino.is_some() && fs::metadata(&path).map(|m| m.ino()).ok() == ino {
+            let _ = fs::remove_file(&path);
+        }
+    }
+
+    pub async fn bind(
+        &mut self,
+    ) -> ProxyResult<(Option<TlsAcceptor>, Vec<bool>, Vec<TcpListener>)> {
+        self.start_health_check();
+        self.start_resolve().await;
+        let mut listeners = vec![];
+        let mut tlss = vec![];
+        let resolver = self.build_cert_resolver(None)?;
+        let has_default = !resolver.default.is_empty();
+        for (v, is_tls, options) in self.group_bind_addrs(has_default)? {
+            if is_tls {
                 let url = format!("https://{}", v);
                 log::info!("HTTPs服务：{}，提供https处理及转发功能。", Style::new().blink().green().apply_to(url));
-                let listener = Helper::bind(v).await?;
+            } else {
+                let url = format!("http://{}", v);
+                log::info!("HTTP服务：{}，提供http处理及转发功能。", Style::new().blink().green().apply_to(url));
+            }
+            for listener in Helper::bind_workers(v, self.workers, &options).await? {
                 listeners.push(listener);
-                tlss.push(is_ssl);
+                tlss.push(is_tls);
             }
         }
 
-        let mut config = if is_single && one_cert.is_some() && one_key.is_some() {
-            config
-                .with_no_client_auth()
-                .with_single_cert(one_cert.unwrap(), one_key.unwrap())
-                .map_err(|e| {
-                    log::warn!("添加证书时失败:{:?}", e);
-                    ProtError::Extension("key error")
-                })?
+        let resolver = Arc::new(ReloadCertResolver {
+            inner: RwLock::new(Arc::new(resolver)),
+        });
+        self.cert_resolver = Some(resolver.clone());
+        let builder = Self::tls_builder(
+            self.min_tls_version,
+            self.max_tls_version,
+            &self.ciphers,
+            "http",
+        )?;
+        let config = Self::build_tls_config(
+            builder.with_no_client_auth(),
+            resolver.clone(),
+            self.alpn_protocols(None),
+        );
+        self.build_server_tls(&config, resolver)?;
+        if let Some(cancel) = &self.health_cancel {
+            Acme::start(self, cancel);
+            self.start_cert_watch(cancel);
+        }
+        Ok((Some(TlsAcceptor::from(config)), tlss, listeners))
+    }
+
+    /// 握手时协商的ALPN协议, server未配置时使用http中的配置, 关闭http2时移除h2
+    fn alpn_protocols(&self, alpn: Option<&Vec<String>>) -> Vec<Vec<u8>> {
+        let protocols: Vec<&str> = match alpn.or(self.alpn.as_ref()) {
+            Some(alpn) => alpn.iter().map(|p| p.as_str()).collect(),
+            None => DEFAULT_ALPN.to_vec(),
+        };
+        protocols
+            .into_iter()
+            .filter(|p| self.http2 || *p != "h2")
+            .map(|p| p.as_bytes().to_vec())
+            .collect()
+    }
+
+    /// 按允许的TLS版本及加密套件生成配置, 最低版本大于最高版本或套件不可用时返回错误
+    fn tls_builder(
+        min: Option<TlsVersion>,
+        max: Option<TlsVersion>,
+        ciphers: &[String],
+        name: &str,
+    ) -> ProxyResult<ConfigBuilder<rustls::ServerConfig, WantsVerifier>> {
+        let versions = TlsOption::versions(min, max)?;
+        let provider = TlsOption::provider(ciphers, &versions)?;
+        let builder = rustls::ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_protocol_versions(&versions)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}的TLS配置错误:{:?}", name, e),
+                )
+            })?;
+        Ok(builder)
+    }
+
+    fn build_tls_config(
+        builder: ConfigBuilder<rustls::ServerConfig, WantsServerCert>,
+        resolver: Arc<ReloadCertResolver>,
+        alpn: Vec<Vec<u8>>,
+    ) -> Arc<rustls::ServerConfig> {
+        let mut config = builder.with_cert_resolver(resolver);
+        config.alpn_protocols = alpn;
+        Arc::new(config)
+    }
+
+    /// 按server的配置限制TLS版本及加密套件, 开启`verify_client`时按`client_ca`校验客户端证书
+    fn server_tls_builder(
+        &self,
+        value: &ServerConfig,
+    ) -> ProxyResult<ConfigBuilder<rustls::ServerConfig, WantsServerCert>> {
+        // 未配置的项使用http中的配置
+        let ciphers = if value.ciphers.is_empty() {
+            &self.ciphers
         } else {
-            config
-                .with_no_client_auth()
-                .with_cert_resolver(Arc::new(resolve))
+            &value.ciphers
         };
-        config.alpn_protocols.push("h2".as_bytes().to_vec());
-        config.alpn_protocols.push("http/1.1".as_bytes().to_vec());
-        Ok((Some(TlsAcceptor::from(Arc::new(config))), tlss, listeners))
+        let builder = Self::tls_builder(
+            value.min_tls_version.or(self.min_tls_version),
+            value.max_tls_version.or(self.max_tls_version),
+            ciphers,
+            &format!("server{}", value.up_name),
+        )?;
+        let verify = value.get_verify_client();
+        if verify == VerifyClient::Off {
+            return Ok(builder.with_no_client_auth());
+        }
+        if value.client_ca.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("server{}开启verify_client但未配置client_ca", value.up_name),
+            )
+            .into());
+        }
+        let mut roots = RootCertStore::empty();
+        for cert in Self::load_certs(&value.client_ca)? {
+            roots.add(cert).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("加载客户端CA失败:{:?}", e),
+                )
+            })?;
+        }
+        let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+        if verify == VerifyClient::Optional {
+            verifier = verifier.allow_unauthenticated();
+        }
+        let verifier = verifier.build().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("生成客户端证书校验失败:{:?}", e),
+            )
+        })?;
+        Ok(builder.with_client_cert_verifier(verifier))
+    }
+
+    /// 生成各server握手使用的TLS配置, 按配置限制ALPN, TLS版本及加密套件,
+    /// 开启`verify_client`的server按`client_ca`校验客户端证书
+    fn build_server_tls(
+        &self,
+        default: &Arc<rustls::ServerConfig>,
+        resolver: Arc<ReloadCertResolver>,
+    ) -> ProxyResult<()> {
+        for value in &self.server {
+            if !value.is_custom_tls() {
+                if let Ok(mut tls_config) = value.tls_config.write() {
+                    *tls_config = Some(default.clone());
+                }
+                continue;
+            }
+            let builder = self.server_tls_builder(value)?;
+            let alpn = self.alpn_protocols(value.alpn.as_ref());
+            let config = Self::build_tls_config(builder, resolver.clone(), alpn);
+            if let Ok(mut tls_config) = value.tls_config.write() {
+                *tls_config = Some(config);
+            }
+        }
+        Ok(())
     }
 
     #[async_recursion]
     async fn deal_match_location(
         req: &mut Request<Body>,
-        // 缓存客户端请求
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
         // 该Server的配置选项
         server: Arc<ServerConfig>,
         // 已处理的匹配路由
@@ -275,26 +1215,79 @@ impl HttpConfig {
         try_deals: &mut HashSet<usize>,
     ) -> ProtResult<Response<Body>> {
         let path = req.path().clone();
-        let mut l = None;
-        let mut now = usize::MAX;
-        for idx in 0..server.location.len() {
-            if deals.contains(&idx) {
-                continue;
+        let now = match server.match_location(req, deals) {
+            LocationMatch::Found(idx) => idx,
+            LocationMatch::MethodNotAllowed(allow) => {
+                return Ok(Response::text()
+                    .status(405)
+                    .header(HeaderName::ALLOW, allow.join(", "))
+                    .body("method not allowed")
+                    .unwrap()
+                    .into_type());
             }
-            if server.location[idx].is_match_rule(&path, req) {
-                l = Some(&server.location[idx]);
-                now = idx;
-                break;
+            LocationMatch::NotFound => {
+                return Ok(Response::status404()
+                    .body("unknow location to deal")
+                    .unwrap()
+                    .into_type());
+            }
+        };
+        let l = &server.location[now];
+        match l.rule.path_captures(&path) {
+            Some(caps) => {
+                req.extensions_mut().insert(PathCaptures(caps));
+            }
+            None => {
+                req.extensions_mut().remove::<PathCaptures>();
             }
         }
-        if l.is_none() {
-            return Ok(Response::status404()
-                .body("unknow location to deal")
-                .unwrap()
-                .into_type());
+        if l.comm.deny_ip.is_some() || l.comm.allow_ip.is_some() || !l.comm.access.is_empty() {
+            let ip = match req.extensions().get::<SocketAddr>() {
+                Some(addr) => Some(addr.ip()),
+                None => req
+                    .headers()
+                    .system_get("{client_ip}")
+                    .and_then(|ip| ip.parse::<IpAddr>().ok()),
+            };
+            if let Some(ip) = ip {
+                if let Err(status) = l.comm.check_ip(&ip) {
+                    log::info!("IP:{}访问{}被拒绝", ip, req.path());
+                    return Ok(Response::text()
+                        .status(status)
+                        .body("access denied")
+                        .unwrap()
+                        .into_type());
+                }
+            }
+        }
+        match &l.cors {
+            Some(cors) => {
+                if CorsConfig::is_preflight(req) {
+                    return Ok(cors.preflight(req));
+                }
+                req.extensions_mut().insert(cors.clone());
+            }
+            None => {
+                req.extensions_mut().remove::<CorsConfig>();
+            }
+        }
+        if let Some(metrics) = &l.metrics {
+            req.extensions_mut().insert(metrics.clone());
+        }
+        if let Some(compression) = l.comm.get_compression() {
+            req.extensions_mut().insert(compression);
+        }
+        if let Some(policy) = &l.comm.header_policy {
+            let version = req.version();
+            if let Err(e) = policy.apply(req.headers_mut(), version) {
+                log::warn!("请求头不符合规范, 拒绝该请求: {}", e);
+                return Ok(Response::text()
+                    .status(400)
+                    .body("invalid header")
+                    .unwrap()
+                    .into_type());
+            }
         }
-
-        let l = l.unwrap();
         if let Some(limit_req) = &l.comm.limit_req {
             if let Some(res) = LimitReqMiddleware::new(limit_req.clone())
                 .process_request(req)
@@ -303,26 +1296,6 @@ impl HttpConfig {
                 return Ok(res);
             }
         }
-        if l.comm.deny_ip.is_some() || l.comm.allow_ip.is_some() {
-            if let Some(ip) = req.headers().system_get("{client_ip}") {
-                let ip = ip
-                    .parse::<IpAddr>()
-                    .map_err(|_| ProtError::Extension("client ip error"))?;
-                if let Some(allow) = &l.comm.allow_ip {
-                    if !allow.contains(&ip) {
-                        return Ok(Response::status503()
-                            .body("now allow ip")
-                            .unwrap()
-                            .into_type());
-                    }
-                }
-                if let Some(deny) = &l.comm.deny_ip {
-                    if deny.contains(&ip) {
-                        return Ok(Response::status503().body("deny ip").unwrap().into_type());
-                    }
-                }
-            }
-        }
 
         // 判定该try是否处理过, 防止死循环
         if !try_deals.contains(&now) && l.try_paths.is_some() {
@@ -337,7 +1310,7 @@ impl HttpConfig {
                 // 重写path好方便后续处理无感
                 req.set_path(new_path);
                 if let Ok(res) =
-                    Self::deal_match_location(req, cache, server.clone(), deals, try_deals).await
+                    Self::deal_match_location(req, server.clone(), deals, try_deals).await
                 {
                     if !res.status().is_client_error() && !res.status().is_server_error() {
                         return Ok(res);
@@ -351,105 +1324,343 @@ impl HttpConfig {
                 .into_type());
         } else {
             deals.insert(now);
-            let clone = l.clone_only_hash();
-            if cache.contains_key(&clone) {
-                let mut cache_client = cache.remove(&clone).unwrap();
-                if !cache_client.0.is_closed() {
-                    let _send = cache_client.0.send(req.replace_clone(Body::empty())).await;
-                    match cache_client.1.recv().await {
-                        Some(res) => {
-                            if let Ok(r) = &res {
-                                log::trace!("复用连接收到Response {}", r.status());
-                                cache.insert(clone, cache_client);
-                            }
-                            return res;
-                        }
-                        None => {
-                            log::trace!("复用连接收到空消息,关闭复用连接");
-                            return Ok(Response::status503()
-                                .body("意外的服务端关闭连接")
-                                .unwrap()
-                                .into_type());
-                        }
-                    }
+            // 透传的升级请求通过检查后再连接后端, 之后由连接处理方接管双向拷贝
+            if let Some(slot) = req.extensions().get::<UpgradeSlot>().cloned() {
+                if l.is_upgrade_passthrough() {
+                    return slot.upgrade(l, req).await;
                 }
-            } else {
-                let (res, sender, receiver) = l.deal_request(req).await?;
-                if sender.is_some() && receiver.is_some() {
-                    cache.insert(clone, (sender.unwrap(), receiver.unwrap()));
+            }
+            let mut clone = l.clone_only_hash();
+            // 仅复用所选upstream的连接
+            if let Some(name) = l.choose_split(req) {
+                let chosen = SplitUpstream { name, percent: 100 };
+                clone.split = Some(SplitConfig::new(vec![chosen]));
+            }
+            let sticky = l.get_sticky_addr(req);
+            let reuse = server.pool.checkout(
+                &clone,
+                server.keepalive_timeout.0,
+                server.keepalive_lifetime.0,
+                sticky.as_ref(),
+                l.get_proxy_protocol_peer(req).as_ref(),
+            );
+            // 复用的连接与新建的连接经过相同的处理, 如重试, 请求体大小限制及幂等
+            let reused = reuse.map(ReusedClient::new);
+            if let Some(reused) = &reused {
+                req.extensions_mut().insert(reused.clone());
+            }
+            let ret = l.deal_request(req).await;
+            req.extensions_mut().remove::<ReusedClient>();
+            let (mut res, sender, receiver) = ret?;
+            match reused.and_then(ReusedClient::into_client) {
+                Some((mut cache_client, true)) => {
+                    cache_client.last = Instant::now();
+                    cache_client.keep_alive = CacheClient::is_keep_alive(&res);
+                    Self::checkin_client(&server, clone.clone_only_hash(), cache_client, &mut res);
                 }
-                return Ok(res);
+                // 未使用的连接, 如命中缓存, 直接放回连接池
+                Some((cache_client, false)) => {
+                    server.pool.checkin(clone.clone_only_hash(), cache_client, server.keepalive);
+                }
+                None => {}
             }
+            // 动态计算的后端每次请求可能不同, 不复用连接
+            if let (Some(sender), Some(receiver), None) = (sender, receiver, &l.proxy_pass) {
+                let addr = res.extensions().get::<UpstreamTiming>().and_then(|t| t.addr);
+                let is_h2 = res.version() == Version::Http2;
+                let mut cache_client = CacheClient::new(sender, receiver, addr, is_h2);
+                cache_client.flow = res.extensions_mut().remove::<Arc<FlowSlot>>();
+                cache_client.ping = res.extensions_mut().remove::<Arc<H2Ping>>();
+                cache_client.peer = l.get_proxy_protocol_peer(req);
+                cache_client.keep_alive = CacheClient::is_keep_alive(&res);
+                Self::checkin_client(&server, clone, cache_client, &mut res);
+            }
+            return Ok(res);
         }
+    }
 
-        return Ok(Response::status404()
-            .body("unknow location to deal")
-            .unwrap()
-            .into_type());
+    /// 收到返回后将连接放回连接池, HTTP/1.1的返回体仍在传输时待返回发送完毕后再放回
+    fn checkin_client(
+        server: &ServerConfig,
+        key: LocationConfig,
+        client: CacheClient,
+        res: &mut Response<Body>,
+    ) {
+        if client.is_h2 || res.body().is_end() {
+            server.pool.checkin(key, client, server.keepalive);
+            return;
+        }
+        let back = PoolReturn::new(server.pool.clone(), key, client, server.keepalive);
+        res.extensions_mut().insert(back);
+    }
+
+    /// 错误的返回替换为配置的错误页面并保留原状态码, 后端的返回仅在`intercept_errors`时替换
+    /// 错误页面不存在或处理失败时按原返回处理
+    async fn deal_error_page(
+        req: &mut Request<Body>,
+        server: Arc<ServerConfig>,
+        res: Response<Body>,
+    ) -> ProtResult<Response<Body>> {
+        if res.extensions().get::<UpstreamTiming>().is_some() && !server.intercept_errors {
+            return Ok(res);
+        }
+        let status = res.status().as_u16();
+        let page = match ErrorPage::find(&server.error_page, status) {
+            Some(page) => page,
+            None => return Ok(res),
+        };
+        if !page.is_redirect() {
+            return Ok(page.load_file(status, req.version()).await.unwrap_or(res));
+        }
+        // 内部跳转后重新匹配location, 跳转的页面本身出错时不再跳转
+        log::trace!("返回{}时内部跳转至错误页面{}", status, page.page);
+        req.set_path(page.page.clone());
+        match Self::deal_match_location(
+            req,
+            server.clone(),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await
+        {
+            Ok(mut page_res) if page_res.status().is_success() => {
+                *page_res.status_mut() = res.status();
+                Ok(page_res)
+            }
+            _ => Ok(res),
+        }
     }
 
     async fn inner_operate_by_http(
         req: &mut Request<Body>,
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
         servers: Vec<Arc<ServerConfig>>,
     ) -> ProtResult<Response<Body>> {
-        let server_len = servers.len();
-        let host = req.get_host().unwrap_or(String::new());
-        // 不管有没有匹配, 都执行最后一个
-        for (index, s) in servers.iter().enumerate() {
-            if s.up_name == host || host.is_empty() || index == server_len - 1 {
-                return Self::deal_match_location(
-                    req,
-                    cache,
-                    s.clone(),
-                    &mut HashSet::new(),
-                    &mut HashSet::new(),
-                )
-                .await;
+        if req.extensions().get::<RequestId>().is_none() {
+            req.extensions_mut().insert(RequestId::generate());
+        }
+        if let Some(key) = Acme::challenge(req.path()) {
+            return Ok(Response::text().status(200).body(key).unwrap().into_type());
+        }
+        let host = req.get_host().unwrap_or_default();
+        // 未匹配的Host不交由任意的server处理, 避免请求被转发至无关的后端
+        let s = match ReverseHelper::select_server(&servers, &host) {
+            Some(s) => s,
+            None => {
+                log::trace!("反向代理：请求{} host:{} 未匹配任何server", req.path(), host);
+                return Ok(Response::text()
+                    .status(421)
+                    .body("misdirected request")
+                    .unwrap()
+                    .into_type());
             }
+        };
+        log::trace!(
+            "反向代理：请求{} host:{} 由server:{}处理",
+            req.path(),
+            host,
+            s.up_name
+        );
+        // ACME的校验路径不跳转, 无需配置location及upstream
+        if s.redirect_https
+            && req.extensions().get::<TlsConnection>().is_none()
+            && !req.path().starts_with(ACME_CHALLENGE_PREFIX)
+        {
+            return ReverseHelper::redirect_https(req, &s.up_name);
         }
-        return Ok(Response::status503()
-            .body("unknow location")
-            .unwrap()
-            .into_type());
+        let _conn = match (s.limit_conn, req.extensions().get::<SocketAddr>()) {
+            (Some(max), Some(addr)) => match LimitConn::try_acquire(&s.conns, addr.ip(), max) {
+                Some(guard) => Some(guard),
+                None => {
+                    return LimitReqMiddleware::too_many_requests(Duration::from_secs(1))
+                }
+            },
+            _ => None,
+        };
+        let _concurrency = match (s.limit_concurrency, req.extensions().get::<SocketAddr>()) {
+            (Some(fair), Some(addr)) => {
+                match LimitConn::try_acquire_fair(&s.concurrency, addr.ip(), fair) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        return LimitReqMiddleware::too_many_requests(Duration::from_secs(1))
+                    }
+                }
+            }
+            _ => None,
+        };
+        // 处理出错时同样使用配置的错误页面
+        let res = match Self::deal_match_location(
+            req,
+            s.clone(),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => Self::error_response(&e)?,
+        };
+        // 按最终匹配的location添加跨域头, 错误页面同样需要
+        let cors = req.extensions_mut().remove::<CorsConfig>();
+        let mut res = Self::deal_error_page(req, s.clone(), res).await?;
+        if let Some(cors) = cors {
+            cors.apply_response(req, &mut res);
+        }
+        Ok(res)
     }
 
-    async fn inner_operate(
+    pub(crate) async fn inner_operate(
         req: &mut Request<Body>,
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
         let servers = data.servers.clone();
-        return Self::inner_operate_by_http(req, &mut data.cache_sender, servers).await;
+        req.extensions_mut().insert(data.addr);
+        if let Some(trusted) = servers.first().and_then(|s| s.trusted_proxies.as_ref()) {
+            Self::apply_trusted_proxies(req, trusted, data.addr);
+        }
+        if data.is_tls {
+            req.extensions_mut().insert(TlsConnection);
+        }
+        if let Some(addr) = data.listen_addr {
+            req.extensions_mut().insert(ListenInfo {
+                addr,
+                is_ssl: data.is_tls,
+            });
+        }
+        ClientCert::apply_request(req, data.client_cert.as_ref());
+        return Self::inner_operate_by_http(req, servers).await;
+    }
+
+    /// 直连的地址为可信的代理时, 以X-Forwarded-For中解析出的地址作为客户端地址, 端口未知记为0
+    fn apply_trusted_proxies(req: &mut Request<Body>, trusted: &IpSets, peer: SocketAddr) {
+        if !trusted.contains(&peer.ip()) {
+            return;
+        }
+        let forwarded = req.headers().get_str_value(&"x-forwarded-for");
+        let ip = match ReverseHelper::resolve_client_ip(trusted, forwarded.as_deref()) {
+            Some(ip) => ip,
+            None => return,
+        };
+        req.extensions_mut().insert(SocketAddr::new(ip, 0));
+        req.extensions_mut().insert(ProxyPeer(peer));
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), ip.to_string());
     }
 
     async fn operate(
         req: &mut Request<Body>,
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
+        data.req_num.fetch_add(1, Ordering::Relaxed);
+        let bytes = BodyBytes::start(req);
+        let mut guard = RequestGuard { done: false };
         // body的内容可能重新解密又再重新再加过密, 后续可考虑直接做数据
-        match Self::inner_operate(req, data).await {
+        let res = match Self::inner_operate(req, data).await {
             Ok(mut value) => {
-                value.headers_mut().insert("server", "wmproxy");
-                Ok(value)
-            }
-            Err(e) => {
-                log::trace!("处理HTTP服务发生错误: {:?}", e);
-                let (is_timeout, is_client) = e.is_read_timeout();
-                if is_timeout && !is_client {
-                    Ok(Response::text()
-                        .status(408)
-                        .body("operate timeout")?
-                        .into_type())
-                } else {
-                    Ok(Response::status500()
-                        .body("server inner error")?
-                        .into_type())
+                if value.extensions().get::<ServerHidden>().is_none() {
+                    value.headers_mut().insert("server", "wmproxy");
+                }
+                if Shutdown::state() >= ShutdownState::Draining {
+                    value.headers_mut().insert(HeaderName::CONNECTION, "close");
+                }
+                if let Some(compression) = req.extensions().get::<Compression>().cloned() {
+                    compression.process_response(req, &mut value).await?;
                 }
+                value
             }
+            Err(e) => Self::error_response(&e)?,
+        };
+        guard.done = true;
+        AccessStat::on_request(res.status().as_u16());
+        // 未经过location处理或者处理失败的请求, 按匹配的location记录生成的返回
+        let target = match req.extensions().get::<AccessTarget>() {
+            Some(target) => target.clone(),
+            None => {
+                let target = match ReverseHelper::get_location_by_req(&data.servers, req) {
+                    Some(l) => l.access_target(),
+                    None => {
+                        let host = req.get_host().unwrap_or_default();
+                        let server = ReverseHelper::select_server(&data.servers, &host)
+                            .unwrap_or(&data.servers[0]);
+                        AccessTarget::new(&server.comm.log_format, &server.comm.access_log)
+                            .with_route(Some(server.up_name.clone()), None)
+                    }
+                };
+                req.extensions_mut().insert(target.clone());
+                target
+            }
+        };
+        let mut res = res;
+        let host = req.get_host().unwrap_or_default();
+        if let Some(server) = ReverseHelper::select_server(&data.servers, &host) {
+            server.apply_add_headers(&mut res, data.is_tls);
+        }
+        if res.status().as_u16() == 101 || bytes.count_complete(&mut res).await? {
+            Self::record_body_timing(&mut res);
+            Metrics::record(req, &res);
+            Helper::log_access_target(&target, req, &res);
+        } else {
+            // 流式的返回在发送完毕后再记录, 以便统计实际发送的字节数
+            let (log_req, mut log_res) = Self::detach_for_log(req, &mut res);
+            bytes.count_stream(&mut res, move || {
+                Self::record_body_timing(&mut log_res);
+                Metrics::record(&log_req, &log_res);
+                Helper::log_access_target(&target, &log_req, &log_res);
+            });
+        }
+        Ok(res)
+    }
+
+    /// 返回体转发完毕, 记录后端返回体的传输耗时
+    fn record_body_timing(res: &mut Response<Body>) {
+        if let Some(timing) = res.extensions_mut().get_mut::<UpstreamTiming>() {
+            timing.record_body();
+        }
+    }
+
+    /// 复制用于记录的请求及返回头, 请求已处理完毕, 其extensions转移至复制的请求中
+    fn detach_for_log(
+        req: &mut Request<Body>,
+        res: &mut Response<Body>,
+    ) -> (Request<Body>, Response<Body>) {
+        let mut log_req = req.replace_clone(Body::empty());
+        std::mem::swap(req.body_mut(), log_req.body_mut());
+        *log_req.extensions_mut() = std::mem::take(req.extensions_mut());
+        let mut log_res = res.replace_clone(Body::empty());
+        std::mem::swap(res.body_mut(), log_res.body_mut());
+        if let Some(timing) = res.extensions().get::<UpstreamTiming>().cloned() {
+            log_res.extensions_mut().insert(timing);
         }
+        (log_req, log_res)
+    }
+
+    /// 处理请求出错时生成对应状态码的返回
+    fn error_response(e: &ProtError) -> ProtResult<Response<Body>> {
+        log::trace!("处理HTTP服务发生错误: {:?}", e);
+        let (is_timeout, is_client) = e.is_read_timeout();
+        if is_timeout && !is_client {
+            Ok(Response::text()
+                .status(408)
+                .body("operate timeout")?
+                .into_type())
+        } else if ReverseHelper::is_upstream_timeout(e) {
+            Ok(Self::gateway_timeout())
+        } else if ReverseHelper::is_upstream_unavailable(e) {
+            Ok(Response::status502()
+                .body("bad gateway")?
+                .into_type())
+        } else {
+            Ok(Response::status500()
+                .body("server inner error")?
+                .into_type())
+        }
+    }
+
+    fn gateway_timeout() -> Response<Body> {
+        Response::text()
+            .status(504)
+            .body("upstream timeout")
+            .unwrap()
+            .into_type()
     }
 
     pub fn convert_server_config(&self) -> Vec<Arc<ServerConfig>> {
@@ -465,40 +1676,346 @@ impl HttpConfig {
         inbound: T,
         addr: SocketAddr,
     ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        Self::process_by(servers, inbound, addr, None).await
+    }
+
+    /// 处理明文的连接, `listen_addr`为接入的监听地址
+    pub async fn process_by<T>(
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+        listen_addr: Option<SocketAddr>,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        if servers.is_empty() {
+            return Err(crate::ProxyError::Extension("unknown server"));
+        }
+        // 关闭流程中不再接收新连接
+        if Shutdown::state() >= ShutdownState::Draining {
+            return Ok(());
+        }
+        if servers[0].proxy_protocol {
+            tokio::spawn(async move {
+                let mut inbound = inbound;
+                if let Some(addr) = Self::read_proxy_protocol(&servers, &mut inbound, addr).await {
+                    let _ = Self::accept_conn(servers, inbound, addr, listen_addr).await;
+                }
+            });
+            return Ok(());
+        }
+        Self::accept_conn(servers, inbound, addr, listen_addr).await
+    }
+
+    /// 计入连接数后处理明文的连接, 超出限制时返回503
+    async fn accept_conn<T>(
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+        listen_addr: Option<SocketAddr>,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        let conn = match ServerConnGuard::acquire(&servers, addr) {
+            Some(conn) => conn,
+            None => {
+                tokio::spawn(Self::reject_conn(inbound));
+                return Ok(());
+            }
+        };
+        Self::process_conn(servers, inbound, addr, listen_addr, false, None, conn).await
+    }
+
+    /// 读取PROXY协议头, 返回其中携带的客户端地址, 未携带地址时仍为连接的地址,
+    /// 协议头错误或超时返回None, 由调用方关闭连接
+    async fn read_proxy_protocol<T>(
+        servers: &[Arc<ServerConfig>],
+        inbound: &mut T,
+        addr: SocketAddr,
+    ) -> Option<SocketAddr>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let timeout = servers[0]
+            .comm
+            .client_header_timeout
+            .as_ref()
+            .map(|t| t.0)
+            .unwrap_or(ProxyProtocol::DEFAULT_TIMEOUT);
+        match tokio::time::timeout(timeout, ProxyProtocol::read_header(inbound)).await {
+            Ok(Ok(real)) => {
+                log::trace!("反向代理：{}经PROXY协议转发的客户端地址为{:?}", addr, real);
+                Some(real.unwrap_or(addr))
+            }
+            Ok(Err(e)) => {
+                log::warn!("反向代理：{}的PROXY协议头错误, 关闭连接：{:?}", addr, e);
+                None
+            }
+            Err(_) => {
+                log::warn!("反向代理：{}未在{:?}内发送PROXY协议头, 关闭连接", addr, timeout);
+                None
+            }
+        }
+    }
+
+    /// 连接数超出限制时返回503并关闭连接
+    async fn reject_conn<T>(mut inbound: T)
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let res = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 19\r\nConnection: close\r\n\r\nservice unavailable";
+        let _ = inbound.write_all(res.as_bytes()).await;
+        let _ = inbound.shutdown().await;
+    }
+
+    /// 处理客户端连接, `is_tls`表示是否经由TLS接入, 连接结束时归还连接数
+    async fn process_conn<T>(
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+        listen_addr: Option<SocketAddr>,
+        is_tls: bool,
+        client_cert: Option<ClientCert>,
+        conn: ServerConnGuard,
+    ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
     {
         if servers.is_empty() {
             return Err(crate::ProxyError::Extension("unknown server"));
         }
-        let oper = InnerHttpOper::new(servers.clone());
+        AccessStat::on_accept();
+        let header_deadline = servers[0]
+            .comm
+            .client_header_timeout
+            .as_ref()
+            .map(|t| Arc::new(ReadDeadline::new(t.0)));
+        let flow = Arc::new(FlowWindow::default());
+        let inbound = FlowStream::client(inbound, flow.clone());
+        let inbound = CountStream::new(DeadlineStream::new(inbound, header_deadline.clone()));
+        let record = inbound.record();
+        let mut oper = InnerHttpOper::new(servers, addr, is_tls);
+        oper.listen_addr = listen_addr;
+        oper.client_cert = client_cert;
+        oper.header_deadline = header_deadline.clone();
+        oper.flow = Some(flow);
+        let req_num = oper.req_num.clone();
         tokio::spawn(async move {
+            let _conn = conn;
             let timeout = oper.servers[0].comm.build_client_timeout();
+            let access_log = oper.servers[0].comm.access_log.clone();
             let mut server = Server::builder()
                 .addr(addr)
                 .timeout_layer(timeout)
                 .stream(inbound);
+            // 设置websocket回调,客户端有可能升级到websocket协议, 与HTTP共用连接信息
+            let ws = ServerWsOperate::new(oper.clone());
+            let tunnel = ws.tunnel();
             // 设置HTTP回调
             server.set_callback_http(Box::new(Operate { inner: oper }));
-            // 设置websocket回调,客户端有可能升级到websocket协议
-            server.set_callback_ws(Box::new(ServerWsOperate::new(servers)));
-            if let Err(e) = server.incoming().await {
+            server.set_callback_ws(Box::new(ws));
+            let ret = tokio::select! {
+                ret = server.incoming() => ret,
+                // 排空超时后关闭剩余的连接
+                _ = Shutdown::wait_closed() => return,
+            };
+            // 升级请求已透传到后端, 接管客户端连接后双向拷贝数据
+            let tunnel = tunnel.lock().unwrap().take();
+            if let Some(tunnel) = tunnel {
+                let mut inbound = server.into_io();
+                if let Err(e) = tunnel.splice(&mut inbound).await {
+                    log::info!("反向代理：升级连接透传时发生错误：{:?}", e);
+                }
+                AccessStat::on_served();
+                return;
+            }
+            if let Err(e) = &ret {
                 if server.get_req_num() == 0 {
                     log::info!("反向代理：未处理任何请求时发生错误：{:?}", e);
-                } else {
-                    if !e.is_io() {
-                        log::info!("反向代理：处理信息时发生错误：{:?}", e);
-                    }
+                } else if !e.is_io() {
+                    log::info!("反向代理：处理信息时发生错误：{:?}", e);
+                }
+            }
+            // 未在限制时间内接收完请求头, 返回408并关闭连接
+            let header_timeout = header_deadline.is_some_and(|d| d.is_expired());
+            if header_timeout {
+                let mut inbound = server.into_io();
+                let res = "HTTP/1.1 408 Request Timeout\r\nContent-Length: 15\r\nConnection: close\r\n\r\nrequest timeout";
+                let _ = inbound.write_all(res.as_bytes()).await;
+                let _ = inbound.shutdown().await;
+            }
+            if req_num.load(Ordering::Relaxed) > 0 {
+                AccessStat::on_served();
+                return;
+            }
+            // 未产生任何请求的连接, 记录关闭原因
+            let reason = if header_timeout {
+                ConnCloseReason::Timeout
+            } else {
+                Self::close_reason(&ret, &record)
+            };
+            AccessStat::on_close(reason);
+            Helper::log_conn_close(&access_log, addr, reason, record.bytes());
+        });
+        Ok(())
+    }
+
+    fn close_reason(ret: &ProtResult<()>, record: &ReadRecord) -> ConnCloseReason {
+        let bytes = record.bytes();
+        match ret {
+            Ok(()) if bytes == 0 => ConnCloseReason::Empty,
+            // 数据与关闭信号同时到达时服务端未解析即结束, 此处重新解析已收到的数据
+            Ok(()) => {
+                let mut req = Request::new();
+                match req.parse_buffer(&mut BinaryMut::from(record.head())) {
+                    Err(e) if !e.is_partial() => ConnCloseReason::Parse,
+                    _ => ConnCloseReason::Incomplete,
+                }
+            }
+            Err(e) if e.is_read_timeout().0 || matches!(e, ProtError::Timeout(_)) => {
+                ConnCloseReason::Timeout
+            }
+            Err(e) if e.is_io() && bytes == 0 => ConnCloseReason::Empty,
+            Err(e) if e.is_io() => ConnCloseReason::Incomplete,
+            Err(_) => ConnCloseReason::Parse,
+        }
+    }
+
+    /// 完成TLS握手后处理, 优先选择与SNI匹配的Server
+    pub async fn process_tls<T>(
+        accept: TlsAcceptor,
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        Self::process_tls_by(accept, servers, inbound, addr, None).await
+    }
+
+    /// 处理TLS的连接, `listen_addr`为接入的监听地址
+    pub async fn process_tls_by<T>(
+        accept: TlsAcceptor,
+        servers: Vec<Arc<ServerConfig>>,
+        inbound: T,
+        addr: SocketAddr,
+        listen_addr: Option<SocketAddr>,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        if servers.is_empty() {
+            return Err(crate::ProxyError::Extension("unknown server"));
+        }
+        if Shutdown::state() >= ShutdownState::Draining {
+            return Ok(());
+        }
+        tokio::spawn(async move {
+            let mut inbound = inbound;
+            // 须在TLS握手前读取PROXY协议头
+            let addr = if servers[0].proxy_protocol {
+                match Self::read_proxy_protocol(&servers, &mut inbound, addr).await {
+                    Some(addr) => addr,
+                    None => return,
                 }
+            } else {
+                addr
+            };
+            // 握手前即计入连接数, 超出时直接关闭
+            let conn = match ServerConnGuard::acquire(&servers, addr) {
+                Some(conn) => conn,
+                None => return,
+            };
+            let inbound = CountStream::new(inbound);
+            let record = inbound.record();
+            // 有server使用不同的TLS配置时, 需在握手前根据SNI选择对应的配置
+            let handshake = if servers.iter().any(|s| s.is_custom_tls()) {
+                Self::accept_by_sni(&servers, inbound)
+                    .await
+                    .map(|(stream, server)| (stream, Some(server)))
+            } else {
+                accept.accept(inbound).await.map(|stream| (stream, None))
+            };
+            let (stream, handshake_server) = match handshake {
+                Ok(ret) => ret,
+                Err(e) => {
+                    log::trace!("反向代理：TLS握手失败：{:?}", e);
+                    let reason = ConnCloseReason::Tls;
+                    AccessStat::on_accept();
+                    AccessStat::on_close(reason);
+                    let access_log = &servers[0].comm.access_log;
+                    Helper::log_conn_close(access_log, addr, reason, record.bytes());
+                    return;
+                }
+            };
+            let up_name = stream.get_ref().1.server_name().map(|s| s.to_string());
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(ClientCert::parse);
+            if let Some(s) = up_name.and_then(|n| ReverseHelper::find_server_name(&servers, &n)) {
+                let servers = vec![s.clone()];
+                let _ = Self::process_conn(servers, stream, addr, listen_addr, true, client_cert, conn).await;
+                return;
             }
+            // 必须校验客户端证书的server, 仅可由使用其配置完成握手的连接访问
+            let verified = handshake_server.filter(|_| client_cert.is_some());
+            let servers = servers
+                .into_iter()
+                .filter(|s| {
+                    s.get_verify_client() != VerifyClient::On
+                        || verified.as_ref().is_some_and(|v| Arc::ptr_eq(v, s))
+                })
+                .collect();
+            let _ = Self::process_conn(servers, stream, addr, listen_addr, true, client_cert, conn).await;
         });
         Ok(())
     }
 
+    /// 读取ClientHello后选择SNI对应server的TLS配置完成握手, 未匹配时优先选择使用默认配置的server,
+    /// 其次为不校验客户端证书的server
+    async fn accept_by_sni<T>(
+        servers: &[Arc<ServerConfig>],
+        inbound: T,
+    ) -> io::Result<(tokio_rustls::server::TlsStream<T>, Arc<ServerConfig>)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), inbound).await?;
+        let up_name = start.client_hello().server_name().map(|s| s.to_string());
+        let server = up_name
+            .and_then(|n| ReverseHelper::find_server_name(servers, &n))
+            .or_else(|| servers.iter().find(|s| !s.is_custom_tls()))
+            .or_else(|| servers.iter().find(|s| s.get_verify_client() == VerifyClient::Off))
+            .unwrap_or(&servers[0])
+            .clone();
+        let config = match server.get_tls_config() {
+            Some(config) => config,
+            None => return Err(io::Error::other("未生成TLS配置")),
+        };
+        let stream = start.into_stream(config).await?;
+        Ok((stream, server))
+    }
+
     pub fn get_log_names(&self, names: &mut HashMap<String, String>) {
         self.comm.get_log_names(names);
         for s in &self.server {
             s.get_log_names(names);
         }
     }
+
+    pub fn get_access_names(&self, names: &mut HashSet<String>) {
+        self.comm.get_access_names(names);
+        for s in &self.server {
+            s.get_access_names(names);
+        }
+    }
 }
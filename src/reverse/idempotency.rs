@@ -0,0 +1,349 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/07 15:32:08
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use tokio::{
+    io::AsyncReadExt,
+    sync::{mpsc::channel, watch},
+};
+use webparse::{Binary, BinaryMut, HeaderMap, Request, Response};
+use wenmeng::Body;
+
+lazy_static! {
+    // 每个location独立的幂等返回缓存
+    static ref IDEMPOTENCY_STORES: Mutex<HashMap<String, IdempotencyStore>> =
+        Mutex::new(HashMap::new());
+}
+
+/// 客户端标识幂等请求的头
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// 缓存的后端返回
+#[derive(Debug, Clone)]
+pub struct IdempotencyResponse {
+    pub status: u16,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl IdempotencyResponse {
+    pub fn to_response(&self) -> Response<Body> {
+        let mut res = Response::builder()
+            .status(self.status)
+            .body(Body::only(Binary::from(self.body.clone())))
+            .unwrap();
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+enum IdempotencyState {
+    /// 正在访问后端, 完成时通知等待者
+    Pending(watch::Receiver<bool>),
+    Done(IdempotencyResponse),
+}
+
+struct IdempotencyEntry {
+    hash: u64,
+    state: IdempotencyState,
+    expire: Instant,
+    size: usize,
+}
+
+/// 查询幂等缓存的结果
+pub enum IdempotencyLookup {
+    /// 首次请求, 由该请求访问后端, 完成后通过sender通知等待者
+    Miss(watch::Sender<bool>),
+    /// 相同的请求正在处理中
+    Wait(watch::Receiver<bool>),
+    /// 已缓存的返回
+    Hit(IdempotencyResponse),
+    /// 相同的key但请求内容不同
+    Conflict,
+}
+
+/// 单个location的幂等缓存, 超出大小时优先淘汰最早过期的返回
+#[derive(Default)]
+pub struct IdempotencyStore {
+    entries: HashMap<String, IdempotencyEntry>,
+    size: usize,
+}
+
+impl IdempotencyStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn purge(&mut self, now: Instant) {
+        let size = &mut self.size;
+        self.entries.retain(|_, e| {
+            if e.expire > now {
+                true
+            } else {
+                *size -= e.size;
+                false
+            }
+        });
+    }
+
+    pub fn lookup(&mut self, key: &str, hash: u64, ttl: Duration) -> IdempotencyLookup {
+        let now = Instant::now();
+        self.purge(now);
+        if let Some(entry) = self.entries.get(key) {
+            match &entry.state {
+                // 原先处理的请求已被取消, 由本次请求重新处理
+                IdempotencyState::Pending(receiver) if receiver.has_changed().is_err() => {}
+                _ if entry.hash != hash => return IdempotencyLookup::Conflict,
+                IdempotencyState::Pending(receiver) => {
+                    return IdempotencyLookup::Wait(receiver.clone())
+                }
+                IdempotencyState::Done(res) => return IdempotencyLookup::Hit(res.clone()),
+            }
+        }
+        let (sender, receiver) = watch::channel(false);
+        let entry = IdempotencyEntry {
+            hash,
+            state: IdempotencyState::Pending(receiver),
+            expire: now + ttl,
+            size: 0,
+        };
+        if let Some(old) = self.entries.insert(key.to_string(), entry) {
+            self.size -= old.size;
+        }
+        IdempotencyLookup::Miss(sender)
+    }
+
+    /// 记录后端的返回, 为None时表示处理失败, 后续相同的请求将重新访问后端
+    pub fn finish(
+        &mut self,
+        key: &str,
+        res: Option<IdempotencyResponse>,
+        ttl: Duration,
+        max_size: usize,
+    ) {
+        let old = match self.entries.remove(key) {
+            Some(old) => old,
+            None => return,
+        };
+        self.size -= old.size;
+        let res = match res {
+            Some(res) => res,
+            None => return,
+        };
+        let size = key.len() + res.body.len();
+        if size > max_size {
+            return;
+        }
+        while self.size + size > max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, e)| e.size > 0)
+                .min_by_key(|(_, e)| e.expire)
+                .map(|(k, _)| k.clone());
+            match oldest.and_then(|k| self.entries.remove(&k)) {
+                Some(e) => self.size -= e.size,
+                None => break,
+            }
+        }
+        self.size += size;
+        self.entries.insert(
+            key.to_string(),
+            IdempotencyEntry {
+                hash: old.hash,
+                state: IdempotencyState::Done(res),
+                expire: Instant::now() + ttl,
+                size,
+            },
+        );
+    }
+}
+
+/// 幂等请求的相关处理
+pub struct Idempotency;
+
+impl Idempotency {
+    /// 获取请求中的幂等key
+    pub fn get_key(req: &Request<Body>) -> Option<String> {
+        req.headers()
+            .get_str_value(&IDEMPOTENCY_KEY)
+            .filter(|k| !k.is_empty())
+    }
+
+    /// 请求的指纹, 包含方法, 路径及body
+    pub fn hash_request(req: &Request<Body>, body: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        req.method().as_str().hash(&mut hasher);
+        req.path().hash(&mut hasher);
+        body.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn lookup(store: &str, key: &str, hash: u64, ttl: Duration) -> IdempotencyLookup {
+        let mut stores = IDEMPOTENCY_STORES.lock().unwrap();
+        stores
+            .entry(store.to_string())
+            .or_default()
+            .lookup(key, hash, ttl)
+    }
+
+    pub fn finish(
+        store: &str,
+        key: &str,
+        res: Option<IdempotencyResponse>,
+        ttl: Duration,
+        max_size: usize,
+    ) {
+        let mut stores = IDEMPOTENCY_STORES.lock().unwrap();
+        if let Some(s) = stores.get_mut(store) {
+            s.finish(key, res, ttl, max_size);
+        }
+    }
+
+    /// 读取body, 最多读取`limit`, 返回是否完整读取
+    pub async fn read_body(body: &mut Body, limit: usize) -> io::Result<(Vec<u8>, bool)> {
+        let mut data = vec![];
+        let mut buf = vec![0u8; 4096];
+        loop {
+            match body.read(&mut buf).await? {
+                0 if body.is_end() => return Ok((data, true)),
+                0 => continue,
+                n => {
+                    data.extend_from_slice(&buf[..n]);
+                    if data.len() > limit {
+                        return Ok((data, false));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 将已读取的数据与剩余未读取的body拼接
+    pub fn chain_body(data: Vec<u8>, mut body: Body) -> Body {
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            loop {
+                match body.read(&mut buf).await {
+                    Ok(0) if body.is_end() => break,
+                    Ok(0) => continue,
+                    Ok(n) => {
+                        let data = Binary::from(buf[..n].to_vec());
+                        if sender.send((false, data)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        log::trace!("读取返回内容失败:{:?}", e);
+                        break;
+                    }
+                }
+            }
+            let _ = sender.send((true, Binary::new())).await;
+        });
+        Body::new(receiver, BinaryMut::from(data), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use webparse::HeaderMap;
+
+    use super::{IdempotencyLookup, IdempotencyResponse, IdempotencyStore};
+
+    fn build_res(body: &str) -> Option<IdempotencyResponse> {
+        Some(IdempotencyResponse {
+            status: 200,
+            headers: HeaderMap::new(),
+            body: body.as_bytes().to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_store() {
+        let ttl = Duration::from_secs(60);
+        let mut store = IdempotencyStore::new();
+        let sender = match store.lookup("k1", 1, ttl) {
+            IdempotencyLookup::Miss(sender) => sender,
+            _ => unreachable!(),
+        };
+        assert!(matches!(
+            store.lookup("k1", 1, ttl),
+            IdempotencyLookup::Wait(_)
+        ));
+        assert!(matches!(
+            store.lookup("k1", 2, ttl),
+            IdempotencyLookup::Conflict
+        ));
+        store.finish("k1", build_res("ok"), ttl, 1024);
+        drop(sender);
+        match store.lookup("k1", 1, ttl) {
+            IdempotencyLookup::Hit(res) => assert_eq!(res.body, b"ok"),
+            _ => unreachable!(),
+        }
+        assert!(matches!(
+            store.lookup("k1", 2, ttl),
+            IdempotencyLookup::Conflict
+        ));
+
+        // 处理中的请求被取消, 后续请求重新处理
+        let sender = store.lookup("k2", 1, ttl);
+        drop(sender);
+        assert!(matches!(
+            store.lookup("k2", 1, ttl),
+            IdempotencyLookup::Miss(_)
+        ));
+        // 处理失败时不缓存
+        store.finish("k2", None, ttl, 1024);
+        assert!(matches!(
+            store.lookup("k2", 3, ttl),
+            IdempotencyLookup::Miss(_)
+        ));
+    }
+
+    #[test]
+    fn test_store_budget() {
+        let ttl = Duration::from_secs(60);
+        let mut store = IdempotencyStore::new();
+        for key in ["a", "b", "c"] {
+            let _ = store.lookup(key, 1, ttl);
+            store.finish(key, build_res("0123456789"), ttl, 25);
+        }
+        // 超出大小时淘汰最早的返回
+        assert!(matches!(
+            store.lookup("a", 1, ttl),
+            IdempotencyLookup::Miss(_)
+        ));
+        assert!(matches!(
+            store.lookup("c", 1, ttl),
+            IdempotencyLookup::Hit(_)
+        ));
+        assert!(store.size <= 25);
+
+        let _ = store.lookup("ttl", 1, Duration::ZERO);
+        store.finish("ttl", build_res("1"), Duration::ZERO, 25);
+        assert!(matches!(
+            store.lookup("ttl", 1, ttl),
+            IdempotencyLookup::Miss(_)
+        ));
+    }
+}
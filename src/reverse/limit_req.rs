@@ -10,9 +10,16 @@
 // -----
 // Created Date: 2023/11/24 03:29:55
 
-use std::{fmt::Display, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use webparse::Response;
+use webparse::{HeaderName, Response};
 use wenmeng::{Middleware};
 
 use async_trait::async_trait;
@@ -41,15 +48,48 @@ impl LimitReqZone {
     }
 }
 
+/// 请求的频率限制, 可引用`limit_req_zone`中的zone, 如`zone=limit burst=1`
+/// 也可直接配置速率, 如`rate=10r/s burst=20 limit=10k`, 该限制只作用于当前location
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LimitReq {
     zone: String,
     burst: u64,
+    /// 直接配置的速率
+    rate: Option<Rate>,
+    /// 直接配置时最多记录的IP个数
+    limit: u64,
 }
 
 impl LimitReq {
     pub fn new(zone: String, burst: u64) -> Self {
-        Self { zone, burst }
+        Self {
+            zone,
+            burst,
+            rate: None,
+            limit: 0,
+        }
+    }
+
+    pub fn with_rate(rate: Rate, burst: u64, limit: u64) -> Self {
+        Self {
+            zone: String::new(),
+            burst,
+            rate: Some(rate),
+            limit,
+        }
+    }
+
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    /// 直接配置速率的限制, 按server及location生成独立的zone
+    pub fn bind_location(&mut self, server: &str, location: &str) -> ProtResult<()> {
+        if let Some(rate) = &self.rate {
+            self.zone = format!("@{}{}", server, location);
+            LimitReqData::cache(self.zone.clone(), self.limit, rate.nums, rate.per)?;
+        }
+        Ok(())
     }
 }
 
@@ -61,6 +101,16 @@ impl LimitReqMiddleware {
     pub fn new(req: LimitReq) -> Self {
         Self { req }
     }
+
+    /// 超出限制时的返回, Retry-After向上取整到秒
+    pub fn too_many_requests(retry: Duration) -> ProtResult<RecvResponse> {
+        let secs = retry.as_secs() + u64::from(retry.subsec_nanos() > 0);
+        Ok(Response::text()
+            .status(429)
+            .header(HeaderName::RETRY_AFTER, format!("{}", secs.max(1)))
+            .body("too many requests")?
+            .into_type())
+    }
 }
 
 #[async_trait]
@@ -70,18 +120,10 @@ impl Middleware for LimitReqMiddleware {
         request: &mut RecvRequest,
     ) -> ProtResult<Option<RecvResponse>> {
         if let Some(client_ip) = request.headers().system_get("{client_ip}") {
-            // let a = LimitReqData::recv_new_req(&self.req.zone, client_ip, self.req.burst)?;
-            // println!("a === {:?}", a);
             match LimitReqData::recv_new_req(&self.req.zone, client_ip, self.req.burst)? {
                 LimitResult::Ok => return Ok(None),
-                LimitResult::Refuse => {
-                    return Ok(Some(
-                        Response::text().status(404).body("limit req")?.into_type(),
-                    ));
-                }
-                LimitResult::Delay(delay) => {
-                    tokio::time::sleep(delay).await;
-                    return Ok(None);
+                LimitResult::Refuse(retry) => {
+                    return Ok(Some(Self::too_many_requests(retry)?));
                 }
             }
         }
@@ -142,7 +184,21 @@ impl FromStr for LimitReqZone {
 
 impl Display for LimitReq {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("zone={} brust={}", self.zone, self.burst))
+        match &self.rate {
+            Some(rate) => {
+                f.write_fmt(format_args!(
+                    "rate={}r/{} burst={}",
+                    rate.nums,
+                    ConfigDuration::new(rate.per),
+                    self.burst
+                ))?;
+                if self.limit > 0 {
+                    f.write_fmt(format_args!(" limit={}", ConfigSize::new(self.limit)))?;
+                }
+                Ok(())
+            }
+            None => f.write_fmt(format_args!("zone={} brust={}", self.zone, self.burst)),
+        }
     }
 }
 
@@ -150,9 +206,11 @@ impl FromStr for LimitReq {
     type Err = ProxyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let v = s.split(" ").collect::<Vec<&str>>();
+        let v = s.split_whitespace().collect::<Vec<&str>>();
         let mut zone = String::new();
         let mut brust = 0;
+        let mut rate = None;
+        let mut limit = 0;
         for idx in 0..v.len() {
             let key_value = v[idx].split("=").map(|k| k.trim()).collect::<Vec<&str>>();
             if key_value.len() <= 1 {
@@ -162,17 +220,216 @@ impl FromStr for LimitReq {
                 "zone" => {
                     zone = key_value[1].to_string();
                 }
-                "brust" => {
+                "brust" | "burst" => {
                     brust = key_value[1]
                         .parse::<u64>()
                         .map_err(|_e| ProxyError::Extension("parse error"))?;
                 }
+                "rate" => {
+                    rate = Some(ConfigRate::from_str(key_value[1])?.0);
+                }
+                "limit" => {
+                    limit = ConfigSize::from_str(key_value[1])?.0;
+                }
                 _ => {
                     return Err(ProxyError::Extension("LimitReq的输入异常,无法正确解析"));
                 }
             }
         }
 
-        Ok(LimitReq::new(zone, brust))
+        match rate {
+            Some(rate) if zone.is_empty() => Ok(LimitReq::with_rate(rate, brust, limit)),
+            Some(_) => Err(ProxyError::Extension("LimitReq不能同时配置zone及rate")),
+            None => Ok(LimitReq::new(zone, brust)),
+        }
+    }
+}
+
+/// server同时处理的请求数限制, 在总数`max`内按客户端IP公平分配, 单个IP最多`per_ip`个,
+/// 如`max=64 per_ip=8`, 未配置per_ip时默认为max的1/4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitConcurrency {
+    pub max: usize,
+    pub per_ip: usize,
+}
+
+impl LimitConcurrency {
+    pub fn new(max: usize, per_ip: usize) -> Self {
+        Self { max, per_ip }
+    }
+}
+
+impl Display for LimitConcurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("max={} per_ip={}", self.max, self.per_ip))
+    }
+}
+
+impl FromStr for LimitConcurrency {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut max = None;
+        let mut per_ip = None;
+        for v in s.split_whitespace() {
+            let key_value = v.split("=").map(|k| k.trim()).collect::<Vec<&str>>();
+            if key_value.len() <= 1 {
+                return Err(ProxyError::Extension("LimitConcurrency的输入异常,无法正确解析"));
+            }
+            let value = key_value[1]
+                .parse::<usize>()
+                .map_err(|_e| ProxyError::Extension("parse error"))?;
+            match key_value[0] {
+                "max" => max = Some(value),
+                "per_ip" => per_ip = Some(value),
+                _ => {
+                    return Err(ProxyError::Extension("LimitConcurrency的输入异常,无法正确解析"));
+                }
+            }
+        }
+        match max {
+            Some(max) if max > 0 => Ok(LimitConcurrency::new(
+                max,
+                per_ip.unwrap_or(max / 4).clamp(1, max),
+            )),
+            _ => Err(ProxyError::Extension("LimitConcurrency必须配置大于0的max")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ConnCount {
+    total: usize,
+    ips: HashMap<IpAddr, usize>,
+}
+
+/// 同时处理的请求数统计, 用于server的`limit_conn`及`limit_concurrency`
+#[derive(Debug, Default)]
+pub struct LimitConn {
+    conns: Mutex<ConnCount>,
+}
+
+/// 请求处理完毕时归还数量
+pub struct LimitConnGuard {
+    limit: Arc<LimitConn>,
+    ip: IpAddr,
+}
+
+impl LimitConn {
+    /// 单个IP未超出限制时返回guard, 超出时返回None
+    pub fn try_acquire(limit: &Arc<LimitConn>, ip: IpAddr, max: usize) -> Option<LimitConnGuard> {
+        Self::try_acquire_fair(limit, ip, LimitConcurrency::new(usize::MAX, max))
+    }
+
+    /// 总数及单个IP均未超出限制时返回guard, 超出时返回None
+    pub fn try_acquire_fair(
+        limit: &Arc<LimitConn>,
+        ip: IpAddr,
+        fair: LimitConcurrency,
+    ) -> Option<LimitConnGuard> {
+        let mut conns = limit.conns.lock().unwrap();
+        if conns.total >= fair.max {
+            return None;
+        }
+        let now = conns.ips.entry(ip).or_insert(0);
+        if *now >= fair.per_ip {
+            if *now == 0 {
+                conns.ips.remove(&ip);
+            }
+            return None;
+        }
+        *now += 1;
+        conns.total += 1;
+        Some(LimitConnGuard {
+            limit: limit.clone(),
+            ip,
+        })
+    }
+
+    pub fn get_conns(&self, ip: &IpAddr) -> usize {
+        self.conns.lock().unwrap().ips.get(ip).cloned().unwrap_or(0)
+    }
+
+    pub fn get_total(&self) -> usize {
+        self.conns.lock().unwrap().total
+    }
+}
+
+impl Drop for LimitConnGuard {
+    fn drop(&mut self) {
+        let mut conns = self.limit.conns.lock().unwrap();
+        if let Some(now) = conns.ips.get_mut(&self.ip) {
+            *now = now.saturating_sub(1);
+            if *now == 0 {
+                conns.ips.remove(&self.ip);
+            }
+            conns.total = conns.total.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc, time::Duration};
+
+    use super::{LimitConcurrency, LimitConn, LimitReq, LimitReqMiddleware};
+
+    #[test]
+    fn test_limit_req_parse() {
+        let zone = LimitReq::from_str("zone=limit brust=1").unwrap();
+        assert_eq!(zone.zone(), "limit");
+        assert_eq!(zone.to_string(), "zone=limit brust=1");
+        let mut rate = LimitReq::from_str("rate=10r/s burst=20").unwrap();
+        assert_eq!(rate.to_string(), "rate=10r/1s burst=20");
+        rate.bind_location("local", "/api").unwrap();
+        assert_eq!(rate.zone(), "@local/api");
+        assert!(LimitReq::from_str("zone=limit rate=10r/s").is_err());
+        assert!(LimitReq::from_str("rate").is_err());
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        let res = LimitReqMiddleware::too_many_requests(Duration::from_millis(1500)).unwrap();
+        assert_eq!(res.status().as_u16(), 429);
+        assert_eq!(res.headers().get_str_value(&"Retry-After").unwrap(), "2");
+        let res = LimitReqMiddleware::too_many_requests(Duration::from_millis(10)).unwrap();
+        assert_eq!(res.headers().get_str_value(&"Retry-After").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_limit_conn() {
+        let limit = Arc::new(LimitConn::default());
+        let ip = "127.0.0.1".parse().unwrap();
+        let first = LimitConn::try_acquire(&limit, ip, 2).unwrap();
+        let _second = LimitConn::try_acquire(&limit, ip, 2).unwrap();
+        assert!(LimitConn::try_acquire(&limit, ip, 2).is_none());
+        assert!(LimitConn::try_acquire(&limit, "127.0.0.2".parse().unwrap(), 2).is_some());
+        drop(first);
+        assert_eq!(limit.get_conns(&ip), 1);
+        assert!(LimitConn::try_acquire(&limit, ip, 2).is_some());
+    }
+
+    #[test]
+    fn test_limit_concurrency() {
+        let fair = LimitConcurrency::from_str("max=3 per_ip=2").unwrap();
+        assert_eq!(fair.to_string(), "max=3 per_ip=2");
+        assert_eq!(LimitConcurrency::from_str("max=64").unwrap().per_ip, 16);
+        assert_eq!(LimitConcurrency::from_str("max=2").unwrap().per_ip, 1);
+        assert!(LimitConcurrency::from_str("per_ip=2").is_err());
+        assert!(LimitConcurrency::from_str("max=0").is_err());
+
+        let limit = Arc::new(LimitConn::default());
+        let flood = "127.0.0.1".parse().unwrap();
+        let other = "127.0.0.2".parse().unwrap();
+        let first = LimitConn::try_acquire_fair(&limit, flood, fair).unwrap();
+        let _second = LimitConn::try_acquire_fair(&limit, flood, fair).unwrap();
+        // 单个IP占满自己的份额后, 其它IP仍可使用剩余的总数
+        assert!(LimitConn::try_acquire_fair(&limit, flood, fair).is_none());
+        let _third = LimitConn::try_acquire_fair(&limit, other, fair).unwrap();
+        assert!(LimitConn::try_acquire_fair(&limit, other, fair).is_none());
+        assert_eq!(limit.get_total(), 3);
+        drop(first);
+        assert_eq!(limit.get_total(), 2);
+        assert!(LimitConn::try_acquire_fair(&limit, other, fair).is_some());
     }
 }
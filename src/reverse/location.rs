@@ -10,19 +10,161 @@
 // -----
 // Created Date: 2023/10/18 02:31:52
 
-use std::{collections::HashMap, hash::Hash, net::SocketAddr};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use tokio::sync::mpsc::{Receiver, Sender};
-use webparse::{HeaderName, Request, Response, Scheme, Url};
-use wenmeng::{Body, Client, ProtError, ProtResult, RecvRequest};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{Receiver, Sender},
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+use webparse::{Binary, BinaryMut, HeaderName, Request, Response, Scheme, Url, Version};
+use wenmeng::{Body, Client, ClientOption, MaybeHttpsStream, ProtError, ProtResult, RecvRequest, TimeoutLayer};
 
-use crate::{ConfigHeader, FileServer, HealthCheck, Helper, StaticResponse};
+use crate::{
+    data::{AccessTarget, UpstreamTiming}, ConfigDuration, ConfigHeader, ConfigResponseId, ConfigSize, DisplayFromStrOrNumber, DisplayFromStrOrSeq, HeaderOper, FileServer, FlowSlot, FlowStream, FlowWindow, H2Ping, H2PingStream, HealthCheck,
+    Helper, LocationMetrics, ReturnResponse, StaticResponse, SubFilter, SubFilterRule,
+};
 
-use super::{common::CommonConfig, ReverseHelper, TryPathsConfig, UpstreamConfig, Matcher, string_or_struct};
+use super::{common::CommonConfig, ReverseHelper, RewriteConfig, SingleStreamConfig, TryPathsConfig, UpstreamConfig, UpstreamConnGuard, Matcher, string_or_struct};
+use super::{pool::{CacheClient, ReusedClient}, ws::UpgradeTunnel, BodyLimit, CacheConfig, CorsConfig, Idempotency, SplitChosen, SplitConfig, IdempotencyLookup, IdempotencyResponse, ProxyPass, ProxyProtocol, CACHE_STATUS_HEADER};
+
+/// `strip_prefix`可为前缀或布尔值, 为`true`时记为空, 表示去掉rule的前缀
+fn bool_or_prefix<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrPrefix {
+        Bool(bool),
+        Prefix(String),
+    }
+    Ok(match Option::<BoolOrPrefix>::deserialize(deserializer)? {
+        Some(BoolOrPrefix::Bool(true)) => Some(String::new()),
+        Some(BoolOrPrefix::Prefix(prefix)) => Some(prefix),
+        _ => None,
+    })
+}
+
+fn default_idempotency_ttl() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(3600))
+}
+
+fn default_idempotency_body() -> ConfigSize {
+    ConfigSize::new(1024 * 1024)
+}
+
+fn default_idempotency_cache() -> ConfigSize {
+    ConfigSize::new(16 * 1024 * 1024)
+}
 
 /// 负载均衡中的location匹配，将匹配合适的处理逻辑
+/// 经由TLS接入的请求标记, 存放于请求的extensions中
+#[derive(Debug, Clone, Copy)]
+pub struct TlsConnection;
+
+/// 请求接入的监听地址及该监听是否为TLS, 存放于请求的extensions中, 可用于按端口区分处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListenInfo {
+    pub addr: SocketAddr,
+    pub is_ssl: bool,
+}
+
+/// 返回头中已按配置移除`server`, 存放于返回的extensions中
+#[derive(Debug, Clone, Copy)]
+pub struct ServerHidden;
+
+/// 经由可信代理转发的请求, 记录直连的代理地址, 存放于请求的extensions中
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyPeer(pub SocketAddr);
+
+/// 请求的唯一ID, 存放于请求的extensions中, 可在头信息中以`$request_id`引用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        Self(format!("{:032x}", rand::random::<u128>()))
+    }
+}
+
+/// location正则匹配到的分组, 存放于请求的extensions中, 可在头信息中以`$1`等引用
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathCaptures(pub Vec<String>);
+
+/// 已添加转发头的标记, 防止重试时重复添加
+#[derive(Debug, Clone, Copy)]
+struct ForwardedSet;
+
+/// 被动健康检查中单次请求的记录, 未得到结果即被丢弃时(如客户端断开)不计入后端的失败
+/// 发往后端的连接, 明文的连接按客户端的窗口转发返回体
+enum UpstreamClient {
+    Http(Client<FlowStream<H2PingStream<TcpStream>>>),
+    Https(Client<H2PingStream<Box<TlsStream<TcpStream>>>>),
+}
+
+impl UpstreamClient {
+    async fn send(
+        self,
+        req: &mut Request<Body>,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        match self {
+            UpstreamClient::Http(client) => LocationConfig::deal_client(req, client).await,
+            UpstreamClient::Https(client) => LocationConfig::deal_client(req, client).await,
+        }
+    }
+}
+
+struct PassiveGuard {
+    server: SingleStreamConfig,
+    trial: bool,
+}
+
+impl PassiveGuard {
+    fn new(server: SingleStreamConfig) -> Self {
+        Self {
+            trial: HealthCheck::passive_begin(&server.addr),
+            server,
+        }
+    }
+
+    fn fail(guard: Option<Self>) {
+        if let Some(mut g) = guard {
+            g.trial = false;
+            g.server.add_passive_fail();
+        }
+    }
+
+    fn success(guard: Option<Self>) {
+        if let Some(mut g) = guard {
+            g.trial = false;
+            HealthCheck::add_passive_success(g.server.addr);
+        }
+    }
+}
+
+impl Drop for PassiveGuard {
+    fn drop(&mut self) {
+        if self.trial {
+            HealthCheck::passive_cancel(&self.server.addr);
+        }
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocationConfig {
@@ -33,27 +175,138 @@ pub struct LocationConfig {
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub static_response: Option<StaticResponse>,
 
-    #[serde_as(as = "Vec<DisplayFromStr>")]
+    /// 直接返回状态码, 如`[301, "https://example.com$path"]`或`[403, "forbidden"]`
+    #[serde_as(as = "Option<DisplayFromStrOrSeq>")]
+    #[serde(default, rename = "return")]
+    pub return_response: Option<ReturnResponse>,
+
+    /// 头信息的处理, 以proxy开头的处理发往后端的请求头, 其它处理返回头
+    /// 可为字符串如`"proxy + x-trace $remote_addr"`或数组如`["proxy", "-Authorization"]`
+    #[serde_as(as = "Vec<DisplayFromStrOrSeq>")]
     #[serde(default = "Vec::new")]
     pub headers: Vec<ConfigHeader>,
 
+    /// 添加或覆盖后端的返回头, 如`"Strict-Transport-Security max-age=31536000"`, 值中可使用`$host`或`${remote_addr}`等变量
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub proxy_set_header: Vec<ConfigHeader>,
+
+    /// 移除后端的返回头, 如`["server", "x-powered-by"]`, 移除`server`时不再添加`server: wmproxy`
+    #[serde(default = "Vec::new")]
+    pub proxy_hide_header: Vec<String>,
+
+    /// 返回内容的替换规则, 按顺序执行
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub sub_filter: Vec<SubFilterRule>,
+
+    /// 将后端返回头中的ID复制到返回给客户端的头中, 并可用`{up_id}`记录到访问日志
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub response_id: Option<ConfigResponseId>,
+
     /// 请求方法
     pub method: Option<String>,
+    /// 允许的请求方法, 如`["GET", "HEAD"]`, 路径匹配但方法不允许时返回405, 为空时允许所有方法
+    #[serde(default = "Vec::new")]
+    pub methods: Vec<String>,
+    /// 匹配请求头, 如`["X-Canary", "1"]`, 只有名称时仅要求存在该头, 不匹配时继续匹配其它location
+    #[serde(default = "Vec::new")]
+    pub if_header: Vec<String>,
+    /// 匹配请求参数, 如`["debug", "1"]`, 只有名称时仅要求存在该参数, 不匹配时继续匹配其它location
+    #[serde(default = "Vec::new")]
+    pub if_query: Vec<String>,
     pub up_name: Option<String>,
 
     #[serde(default)]
     pub is_ws: bool,
 
+    /// 静态文件的根目录, 未配置其它处理方式时将请求路径映射到该目录下
     pub root: Option<String>,
+    /// 访问目录时查找的默认文件, 为空时为`index.html index.htm`
+    #[serde(default = "Vec::new")]
+    pub index: Vec<String>,
+    /// 目录无index文件时是否列出目录内容
+    #[serde(default)]
+    pub autoindex: bool,
     #[serde(default = "Vec::new")]
     pub upstream: Vec<UpstreamConfig>,
 
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub try_paths: Option<TryPathsConfig>,
 
+    /// 发往后端前重写路径, 如`"/api/v1/ /"`去掉前缀, 或`"^/user/(\\d+) /users/$1"`
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub rewrite: Option<RewriteConfig>,
+
+    /// 发往后端前去掉路径的前缀, 如`"/api"`将`/api/users`转发为`/users`, 在`rewrite`之前处理
+    /// 为`true`时去掉rule的前缀, 如rule为`/grafana/`时将`/grafana/api/x`转发为`/api/x`
+    #[serde(default, deserialize_with = "bool_or_prefix")]
+    pub strip_prefix: Option<String>,
+    /// 去掉前缀时, 后端返回的以`/`开头的`Location`重新加上该前缀, 避免跳转到子路径之外
+    #[serde(default)]
+    pub strip_prefix_redirect: bool,
+
+    /// 缓存GET请求的返回, 命中时不再访问后端, 返回头中以`X-Cache: HIT/MISS`标记
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+
+    /// 跨域配置, 预检请求直接返回, 普通请求的返回按允许的Origin添加跨域头
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+
+    /// 按比例将请求分配到多个upstream, 如灰度发布, 所选的upstream可用`{up_name}`记录到访问日志
+    #[serde(default)]
+    pub split: Option<SplitConfig>,
+
+    /// 携带`Idempotency-Key`的请求将缓存请求内容, 失败时可重试其它后端, 并缓存后端的返回
+    #[serde(default)]
+    pub idempotency: bool,
+    /// 幂等返回的缓存时间
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_idempotency_ttl")]
+    pub idempotency_ttl: ConfigDuration,
+    /// 可缓存的请求及返回的最大body
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_idempotency_body")]
+    pub idempotency_max_body: ConfigSize,
+    /// 该location幂等缓存的总大小
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_idempotency_cache")]
+    pub idempotency_cache_size: ConfigSize,
+
+    /// 请求后端失败时重试其它后端的次数, 仅请求体已完整接收的请求可重试
+    #[serde(default, alias = "max_retries")]
+    pub retries: usize,
+    /// 重试下一个后端的情况, 可选`error`, `timeout`, `http_502`, `http_503`, `http_504`, 为空时为error及timeout
+    #[serde(default = "Vec::new")]
+    pub proxy_next_upstream: Vec<String>,
+    /// 重试的总耗时上限, 超出后不再重试
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub proxy_next_upstream_timeout: Option<ConfigDuration>,
+    /// 返回头中添加`X-Upstream-Addr`, 标识最终处理请求的后端, 用于调试
+    #[serde(default)]
+    pub upstream_addr_header: bool,
+
+    /// 按请求动态计算的后端地址, 可使用`$host`, `$http_<name>`等变量, 如`http://$http_x_backend.internal/`
+    pub proxy_pass: Option<String>,
+    /// proxy_pass计算后允许访问的目标, 如`*.internal`或`10.0.0.*:8080`, 为空时拒绝所有目标
+    #[serde(default = "Vec::new")]
+    pub proxy_pass_allow: Vec<String>,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
+
+    /// 该location的统计, 加载配置时按server及rule注册
+    #[serde(skip)]
+    pub metrics: Option<Arc<LocationMetrics>>,
+
+    /// 由root及index生成的静态文件服务
+    #[serde(skip)]
+    pub root_server: Option<FileServer>,
 }
 
 impl Hash for LocationConfig {
@@ -65,13 +318,26 @@ impl Hash for LocationConfig {
         if let Some(method) = &self.method {
             state.write(method.as_bytes());
         }
-        state.finish();
+        // 相同rule按方法, 请求头及参数区分的location使用不同的连接池
+        for v in self.methods.iter().chain(&self.if_header).chain(&self.if_query) {
+            state.write(v.as_bytes());
+        }
+        // 分流到不同upstream的请求使用不同的连接池
+        for u in self.split.iter().flat_map(|s| s.upstreams.iter()) {
+            state.write(u.name.as_bytes());
+        }
     }
 }
 
 impl PartialEq for LocationConfig {
     fn eq(&self, other: &LocationConfig) -> bool {
-        self.rule == other.rule && self.up_name == other.up_name && self.method == other.method
+        self.rule == other.rule
+            && self.up_name == other.up_name
+            && self.method == other.method
+            && self.methods == other.methods
+            && self.if_header == other.if_header
+            && self.if_query == other.if_query
+            && self.split == other.split
     }
 }
 
@@ -83,50 +349,179 @@ impl LocationConfig {
             rule: Matcher::new(),
             file_server: None,
             static_response: None,
+            return_response: None,
             headers: vec![],
+            proxy_set_header: vec![],
+            proxy_hide_header: vec![],
+            sub_filter: vec![],
+            response_id: None,
             method: None,
+            methods: vec![],
+            if_header: vec![],
+            if_query: vec![],
             up_name: None,
             is_ws: false,
             root: None,
+            index: vec![],
+            autoindex: false,
             upstream: vec![],
             try_paths: None,
+            rewrite: None,
+            strip_prefix: None,
+            strip_prefix_redirect: false,
+            cache: None,
+            cors: None,
+            split: None,
+            idempotency: false,
+            idempotency_ttl: default_idempotency_ttl(),
+            idempotency_max_body: default_idempotency_body(),
+            idempotency_cache_size: default_idempotency_cache(),
+            retries: 0,
+            proxy_next_upstream: vec![],
+            proxy_next_upstream_timeout: None,
+            upstream_addr_header: false,
+            proxy_pass: None,
+            proxy_pass_allow: vec![],
             comm: CommonConfig::new(),
+            metrics: None,
+            root_server: None,
         }
     }
     pub fn clone_only_hash(&self) -> LocationConfig {
         LocationConfig {
             rule: self.rule.clone(),
             method: self.method.clone(),
+            methods: self.methods.clone(),
+            if_header: self.if_header.clone(),
+            if_query: self.if_query.clone(),
             up_name: self.up_name.clone(),
             is_ws: self.is_ws,
             file_server: None,
             static_response: None,
+            return_response: None,
             headers: vec![],
+            proxy_set_header: vec![],
+            proxy_hide_header: vec![],
+            sub_filter: vec![],
+            response_id: None,
             try_paths: None,
+            rewrite: None,
+            strip_prefix: None,
+            strip_prefix_redirect: false,
             root: None,
+            index: vec![],
+            autoindex: false,
             upstream: vec![],
+            cache: None,
+            cors: None,
+            split: None,
+            idempotency: false,
+            idempotency_ttl: default_idempotency_ttl(),
+            idempotency_max_body: default_idempotency_body(),
+            idempotency_cache_size: default_idempotency_cache(),
+            retries: 0,
+            proxy_next_upstream: vec![],
+            proxy_next_upstream_timeout: None,
+            upstream_addr_header: false,
+            proxy_pass: None,
+            proxy_pass_allow: vec![],
             comm: CommonConfig::new(),
+            metrics: None,
+            root_server: None,
         }
     }
 
+    /// 根据root及index生成静态文件服务, 开启autoindex时可浏览目录
+    pub fn build_root_server(&mut self) {
+        self.root_server = self.root.as_ref().map(|root| {
+            let mut server = FileServer::new(root.clone(), String::new());
+            if !self.index.is_empty() {
+                server.index = self.index.clone();
+            }
+            server.set_browse(self.autoindex);
+            server.set_common(self.comm.clone());
+            server
+        });
+    }
+
     /// 当本地限制方法时,优先匹配方法,在进行路径的匹配
     pub fn is_match_rule(&self, path: &String, req: &RecvRequest) -> bool {
         match self.rule.is_match_rule(path, req) {
             Err(_) => false,
-            Ok(b) => b,
+            Ok(b) => b && self.is_match_condition(req),
         }
         
     }
 
-    async fn deal_client(
+    /// 是否满足`if_header`及`if_query`的条件
+    fn is_match_condition(&self, req: &RecvRequest) -> bool {
+        if let Some(name) = self.if_header.first() {
+            match req.headers().get_str_value(name) {
+                Some(v) => {
+                    if self.if_header.get(1).is_some_and(|expect| v.trim() != expect) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        if let Some(name) = self.if_query.first() {
+            let query = req.url().query.clone().unwrap_or_default();
+            let find = query.split('&').find_map(|kv| {
+                let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+                let k = Url::url_decode(k).unwrap_or_else(|_| k.to_string());
+                (&k == name).then(|| Url::url_decode(v).unwrap_or_else(|_| v.to_string()))
+            });
+            match find {
+                Some(v) => {
+                    if self.if_query.get(1).is_some_and(|expect| &v != expect) {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// 是否配置了处理请求的方式, 如后端地址, 静态文件或直接返回
+    pub fn has_handler(&self) -> bool {
+        self.file_server.is_some()
+            || self.static_response.is_some()
+            || self.return_response.is_some()
+            || self.comm.proxy_url.is_some()
+            || self.proxy_pass.is_some()
+            || self.split.is_some()
+            || self.root.is_some()
+            || self.try_paths.is_some()
+    }
+
+    /// 是否允许该请求方法, 未配置`methods`时允许所有方法
+    pub fn is_allow_method(&self, req: &RecvRequest) -> bool {
+        self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(req.method().as_str()))
+    }
+
+    async fn deal_client<T>(
         req: &mut Request<Body>,
-        client: Client,
+        client: Client<T>,
     ) -> ProtResult<(
         Response<Body>,
         Option<Sender<Request<Body>>>,
         Option<Receiver<ProtResult<Response<Body>>>>,
-    )> {
-        println!("处理客户端!!!!");
+    )>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        log::trace!(
+            "反向代理：向后端发送请求 {} {}, host:{:?}",
+            req.method().as_str(),
+            req.path(),
+            req.get_host()
+        );
         let (mut recv, sender) = client.send2(req.replace_clone(Body::empty())).await?;
         match recv.recv().await {
             Some(res) => Ok((res?, Some(sender), Some(recv))),
@@ -143,10 +538,49 @@ impl LocationConfig {
         Option<Sender<Request<Body>>>,
         Option<Receiver<ProtResult<Response<Body>>>>,
     )> {
+        self.deal_reverse_proxy_except(req, url, &mut vec![]).await
+    }
+
+    /// 反向代理到后端, 跳过`except`中已尝试过的后端, 本次选中的后端将加入`except`
+    pub async fn deal_reverse_proxy_except(
+        &self,
+        req: &mut Request<Body>,
+        url: &Url,
+        except: &mut Vec<SocketAddr>,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        if let Some(reused) = req.extensions().get::<ReusedClient>().cloned() {
+            if let Some(client) = reused.take() {
+                return self.deal_reused(req, &reused, client).await;
+            }
+        }
         let mut url = url.clone();
         let domain = url.domain.clone().unwrap();
+        let mut timing = UpstreamTiming::new(false);
 
-        if let Some(addr) = ReverseHelper::get_upstream_addr(&self.upstream, &*domain) {
+        if let Some(path) = ReverseHelper::get_unix_path(&self.upstream, &domain, &url) {
+            return self.deal_unix_proxy(req, &path).await;
+        }
+
+        let client = req.extensions().get::<SocketAddr>().cloned();
+        let mut passive = None;
+        let mut _conn = None;
+        let mut ping = None;
+        // 会话保持固定的后端可用时优先转发到该后端
+        let sticky = ReverseHelper::get_sticky_addr(&self.upstream, &domain, req)
+            .filter(|addr| !except.contains(addr));
+        if let Some(addr) = sticky.or_else(|| {
+            ReverseHelper::get_upstream_addr_except(&self.upstream, &domain, client.as_ref(), except)
+        }) {
+            except.push(addr);
+            _conn = Some(UpstreamConnGuard::new(addr));
+            ping = ReverseHelper::get_h2_ping(&self.upstream, &domain);
+            passive = ReverseHelper::get_upstream_server(&self.upstream, &domain, &addr)
+                .filter(|s| s.max_fails > 0)
+                .map(PassiveGuard::new);
             url.domain = Some(addr.ip().to_string());
             url.port = Some(addr.port());
         }
@@ -154,38 +588,504 @@ impl LocationConfig {
             url.scheme = req.scheme().clone();
         }
         if let Some(connect) = url.get_connect_url() {
-            req.headers_mut().insert(HeaderName::HOST, connect.clone());
+            if !self.has_proxy_host() {
+                req.headers_mut().insert(HeaderName::HOST, connect.clone());
+            }
         }
         let proxy_timeout = self.comm.build_proxy_timeout();
-
-        let mut connect_timeout = None;
-        if proxy_timeout.is_some() {
-            connect_timeout = proxy_timeout.as_ref().unwrap().connect_timeout.clone();
-        }
-        let stream = match url.get_connect_url() {
-            Some(connect) => HealthCheck::connect_timeout(&connect, connect_timeout).await?,
+        let (connect_timeout, read_timeout, write_timeout) = self.get_proxy_timeouts();
+        let connect = match url.get_connect_url() {
+            Some(connect) => connect,
             None => {
                 return Err(ProtError::Extension("get url error"));
             }
         };
-        let mut res = if url.scheme.is_http() {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .connect_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
+        timing.dns_cached = url
+            .domain
+            .as_ref()
+            .map(|d| d.parse::<IpAddr>().is_ok())
+            .unwrap_or(false);
+        // 仅统计域名解析的耗时
+        timing.mark();
+        let addrs = tokio::net::lookup_host(connect).await?.collect::<Vec<_>>();
+        timing.dns = timing.mark();
+        timing.addr = addrs.first().cloned();
+        // 限制同时建立的连接数, 等待的时间计入连接耗时
+        let connecting = ReverseHelper::get_connect_permit(&self.upstream, &domain).await;
+        let mut stream = match HealthCheck::connect_timeout(&&addrs[..], Some(connect_timeout)).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                timing.connect = timing.mark();
+                timing.record();
+                PassiveGuard::fail(passive);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = self.send_proxy_protocol(&domain, req, &mut stream).await {
+            timing.connect = timing.mark();
+            timing.record();
+            PassiveGuard::fail(passive);
+            return Err(e.into());
+        }
+        if let Some(limit) = req.extensions().get::<Arc<BodyLimit>>() {
+            stream = limit.watch_upstream(stream)?;
+        }
+        timing.connect = timing.mark();
+        // 明文的后端按客户端的窗口逐段转发返回体, 配置了PING探测时在HTTP/2的连接上发送PING
+        let mut flow = None;
+        let client = if url.scheme.is_http() {
+            let option = Client::builder().timeout_layer(proxy_timeout).value();
+            let stream = H2PingStream::new(stream, ping.clone(), false);
+            let (slot, stream) = Self::flow_stream(req, stream);
+            flow = Some(slot);
+            Ok(UpstreamClient::Http(Client::new(option, MaybeHttpsStream::Http(stream))))
+        } else {
+            let connect = Self::connect_tls(&url, proxy_timeout, stream, ping.clone());
+            match tokio::time::timeout(connect_timeout, connect).await {
+                Ok(client) => client.map(UpstreamClient::Https),
+                Err(_) => Err(ProtError::connect_timeout("client")),
+            }
+        };
+        if url.scheme.is_http() {
+            let cost = timing.mark();
+            timing.connect += cost;
         } else {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .url(url.clone())?
-                .connect_tls_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
-        };
-        Helper::rewrite_response(&mut res.0, &self.headers);
+            timing.tls = timing.mark();
+        }
+        drop(connecting);
+        let client = match client {
+            Ok(client) => client,
+            Err(e) => {
+                timing.record();
+                PassiveGuard::fail(passive);
+                return Err(e);
+            }
+        };
+        // 等待后端返回头超时则返回504, 该连接不再复用
+        let wait = write_timeout + read_timeout;
+        let (ret, timed_out) = match tokio::time::timeout(wait, client.send(req)).await {
+            Ok(ret) => (ret, false),
+            Err(_) => (Err(ProtError::read_timeout("client")), true),
+        };
+        timing.header = timing.mark();
+        timing.record();
+        match &ret {
+            _ if timed_out => PassiveGuard::fail(passive),
+            Ok((res, _, _)) if res.status().is_server_error() => PassiveGuard::fail(passive),
+            Ok(_) => PassiveGuard::success(passive),
+            // 客户端读取超时由客户端导致, 不计入后端的失败
+            Err(e) if e.is_read_timeout() == (true, true) => {}
+            Err(_) => PassiveGuard::fail(passive),
+        }
+        let mut res = ret?;
+        res.0.extensions_mut().insert(timing);
+        if let Some(slot) = flow {
+            Self::keep_flow(&mut res.0, slot);
+        }
+        // 放入连接池后由其发送PING
+        if let Some(ping) = ping.filter(|_| res.0.version() == Version::Http2) {
+            res.0.extensions_mut().insert(ping);
+        }
+        self.rewrite_response(req, &mut res.0);
         Ok(res)
     }
 
+    /// 使用连接池中复用的连接转发, 收到返回后将连接交回`reused`, 失败时丢弃该连接
+    async fn deal_reused(
+        &self,
+        req: &mut Request<Body>,
+        reused: &ReusedClient,
+        mut client: CacheClient,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        if let Some(slot) = client.flow.as_ref().filter(|_| !client.is_h2) {
+            slot.attach(req.extensions().get::<Arc<FlowWindow>>());
+        }
+        let mut timing = UpstreamTiming::new(true);
+        // 复用的连接失败不代表后端不可用, 不加入`except`, 重试时可重新连接该后端
+        timing.addr = client.addr;
+        let _conn = client.addr.map(UpstreamConnGuard::new);
+        // 复用的连接可能已卡死, 超时后丢弃该连接
+        let (_, read, write) = self.get_proxy_timeouts();
+        let _send = tokio::time::timeout(
+            write,
+            client.sender.send(req.replace_clone(Body::empty())),
+        )
+        .await;
+        let recv = tokio::time::timeout(write + read, client.receiver.recv()).await;
+        timing.header = timing.mark();
+        timing.record();
+        let mut res = match recv {
+            Ok(Some(res)) => res?,
+            Ok(None) => {
+                // 后端已关闭连接, 之后的请求重新建立连接
+                log::trace!("复用连接收到空消息,关闭复用连接");
+                let e = io::Error::new(io::ErrorKind::ConnectionAborted, "意外的服务端关闭连接");
+                return Err(e.into());
+            }
+            Err(_) => {
+                log::warn!("复用连接等待后端返回超时, 丢弃该连接");
+                return Err(ProtError::read_timeout("client"));
+            }
+        };
+        log::trace!("复用连接收到Response {}", res.status());
+        res.extensions_mut().insert(timing);
+        self.rewrite_response(req, &mut res);
+        reused.give_back(client);
+        Ok((res, None, None))
+    }
+
+    /// 代理到的upstream配置了proxy_protocol时, 在新建立的连接上先发送PROXY协议头,
+    /// 源地址为请求的客户端地址, 目标地址为客户端接入的监听地址, 未知时为本地地址
+    async fn send_proxy_protocol(
+        &self,
+        name: &str,
+        req: &Request<Body>,
+        stream: &mut TcpStream,
+    ) -> std::io::Result<()> {
+        let version = match ReverseHelper::get_proxy_protocol(&self.upstream, name) {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        let src = match req.extensions().get::<SocketAddr>() {
+            Some(addr) => *addr,
+            None => stream.local_addr()?,
+        };
+        let dst = match req.extensions().get::<ListenInfo>() {
+            Some(listen) => listen.addr,
+            None => stream.local_addr()?,
+        };
+        stream
+            .write_all(&ProxyProtocol::encode(version, src, dst))
+            .await
+    }
+
+    /// 按请求所属客户端连接的窗口读取后端的返回, 复用该连接时切换窗口
+    fn flow_stream<T>(req: &Request<Body>, stream: T) -> (Arc<FlowSlot>, FlowStream<T>) {
+        let slot = Arc::new(FlowSlot::default());
+        slot.attach(req.extensions().get::<Arc<FlowWindow>>());
+        (slot.clone(), FlowStream::upstream(stream, slot))
+    }
+
+    /// 与后端建立TLS连接, 按ALPN协商的结果使用HTTP/2或HTTP/1.1, 这里的域名只为认证设置
+    async fn connect_tls(
+        url: &Url,
+        proxy_timeout: Option<TimeoutLayer>,
+        stream: TcpStream,
+        ping: Option<Arc<H2Ping>>,
+    ) -> ProtResult<Client<H2PingStream<Box<TlsStream<TcpStream>>>>> {
+        let domain = url
+            .domain
+            .clone()
+            .ok_or(ProtError::Extension("unknown connection domain"))?;
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = Client::builder().value().get_alpn_protocol();
+        let name = rustls::pki_types::ServerName::try_from(domain)
+            .map_err(|_| ProtError::Extension("invalid dnsname"))?;
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = connector.connect(name, stream).await?;
+        let h2 = stream.get_ref().1.alpn_protocol() == Some(&ClientOption::H2_PROTOCOL[..]);
+        let option = Client::builder()
+            .timeout_layer(proxy_timeout)
+            .http2_only(h2)
+            .value();
+        let stream = H2PingStream::new(Box::new(stream), ping, h2);
+        Ok(Client::new(option, MaybeHttpsStream::Http(stream)))
+    }
+
+    /// 记录连接的窗口以便复用时切换, HTTP/2的连接同时服务多个请求, 不再限制
+    fn keep_flow(res: &mut Response<Body>, slot: Arc<FlowSlot>) {
+        if res.version() == Version::Http2 {
+            slot.detach();
+        } else {
+            res.extensions_mut().insert(slot);
+        }
+    }
+
+    /// 反向代理到unix socket的后端, 除连接方式外与TCP的后端一致, 未配置proxy_host时Host为localhost
+    async fn deal_unix_proxy(
+        &self,
+        req: &mut Request<Body>,
+        path: &str,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        #[cfg(not(unix))]
+        {
+            let _ = (req, path);
+            return Err(ProtError::Extension("unix socket仅支持unix平台"));
+        }
+        #[cfg(unix)]
+        {
+            let mut timing = UpstreamTiming::new(false);
+            if !self.has_proxy_host() {
+                req.headers_mut().insert(HeaderName::HOST, "localhost");
+            }
+            let (connect_timeout, read_timeout, write_timeout) = self.get_proxy_timeouts();
+            timing.dns = timing.mark();
+            let connect = tokio::net::UnixStream::connect(path);
+            let stream = match tokio::time::timeout(connect_timeout, connect).await {
+                Ok(Ok(stream)) => stream,
+                ret => {
+                    timing.connect = timing.mark();
+                    timing.record();
+                    return match ret {
+                        Ok(Err(e)) => Err(e.into()),
+                        _ => Err(ProtError::connect_timeout("client")),
+                    };
+                }
+            };
+            timing.connect = timing.mark();
+            let option = Client::builder()
+                .timeout_layer(self.comm.build_proxy_timeout())
+                .value();
+            let (slot, stream) = Self::flow_stream(req, stream);
+            let client = Client::new(option, MaybeHttpsStream::Http(stream));
+            let wait = write_timeout + read_timeout;
+            let ret = match tokio::time::timeout(wait, Self::deal_client(req, client)).await {
+                Ok(ret) => ret,
+                Err(_) => Err(ProtError::read_timeout("client")),
+            };
+            timing.header = timing.mark();
+            timing.record();
+            let mut res = ret?;
+            res.0.extensions_mut().insert(timing);
+            Self::keep_flow(&mut res.0, slot);
+            self.rewrite_response(req, &mut res.0);
+            Ok(res)
+        }
+    }
+
+    /// proxy_url代理到的upstream
+    fn proxy_upstream(&self) -> Option<&UpstreamConfig> {
+        let domain = self.comm.proxy_url.as_ref()?.domain.as_ref()?;
+        self.upstream.iter().find(|u| &u.name == domain)
+    }
+
+    /// 请求的会话保持cookie固定的可用后端, 复用连接时只取出连接该后端的连接
+    pub fn get_sticky_addr(&self, req: &Request<Body>) -> Option<SocketAddr> {
+        self.proxy_upstream()?
+            .get_sticky_addr(req.headers().get_cookie().as_deref())
+    }
+
+    /// 发送过PROXY协议头的连接已声明客户端地址, 只复用于同一客户端地址的请求
+    pub fn get_proxy_protocol_peer(&self, req: &Request<Body>) -> Option<SocketAddr> {
+        self.proxy_upstream()?.proxy_protocol?;
+        req.extensions().get::<SocketAddr>().cloned()
+    }
+
+    /// 连接, 读取及发送的超时时间, 代理到的upstream中配置的优先
+    pub fn get_proxy_timeouts(&self) -> (Duration, Duration, Duration) {
+        let upstream = self.proxy_upstream();
+        let connect = upstream
+            .and_then(|u| u.connect_timeout.as_ref())
+            .map(|t| t.0)
+            .unwrap_or_else(|| self.comm.get_proxy_connect_timeout());
+        let read = upstream
+            .and_then(|u| u.read_timeout.as_ref())
+            .map(|t| t.0)
+            .unwrap_or_else(|| self.comm.get_proxy_read_timeout());
+        let write = upstream
+            .and_then(|u| u.write_timeout.as_ref())
+            .map(|t| t.0)
+            .unwrap_or_else(|| self.comm.get_proxy_write_timeout());
+        (connect, read, write)
+    }
+
+    /// 升级请求能否原样透传到后端, `is_ws`的location按消息转发
+    pub fn is_upgrade_passthrough(&self) -> bool {
+        !self.is_ws && self.comm.proxy_url.is_some()
+    }
+
+    /// 将升级请求转发到后端并读取后端返回的头信息, 连接保留用于之后的双向透传
+    pub async fn deal_upgrade(&self, req: &mut Request<Body>) -> ProtResult<UpgradeTunnel> {
+        let mut url = match &self.comm.proxy_url {
+            Some(url) => url.clone(),
+            None => return Err(ProtError::Extension("miss proxy url")),
+        };
+        let domain = url.domain.clone().unwrap_or_default();
+        self.set_forwarded_headers(req);
+        self.rewrite_request(req);
+
+        let client = req.extensions().get::<SocketAddr>().cloned();
+        let mut conn = None;
+        if let Some(addr) =
+            ReverseHelper::get_upstream_addr_except(&self.upstream, &domain, client.as_ref(), &[])
+        {
+            conn = Some(UpstreamConnGuard::new(addr));
+            url.domain = Some(addr.ip().to_string());
+            url.port = Some(addr.port());
+        }
+        if url.scheme == Scheme::None {
+            url.scheme = req.scheme().clone();
+        }
+        let connect = match url.get_connect_url() {
+            Some(connect) => connect,
+            None => return Err(ProtError::Extension("get url error")),
+        };
+        if !self.has_proxy_host() {
+            req.headers_mut().insert(HeaderName::HOST, connect.clone());
+        }
+        let (connect_timeout, _, _) = self.get_proxy_timeouts();
+        let addrs = tokio::net::lookup_host(&connect).await?.collect::<Vec<_>>();
+        let mut stream = HealthCheck::connect_timeout(&&addrs[..], Some(connect_timeout)).await?;
+        self.send_proxy_protocol(&domain, req, &mut stream).await?;
+        let mut stream = if url.scheme.is_http() {
+            MaybeHttpsStream::Http(stream)
+        } else {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            let config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            // 这里的域名只为认证设置
+            let name = rustls::pki_types::ServerName::try_from(domain)
+                .map_err(|_| ProtError::Extension("invalid dnsname"))?;
+            let connector = TlsConnector::from(Arc::new(config));
+            MaybeHttpsStream::Https(connector.connect(name, stream).await?)
+        };
+
+        let mut buf = BinaryMut::new();
+        req.encode_header(&mut buf)?;
+        stream.write_all(buf.as_slice()).await?;
+
+        // 读取后端返回的头信息, 头之后多读的数据一并保留
+        let mut head = vec![];
+        let mut data = [0u8; 4096];
+        let end = loop {
+            if let Some(pos) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos;
+            }
+            if head.len() > UpgradeTunnel::MAX_HEAD_LEN {
+                return Err(ProtError::Extension("upgrade response head too large"));
+            }
+            let n = stream.read(&mut data).await?;
+            if n == 0 {
+                return Err(ProtError::Extension("upstream closed before response"));
+            }
+            head.extend_from_slice(&data[..n]);
+        };
+        let status = String::from_utf8_lossy(&head[..end])
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or(ProtError::Extension("invalid upgrade response"))?;
+        Ok(UpgradeTunnel {
+            status,
+            head,
+            stream,
+            conn,
+        })
+    }
+
+    /// 处理反向代理的返回, 修改头信息及替换返回内容
+    /// 后端的返回头不符合规范时替换成502
+    /// 按`strip_prefix`及`rewrite`重写发往后端的路径, 并按`proxy`开头的头配置修改发往后端的请求头, 值中可使用`$host`等变量
+    pub fn rewrite_request(&self, req: &mut Request<Body>) {
+        if let Some(prefix) = self.get_strip_prefix() {
+            RewriteConfig::strip_prefix(&prefix).rewrite_request(req);
+        }
+        if let Some(rewrite) = &self.rewrite {
+            rewrite.rewrite_request(req);
+        }
+        for h in self.headers.iter().filter(|h| h.is_proxy) {
+            let value = Helper::format_header_value(req, &h.val);
+            Helper::apply_header(req.headers_mut(), h, value);
+        }
+    }
+
+    /// 发往后端前需去掉的前缀, 配置为空时使用rule的前缀
+    fn get_strip_prefix(&self) -> Option<String> {
+        match self.strip_prefix.as_ref()? {
+            prefix if prefix.is_empty() => self.rule.get_prefix(),
+            prefix => Some(prefix.clone()),
+        }
+    }
+
+    /// 后端返回的以`/`开头的跳转地址加上去掉的前缀
+    fn restore_redirect_prefix(&self, res: &mut Response<Body>) {
+        let prefix = match self.get_strip_prefix() {
+            Some(prefix) if self.strip_prefix_redirect => prefix,
+            _ => return,
+        };
+        let prefix = prefix.trim_end_matches('/');
+        let location = match res.headers().get_str_value(&HeaderName::LOCATION) {
+            Some(location) if location.starts_with('/') && !location.starts_with("//") => location,
+            _ => return,
+        };
+        if !prefix.is_empty() {
+            res.headers_mut()
+                .insert(HeaderName::LOCATION, format!("{}{}", prefix, location));
+        }
+    }
+
+    /// 是否配置了发往后端的Host, 配置后不再按后端地址设置Host
+    fn has_proxy_host(&self) -> bool {
+        self.headers.iter().any(|h| {
+            h.is_proxy && h.oper != HeaderOper::Del && h.key.eq_ignore_ascii_case("host")
+        })
+    }
+
+    pub fn rewrite_response(&self, req: &Request<Body>, res: &mut Response<Body>) {
+        if let Some(policy) = &self.comm.header_policy {
+            let version = res.version();
+            if let Err(e) = policy.apply(res.headers_mut(), version) {
+                log::warn!("后端返回头不符合规范: {}", e);
+                *res = Response::status502()
+                    .body("invalid upstream header")
+                    .unwrap()
+                    .into_type();
+                return;
+            }
+        }
+        if let Some(response_id) = &self.response_id {
+            response_id.copy_response(res);
+        }
+        for h in self.headers.iter().filter(|h| !h.is_proxy) {
+            let value = Helper::format_header_value(req, &h.val);
+            Helper::apply_header(res.headers_mut(), h, value);
+        }
+        for h in &self.proxy_set_header {
+            let value = Helper::format_header_value(req, &h.val);
+            Helper::apply_header(res.headers_mut(), h, value);
+        }
+        for name in &self.proxy_hide_header {
+            res.headers_mut().remove(name);
+            if name.eq_ignore_ascii_case("server") {
+                res.extensions_mut().insert(ServerHidden);
+            }
+        }
+        if !self.sub_filter.is_empty() {
+            SubFilter::rewrite_response(&self.sub_filter, res);
+        }
+        self.restore_redirect_prefix(res);
+        let addr = res.extensions().get::<UpstreamTiming>().and_then(|t| t.addr);
+        if let (Some(addr), true) = (addr, self.upstream_addr_header) {
+            res.headers_mut().insert("X-Upstream-Addr", addr.to_string());
+        }
+        let chosen = req.extensions().get::<SplitChosen>();
+        if let (Some(chosen), true) = (chosen, self.upstream_addr_header) {
+            res.headers_mut().insert("X-Upstream-Name", chosen.0.clone());
+        }
+        // 首次转发或固定的后端不可用而重新选择时, 写入所选后端的会话保持cookie
+        let sticky = addr.and_then(|addr| {
+            self.proxy_upstream()?
+                .get_sticky_cookie(req.headers().get_cookie().as_deref(), &addr)
+        });
+        if let Some(cookie) = sticky {
+            res.headers_mut().push("Set-Cookie", cookie);
+        }
+    }
+
     pub async fn deal_request(
         &self,
         req: &mut Request<Body>,
@@ -194,7 +1094,75 @@ impl LocationConfig {
         Option<Sender<Request<Body>>>,
         Option<Receiver<ProtResult<Response<Body>>>>,
     )> {
-        Helper::log_acess(&self.comm.log_format, &self.comm.access_log, &req);
+        let ret = self.inner_deal_request(req).await;
+        // 记录由该location写入访问日志, 失败时由上层生成错误返回后再按匹配的location记录
+        if ret.is_ok() {
+            self.log_access(req);
+        }
+        ret
+    }
+
+    /// 向后端传递客户端的地址及协议, 同一请求只添加一次
+    pub fn set_forwarded_headers(&self, req: &mut Request<Body>) {
+        if self.comm.forwarded_headers == Some(false)
+            || req.extensions().get::<ForwardedSet>().is_some()
+        {
+            return;
+        }
+        let ip = match req.extensions().get::<SocketAddr>() {
+            Some(client) => client.ip().to_string(),
+            None => return,
+        };
+        // 经由可信代理时追加直连的代理地址
+        let hop = match req.extensions().get::<ProxyPeer>() {
+            Some(peer) => peer.0.ip().to_string(),
+            None => ip.clone(),
+        };
+        let forwarded = match req.headers().get_str_value(&"x-forwarded-for") {
+            Some(v) if !v.trim().is_empty() => format!("{}, {}", v.trim(), hop),
+            _ => hop,
+        };
+        let proto = if req.extensions().get::<TlsConnection>().is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        // 已知接入的监听地址时传递其端口
+        let port = req.extensions().get::<ListenInfo>().map(|l| l.addr.port());
+        let headers = req.headers_mut();
+        headers.insert("X-Forwarded-For", forwarded);
+        headers.insert("X-Real-IP", ip);
+        headers.insert("X-Forwarded-Proto", proto);
+        if let Some(port) = port {
+            headers.insert("X-Forwarded-Port", port.to_string());
+        }
+        req.extensions_mut().insert(ForwardedSet);
+    }
+
+    /// 记录该请求使用的访问日志, 在返回发送完毕后由上层写入
+    pub fn log_access(&self, req: &mut Request<Body>) {
+        let mut target = self.access_target();
+        target.upstream = req.extensions().get::<SplitChosen>().map(|c| c.0.clone());
+        req.extensions_mut().insert(target);
+    }
+
+    /// 该location的访问日志, 附带匹配的server名称及location规则
+    pub fn access_target(&self) -> AccessTarget {
+        AccessTarget::new(&self.comm.log_format, &self.comm.access_log)
+            .with_route(self.up_name.clone(), Some(self.rule.to_string()))
+    }
+
+    async fn inner_deal_request(
+        &self,
+        req: &mut Request<Body>,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        if let Some(ret) = &self.return_response {
+            return Ok((ret.deal_request(req)?, None, None));
+        }
         if let Some(file_server) = &self.file_server {
             let res = file_server.deal_request(req).await?;
             return Ok((res, None, None));
@@ -203,16 +1171,357 @@ impl LocationConfig {
             let res = static_reponse.deal_request(req).await?;
             return Ok((res, None, None));
         }
-        if let Some(reverse) = &self.comm.proxy_url {
-            return self.deal_reverse_proxy(req, reverse).await;
+        let max_body = self.comm.get_max_body_size();
+        if BodyLimit::is_declared_over(req, max_body) {
+            return Ok((BodyLimit::too_large(), None, None));
+        }
+        let dynamic = match &self.proxy_pass {
+            Some(template) => match self.resolve_proxy_pass(req, template) {
+                Ok(url) => Some(url),
+                Err((status, text)) => {
+                    let res = Response::text().status(status).body(text)?.into_type();
+                    return Ok((res, None, None));
+                }
+            },
+            None => None,
+        };
+        let dynamic = dynamic.or_else(|| self.resolve_split(req));
+        if let Some(reverse) = dynamic.as_ref().or(self.comm.proxy_url.as_ref()) {
+            // 未知长度的请求体转发时计数, 超出时中断后端并返回413, 接收超时时返回408
+            let body_timeout = self.comm.client_body_timeout.as_ref().map(|t| t.0);
+            return match BodyLimit::wrap(req, max_body, body_timeout) {
+                Some(limit) => tokio::select! {
+                    ret = self.deal_proxy_url(req, reverse) => {
+                        if limit.is_over() {
+                            Ok((limit.reject(), None, None))
+                        } else {
+                            ret
+                        }
+                    }
+                    _ = limit.wait_over() => Ok((limit.reject(), None, None)),
+                },
+                None => self.deal_proxy_url(req, reverse).await,
+            };
+        }
+        if let Some(root_server) = &self.root_server {
+            let res = root_server.deal_request(req).await?;
+            return Ok((res, None, None));
         }
         return Err(ProtError::Extension("unknow data"));
     }
 
+    /// 按split选择本次请求的upstream并记录到请求中, 已选择过时沿用之前的结果
+    pub fn choose_split(&self, req: &mut Request<Body>) -> Option<String> {
+        let split = self.split.as_ref()?;
+        if let Some(chosen) = req.extensions().get::<SplitChosen>() {
+            if split.upstreams.iter().any(|u| u.name == chosen.0) {
+                return Some(chosen.0.clone());
+            }
+        }
+        let name = split.choose(req)?.name.clone();
+        req.extensions_mut().insert(SplitChosen(name.clone()));
+        Some(name)
+    }
+
+    fn resolve_split(&self, req: &mut Request<Body>) -> Option<Url> {
+        let name = self.choose_split(req)?;
+        SplitConfig::build_url(self.comm.proxy_url.as_ref(), &name)
+    }
+
+    /// 计算proxy_pass的目标, 失败时返回状态码及内容, 无法解析时为502, 不在允许列表中时为403
+    fn resolve_proxy_pass(
+        &self,
+        req: &Request<Body>,
+        template: &str,
+    ) -> Result<Url, (u16, &'static str)> {
+        let url = match ProxyPass::resolve(req, template) {
+            Some(url) => url,
+            None => {
+                log::warn!("proxy_pass无法解析出有效的后端地址: {}", template);
+                return Err((502, "invalid proxy target"));
+            }
+        };
+        if !ProxyPass::is_allowed(&self.proxy_pass_allow, &url) {
+            log::warn!(
+                "proxy_pass的目标{}不在允许列表中",
+                url.get_connect_url().unwrap_or_default()
+            );
+            return Err((403, "proxy target not allowed"));
+        }
+        Ok(url)
+    }
+
+    async fn deal_proxy_url(
+        &self,
+        req: &mut Request<Body>,
+        reverse: &Url,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        if let Some(cache) = &self.cache {
+            if let Some(key) = CacheConfig::get_key(req) {
+                return self.deal_cache(req, reverse, cache, key).await;
+            }
+        }
+        self.deal_proxy_forward(req, reverse).await
+    }
+
+    /// 先查找缓存, 未命中时访问后端, 可缓存的返回读取完整后存入缓存, 其它返回正常转发
+    async fn deal_cache(
+        &self,
+        req: &mut Request<Body>,
+        reverse: &Url,
+        cache: &CacheConfig,
+        key: String,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        let store = format!("{}", self.rule);
+        if let Some(cached) = CacheConfig::lookup(&store, &key) {
+            let mut res = cached.to_response();
+            res.headers_mut().insert(CACHE_STATUS_HEADER, "HIT");
+            return Ok((res, None, None));
+        }
+        let (mut res, sender, receiver) = self.deal_proxy_forward(req, reverse).await?;
+        if cache.is_cacheable(&res) {
+            let limit = cache.max_size.0 as usize;
+            let (data, complete) = Idempotency::read_body(res.body_mut(), limit).await?;
+            if complete {
+                res.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+                res.headers_mut()
+                    .insert(HeaderName::CONTENT_LENGTH, data.len());
+                let cached = IdempotencyResponse {
+                    status: res.status().as_u16(),
+                    headers: res.headers().clone(),
+                    body: data.clone(),
+                };
+                cache.insert(&store, &key, cached);
+                *res.body_mut() = Body::only(Binary::from(data));
+            } else {
+                let origin = std::mem::replace(res.body_mut(), Body::empty());
+                *res.body_mut() = Idempotency::chain_body(data, origin);
+            }
+        }
+        res.headers_mut().insert(CACHE_STATUS_HEADER, "MISS");
+        Ok((res, sender, receiver))
+    }
+
+    async fn deal_proxy_forward(
+        &self,
+        req: &mut Request<Body>,
+        reverse: &Url,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        self.set_forwarded_headers(req);
+        self.rewrite_request(req);
+        if self.idempotency {
+            if let Some(key) = Idempotency::get_key(req) {
+                return self.deal_idempotency(req, reverse, key).await;
+            }
+        }
+        if self.retries > 0 && Self::is_retryable(req) {
+            return self.deal_reverse_proxy_retry(req, reverse).await;
+        }
+        self.deal_reverse_proxy(req, reverse).await
+    }
+
+    /// 请求体已完整接收(含无请求体)时才可重试, 流式的请求体发送后无法再次发送
+    fn is_retryable(req: &Request<Body>) -> bool {
+        req.body().is_end()
+    }
+
+    /// 该情况是否需要重试下一个后端, 未配置时连接失败及超时重试
+    fn is_next_upstream(&self, cond: &str) -> bool {
+        if self.proxy_next_upstream.is_empty() {
+            return cond == "error" || cond == "timeout";
+        }
+        self.proxy_next_upstream.iter().any(|c| c == cond)
+    }
+
+    /// 反向代理到后端, 失败时按`retries`依次重试尚未尝试过的后端
+    async fn deal_reverse_proxy_retry(
+        &self,
+        req: &mut Request<Body>,
+        url: &Url,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        let domain = url.domain.clone().unwrap_or_default();
+        let mut tries = self.retries + 1;
+        // 负载均衡时每个后端只尝试一次
+        if self.upstream.iter().any(|u| u.name == domain) {
+            tries = tries.min(ReverseHelper::get_upstream_tries(&self.upstream, &domain));
+        }
+        // 缓存已接收的请求体, 每次重试时重新发送
+        let body = if tries > 1 && req.get_body_len() != 0 {
+            let (body, _) = Idempotency::read_body(req.body_mut(), usize::MAX).await?;
+            req.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+            req.headers_mut()
+                .insert(HeaderName::CONTENT_LENGTH, body.len());
+            Some(body)
+        } else {
+            None
+        };
+        let start = Instant::now();
+        let mut except = vec![];
+        let mut times = 1;
+        loop {
+            if let Some(body) = &body {
+                *req.body_mut() = Body::only(Binary::from(body.clone()));
+            }
+            let ret = self.deal_reverse_proxy_except(req, url, &mut except).await;
+            let reason = match &ret {
+                Err(e) if ReverseHelper::is_upstream_timeout(e) => {
+                    self.is_next_upstream("timeout").then(|| format!("{:?}", e))
+                }
+                Err(e) => self.is_next_upstream("error").then(|| format!("{:?}", e)),
+                Ok((res, _, _)) => {
+                    let status = res.status().as_u16();
+                    self.is_next_upstream(&format!("http_{}", status))
+                        .then(|| format!("status {}", status))
+                }
+            };
+            let in_budget = self
+                .proxy_next_upstream_timeout
+                .as_ref()
+                .map(|t| start.elapsed() < t.0)
+                .unwrap_or(true);
+            match reason {
+                Some(reason) if times < tries && in_budget => {
+                    let addr = except
+                        .last()
+                        .map(|a| a.to_string())
+                        .or_else(|| url.get_connect_url())
+                        .unwrap_or_default();
+                    log::warn!("反向代理：后端{}请求失败, 进行第{}次重试, 原因：{}", addr, times, reason);
+                    times += 1;
+                }
+                _ => return ret,
+            }
+        }
+    }
+
+    /// 处理携带幂等key的请求, 相同key及内容的请求只访问一次后端
+    async fn deal_idempotency(
+        &self,
+        req: &mut Request<Body>,
+        url: &Url,
+        key: String,
+    ) -> ProtResult<(
+        Response<Body>,
+        Option<Sender<Request<Body>>>,
+        Option<Receiver<ProtResult<Response<Body>>>>,
+    )> {
+        let limit = self.idempotency_max_body.0 as usize;
+        let (body, complete) = Idempotency::read_body(req.body_mut(), limit).await?;
+        if !complete {
+            return Ok((
+                Response::text()
+                    .status(413)
+                    .body("request body too large")
+                    .unwrap()
+                    .into_type(),
+                None,
+                None,
+            ));
+        }
+        req.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+        req.headers_mut()
+            .insert(HeaderName::CONTENT_LENGTH, format!("{}", body.len()));
+
+        let store = format!("{}", self.rule);
+        let hash = Idempotency::hash_request(req, &body);
+        let ttl = self.idempotency_ttl.0;
+        let sender = loop {
+            match Idempotency::lookup(&store, &key, hash, ttl) {
+                IdempotencyLookup::Miss(sender) => break sender,
+                IdempotencyLookup::Wait(mut receiver) => {
+                    let _ = receiver.wait_for(|v| *v).await;
+                }
+                IdempotencyLookup::Hit(res) => return Ok((res.to_response(), None, None)),
+                IdempotencyLookup::Conflict => {
+                    return Ok((
+                        Response::text()
+                            .status(422)
+                            .body("idempotency key reused with different request")
+                            .unwrap()
+                            .into_type(),
+                        None,
+                        None,
+                    ))
+                }
+            }
+        };
+
+        // 请求内容已缓存, 失败时可安全的重试下一个后端
+        let domain = url.domain.clone().unwrap_or_default();
+        let tries = ReverseHelper::get_upstream_tries(&self.upstream, &domain);
+        let mut except = vec![];
+        let mut ret = Err(ProtError::Extension("no upstream"));
+        for _ in 0..tries {
+            *req.body_mut() = Body::only(Binary::from(body.clone()));
+            ret = self.deal_reverse_proxy_except(req, url, &mut except).await;
+            if ret.is_ok() {
+                break;
+            }
+        }
+        let mut res = match ret {
+            Ok((res, _, _)) => res,
+            Err(e) => {
+                Idempotency::finish(&store, &key, None, ttl, 0);
+                let _ = sender.send(true);
+                return Err(e);
+            }
+        };
+        let (data, complete) = match Idempotency::read_body(res.body_mut(), limit).await {
+            Ok(v) => v,
+            Err(e) => {
+                Idempotency::finish(&store, &key, None, ttl, 0);
+                let _ = sender.send(true);
+                return Err(e.into());
+            }
+        };
+        let mut cached = None;
+        if complete {
+            res.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+            res.headers_mut()
+                .insert(HeaderName::CONTENT_LENGTH, format!("{}", data.len()));
+            // 服务端错误不缓存, 客户端可重试
+            if !res.status().is_server_error() {
+                cached = Some(IdempotencyResponse {
+                    status: res.status().as_u16(),
+                    headers: res.headers().clone(),
+                    body: data.clone(),
+                });
+            }
+            *res.body_mut() = Body::only(Binary::from(data));
+        } else {
+            let origin = std::mem::replace(res.body_mut(), Body::empty());
+            *res.body_mut() = Idempotency::chain_body(data, origin);
+        }
+        let max_size = self.idempotency_cache_size.0 as usize;
+        Idempotency::finish(&store, &key, cached, ttl, max_size);
+        let _ = sender.send(true);
+        Ok((res, None, None))
+    }
+
     pub fn get_log_names(&self, names: &mut HashMap<String, String>) {
         self.comm.get_log_names(names);
     }
 
+    pub fn get_access_names(&self, names: &mut HashSet<String>) {
+        self.comm.get_access_names(names);
+    }
+
     pub fn get_upstream_addr(&self) -> Option<SocketAddr> {
         let mut name = String::new();
         if let Some(r) = &self.comm.proxy_url {
@@ -220,9 +1529,9 @@ impl LocationConfig {
         }
         for stream in &self.upstream {
             if stream.name == name {
-                return stream.get_server_addr();
+                return stream.get_server_addr(None);
             } else if name == "" {
-                return stream.get_server_addr();
+                return stream.get_server_addr(None);
             }
         }
         return None;
@@ -77,6 +77,43 @@ impl Matcher {
         "/".to_string()
     }
 
+    /// 路径匹配的固定前缀, 如`/api/*`为`/api/`, 正则匹配或无前缀时为None
+    pub fn get_prefix(&self) -> Option<String> {
+        let p = self.path.as_ref()?;
+        let prefix = p.split('*').next().unwrap_or_default();
+        let is_regex = prefix.contains(|c| "^$()[]{}+?\\|".contains(c));
+        if !prefix.starts_with('/') || prefix == "/" || is_regex {
+            return None;
+        }
+        Some(prefix.to_string())
+    }
+
+    /// 按前缀或通配符匹配时固定前缀的长度, 用于多个location均匹配时优先选择更长的前缀
+    /// 正则匹配或不匹配时为0
+    pub fn match_len(&self, path: &str) -> usize {
+        match &self.path {
+            Some(p) if Helper::is_match(path, p) => {
+                p.split('*').next().unwrap_or_default().len()
+            }
+            _ => 0,
+        }
+    }
+
+    /// 路径为正则匹配时返回匹配到的分组, 下标0为完整匹配的内容
+    pub fn path_captures(&self, path: &str) -> Option<Vec<String>> {
+        let p = self.path.as_ref()?;
+        if Helper::is_match(path, p) {
+            return None;
+        }
+        let re = Helper::try_cache_regex(p)?;
+        let caps = re.captures(path)?;
+        Some(
+            caps.iter()
+                .map(|m| m.map(|m| m.as_str().to_string()).unwrap_or_default())
+                .collect(),
+        )
+    }
+
     /// 当本地限制方法时,优先匹配方法,在进行路径的匹配
     pub fn is_match_rule(&self, path: &String, req: &RecvRequest) -> ProtResult<bool>  {
         if let Some(p) = &self.path {
@@ -213,3 +250,48 @@ impl Display for Matcher {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Matcher;
+
+    #[test]
+    fn test_path_captures() {
+        let matcher = "^/tenant/(\\w+)/(.*)".parse::<Matcher>().unwrap();
+        assert_eq!(
+            matcher.path_captures("/tenant/t1/list"),
+            Some(vec![
+                "/tenant/t1/list".to_string(),
+                "t1".to_string(),
+                "list".to_string()
+            ])
+        );
+        assert_eq!(matcher.path_captures("/other"), None);
+        // 非正则的匹配无分组
+        assert_eq!("/tenant*".parse::<Matcher>().unwrap().path_captures("/tenant/t1"), None);
+    }
+
+    #[test]
+    fn test_match_len() {
+        let len = |rule: &str, path: &str| rule.parse::<Matcher>().unwrap().match_len(path);
+        assert_eq!(len("/api/v1/", "/api/v1/users"), 8);
+        assert_eq!(len("/api/", "/api/v1/users"), 5);
+        assert_eq!(len("/static/*.js", "/static/a.js"), 8);
+        assert_eq!(len("*", "/a"), 0);
+        assert_eq!(len("^/api/", "/api/v1"), 0);
+        assert_eq!(len("/api/", "/other"), 0);
+    }
+
+    #[test]
+    fn test_get_prefix() {
+        let prefix = |rule: &str| rule.parse::<Matcher>().unwrap().get_prefix();
+        assert_eq!(prefix("/grafana/"), Some("/grafana/".to_string()));
+        assert_eq!(prefix("/grafana"), Some("/grafana".to_string()));
+        assert_eq!(prefix("/static/*.js"), Some("/static/".to_string()));
+        assert_eq!(prefix("/"), None);
+        assert_eq!(prefix("*"), None);
+        assert_eq!(prefix("^/user/(\\d+)"), None);
+        assert_eq!(prefix("/user/(\\d+)"), None);
+        assert_eq!(prefix("@name"), None);
+    }
+}
@@ -10,28 +10,62 @@
 // -----
 // Created Date: 2023/10/16 04:28:22
 
+mod acme;
+mod add_header;
+mod body_limit;
+mod cache;
+mod client_cert;
 mod common;
+mod cors;
+mod der;
+mod error_page;
 mod http;
+mod idempotency;
 mod limit_req;
 mod location;
 mod matcher;
+mod pool;
+mod proxy_pass;
+mod proxy_protocol;
 mod reverse_helper;
+mod rewrite;
 mod server;
+mod split;
 mod stream;
+mod tls_option;
 mod try_paths;
 mod upstream;
 mod ws;
 
+pub use acme::{Acme, AcmeConfig, ACME_CHALLENGE_PREFIX};
+pub use add_header::{AddHeader, HSTS_HEADER};
+pub use body_limit::BodyLimit;
+pub use cache::{CacheConfig, CacheStore, CACHE_STATUS_HEADER};
+pub use client_cert::{ClientCert, VerifyClient};
 pub use common::CommonConfig;
+pub use cors::CorsConfig;
+pub use error_page::ErrorPage;
 pub use http::HttpConfig;
-pub use limit_req::{LimitReq, LimitReqMiddleware};
-pub use location::LocationConfig;
+pub use idempotency::{
+    Idempotency, IdempotencyLookup, IdempotencyResponse, IdempotencyStore, IDEMPOTENCY_KEY,
+};
+pub use limit_req::{LimitConcurrency, LimitConn, LimitReq, LimitReqMiddleware};
+pub use location::{ListenInfo, LocationConfig, PathCaptures, ProxyPeer, RequestId, ServerHidden, TlsConnection};
 pub use matcher::Matcher;
+pub use pool::{PoolStats, UpstreamPool};
+pub use proxy_pass::ProxyPass;
+pub use proxy_protocol::{ProxyProtocol, ProxyProtocolVersion};
 pub use reverse_helper::ReverseHelper;
-pub use server::ServerConfig;
+pub use rewrite::RewriteConfig;
+pub use server::{LocationMatch, ServerConfig};
+pub use split::{SplitChosen, SplitConfig, SplitKey, SplitUpstream};
 pub use stream::{StreamConfig, StreamUdp};
+pub use tls_option::{TlsOption, TlsVersion, DEFAULT_ALPN};
 pub use try_paths::TryPathsConfig;
-pub use upstream::UpstreamConfig;
+pub use upstream::{
+    ActiveCheckConfig, SingleStreamConfig, StickyConfig, UpstreamBalance, UpstreamConfig,
+    UpstreamConnGuard, UNIX_PREFIX,
+};
 
 use std::{
     fmt::{self},
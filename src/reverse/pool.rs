@@ -0,0 +1,437 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/26 10:21:37
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc::{Receiver, Sender};
+use webparse::{HeaderName, Request, Response};
+use wenmeng::{Body, ProtResult};
+
+use super::LocationConfig;
+use crate::{FlowSlot, H2Ping};
+
+/// 复用的后端连接
+pub(crate) struct CacheClient {
+    pub sender: Sender<Request<Body>>,
+    pub receiver: Receiver<ProtResult<Response<Body>>>,
+    /// 后端地址
+    pub addr: Option<SocketAddr>,
+    /// 是否为HTTP/2的连接
+    pub is_h2: bool,
+    /// 最后一次收到返回的时间
+    pub last: Instant,
+    /// 后端是否保持连接, 声明了`Connection: close`时不再复用
+    pub keep_alive: bool,
+    /// 建立连接的时间
+    pub created: Instant,
+    /// 放入过连接池时记录连接池的连接数, 连接关闭时减少
+    open: Option<Arc<AtomicUsize>>,
+    /// 按客户端窗口转发的连接, 复用时切换为新请求的窗口
+    pub flow: Option<Arc<FlowSlot>>,
+    /// 建立连接时以PROXY协议发送的客户端地址
+    pub peer: Option<SocketAddr>,
+    /// HTTP/2连接的PING探测, 在连接池中空闲时发送PING
+    pub ping: Option<Arc<H2Ping>>,
+}
+
+impl CacheClient {
+    pub fn new(
+        sender: Sender<Request<Body>>,
+        receiver: Receiver<ProtResult<Response<Body>>>,
+        addr: Option<SocketAddr>,
+        is_h2: bool,
+    ) -> Self {
+        CacheClient {
+            sender,
+            receiver,
+            addr,
+            is_h2,
+            last: Instant::now(),
+            keep_alive: true,
+            created: Instant::now(),
+            open: None,
+            flow: None,
+            peer: None,
+            ping: None,
+        }
+    }
+
+    /// 判断该连接是否可以复用, HTTP/2的连接超时未回应PING将被淘汰
+    pub fn is_usable(&self) -> bool {
+        if self.sender.is_closed() || !self.keep_alive {
+            return false;
+        }
+        !self.ping.as_ref().is_some_and(|p| p.is_dead())
+    }
+
+    /// 后端声明了`Connection: close`的返回结束后连接即关闭
+    pub fn is_keep_alive(res: &Response<Body>) -> bool {
+        match res.headers().get_str_value(&HeaderName::CONNECTION) {
+            Some(v) => !v.split(',').any(|v| v.trim().eq_ignore_ascii_case("close")),
+            None => true,
+        }
+    }
+
+    /// 是否超出空闲时间或最长存活时间, 为0时不限制
+    fn is_expired(&self, idle_timeout: Duration, lifetime: Duration) -> bool {
+        (!idle_timeout.is_zero() && self.last.elapsed() > idle_timeout)
+            || (!lifetime.is_zero() && self.created.elapsed() > lifetime)
+    }
+}
+
+impl Drop for CacheClient {
+    fn drop(&mut self) {
+        if let Some(open) = &self.open {
+            open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// 放入返回的extensions中, 返回体发送完毕释放返回时将连接放回连接池,
+/// 避免返回体仍在传输时连接被其它请求使用
+pub(crate) struct PoolReturn {
+    pool: Arc<UpstreamPool>,
+    key: LocationConfig,
+    client: Option<CacheClient>,
+    max_idle: usize,
+}
+
+impl PoolReturn {
+    pub fn new(
+        pool: Arc<UpstreamPool>,
+        key: LocationConfig,
+        client: CacheClient,
+        max_idle: usize,
+    ) -> Self {
+        PoolReturn {
+            pool,
+            key,
+            client: Some(client),
+            max_idle,
+        }
+    }
+}
+
+impl Drop for PoolReturn {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool
+                .checkin(self.key.clone_only_hash(), client, self.max_idle);
+        }
+    }
+}
+
+/// 从连接池取出的连接, 放入请求的extensions中, 转发时与新建的连接经过相同的处理,
+/// 使用后交回以便上层放回连接池
+#[derive(Clone)]
+pub(crate) struct ReusedClient(Arc<Mutex<(Option<CacheClient>, bool)>>);
+
+impl ReusedClient {
+    pub fn new(client: CacheClient) -> Self {
+        ReusedClient(Arc::new(Mutex::new((Some(client), false))))
+    }
+
+    /// 取出尚未使用的连接
+    pub fn take(&self) -> Option<CacheClient> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.1 {
+            return None;
+        }
+        inner.0.take()
+    }
+
+    /// 收到返回后交回已使用的连接
+    pub fn give_back(&self, client: CacheClient) {
+        *self.0.lock().unwrap() = (Some(client), true);
+    }
+
+    /// 取回连接及是否已使用, 转发失败时连接已丢弃
+    pub fn into_client(self) -> Option<(CacheClient, bool)> {
+        let mut inner = self.0.lock().unwrap();
+        let used = inner.1;
+        inner.0.take().map(|c| (c, used))
+    }
+}
+
+/// 连接池中各状态的连接数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub open: usize,
+    pub idle: usize,
+    pub in_use: usize,
+}
+
+/// 同一server的各location共享的后端连接池, 所有客户端连接均可复用,
+/// 处理请求时取出连接, 收到返回后如连接仍可用则放回
+#[derive(Default)]
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<LocationConfig, VecDeque<CacheClient>>>,
+    open: Arc<AtomicUsize>,
+}
+
+impl UpstreamPool {
+    /// 取出该location最近放回的可用连接, 已关闭或超时的连接直接关闭,
+    /// 指定`addr`时只取出连接该后端的连接, 如会话保持固定的后端,
+    /// `peer`为需以PROXY协议发送的客户端地址, 只取出发送过该地址的连接
+    pub(crate) fn checkout(
+        &self,
+        key: &LocationConfig,
+        idle_timeout: Duration,
+        lifetime: Duration,
+        addr: Option<&SocketAddr>,
+        peer: Option<&SocketAddr>,
+    ) -> Option<CacheClient> {
+        let client = {
+            let mut idle = self.idle.lock().ok()?;
+            let clients = idle.get_mut(key)?;
+            let mut found = None;
+            let mut index = clients.len();
+            while index > 0 {
+                index -= 1;
+                if addr.is_some_and(|addr| clients[index].addr.as_ref() != Some(addr))
+                    || clients[index].peer.as_ref() != peer
+                {
+                    continue;
+                }
+                let client = clients.remove(index)?;
+                if client.is_usable() && !client.is_expired(idle_timeout, lifetime) {
+                    if let Some(ping) = &client.ping {
+                        ping.stop();
+                    }
+                    found = Some(client);
+                    break;
+                }
+                log::trace!("复用连接已关闭或空闲过久, 关闭该连接");
+            }
+            found
+        };
+        log::debug!("后端连接池取出连接: {:?}", self.stats());
+        client
+    }
+
+    /// 放回可继续使用的连接, 超出`max_idle`时关闭最久未使用的连接, 为0时不保留,
+    /// HTTP/2的连接空闲期间发送PING
+    pub(crate) fn checkin(&self, key: LocationConfig, mut client: CacheClient, max_idle: usize) {
+        if max_idle == 0 || !client.is_usable() {
+            return;
+        }
+        if let Some(ping) = client.ping.as_ref().filter(|_| client.is_h2) {
+            ping.start();
+        }
+        if client.open.is_none() {
+            self.open.fetch_add(1, Ordering::Relaxed);
+            client.open = Some(self.open.clone());
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.retain(|_, clients| {
+                clients.retain(|c| c.is_usable());
+                !clients.is_empty()
+            });
+            let clients = idle.entry(key).or_default();
+            clients.push_back(client);
+            while clients.len() > max_idle {
+                log::trace!("空闲连接数超出{}, 关闭最久未使用的连接", max_idle);
+                clients.pop_front();
+            }
+        }
+        log::debug!("后端连接池放回连接: {:?}", self.stats());
+    }
+
+    /// 当前的连接数, 使用中的连接为已放入过连接池且正在处理请求的连接
+    pub fn stats(&self) -> PoolStats {
+        let idle = self
+            .idle
+            .lock()
+            .map(|idle| idle.values().map(|c| c.len()).sum())
+            .unwrap_or(0);
+        let open = self.open.load(Ordering::Relaxed);
+        PoolStats {
+            open,
+            idle,
+            in_use: open.saturating_sub(idle),
+        }
+    }
+}
+
+impl Debug for UpstreamPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamPool")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::mpsc::{channel, Receiver};
+    use webparse::{Request, Response};
+    use wenmeng::Body;
+
+    use super::{CacheClient, PoolStats, UpstreamPool};
+    use crate::{H2Ping, LocationConfig};
+
+    #[test]
+    fn test_cache_client_usable() {
+        let (sender, req_receiver) = channel(1);
+        let (_res_sender, receiver) = channel(1);
+        let mut client = CacheClient::new(sender, receiver, None, true);
+        let interval = Duration::from_secs(5);
+        client.ping = Some(Arc::new(H2Ping::new(interval, interval)));
+        assert!(client.is_usable());
+        // 后端声明关闭的连接不再复用
+        client.keep_alive = false;
+        assert!(!client.is_usable());
+        client.keep_alive = true;
+        drop(req_receiver);
+        assert!(!client.is_usable());
+    }
+
+    fn build_client() -> (CacheClient, Receiver<Request<Body>>) {
+        let (sender, req_receiver) = channel(1);
+        let (_res_sender, receiver) = channel(1);
+        (
+            CacheClient::new(sender, receiver, None, false),
+            req_receiver,
+        )
+    }
+
+    fn build_location(rule: &str) -> LocationConfig {
+        let mut location = LocationConfig::new();
+        location.rule = rule.parse().unwrap();
+        location
+    }
+
+    #[test]
+    fn test_upstream_pool() {
+        let pool = UpstreamPool::default();
+        let zero = Duration::ZERO;
+        let mut receivers = vec![];
+        for _ in 0..6 {
+            let (client, receiver) = build_client();
+            receivers.push(receiver);
+            pool.checkin(build_location("/"), client, 4);
+        }
+        // 超出时关闭最久未使用的连接, 并关闭其通道
+        let stats = PoolStats {
+            open: 4,
+            idle: 4,
+            in_use: 0,
+        };
+        assert_eq!(pool.stats(), stats);
+        assert!(receivers[0].is_closed());
+        assert!(!receivers[5].is_closed());
+
+        // 取出最近放回的连接, 不同的location互不影响
+        let client = pool
+            .checkout(&build_location("/"), zero, zero, None, None)
+            .unwrap();
+        assert_eq!(pool.stats().in_use, 1);
+        assert!(pool
+            .checkout(&build_location("/a"), zero, zero, None, None)
+            .is_none());
+        drop(client);
+        assert_eq!(pool.stats().open, 3);
+        assert!(receivers[5].is_closed());
+
+        // 已关闭及超时的连接不再取出
+        let pool = UpstreamPool::default();
+        let (client, receiver) = build_client();
+        pool.checkin(build_location("/"), client, 4);
+        drop(receiver);
+        assert!(pool
+            .checkout(&build_location("/"), zero, zero, None, None)
+            .is_none());
+        let (mut client, _receiver) = build_client();
+        client.last = Instant::now() - Duration::from_secs(6);
+        pool.checkin(build_location("/"), client, 4);
+        let timeout = Duration::from_secs(5);
+        assert!(pool
+            .checkout(&build_location("/"), timeout, zero, None, None)
+            .is_none());
+        let (mut client, _receiver) = build_client();
+        client.created = Instant::now() - Duration::from_secs(6);
+        pool.checkin(build_location("/"), client, 4);
+        assert!(pool
+            .checkout(&build_location("/"), zero, timeout, None, None)
+            .is_none());
+        assert_eq!(pool.stats(), PoolStats::default());
+
+        // 指定后端时只取出连接该后端的连接
+        let addr = "127.0.0.1:19103".parse().unwrap();
+        let (mut client, _receiver) = build_client();
+        client.addr = Some(addr);
+        pool.checkin(build_location("/"), client, 4);
+        let (client, _other) = build_client();
+        pool.checkin(build_location("/"), client, 4);
+        let client = pool
+            .checkout(&build_location("/"), zero, zero, Some(&addr), None)
+            .unwrap();
+        assert_eq!(client.addr, Some(addr));
+        assert!(pool
+            .checkout(&build_location("/"), zero, zero, Some(&addr), None)
+            .is_none());
+        assert_eq!(pool.stats().idle, 1);
+        drop(client);
+
+        // 发送过PROXY协议头的连接只复用于同一客户端
+        let peer = "127.0.0.1:19104".parse().unwrap();
+        let (mut client, _receiver) = build_client();
+        client.peer = Some(peer);
+        pool.checkin(build_location("/"), client, 4);
+        let other = "127.0.0.1:19105".parse().unwrap();
+        assert!(pool
+            .checkout(&build_location("/"), zero, zero, None, Some(&other))
+            .is_none());
+        let client = pool
+            .checkout(&build_location("/"), zero, zero, None, Some(&peer))
+            .unwrap();
+        assert_eq!(client.peer, Some(peer));
+        let client = pool
+            .checkout(&build_location("/"), zero, zero, None, None)
+            .unwrap();
+        assert_eq!(client.peer, None);
+
+        // 为0时不保留连接
+        let (client, receiver) = build_client();
+        pool.checkin(build_location("/"), client, 0);
+        assert!(receiver.is_closed());
+    }
+
+    #[test]
+    fn test_cache_client_keep_alive() {
+        let build = |conn: Option<&'static str>| {
+            let mut builder = Response::builder();
+            if let Some(conn) = conn {
+                builder = builder.header("Connection", conn);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        assert!(CacheClient::is_keep_alive(&build(None)));
+        assert!(CacheClient::is_keep_alive(&build(Some("keep-alive"))));
+        assert!(!CacheClient::is_keep_alive(&build(Some("Close"))));
+        assert!(!CacheClient::is_keep_alive(&build(Some("upgrade, close"))));
+    }
+}
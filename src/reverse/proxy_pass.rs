@@ -0,0 +1,87 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/16 10:23:51
+
+use webparse::{Request, Scheme, Url};
+use wenmeng::Body;
+
+use crate::Helper;
+
+/// 按请求动态计算后端地址, 如`http://$http_x_backend:8080/`,
+/// 计算后的目标须匹配允许列表, 避免被利用访问任意的内部地址
+pub struct ProxyPass;
+
+impl ProxyPass {
+    /// 将模板中的变量替换成请求中的值并解析成地址, 仅支持http及https
+    pub fn resolve(req: &Request<Body>, template: &str) -> Option<Url> {
+        let value = Helper::format_header_value(req, template);
+        let url = Url::parse(value.into_bytes()).ok()?;
+        if !matches!(url.scheme, Scheme::Http | Scheme::Https) {
+            return None;
+        }
+        match &url.domain {
+            Some(domain) if !domain.is_empty() => Some(url),
+            _ => None,
+        }
+    }
+
+    /// 目标是否匹配允许列表, 列表为空时均不允许
+    /// 如`*.internal`只匹配域名, `10.0.0.*:8080`同时匹配端口, `*`可匹配任意的域名字符
+    pub fn is_allowed(allow: &[String], url: &Url) -> bool {
+        let domain = match &url.domain {
+            Some(domain) => domain.to_ascii_lowercase(),
+            None => return false,
+        };
+        let port = url.port.unwrap_or(if url.scheme == Scheme::Https { 443 } else { 80 });
+        allow.iter().any(|pattern| {
+            let target = if pattern.contains(':') {
+                format!("{}:{}", domain, port)
+            } else {
+                domain.clone()
+            };
+            let re = format!(
+                "^{}$",
+                regex::escape(&pattern.to_ascii_lowercase()).replace(r"\*", "[a-z0-9.-]*")
+            );
+            Helper::try_cache_regex(&re)
+                .map(|re| re.is_match(&target))
+                .unwrap_or(false)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{Request, Url};
+    use wenmeng::Body;
+
+    use super::ProxyPass;
+
+    #[test]
+    fn test_proxy_pass() {
+        let req = Request::builder()
+            .url("http://example.com/api")
+            .header("X-Backend", "orders")
+            .body(Body::empty())
+            .unwrap();
+        let url = ProxyPass::resolve(&req, "http://$http_x_backend-backend.internal/").unwrap();
+        assert_eq!(url.domain.as_deref(), Some("orders-backend.internal"));
+        assert!(ProxyPass::resolve(&req, "ftp://$http_x_backend/").is_none());
+
+        let allow = vec!["*.internal".to_string(), "10.0.0.*:8080".to_string()];
+        assert!(ProxyPass::is_allowed(&allow, &url));
+        let parse = |s: &str| Url::parse(s.as_bytes().to_vec()).unwrap();
+        assert!(ProxyPass::is_allowed(&allow, &parse("http://10.0.0.3:8080/")));
+        assert!(!ProxyPass::is_allowed(&allow, &parse("http://10.0.0.3/")));
+        assert!(!ProxyPass::is_allowed(&allow, &parse("http://internal.evil.com/")));
+        assert!(!ProxyPass::is_allowed(&[], &url));
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/28 09:12:36
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// v1头的起始标识
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// v2头的起始标识
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// v1头的最大长度, 包含结尾的`\r\n`
+const V1_MAX_LEN: usize = 107;
+
+/// 发送给后端的PROXY协议版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// 文本格式
+    V1,
+    /// 二进制格式
+    V2,
+}
+
+/// PROXY协议, 由前置的负载均衡(如AWS NLB、HAProxy)在连接开始时发送,
+/// 携带真实的客户端地址, 支持v1文本格式及v2二进制格式
+pub struct ProxyProtocol;
+
+impl ProxyProtocol {
+    /// 未配置`client_header_timeout`时等待协议头的时间
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// 读取并解析协议头, 只读取协议头本身的数据, 后续数据留给TLS或HTTP处理,
+    /// 返回其中的客户端地址, LOCAL或UNKNOWN等未携带地址时返回None
+    pub async fn read_header<T>(stream: &mut T) -> io::Result<Option<SocketAddr>>
+    where
+        T: AsyncRead + Unpin,
+    {
+        let mut head = vec![0u8; V1_PREFIX.len()];
+        stream.read_exact(&mut head).await?;
+        if head == V1_PREFIX {
+            // 逐字节读取直至行尾, 避免读取到协议头之后的数据
+            let mut byte = [0u8; 1];
+            while !head.ends_with(b"\r\n") {
+                if head.len() >= V1_MAX_LEN {
+                    return Err(Self::invalid("v1协议头过长"));
+                }
+                stream.read_exact(&mut byte).await?;
+                head.push(byte[0]);
+            }
+            return Self::parse_v1(&head);
+        }
+        if !V2_SIGNATURE.starts_with(&head) {
+            return Err(Self::invalid("未收到协议头"));
+        }
+        head.resize(16, 0);
+        stream.read_exact(&mut head[V1_PREFIX.len()..]).await?;
+        let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        Self::parse_v2(&head, &body)
+    }
+
+    /// 解析v1的协议头, 如`PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n`
+    pub fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let line = line
+            .strip_prefix(V1_PREFIX)
+            .and_then(|l| l.strip_suffix(b"\r\n"))
+            .ok_or_else(|| Self::invalid("v1协议头格式错误"))?;
+        let line = std::str::from_utf8(line).map_err(|_| Self::invalid("v1协议头非ASCII"))?;
+        let parts: Vec<&str> = line.split(' ').collect();
+        match parts[0] {
+            "UNKNOWN" => return Ok(None),
+            "TCP4" | "TCP6" if parts.len() == 5 => {}
+            _ => return Err(Self::invalid("v1协议头格式错误")),
+        }
+        let ip = parts[1]
+            .parse::<IpAddr>()
+            .map_err(|_| Self::invalid("v1协议头地址错误"))?;
+        if ip.is_ipv4() != (parts[0] == "TCP4") || parts[2].parse::<IpAddr>().is_err() {
+            return Err(Self::invalid("v1协议头地址错误"));
+        }
+        let port = parts[3]
+            .parse::<u16>()
+            .map_err(|_| Self::invalid("v1协议头端口错误"))?;
+        parts[4]
+            .parse::<u16>()
+            .map_err(|_| Self::invalid("v1协议头端口错误"))?;
+        Ok(Some(SocketAddr::new(ip, port)))
+    }
+
+    /// 解析v2的协议头, `head`为固定的16字节, `body`为其后声明长度的地址及TLV数据
+    pub fn parse_v2(head: &[u8], body: &[u8]) -> io::Result<Option<SocketAddr>> {
+        if head.len() != 16 || !head.starts_with(V2_SIGNATURE) {
+            return Err(Self::invalid("v2协议头格式错误"));
+        }
+        if head[12] >> 4 != 2 {
+            return Err(Self::invalid("v2协议头版本错误"));
+        }
+        match head[12] & 0x0F {
+            // LOCAL, 如负载均衡的健康检查, 使用连接本身的地址
+            0 => return Ok(None),
+            1 => {}
+            _ => return Err(Self::invalid("v2协议头命令错误")),
+        }
+        match head[13] >> 4 {
+            // AF_INET
+            1 if body.len() >= 12 => {
+                let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+                let port = u16::from_be_bytes([body[8], body[9]]);
+                Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+            }
+            // AF_INET6
+            2 if body.len() >= 36 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&body[..16]);
+                let port = u16::from_be_bytes([body[32], body[33]]);
+                Ok(Some(SocketAddr::new(
+                    IpAddr::V6(Ipv6Addr::from(octets)),
+                    port,
+                )))
+            }
+            // AF_UNSPEC及AF_UNIX未携带IP地址
+            0 | 3 => Ok(None),
+            _ => Err(Self::invalid("v2协议头地址错误")),
+        }
+    }
+
+    /// 编码协议头, `src`为客户端地址, `dst`为客户端接入的地址, 地址族不同时均转为IPv6
+    pub fn encode(version: ProxyProtocolVersion, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let (src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V6(d)) => (IpAddr::V6(s.to_ipv6_mapped()), IpAddr::V6(d)),
+            (IpAddr::V6(s), IpAddr::V4(d)) => (IpAddr::V6(s), IpAddr::V6(d.to_ipv6_mapped())),
+            ips => ips,
+        };
+        match version {
+            ProxyProtocolVersion::V1 => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if src_ip.is_ipv4() { "TCP4" } else { "TCP6" },
+                src_ip,
+                dst_ip,
+                src.port(),
+                dst.port()
+            )
+            .into_bytes(),
+            ProxyProtocolVersion::V2 => {
+                let mut data = V2_SIGNATURE.to_vec();
+                // 版本2, PROXY命令
+                data.push(0x21);
+                match (src_ip, dst_ip) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        data.extend_from_slice(&[0x11, 0, 12]);
+                        data.extend_from_slice(&s.octets());
+                        data.extend_from_slice(&d.octets());
+                    }
+                    (IpAddr::V6(s), IpAddr::V6(d)) => {
+                        data.extend_from_slice(&[0x21, 0, 36]);
+                        data.extend_from_slice(&s.octets());
+                        data.extend_from_slice(&d.octets());
+                    }
+                    _ => unreachable!(),
+                }
+                data.extend_from_slice(&src.port().to_be_bytes());
+                data.extend_from_slice(&dst.port().to_be_bytes());
+                data
+            }
+        }
+    }
+
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("PROXY协议: {}", msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{ProxyProtocol, ProxyProtocolVersion};
+
+    fn v2_header(cmd: u8, fam: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        data.push(0x20 | cmd);
+        data.push(fam);
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let addr = "192.168.0.1:56324".parse::<SocketAddr>().unwrap();
+        let line = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n";
+        assert_eq!(ProxyProtocol::parse_v1(line).unwrap(), Some(addr));
+        let addr = "[2001:db8::1]:1000".parse::<SocketAddr>().unwrap();
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 1000 443\r\n";
+        assert_eq!(ProxyProtocol::parse_v1(line).unwrap(), Some(addr));
+        let line = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(ProxyProtocol::parse_v1(line).unwrap(), None);
+
+        assert!(ProxyProtocol::parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n").is_err());
+        assert!(ProxyProtocol::parse_v1(b"PROXY TCP6 192.168.0.1 192.168.0.11 1 2\r\n").is_err());
+        assert!(
+            ProxyProtocol::parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 1 70000\r\n").is_err()
+        );
+        assert!(ProxyProtocol::parse_v1(b"GET / HTTP/1.1\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let body = [127, 0, 0, 2, 127, 0, 0, 1, 0x1F, 0x90, 0, 80, 1, 0, 1, 0];
+        let data = v2_header(1, 0x11, &body);
+        let addr = "127.0.0.2:8080".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            ProxyProtocol::parse_v2(&data[..16], &data[16..]).unwrap(),
+            Some(addr)
+        );
+
+        let mut body = [0u8; 36];
+        body[15] = 1;
+        body[32..34].copy_from_slice(&443u16.to_be_bytes());
+        let data = v2_header(1, 0x21, &body);
+        let addr = "[::1]:443".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            ProxyProtocol::parse_v2(&data[..16], &data[16..]).unwrap(),
+            Some(addr)
+        );
+
+        // LOCAL命令不携带地址, 地址长度不足为错误
+        let data = v2_header(0, 0x00, &[]);
+        assert_eq!(
+            ProxyProtocol::parse_v2(&data[..16], &data[16..]).unwrap(),
+            None
+        );
+        let data = v2_header(1, 0x11, &[127, 0, 0, 1]);
+        assert!(ProxyProtocol::parse_v2(&data[..16], &data[16..]).is_err());
+        let data = v2_header(2, 0x11, &[0u8; 12]);
+        assert!(ProxyProtocol::parse_v2(&data[..16], &data[16..]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode() {
+        let src = "10.0.0.1:1234".parse::<SocketAddr>().unwrap();
+        let dst = "10.0.0.2:443".parse::<SocketAddr>().unwrap();
+        let data = ProxyProtocol::encode(ProxyProtocolVersion::V1, src, dst);
+        assert_eq!(data, b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 443\r\n");
+        let data = ProxyProtocol::encode(ProxyProtocolVersion::V2, src, dst);
+        assert_eq!(data.len(), 28);
+        assert_eq!(
+            ProxyProtocol::read_header(&mut &data[..]).await.unwrap(),
+            Some(src)
+        );
+
+        // 地址族不同时按IPv6编码
+        let dst = "[::1]:443".parse::<SocketAddr>().unwrap();
+        let mapped = "[::ffff:10.0.0.1]:1234".parse::<SocketAddr>().unwrap();
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let data = ProxyProtocol::encode(version, src, dst);
+            assert_eq!(
+                ProxyProtocol::read_header(&mut &data[..]).await.unwrap(),
+                Some(mapped)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        // 协议头分多次到达, 读取后不影响之后的数据
+        let (mut client, mut server) = duplex(1024);
+        let data = b"PROXY TCP4 10.0.0.1 10.0.0.2 1234 80\r\nGET / HTTP/1.1\r\n\r\n";
+        let task = tokio::spawn(async move {
+            for part in data.chunks(5) {
+                client.write_all(part).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            }
+            client
+        });
+        let addr = ProxyProtocol::read_header(&mut server).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.1:1234".parse().unwrap()));
+        let _client = task.await.unwrap();
+        let mut rest = [0u8; 18];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"GET / HTTP/1.1\r\n\r\n");
+
+        let (mut client, mut server) = duplex(1024);
+        let mut data = v2_header(1, 0x11, &[10, 0, 0, 3, 10, 0, 0, 4, 0, 1, 0, 2]);
+        data.extend_from_slice(b"\x16\x03\x01");
+        client.write_all(&data[..10]).await.unwrap();
+        let task = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            client.write_all(&data[10..]).await.unwrap();
+            client
+        });
+        let addr = ProxyProtocol::read_header(&mut server).await.unwrap();
+        assert_eq!(addr, Some("10.0.0.3:1".parse().unwrap()));
+        let _client = task.await.unwrap();
+        let mut rest = [0u8; 3];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"\x16\x03\x01");
+
+        let (mut client, mut server) = duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert!(ProxyProtocol::read_header(&mut server).await.is_err());
+    }
+}
@@ -10,42 +10,288 @@
 // -----
 // Created Date: 2023/10/21 10:39:07
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashSet, io, net::{IpAddr, SocketAddr}, sync::Arc};
 
-use wenmeng::{RecvRequest};
+use tokio::sync::OwnedSemaphorePermit;
+use webparse::{Method, Request, Response, Scheme, Url};
+use wenmeng::{Body, ProtError, ProtResult, RecvRequest};
 
-use super::{UpstreamConfig, ServerConfig, LocationConfig};
+use crate::{H2Ping, IpSets};
+
+use super::{UpstreamConfig, ServerConfig, LocationConfig, LocationMatch, ProxyProtocolVersion, SingleStreamConfig};
 
 
 pub struct ReverseHelper;
 
 impl ReverseHelper {
+    /// 从右往左跳过可信的代理, 取X-Forwarded-For中首个不可信的地址, 均可信时取最左侧的地址
+    /// 遇到无法解析的地址时停止, 取已解析的最后一个地址
+    pub fn resolve_client_ip(trusted: &IpSets, forwarded: Option<&str>) -> Option<IpAddr> {
+        let mut client = None;
+        for hop in forwarded?.rsplit(',') {
+            let ip = match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => break,
+            };
+            client = Some(ip);
+            if !trusted.contains(&ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// 连接或等待后端超时
+    pub fn is_upstream_timeout(e: &ProtError) -> bool {
+        match e {
+            ProtError::IoError(e) => e.kind() == io::ErrorKind::TimedOut,
+            e => e.is_timeout() == (true, true),
+        }
+    }
+
+    /// 无法连接后端或后端异常断开
+    pub fn is_upstream_unavailable(e: &ProtError) -> bool {
+        match e {
+            ProtError::IoError(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            ),
+            _ => false,
+        }
+    }
 
-    pub fn get_upstream_addr(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<SocketAddr> {
+    pub fn get_upstream_addr(upstream: &Vec<UpstreamConfig>, name: &str, client: Option<&SocketAddr>) -> Option<SocketAddr> {
         for stream in upstream {
             if &stream.name == name {
-                return stream.get_server_addr()
+                return stream.get_server_addr(client)
             } else if name == "" {
-                return stream.get_server_addr()
+                return stream.get_server_addr(client)
             }
         }
         return None;
     }
+
+    /// 获取后端地址, 跳过已尝试过的后端
+    pub fn get_upstream_addr_except(upstream: &[UpstreamConfig], name: &str, client: Option<&SocketAddr>, except: &[SocketAddr]) -> Option<SocketAddr> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_server_addr_except(client, except);
+            }
+        }
+        None
+    }
+
+    /// 配置为`unix:/run/app.sock`的proxy_url中unix socket的路径
+    pub fn unix_url_path(url: &Url) -> Option<&str> {
+        match &url.scheme {
+            Scheme::Extension(s) if s == "unix" => Some(&url.path),
+            _ => None,
+        }
+    }
+
+    /// 代理的目标为unix socket时的路径, proxy_url为unix socket或者名为`name`的upstream配置了unix socket
+    pub fn get_unix_path(upstream: &[UpstreamConfig], name: &str, url: &Url) -> Option<String> {
+        if let Some(path) = Self::unix_url_path(url) {
+            return Some(path.to_string());
+        }
+        upstream
+            .iter()
+            .find(|s| s.name == name)
+            .and_then(|s| s.get_unix_path())
+    }
+
+    /// 请求的会话保持cookie固定的可用后端
+    pub fn get_sticky_addr(upstream: &[UpstreamConfig], name: &str, req: &Request<Body>) -> Option<SocketAddr> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_sticky_addr(req.headers().get_cookie().as_deref());
+            }
+        }
+        None
+    }
+
+    /// 连接upstream时需发送的PROXY协议版本
+    pub fn get_proxy_protocol(upstream: &[UpstreamConfig], name: &str) -> Option<ProxyProtocolVersion> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.proxy_protocol;
+            }
+        }
+        None
+    }
+
+    /// 获取后端地址对应的配置
+    pub fn get_upstream_server(upstream: &[UpstreamConfig], name: &str, addr: &SocketAddr) -> Option<SingleStreamConfig> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.get_server(addr);
+            }
+        }
+        None
+    }
+
+    /// 新建连接的HTTP/2 PING探测, 未配置时返回None
+    pub fn get_h2_ping(upstream: &[UpstreamConfig], name: &str) -> Option<Arc<H2Ping>> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.build_h2_ping();
+            }
+        }
+        None
+    }
+
+    /// 获取upstream建立连接的许可, 未配置上限时返回None
+    pub async fn get_connect_permit(upstream: &[UpstreamConfig], name: &str) -> Option<OwnedSemaphorePermit> {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.connect_permit().await;
+            }
+        }
+        None
+    }
+
+    /// 可重试的次数, 即该upstream中后端的数量
+    pub fn get_upstream_tries(upstream: &[UpstreamConfig], name: &str) -> usize {
+        for stream in upstream {
+            if stream.name == name || name.is_empty() {
+                return stream.servers().len().max(1);
+            }
+        }
+        1
+    }
     
-    pub fn get_location_by_req<'a>(servers: &'a Vec<Arc<ServerConfig>>, req: &RecvRequest) -> Option<&'a LocationConfig> {
-        let server_len = servers.len();
-        let host = req.get_host().unwrap_or(String::new());
-        // 不管有没有匹配, 都执行最后一个
-        for (index, s) in servers.iter().enumerate() {
-            if s.up_name == host || host.is_empty() || index == server_len - 1 {
-                let path = req.path().clone();
-                for idx in 0..s.location.len() {
-                    if s.location[idx].is_match_rule(&path, req) {
-                        return Some(&s.location[idx]);
-                    }
-                }
+    /// 去除Host中的端口, 如`example.com:443`为`example.com`, `[::1]:8080`为`[::1]`
+    pub fn strip_port(host: &str) -> &str {
+        let host = host.trim();
+        if host.starts_with('[') {
+            match host.find(']') {
+                Some(i) => &host[..=i],
+                None => host,
+            }
+        } else {
+            match host.split_once(':') {
+                Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+                _ => host,
             }
         }
-        return None;
     }
-}
\ No newline at end of file
+
+    /// 规范化Host用于比较, 转为小写并去除端口, 如`Example.com:443`为`example.com`
+    pub fn normalize_host(host: &str) -> String {
+        Self::strip_port(host).to_ascii_lowercase()
+    }
+
+    /// 生成跳转至HTTPS的返回, 去除Host中的端口并保留请求的路径及参数, 未携带Host时使用`server_name`,
+    /// GET及HEAD返回301, 其它方法返回308以保留请求的方法及内容
+    pub fn redirect_https(req: &Request<Body>, server_name: &str) -> ProtResult<Response<Body>> {
+        let host = req.get_host().unwrap_or_default();
+        let mut host = Self::strip_port(&host);
+        if host.is_empty() && !server_name.starts_with("*.") {
+            host = Self::strip_port(server_name);
+        }
+        if host.is_empty() {
+            return Ok(Response::text().status(400).body("missing host")?.into_type());
+        }
+        let url = req.url();
+        let uri = match &url.query {
+            Some(query) => format!("{}?{}", url.path, query),
+            None => url.path.clone(),
+        };
+        let status = if req.method() == &Method::Get || req.method() == &Method::Head {
+            301
+        } else {
+            308
+        };
+        Ok(Response::text()
+            .status(status)
+            .header("Location", format!("https://{}{}", host, uri))
+            .body("")?
+            .into_type())
+    }
+
+    /// 判断Host是否匹配`*.example.com`形式的通配名, 通配符仅匹配一级, 如`a.example.com`匹配但`a.b.example.com`不匹配
+    pub fn is_wildcard_match(name: &str, host: &str) -> bool {
+        match name.strip_prefix("*.") {
+            Some(suffix) => host
+                .split_once('.')
+                .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+            None => false,
+        }
+    }
+
+    /// 按名称查找`up_name`匹配的server, 完全匹配优先于通配名, 比较时忽略大小写及端口
+    pub fn find_server_name<'a>(servers: &'a [Arc<ServerConfig>], name: &str) -> Option<&'a Arc<ServerConfig>> {
+        let name = Self::normalize_host(name);
+        if name.is_empty() {
+            return None;
+        }
+        servers
+            .iter()
+            .find(|s| Self::normalize_host(&s.up_name) == name)
+            .or_else(|| {
+                servers
+                    .iter()
+                    .find(|s| Self::is_wildcard_match(&Self::normalize_host(&s.up_name), &name))
+            })
+    }
+
+    /// 按Host选择处理的server, 优先匹配的`up_name`, 其次为`default_server`, 再次为未配置`up_name`的server
+    /// 比较时忽略大小写及端口, 均不匹配时返回None, 不再交由任意的server处理
+    pub fn select_server<'a>(servers: &'a [Arc<ServerConfig>], host: &str) -> Option<&'a Arc<ServerConfig>> {
+        if let Some(s) = Self::find_server_name(servers, host) {
+            return Some(s);
+        }
+        servers
+            .iter()
+            .find(|s| s.default_server)
+            .or_else(|| servers.iter().find(|s| s.up_name.is_empty()))
+    }
+
+    pub fn get_location_by_req<'a>(servers: &'a [Arc<ServerConfig>], req: &RecvRequest) -> Option<&'a LocationConfig> {
+        let host = req.get_host().unwrap_or_default();
+        let s = Self::select_server(servers, &host)?;
+        match s.match_location(req, &HashSet::new()) {
+            LocationMatch::Found(idx) => Some(&s.location[idx]),
+            _ => None,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use crate::IpSets;
+
+    use super::ReverseHelper;
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(ReverseHelper::is_wildcard_match("*.example.com", "a.example.com"));
+        assert!(!ReverseHelper::is_wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!ReverseHelper::is_wildcard_match("*.example.com", "example.com"));
+        assert!(!ReverseHelper::is_wildcard_match("*.example.com", ".example.com"));
+        assert!(!ReverseHelper::is_wildcard_match("example.com", "a.example.com"));
+    }
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(ReverseHelper::normalize_host("Example.COM"), "example.com");
+        assert_eq!(ReverseHelper::normalize_host("example.com:443"), "example.com");
+        assert_eq!(ReverseHelper::normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(ReverseHelper::normalize_host("127.0.0.1:80"), "127.0.0.1");
+        assert_eq!(ReverseHelper::normalize_host("example.com:abc"), "example.com:abc");
+        assert_eq!(ReverseHelper::normalize_host(""), "");
+    }
+
+    #[test]
+    fn test_resolve_client_ip() {
+        let trusted = "10.0.0.0/8 127.0.0.1".parse::<IpSets>().unwrap();
+        let resolve = |v| ReverseHelper::resolve_client_ip(&trusted, v).map(|ip| ip.to_string());
+        assert_eq!(resolve(Some("1.1.1.1")), Some("1.1.1.1".to_string()));
+        assert_eq!(resolve(Some("2.2.2.2, 1.1.1.1, 10.0.0.2")), Some("1.1.1.1".to_string()));
+        assert_eq!(resolve(Some("10.0.0.3, 127.0.0.1")), Some("10.0.0.3".to_string()));
+        assert_eq!(resolve(Some("unknown, 10.0.0.2")), Some("10.0.0.2".to_string()));
+        assert_eq!(resolve(Some("")), None);
+        assert_eq!(resolve(None), None);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/11 20:12:45
+
+use std::{fmt::Display, str::FromStr};
+
+use regex::Regex;
+use webparse::{Request, Serialize};
+
+use crate::{Helper, ProxyError};
+
+/// 发往后端前的路径重写, 格式为`pattern replacement`
+/// 以`^`开头的为正则, 替换中可用`$1`引用分组; 其它为前缀替换, 如`/api/v1/ /`
+/// 只修改路径部分, 查询参数保持不变
+#[derive(Debug, Clone)]
+pub struct RewriteConfig {
+    pub pattern: String,
+    pub replacement: String,
+    regex: Option<Regex>,
+    /// 前缀须按路径分段匹配, 如`/api`不匹配`/apix`
+    segment: bool,
+}
+
+impl RewriteConfig {
+    /// 去掉路径的前缀, 如`/api`将`/api/users`转成`/users`, 不匹配`/apix`
+    pub fn strip_prefix(prefix: &str) -> Self {
+        Self {
+            pattern: prefix.to_string(),
+            replacement: "/".to_string(),
+            regex: None,
+            segment: true,
+        }
+    }
+
+    /// 重写路径, 不匹配时返回None
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        let ret = match &self.regex {
+            Some(re) => {
+                if !re.is_match(path) {
+                    return None;
+                }
+                re.replace(path, self.replacement.as_str()).to_string()
+            }
+            None => {
+                let left = path.strip_prefix(&self.pattern)?;
+                if self.segment
+                    && !self.pattern.ends_with('/')
+                    && !left.is_empty()
+                    && !left.starts_with('/')
+                {
+                    return None;
+                }
+                if self.replacement.ends_with('/') && left.starts_with('/') {
+                    format!("{}{}", self.replacement, &left[1..])
+                } else {
+                    format!("{}{}", self.replacement, left)
+                }
+            }
+        };
+        if ret.starts_with('/') {
+            Some(ret)
+        } else {
+            Some(format!("/{}", ret))
+        }
+    }
+
+    /// 重写请求的路径, 保留原有的查询参数
+    pub fn rewrite_request<T: Serialize>(&self, req: &mut Request<T>) -> bool {
+        let (path, query) = match req.path().split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (req.path().clone(), req.url().query.clone()),
+        };
+        let new_path = match self.rewrite_path(&path) {
+            Some(new_path) => new_path,
+            None => return false,
+        };
+        // 替换中携带查询参数时与原有的参数合并
+        let (url_path, new_query) = match new_path.split_once('?') {
+            Some((p, q)) => (p.to_string(), Some(q.to_string())),
+            None => (new_path.clone(), None),
+        };
+        let query = match (new_query, query) {
+            (Some(a), Some(b)) => Some(format!("{}&{}", a, b)),
+            (a, b) => a.or(b),
+        };
+        req.parts_mut().url.path = url_path.clone();
+        match query {
+            Some(query) => req.set_path(format!("{}?{}", url_path, query)),
+            None => req.set_path(url_path),
+        }
+        true
+    }
+}
+
+impl FromStr for RewriteConfig {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        if vals.len() != 2 || vals[0].is_empty() {
+            return Err(ProxyError::Extension(
+                "rewrite must be `pattern replacement`",
+            ));
+        }
+        let regex = if vals[0].starts_with('^') {
+            match Regex::new(vals[0]) {
+                Ok(re) => Some(re),
+                Err(_) => return Err(ProxyError::Extension("rewrite regex error")),
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            pattern: vals[0].to_string(),
+            replacement: vals[1].to_string(),
+            regex,
+            segment: false,
+        })
+    }
+}
+
+impl Display for RewriteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let replacement = if self.replacement.is_empty() {
+            "''"
+        } else {
+            &self.replacement
+        };
+        f.write_fmt(format_args!("{} {}", self.pattern, replacement))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use webparse::Request;
+
+    use super::RewriteConfig;
+
+    #[test]
+    fn test_rewrite_path() {
+        let cases = [
+            ("/api/v1/ /", "/api/v1/users", Some("/users")),
+            ("/api/v1/ /", "/api/v1/", Some("/")),
+            ("/api/v1/ /", "/api/v2/users", None),
+            ("/api/v1 /", "/api/v1/users", Some("/users")),
+            ("/api/v1 ''", "/api/v1", Some("/")),
+            ("/api/v1/ ''", "/api/v1/users", Some("/users")),
+            ("/api/ /v2/", "/api/users/1", Some("/v2/users/1")),
+            (
+                "^/user/(\\d+)$ /users?id=$1",
+                "/user/12",
+                Some("/users?id=12"),
+            ),
+            ("^/user/(\\d+)$ /users/$1", "/user/ab", None),
+            ("^/static/(.*) /$1", "/static/", Some("/")),
+            (
+                "^/old/(?P<rest>.*) /new/$rest",
+                "/old/a/b",
+                Some("/new/a/b"),
+            ),
+        ];
+        for (rule, path, expect) in cases {
+            let rewrite = RewriteConfig::from_str(rule).unwrap();
+            assert_eq!(
+                rewrite.rewrite_path(path).as_deref(),
+                expect,
+                "rule `{}` path `{}`",
+                rule,
+                path
+            );
+        }
+        let strip = RewriteConfig::strip_prefix("/api");
+        assert_eq!(strip.rewrite_path("/api/users").as_deref(), Some("/users"));
+        assert_eq!(strip.rewrite_path("/api").as_deref(), Some("/"));
+        assert_eq!(strip.rewrite_path("/apix"), None);
+        let strip = RewriteConfig::strip_prefix("/api/");
+        assert_eq!(strip.rewrite_path("/api/users").as_deref(), Some("/users"));
+        assert!(RewriteConfig::from_str("/api").is_err());
+        assert!(RewriteConfig::from_str("^/(api /").is_err());
+        assert_eq!(
+            RewriteConfig::from_str("/api/v1 ''").unwrap().to_string(),
+            "/api/v1 ''"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_request() {
+        let cases = [
+            (
+                "/api/v1/ /",
+                "/api/v1/users?a=1&b=/api/v1/",
+                "/users?a=1&b=/api/v1/",
+                "/users",
+            ),
+            ("/api/v1/ /", "/api/v1?x=1", "/api/v1?x=1", "/api/v1"),
+            ("/api/ ''", "/api/?x=1", "/?x=1", "/"),
+            ("^/(\\w+)/(\\w+) /$2/$1", "/a/b?q", "/b/a?q", "/b/a"),
+            (
+                "^/user/(\\d+)$ /users?id=$1",
+                "/user/7?x=1",
+                "/users?id=7&x=1",
+                "/users",
+            ),
+        ];
+        for (rule, path, expect, url_path) in cases {
+            let rewrite = RewriteConfig::from_str(rule).unwrap();
+            let mut req = Request::builder()
+                .url(format!("http://localhost{}", path))
+                .body(())
+                .unwrap();
+            // 与解析客户端请求时一致, 请求行中的路径带有查询参数
+            req.set_path(path.to_string());
+            rewrite.rewrite_request(&mut req);
+            assert_eq!(req.path(), expect, "rule `{}` path `{}`", rule, path);
+            assert_eq!(&req.url().path, url_path);
+        }
+    }
+}
@@ -10,16 +10,17 @@
 // -----
 // Created Date: 2023/10/18 02:32:15
 
-use std::{collections::HashMap, net::{SocketAddr, ToSocketAddrs}};
+use std::{collections::{HashMap, HashSet}, net::SocketAddr, sync::{Arc, RwLock}, time::Duration};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use wenmeng::ProtResult;
+use webparse::{Request, Response};
+use wenmeng::{Body, ProtResult};
 
 
-use crate::{ConfigHeader, WrapVecAddr};
+use crate::{ConfigDuration, ConfigHeader, DisplayFromStrOrSeq, IpSets, ListenAddr, Metrics, SocketOptions, WrapVecAddr};
 
-use super::{LocationConfig, UpstreamConfig, common::CommonConfig, ReverseHelper};
+use super::{AcmeConfig, AddHeader, HSTS_HEADER, LocationConfig, UpstreamConfig, common::CommonConfig, ErrorPage, LimitConcurrency, LimitConn, ReverseHelper, TlsOption, TlsVersion, UpstreamPool, VerifyClient};
 
 fn default_bind_mode() -> String {
     "tcp".to_string()
@@ -28,6 +29,18 @@ fn default_bind_mode() -> String {
 fn default_up_name() -> String {
     "".to_string()
 }
+
+fn default_keepalive() -> usize {
+    32
+}
+
+fn default_keepalive_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(60))
+}
+
+fn default_keepalive_lifetime() -> ConfigDuration {
+    ConfigDuration::new(Duration::ZERO)
+}
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
@@ -37,17 +50,75 @@ pub struct ServerConfig {
 
     #[serde_as(as = "DisplayFromStr")]
     pub bind_ssl: WrapVecAddr,
+    /// 以明文处理的其它监听地址, 可为TCP地址或unix socket, 如`unix:/run/wmproxy.sock`,
+    /// 供本机的nginx或systemd转发, unix socket仅支持unix平台
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub listen: Vec<ListenAddr>,
+    /// 监听的socket选项, 同一地址由多个server监听时使用首个server的配置
+    #[serde(default)]
+    pub socket: SocketOptions,
+    /// 连接开始时是否先读取PROXY协议头(v1或v2), 以其中的地址作为客户端地址,
+    /// 用于前置AWS NLB或HAProxy等负载均衡时, 同一端口以首个server的配置为准
+    #[serde(default)]
+    pub proxy_protocol: bool,
     
     #[serde(default = "default_up_name")]
     pub up_name: String,
+    /// 未匹配任何`up_name`或未携带Host的请求由该server处理, 均未配置时由未配置`up_name`的server处理,
+    /// 仍未匹配时返回421, 同一端口只能配置一个, 也可配置为`default`
+    #[serde(default, alias = "default")]
+    pub default_server: bool,
     pub root: Option<String>,
     pub cert: Option<String>,
     pub key: Option<String>,
+    /// 同一域名的备用证书, 一般为与cert签名算法不同的证书(如ECDSA证书),
+    /// 握手时按客户端支持的签名算法选择
+    pub alt_cert: Option<String>,
+    pub alt_key: Option<String>,
+    /// 校验客户端证书的CA文件, 配合`verify_client`使用
+    pub client_ca: Option<String>,
+    /// 是否校验客户端证书, 可选`on`, `optional`, `off`, 校验失败时握手失败,
+    /// 配置了`client_ca`但未配置时按`on`处理
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub verify_client: Option<VerifyClient>,
+    /// 握手时协商的ALPN协议, 按顺序优先, 未配置时使用http中的alpn
+    pub alpn: Option<Vec<String>>,
+    /// 允许的最低TLS版本, 可选`1.2`, `1.3`, 未配置时使用http中的配置
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub min_tls_version: Option<TlsVersion>,
+    /// 允许的最高TLS版本
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_tls_version: Option<TlsVersion>,
+    /// 允许的加密套件, 如`TLS13_AES_128_GCM_SHA256`, 为空时使用http中的配置
+    #[serde(default)]
+    pub ciphers: Vec<String>,
+    /// 该server握手时使用的TLS配置, 绑定端口时生成, 校验客户端证书时与其它server不同
+    /// 明文接入的请求跳转至HTTPS, 不再转发给后端
+    #[serde(default)]
+    pub redirect_https: bool,
+    /// 经由TLS接入时返回的Strict-Transport-Security, 如`max-age=63072000; includeSubDomains`
+    /// 明文接入的返回不会添加, 避免配置错误时客户端无法访问
+    pub hsts: Option<String>,
+    /// 返回时添加的头, 如`X-Content-Type-Options: nosniff`
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub add_headers: Vec<AddHeader>,
+    /// 是否覆盖后端已设置的同名头, 默认保留后端的值
+    #[serde(default)]
+    pub force_headers: bool,
+    /// 通过ACME自动申请证书, 证书及私钥保存在其`data_dir`中
+    pub acme: Option<AcmeConfig>,
+    #[serde(skip)]
+    pub tls_config: Arc<RwLock<Option<Arc<rustls::ServerConfig>>>>,
 
     #[serde(default = "default_bind_mode")]
     pub bind_mode: String,
     
-    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde_as(as = "Vec<DisplayFromStrOrSeq>")]
     #[serde(default = "Vec::new")]
     pub headers: Vec<ConfigHeader>,
     #[serde(default = "Vec::new")]
@@ -55,24 +126,144 @@ pub struct ServerConfig {
     #[serde(default = "Vec::new")]
     pub upstream: Vec<UpstreamConfig>,
 
+    /// 单个客户端IP同时处理的请求数, 超出时返回429
+    pub limit_conn: Option<usize>,
+    #[serde(skip)]
+    pub conns: Arc<LimitConn>,
+    /// 同时处理的总请求数, 并按客户端IP公平分配, 如`max=64 per_ip=8`, 超出时返回429
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub limit_concurrency: Option<LimitConcurrency>,
+    #[serde(skip)]
+    pub concurrency: Arc<LimitConn>,
+    /// 同时保持的总连接数, 超出时返回503并关闭连接, 同一端口以首个server的配置为准
+    pub max_connections: Option<usize>,
+    /// 单个客户端IP同时保持的连接数, 与`limit_conn`按请求计数不同, 此处按TCP连接计数
+    pub max_connections_per_ip: Option<usize>,
+    /// 当前的连接数统计, 该server监听的HTTP及HTTPS端口共用
+    #[serde(skip)]
+    pub connections: Arc<LimitConn>,
+    /// 连接池中每个location保持的空闲后端连接数, 所有客户端连接共享, 超出时关闭最久未使用的连接, 为0时不复用
+    #[serde(default = "default_keepalive", alias = "max_cache_clients")]
+    pub keepalive: usize,
+    /// 空闲超过该时间的后端连接不再复用, 为0时不限制
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: ConfigDuration,
+    /// 后端连接建立后最长复用的时间, 为0时不限制
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_keepalive_lifetime")]
+    pub keepalive_lifetime: ConfigDuration,
+    /// 复用的后端连接池
+    #[serde(skip)]
+    pub pool: Arc<UpstreamPool>,
+    /// 错误状态码对应的页面, 如`["404 /404.html", "502 503 html/50x.html", "504 <h1>timeout</h1>"]`
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default)]
+    pub error_page: Vec<ErrorPage>,
+    /// 后端返回的错误状态码是否也使用`error_page`, 默认只处理代理自身产生的错误
+    #[serde(default)]
+    pub intercept_errors: bool,
+    /// 可信的代理, 加载时由HttpConfig的`trusted_proxies`复制
+    #[serde(skip)]
+    pub trusted_proxies: Option<IpSets>,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
 }
 
+/// 选择location的结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum LocationMatch {
+    /// 匹配到的location的下标
+    Found(usize),
+    /// 路径匹配但方法不允许, 附带允许的方法
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
 impl ServerConfig {
+    /// 按请求选择location, 跳过`skip`中已处理的下标
+    /// 多个location均匹配时, 前缀或通配符匹配的固定前缀最长的优先, 长度相同(包括正则匹配)时按配置的顺序
+    /// 优先级最高的location均不允许该方法时返回405, 不再匹配其它location
+    pub fn match_location(&self, req: &Request<Body>, skip: &HashSet<usize>) -> LocationMatch {
+        let path = req.path().clone();
+        let mut best: Option<(usize, Vec<usize>)> = None;
+        for (idx, l) in self.location.iter().enumerate() {
+            if skip.contains(&idx) || !l.is_match_rule(&path, req) {
+                continue;
+            }
+            let len = l.rule.match_len(&path);
+            match &mut best {
+                Some((best_len, list)) if *best_len == len => list.push(idx),
+                Some((best_len, _)) if *best_len > len => {}
+                _ => best = Some((len, vec![idx])),
+            }
+        }
+        let list = match best {
+            Some((_, list)) => list,
+            None => return LocationMatch::NotFound,
+        };
+        if let Some(idx) = list.iter().find(|idx| self.location[**idx].is_allow_method(req)) {
+            return LocationMatch::Found(*idx);
+        }
+        let mut allow: Vec<String> = vec![];
+        for idx in list {
+            for m in &self.location[idx].methods {
+                let m = m.to_ascii_uppercase();
+                if !allow.contains(&m) {
+                    allow.push(m);
+                }
+            }
+        }
+        LocationMatch::MethodNotAllowed(allow)
+    }
+
     pub fn new(bind_addr: WrapVecAddr) -> Self {
         ServerConfig {
             bind_addr,
             bind_ssl: WrapVecAddr::empty(),
+            listen: vec![],
+            socket: SocketOptions::default(),
+            proxy_protocol: false,
             up_name: default_up_name(),
+            default_server: false,
             root: None,
             cert: None,
             key: None,
+            alt_cert: None,
+            alt_key: None,
+            client_ca: None,
+            verify_client: None,
+            alpn: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            ciphers: vec![],
+            redirect_https: false,
+            hsts: None,
+            add_headers: vec![],
+            force_headers: false,
+            acme: None,
+            tls_config: Arc::new(RwLock::new(None)),
             bind_mode: default_bind_mode(),
             headers: vec![],
             location: vec![],
             upstream: vec![],
+            limit_conn: None,
+            conns: Arc::new(LimitConn::default()),
+            limit_concurrency: None,
+            concurrency: Arc::new(LimitConn::default()),
+            max_connections: None,
+            max_connections_per_ip: None,
+            connections: Arc::new(LimitConn::default()),
+            keepalive: default_keepalive(),
+            keepalive_timeout: default_keepalive_timeout(),
+            keepalive_lifetime: default_keepalive_lifetime(),
+            pool: Arc::new(UpstreamPool::default()),
+            error_page: vec![],
+            intercept_errors: false,
+            trusted_proxies: None,
             comm: CommonConfig::new(),
         }
     }
@@ -81,19 +272,110 @@ impl ServerConfig {
         ServerConfig {
             bind_addr: WrapVecAddr::empty(),
             bind_ssl,
+            listen: vec![],
+            socket: SocketOptions::default(),
+            proxy_protocol: false,
             up_name: default_up_name(),
+            default_server: false,
             root: None,
             cert: None,
             key: None,
+            alt_cert: None,
+            alt_key: None,
+            client_ca: None,
+            verify_client: None,
+            alpn: None,
+            min_tls_version: None,
+            max_tls_version: None,
+            ciphers: vec![],
+            redirect_https: false,
+            hsts: None,
+            add_headers: vec![],
+            force_headers: false,
+            acme: None,
+            tls_config: Arc::new(RwLock::new(None)),
             bind_mode: default_bind_mode(),
             headers: vec![],
             location: vec![],
             upstream: vec![],
+            limit_conn: None,
+            conns: Arc::new(LimitConn::default()),
+            limit_concurrency: None,
+            concurrency: Arc::new(LimitConn::default()),
+            max_connections: None,
+            max_connections_per_ip: None,
+            connections: Arc::new(LimitConn::default()),
+            keepalive: default_keepalive(),
+            keepalive_timeout: default_keepalive_timeout(),
+            keepalive_lifetime: default_keepalive_lifetime(),
+            pool: Arc::new(UpstreamPool::default()),
+            error_page: vec![],
+            intercept_errors: false,
+            trusted_proxies: None,
             comm: CommonConfig::new(),
         }
     }
+    /// 是否监听该端口, 包含`listen`中的TCP地址
+    pub fn is_bind_port(&self, port: u16) -> bool {
+        self.bind_addr.contains(port)
+            || self.bind_ssl.contains(port)
+            || self
+                .listen
+                .iter()
+                .any(|l| matches!(l, ListenAddr::Tcp(addr) if addr.port() == port))
+    }
+
+    pub fn get_tls_config(&self) -> Option<Arc<rustls::ServerConfig>> {
+        self.tls_config.read().ok()?.clone()
+    }
+
+    /// 客户端证书的校验方式, 未配置时不校验
+    pub fn get_verify_client(&self) -> VerifyClient {
+        self.verify_client.unwrap_or_default()
+    }
+
+    /// 是否需要使用与默认不同的TLS配置, 握手时需按SNI选择
+    pub fn is_custom_tls(&self) -> bool {
+        self.get_verify_client() != VerifyClient::Off
+            || self.alpn.is_some()
+            || self.min_tls_version.is_some()
+            || self.max_tls_version.is_some()
+            || !self.ciphers.is_empty()
+    }
+
+    /// 检查TLS相关的配置, 版本范围及加密套件无法组合时返回错误
+    pub fn check_tls(&self) -> std::io::Result<()> {
+        let versions = TlsOption::versions(self.min_tls_version, self.max_tls_version)?;
+        TlsOption::provider(&self.ciphers, &versions)?;
+        if self.alpn.as_ref().is_some_and(|alpn| alpn.iter().any(|p| p.is_empty())) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("server{}的alpn不能为空", self.up_name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 添加配置的返回头, HSTS仅添加到经由TLS接入的返回中
+    pub fn apply_add_headers(&self, res: &mut Response<Body>, is_tls: bool) {
+        if let (Some(hsts), true) = (&self.hsts, is_tls) {
+            AddHeader::apply(res, HSTS_HEADER, hsts, self.force_headers);
+        }
+        for header in &self.add_headers {
+            AddHeader::apply(res, &header.name, &header.value, self.force_headers);
+        }
+    }
+
     /// 将配置参数提前共享给子级
     pub fn copy_to_child(&mut self) {
+        if self.client_ca.is_some() && self.verify_client.is_none() {
+            self.verify_client = Some(VerifyClient::On);
+        }
+        if let Some(acme) = &self.acme {
+            let domain = self.comm.domain.clone().unwrap_or(self.up_name.clone());
+            self.cert = Some(acme.cert_path(&domain));
+            self.key = Some(acme.key_path(&domain));
+        }
         for l in &mut self.location {
             l.comm.copy_from_parent(&self.comm);
             l.comm.pre_deal();
@@ -106,6 +388,12 @@ impl ServerConfig {
                 }
             }
             l.up_name = Some(self.up_name.clone());
+            l.metrics = Some(Metrics::location(&self.up_name, &l.rule.to_string()));
+            if let Some(limit_req) = &mut l.comm.limit_req {
+                if let Err(e) = limit_req.bind_location(&self.up_name, &l.rule.to_string()) {
+                    log::error!("配置请求限制失败: {:?}", e);
+                }
+            }
             l.upstream.append(&mut self.upstream.clone());
             l.headers.append(&mut self.headers.clone());
             if l.root.is_none() && self.root.is_some() {
@@ -120,6 +408,7 @@ impl ServerConfig {
                     file_server.set_common(l.comm.clone());
                 }
             }
+            l.build_root_server();
         }
     }
 
@@ -131,6 +420,13 @@ impl ServerConfig {
         }
     }
 
+    pub fn get_access_names(&self, names: &mut HashSet<String>) {
+        self.comm.get_access_names(names);
+        for l in &self.location {
+            l.get_access_names(names);
+        }
+    }
+
     pub fn build_url(&self, addr: &SocketAddr) -> String {
         if self.comm.proxy_url.is_some() {
             let mut url = self.comm.proxy_url.clone().unwrap();
@@ -142,7 +438,7 @@ impl ServerConfig {
         }
     }
 
-    pub fn get_addr_domain(&self) -> ProtResult<(Option<SocketAddr>, Option<String>)> {
+    pub async fn get_addr_domain(&self) -> ProtResult<(Option<SocketAddr>, Option<String>)> {
         let mut domain = self.comm.domain.clone();
         let mut addr = None;
         if self.comm.proxy_url.is_some() {
@@ -150,20 +446,20 @@ impl ServerConfig {
                 domain = self.comm.proxy_url.as_ref().unwrap().domain.clone();
             }
             if let Some(domain) = &self.comm.proxy_url.as_ref().unwrap().domain {
-                addr = ReverseHelper::get_upstream_addr(&self.upstream, &domain);
+                addr = ReverseHelper::get_upstream_addr(&self.upstream, domain, None);
                 if addr.is_some() && self.comm.proxy_url.as_ref().unwrap().port.is_some() {
                     addr.as_mut().unwrap().set_port(self.comm.proxy_url.as_ref().unwrap().port.unwrap());
                 }
             }
             if addr.is_none() {
                 if let Some(c) = self.comm.proxy_url.as_ref().unwrap().get_connect_url() {
-                    addr = c.to_socket_addrs()?.next();
+                    addr = tokio::net::lookup_host(c).await?.next();
                 }
             }
         }
 
         if addr.is_none() {
-            addr = ReverseHelper::get_upstream_addr(&self.upstream, &self.up_name)
+            addr = ReverseHelper::get_upstream_addr(&self.upstream, &self.up_name, None)
         }
         Ok((addr, domain))
     }
@@ -0,0 +1,223 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/28 16:05:37
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Display},
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use webparse::{Request, Url};
+use wenmeng::Body;
+
+/// 选择分流后端的依据
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum SplitKey {
+    /// 每个请求随机选择
+    #[default]
+    Random,
+    /// 按客户端IP, 同一客户端固定访问同一后端
+    Ip,
+    /// 按cookie的值, 同一cookie固定访问同一后端, 不存在时随机选择
+    Cookie(String),
+}
+
+impl FromStr for SplitKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "random" => Ok(SplitKey::Random),
+            "ip" => Ok(SplitKey::Ip),
+            v => match v.strip_prefix("cookie:").map(|n| n.trim()) {
+                Some(name) if !name.is_empty() => Ok(SplitKey::Cookie(name.to_string())),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("未知的分流依据:{}, 可为random, ip或cookie:名称", s),
+                )),
+            },
+        }
+    }
+}
+
+impl Display for SplitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitKey::Random => f.write_str("random"),
+            SplitKey::Ip => f.write_str("ip"),
+            SplitKey::Cookie(name) => write!(f, "cookie:{}", name),
+        }
+    }
+}
+
+/// 分流的后端及分配的百分比
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitUpstream {
+    /// upstream的名称
+    pub name: String,
+    /// 分配的百分比
+    pub percent: u32,
+}
+
+/// 请求所选的分流后端, 存放于请求的extensions中, 可用`{up_name}`记录到访问日志
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitChosen(pub String);
+
+/// 按比例将location的请求分配到多个upstream, 如灰度发布时95%到稳定版本, 5%到新版本
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitConfig {
+    /// 选择后端的依据, 可为`random`, `ip`或`cookie:名称`
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub key: SplitKey,
+    /// 各后端的百分比之和需为100
+    pub upstreams: Vec<SplitUpstream>,
+}
+
+impl SplitConfig {
+    pub fn new(upstreams: Vec<SplitUpstream>) -> Self {
+        Self {
+            key: SplitKey::Random,
+            upstreams,
+        }
+    }
+
+    /// 检查配置, 百分比之和需为100
+    pub fn check(&self) -> io::Result<()> {
+        if self.upstreams.iter().any(|u| u.name.is_empty()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "split的upstream名称不能为空",
+            ));
+        }
+        let total: u32 = self.upstreams.iter().map(|u| u.percent).sum();
+        if total != 100 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("split的百分比之和需为100, 当前为{}", total),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 按`0..100`中的位置选择后端
+    pub fn choose_by(&self, point: u32) -> Option<&SplitUpstream> {
+        let mut sum = 0;
+        self.upstreams.iter().find(|u| {
+            sum += u.percent;
+            point < sum
+        })
+    }
+
+    /// 将值映射到`0..100`, 同一值始终得到同一位置
+    pub fn hash_point(value: &str) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        (hasher.finish() % 100) as u32
+    }
+
+    fn get_cookie(req: &Request<Body>, name: &str) -> Option<String> {
+        let cookie = req.headers().get_cookie()?;
+        cookie.split(';').find_map(|kv| {
+            let (k, v) = kv.split_once('=')?;
+            (k.trim() == name).then(|| v.trim().to_string())
+        })
+    }
+
+    /// 为请求选择后端
+    pub fn choose(&self, req: &Request<Body>) -> Option<&SplitUpstream> {
+        let value = match &self.key {
+            SplitKey::Random => None,
+            SplitKey::Ip => req
+                .extensions()
+                .get::<SocketAddr>()
+                .map(|addr| addr.ip().to_string()),
+            SplitKey::Cookie(name) => Self::get_cookie(req, name),
+        };
+        let point = match value {
+            Some(value) => Self::hash_point(&value),
+            None => rand::random::<u32>() % 100,
+        };
+        self.choose_by(point)
+    }
+
+    /// 生成转发到所选后端的地址, 以`base`的协议及路径为准, 未配置时为http
+    pub fn build_url(base: Option<&Url>, name: &str) -> Option<Url> {
+        let mut url = match base {
+            Some(base) => base.clone(),
+            None => Url::parse(format!("http://{}/", name).into_bytes()).ok()?,
+        };
+        url.domain = Some(name.to_string());
+        url.port = None;
+        Some(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SplitConfig, SplitKey, SplitUpstream};
+
+    fn build_split(stable: u32, canary: u32) -> SplitConfig {
+        SplitConfig::new(vec![
+            SplitUpstream {
+                name: "stable".to_string(),
+                percent: stable,
+            },
+            SplitUpstream {
+                name: "canary".to_string(),
+                percent: canary,
+            },
+        ])
+    }
+
+    #[test]
+    fn test_split_choose() {
+        let split = build_split(95, 5);
+        assert!(split.check().is_ok());
+        assert!(build_split(95, 10).check().is_err());
+        assert_eq!(split.choose_by(0).unwrap().name, "stable");
+        assert_eq!(split.choose_by(94).unwrap().name, "stable");
+        assert_eq!(split.choose_by(95).unwrap().name, "canary");
+        assert_eq!(split.choose_by(99).unwrap().name, "canary");
+
+        // 不同的值按比例分布
+        let canary = (0..10000)
+            .filter(|i| {
+                split
+                    .choose_by(SplitConfig::hash_point(&i.to_string()))
+                    .unwrap()
+                    .name
+                    == "canary"
+            })
+            .count();
+        assert!((400..600).contains(&canary), "{}", canary);
+        let split = build_split(100, 0);
+        assert!((0..100).all(|i| split.choose_by(i).unwrap().name == "stable"));
+    }
+
+    #[test]
+    fn test_split_key() {
+        assert_eq!("random".parse::<SplitKey>().unwrap(), SplitKey::Random);
+        assert_eq!("ip".parse::<SplitKey>().unwrap(), SplitKey::Ip);
+        let key = "cookie:uid".parse::<SplitKey>().unwrap();
+        assert_eq!(key, SplitKey::Cookie("uid".to_string()));
+        assert_eq!(key.to_string(), "cookie:uid");
+        assert!("cookie:".parse::<SplitKey>().is_err());
+        assert!("header".parse::<SplitKey>().is_err());
+    }
+}
@@ -23,7 +23,7 @@ use futures_core::Stream;
 
 use serde::{Deserialize, Serialize};
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncWrite, Interest, ReadBuf},
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt, Interest, ReadBuf},
     net::{TcpListener, UdpSocket},
     sync::{
         mpsc::{channel, Receiver, Sender},
@@ -31,13 +31,17 @@ use tokio::{
     },
     time::sleep,
 };
-use tokio_util::sync::PollSender;
+use tokio_util::sync::{CancellationToken, PollSender};
 use webparse::{BinaryMut, Buf, BufMut};
 use wenmeng::plugins::{StreamToWs, WsToStream};
 
-use crate::{HealthCheck, Helper, ProxyError, ProxyResult};
+use crate::{HealthCheck, Helper, ProxyError, ProxyResult, UpstreamActiveCheck};
 
-use super::{ServerConfig, UpstreamConfig};
+use super::{ProxyProtocol, ReverseHelper, ServerConfig, UpstreamConfig, UpstreamConnGuard};
+
+fn default_workers() -> usize {
+    1
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamConfig {
@@ -45,6 +49,14 @@ pub struct StreamConfig {
     pub server: Vec<ServerConfig>,
     #[serde(default = "Vec::new")]
     pub upstream: Vec<UpstreamConfig>,
+
+    /// 每个tcp监听地址以SO_REUSEPORT绑定的监听数, 由内核分摊新连接, 仅Linux生效
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+
+    /// 用于取消主动健康检查的任务
+    #[serde(skip)]
+    pub health_cancel: Option<CancellationToken>,
 }
 
 impl StreamConfig {
@@ -52,6 +64,38 @@ impl StreamConfig {
         StreamConfig {
             server: vec![],
             upstream: vec![],
+            workers: default_workers(),
+            health_cancel: None,
+        }
+    }
+
+    /// 启动所有upstream的主动健康检查
+    pub fn start_health_check(&mut self) {
+        self.stop_health_check();
+        let cancel = CancellationToken::new();
+        let upstreams = self
+            .upstream
+            .iter()
+            .chain(self.server.iter().flat_map(|s| s.upstream.iter()));
+        UpstreamActiveCheck::start_all(upstreams, &cancel);
+        self.health_cancel = Some(cancel);
+    }
+
+    /// 解析所有upstream中的域名后端, 之后定时重新解析, 与健康检查一同停止
+    pub async fn start_resolve(&self) {
+        if let Some(cancel) = &self.health_cancel {
+            let upstreams = self
+                .upstream
+                .iter()
+                .chain(self.server.iter().flat_map(|s| s.upstream.iter()));
+            UpstreamConfig::start_resolve_all(upstreams, cancel).await;
+        }
+    }
+
+    /// 停止所有的主动健康检查
+    pub fn stop_health_check(&self) {
+        if let Some(cancel) = &self.health_cancel {
+            cancel.cancel();
         }
     }
 
@@ -65,6 +109,8 @@ impl StreamConfig {
 
     /// stream的绑定，按bind_mode区分出udp或者是tcp，返回相应的列表
     pub async fn bind(&mut self) -> ProxyResult<(Vec<TcpListener>, Vec<StreamUdp>)> {
+        self.start_health_check();
+        self.start_resolve().await;
         let mut listeners = vec![];
         let mut udp_listeners = vec![];
         let mut bind_port = HashSet::new();
@@ -81,8 +127,7 @@ impl StreamConfig {
                 } else {
                     log::info!("负载均衡,stream：{:?}，提供stream中的tcp转发功能。", v);
 
-                    let listener = Helper::bind(v).await?;
-                    listeners.push(listener);
+                    listeners.extend(Helper::bind_workers(*v, self.workers, &value.socket).await?);
                 }
             }
         }
@@ -94,7 +139,7 @@ impl StreamConfig {
         data: Arc<Mutex<StreamConfig>>,
         local_addr: SocketAddr,
         mut inbound: T,
-        _addr: SocketAddr,
+        client_addr: SocketAddr,
     ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
@@ -102,11 +147,12 @@ impl StreamConfig {
         let value = data.lock().await;
         for (_, s) in value.server.iter().enumerate() {
             if s.bind_addr.contains(local_addr.port()) {
-                let (addr, domain) = s.get_addr_domain()?;
+                let (addr, domain) = s.get_addr_domain().await?;
                 if addr.is_none() {
                     return Err(ProxyError::Extension("unknow addr"));
                 }
                 let addr = addr.unwrap();
+                let _conn = UpstreamConnGuard::new(addr);
                 if s.bind_mode == "ws2tcp" {
                     let mut ws_to_stream = WsToStream::new(inbound, addr)?;
                     if domain.is_some() {
@@ -126,7 +172,14 @@ impl StreamConfig {
                     }
                     let _ = stream_to_ws.copy_bidirectional().await;
                 } else {
+                    let name = domain.as_deref().unwrap_or(&s.up_name);
+                    let connecting = ReverseHelper::get_connect_permit(&s.upstream, name).await;
                     let mut connect = HealthCheck::connect(&addr).await?;
+                    drop(connecting);
+                    if let Some(version) = ReverseHelper::get_proxy_protocol(&s.upstream, name) {
+                        let header = ProxyProtocol::encode(version, client_addr, local_addr);
+                        connect.write_all(&header).await?;
+                    }
                     copy_bidirectional(&mut inbound, &mut connect).await?;
                 }
                 break;
@@ -265,7 +318,7 @@ impl StreamUdp {
         let mut remote_addr = None;
         for up in &self.server.upstream {
             if up.name == self.server.up_name {
-                remote_addr = up.get_server_addr();
+                remote_addr = up.get_server_addr(Some(&addr));
             }
         }
         if remote_addr.is_none() {
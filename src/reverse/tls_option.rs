@@ -0,0 +1,149 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/22 14:18:05
+
+use std::{fmt::Display, io, str::FromStr};
+
+use rustls::{
+    crypto::{ring::default_provider, CryptoProvider},
+    version::{TLS12, TLS13},
+    SupportedProtocolVersion,
+};
+
+/// 默认协商的ALPN协议
+pub const DEFAULT_ALPN: [&str; 2] = ["h2", "http/1.1"];
+
+/// 允许的TLS版本, 可配置为`1.2`或`1.3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    pub fn supported(&self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &TLS12,
+            TlsVersion::Tls13 => &TLS13,
+        }
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let v = s.trim_start_matches("tlsv").trim_start_matches("tls");
+        match v {
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(io::Error::other("tls version must be 1.2 or 1.3")),
+        }
+    }
+}
+
+impl Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls12 => "1.2",
+            TlsVersion::Tls13 => "1.3",
+        })
+    }
+}
+
+/// 生成TLS配置时使用的版本及加密套件
+pub struct TlsOption;
+
+impl TlsOption {
+    /// 按最低及最高版本返回允许的TLS版本, 未配置时不做限制
+    pub fn versions(
+        min: Option<TlsVersion>,
+        max: Option<TlsVersion>,
+    ) -> io::Result<Vec<&'static SupportedProtocolVersion>> {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("min_tls_version {} 大于 max_tls_version {}", min, max),
+                ));
+            }
+        }
+        Ok([TlsVersion::Tls13, TlsVersion::Tls12]
+            .into_iter()
+            .filter(|v| min.is_none_or(|min| *v >= min) && max.is_none_or(|max| *v <= max))
+            .map(|v| v.supported())
+            .collect())
+    }
+
+    /// 仅保留允许的加密套件, 为空时使用默认的套件, 存在未知的套件或均不适用于允许的版本时返回错误
+    pub fn provider(
+        ciphers: &[String],
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> io::Result<CryptoProvider> {
+        let mut provider = default_provider();
+        if ciphers.is_empty() {
+            return Ok(provider);
+        }
+        for name in ciphers {
+            let known = provider
+                .cipher_suites
+                .iter()
+                .any(|s| s.suite().as_str() == Some(name.as_str()));
+            if !known {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("不支持的加密套件{}", name),
+                ));
+            }
+        }
+        provider.cipher_suites.retain(|s| {
+            let name = s.suite().as_str().unwrap_or_default();
+            ciphers.iter().any(|c| c == name)
+        });
+        let usable = provider
+            .cipher_suites
+            .iter()
+            .any(|s| versions.contains(&s.version()));
+        if !usable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "加密套件均不适用于允许的TLS版本",
+            ));
+        }
+        Ok(provider)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TlsOption, TlsVersion};
+
+    #[test]
+    fn test_tls_option() {
+        assert_eq!("TLSv1.3".parse::<TlsVersion>().unwrap(), TlsVersion::Tls13);
+        assert_eq!("1.2".parse::<TlsVersion>().unwrap(), TlsVersion::Tls12);
+        assert!("1.1".parse::<TlsVersion>().is_err());
+
+        assert_eq!(TlsOption::versions(None, None).unwrap().len(), 2);
+        let versions = TlsOption::versions(Some(TlsVersion::Tls13), None).unwrap();
+        assert_eq!(versions, vec![TlsVersion::Tls13.supported()]);
+        assert!(TlsOption::versions(Some(TlsVersion::Tls13), Some(TlsVersion::Tls12)).is_err());
+
+        let ciphers = vec!["TLS13_AES_128_GCM_SHA256".to_string()];
+        let provider = TlsOption::provider(&ciphers, &versions).unwrap();
+        assert_eq!(provider.cipher_suites.len(), 1);
+        assert!(TlsOption::provider(&["unknown".to_string()], &versions).is_err());
+        // 仅允许TLS1.2时TLS1.3的套件不可用
+        let versions = TlsOption::versions(None, Some(TlsVersion::Tls12)).unwrap();
+        assert!(TlsOption::provider(&ciphers, &versions).is_err());
+    }
+}
@@ -10,17 +10,72 @@
 // -----
 // Created Date: 2023/10/20 10:19:47
 
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
 
+use lazy_static::lazy_static;
 use rand::Rng;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::CancellationToken;
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_with::DurationSeconds;
+use serde_with::{DisplayFromStr, DurationSeconds};
 
-use crate::HealthCheck;
+use crate::{ConfigDuration, DisplayFromStrOrNumber, H2Ping, HealthCheck};
+
+use super::ProxyProtocolVersion;
+
+lazy_static! {
+    // 每个后端正在处理的请求数, 用于最少连接的负载均衡
+    static ref UPSTREAM_CONNS: RwLock<HashMap<SocketAddr, Arc<AtomicUsize>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// 记录后端正在处理的请求数, 销毁时减少计数
+pub struct UpstreamConnGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl UpstreamConnGuard {
+    pub fn new(addr: SocketAddr) -> Self {
+        let count = Self::counter(&addr).unwrap_or_else(|| {
+            let mut conns = UPSTREAM_CONNS.write().unwrap();
+            conns.entry(addr).or_default().clone()
+        });
+        count.fetch_add(1, Ordering::Relaxed);
+        Self { count }
+    }
+
+    fn counter(addr: &SocketAddr) -> Option<Arc<AtomicUsize>> {
+        UPSTREAM_CONNS.read().ok()?.get(addr).cloned()
+    }
+
+    /// 后端正在处理的请求数
+    pub fn get_conns(addr: &SocketAddr) -> usize {
+        Self::counter(addr)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for UpstreamConnGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 fn default_weight() -> u16 {
-    100
+    1
 }
 
 fn fail_timeout() -> Duration {
@@ -35,12 +90,151 @@ fn default_rise_times() -> usize {
     2
 }
 
+fn default_max_fails() -> usize {
+    0
+}
+
+fn default_check_method() -> String {
+    "http".to_string()
+}
+
+fn default_check_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(5))
+}
+
+fn default_check_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(3))
+}
+
+/// unix socket地址的前缀, 如`unix:/run/app.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+fn default_ping_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(10))
+}
+
+fn default_resolve_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30))
+}
+
+fn default_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_check_rise() -> usize {
+    2
+}
+
+fn default_check_fall() -> usize {
+    3
+}
+
+fn default_sticky_cookie() -> String {
+    "wmproxy_sticky".to_string()
+}
+
+fn default_sticky_path() -> String {
+    "/".to_string()
+}
+
+/// upstream的主动健康检查配置
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveCheckConfig {
+    /// 检查方式, `http`发起GET请求, `tcp`只建立连接
+    #[serde(default = "default_check_method")]
+    pub method: String,
+    /// 每次检查的间隔
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_check_interval")]
+    pub interval: ConfigDuration,
+    /// 单次检查的超时时间
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_check_timeout")]
+    pub timeout: ConfigDuration,
+    /// HTTP检查的请求路径
+    #[serde(default = "default_check_path")]
+    pub path: String,
+    /// 连续成功该次数后标记为可用
+    #[serde(default = "default_check_rise")]
+    pub rise: usize,
+    /// 连续失败该次数后标记为不可用
+    #[serde(default = "default_check_fall")]
+    pub fall: usize,
+}
+
+impl ActiveCheckConfig {
+    pub fn new(method: String) -> Self {
+        Self {
+            method,
+            interval: default_check_interval(),
+            timeout: default_check_timeout(),
+            path: default_check_path(),
+            rise: default_check_rise(),
+            fall: default_check_fall(),
+        }
+    }
+}
+
+/// 按cookie保持会话, 首次返回时写入所选后端的哈希值, 之后的请求在该后端可用时固定转发到该后端
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StickyConfig {
+    /// cookie的名称
+    #[serde(default = "default_sticky_cookie")]
+    pub cookie: String,
+    /// cookie的路径
+    #[serde(default = "default_sticky_path")]
+    pub path: String,
+    /// cookie的有效期, 未配置时为会话cookie
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub max_age: Option<ConfigDuration>,
+}
+
+impl StickyConfig {
+    pub fn new(cookie: String) -> Self {
+        Self {
+            cookie,
+            path: default_sticky_path(),
+            max_age: None,
+        }
+    }
+
+    /// 后端地址对应的cookie值, 不直接暴露后端地址
+    pub fn hash_addr(&self, name: &str, addr: &SocketAddr) -> String {
+        let data = format!("{}/{}", name, addr);
+        let digest = ring::digest::digest(&ring::digest::SHA256, data.as_bytes());
+        digest.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// 从请求的Cookie头中取出该cookie的值
+    pub fn get_value<'a>(&self, cookie: &'a str) -> Option<&'a str> {
+        cookie.split(';').find_map(|kv| {
+            let (k, v) = kv.split_once('=')?;
+            (k.trim() == self.cookie).then(|| v.trim())
+        })
+    }
+
+    /// 返回时写入的Set-Cookie头
+    pub fn build_set_cookie(&self, value: &str) -> String {
+        let mut cookie = format!("{}={}; Path={}; HttpOnly", self.cookie, value, self.path);
+        if let Some(max_age) = &self.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age.0.as_secs()));
+        }
+        cookie
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SingleStreamConfig {
     /// 访问地址
     pub addr: SocketAddr,
-    /// 权重
+    /// 权重, 按权重比例分配请求, 为0时不参与负载均衡, 但仍进行健康检查
     #[serde(default = "default_weight")]
     pub weight: u16,
     /// 失败的恢复时间
@@ -53,18 +247,90 @@ pub struct SingleStreamConfig {
     /// 当前连续成功的次数
     #[serde(default = "default_rise_times")]
     rise_times: usize,
+    /// 被动检查, `fail_timeout`内连接失败或返回5xx达到该次数后,
+    /// 在`fail_timeout`内不再请求该后端, 为0时不开启
+    #[serde(default = "default_max_fails")]
+    pub max_fails: usize,
 
     #[serde(skip)]
     pub status: Option<String>,
 }
 
+/// 负载均衡的选择方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamBalance {
+    /// 按权重随机
+    #[default]
+    Random,
+    /// 按顺序轮流选择
+    #[serde(alias = "roundrobin")]
+    RoundRobin,
+    /// 按客户端IP做哈希, 同一IP落在同一后端
+    #[serde(alias = "iphash")]
+    IpHash,
+    /// 选择正在处理的请求数最少的后端, 按权重折算
+    #[serde(alias = "leastconn")]
+    LeastConn,
+}
+
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpstreamConfig {
     pub name: String,
     #[serde(default = "String::new")]
     pub bind: String,
+    #[serde(default)]
+    pub balance: UpstreamBalance,
     #[serde(default = "Vec::new")]
     pub server: Vec<SingleStreamConfig>,
+    /// 以域名配置的后端, 如`backend.internal:8080`, 解析出的每个地址均作为后端参与负载均衡,
+    /// 也可配置为`unix:/run/app.sock`的unix socket, 多个时轮流选择, 不能与TCP的后端同时配置
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// 重新解析`hosts`的间隔, 为0时只在启动时解析
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: ConfigDuration,
+    /// 空闲的HTTP/2复用连接发送PING的间隔, 未配置时不发送
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub ping_interval: Option<ConfigDuration>,
+    /// 发送PING后等待PONG的时长, 超时未回应则该连接不再复用, 未配置时为10秒
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub ping_timeout: Option<ConfigDuration>,
+    /// 主动健康检查
+    pub health_check: Option<ActiveCheckConfig>,
+    /// 同时建立连接的数量上限, 超出时等待其它连接建立完成, 0表示不限制
+    #[serde(default)]
+    pub max_connecting: usize,
+    /// 连接该组后端的超时时间, 未配置时使用location的proxy_connect_timeout
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub connect_timeout: Option<ConfigDuration>,
+    /// 等待该组后端返回的超时时间, 未配置时使用location的proxy_read_timeout
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub read_timeout: Option<ConfigDuration>,
+    /// 向该组后端发送请求的超时时间, 未配置时使用location的proxy_write_timeout
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub write_timeout: Option<ConfigDuration>,
+    /// 按cookie保持会话, 如`sticky = { cookie = "srv_id", max_age = "1h" }`
+    #[serde(default)]
+    pub sticky: Option<StickyConfig>,
+    /// 建立连接后先发送PROXY协议头(`v1`或`v2`), 携带客户端地址及其接入的地址, 复用的连接只发送一次
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    /// 轮询的计数, 克隆的配置共享同一计数
+    #[serde(skip)]
+    round_robin: Arc<AtomicUsize>,
+    #[serde(skip)]
+    connecting: Arc<OnceLock<Arc<Semaphore>>>,
+    /// 由`hosts`解析出的后端, 克隆的配置共享
+    #[serde(skip)]
+    resolved: Arc<RwLock<Vec<SingleStreamConfig>>>,
 }
 
 impl UpstreamConfig {
@@ -72,64 +338,691 @@ impl UpstreamConfig {
         Self {
             name,
             bind: String::new(),
+            balance: UpstreamBalance::Random,
             server: vec![SingleStreamConfig::new_simple(to)],
+            ping_interval: None,
+            ping_timeout: None,
+            health_check: None,
+            max_connecting: 0,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            sticky: None,
+            proxy_protocol: None,
+            round_robin: Arc::new(AtomicUsize::new(0)),
+            connecting: Arc::new(OnceLock::new()),
+            hosts: vec![],
+            resolve_interval: default_resolve_interval(),
+            resolved: Arc::new(RwLock::new(vec![])),
         }
     }
-    pub fn get_server_addr(&self) -> Option<SocketAddr> {
-        if self.server.is_empty() {
+
+    /// 获取建立连接的许可, 连接建立完成后释放, 未配置上限时返回None
+    pub async fn connect_permit(&self) -> Option<OwnedSemaphorePermit> {
+        if self.max_connecting == 0 {
             return None;
         }
-        let (sum, sum_all) = self.calc_sum_weight();
+        let semaphore = self
+            .connecting
+            .get_or_init(|| Arc::new(Semaphore::new(self.max_connecting)))
+            .clone();
+        semaphore.acquire_owned().await.ok()
+    }
+    /// 参与负载均衡的所有后端, 包含由`hosts`解析出的后端
+    pub fn servers(&self) -> Cow<'_, [SingleStreamConfig]> {
+        if self.hosts.is_empty() {
+            return Cow::Borrowed(&self.server);
+        }
+        let mut servers = self.server.clone();
+        if let Ok(resolved) = self.resolved.read() {
+            servers.extend(resolved.iter().cloned());
+        }
+        Cow::Owned(servers)
+    }
+
+    /// 获取后端地址, `client`为客户端地址, 在`ip_hash`模式下用来固定后端
+    pub fn get_server_addr(&self, client: Option<&SocketAddr>) -> Option<SocketAddr> {
+        let servers = self.servers();
+        if servers.iter().all(|s| s.weight == 0) {
+            return None;
+        }
+        match self.balance {
+            UpstreamBalance::IpHash => {
+                if let Some(client) = client {
+                    return Some(Self::get_server_addr_by_hash(&servers, client));
+                }
+            }
+            UpstreamBalance::RoundRobin => return Some(self.get_server_addr_by_round(&servers)),
+            UpstreamBalance::LeastConn => return Some(self.get_server_addr_by_conns(&servers)),
+            UpstreamBalance::Random => {}
+        }
+        let (sum, sum_all) = Self::sum_weight(&servers);
         let mut rng = rand::thread_rng();
         if sum != 0 {
             let mut random_weight = rng.gen_range(0..sum);
-            for server in &self.server {
-                if !HealthCheck::check_fall_down(
-                    &server.addr,
-                    &server.fail_timeout,
-                    &server.fall_times,
-                    &server.rise_times,
-                ) {
-                    if random_weight <= server.weight {
+            for server in servers.iter() {
+                if !server.is_fall_down() {
+                    if random_weight < server.weight as u32 {
                         return Some(server.addr.clone());
                     }
-                    random_weight -= server.weight;
+                    random_weight -= server.weight as u32;
                 }
             }
         } else {
             let mut random_weight = rng.gen_range(0..sum_all);
-            for server in &self.server {
-                if random_weight <= server.weight {
+            for server in servers.iter() {
+                if random_weight < server.weight as u32 {
                     return Some(server.addr.clone());
                 }
-                random_weight -= server.weight;
+                random_weight -= server.weight as u32;
             }
         }
         return None;
     }
 
-    pub fn calc_sum_weight(&self) -> (u16, u16) {
+    /// 获取后端地址, 跳过`except`中已尝试过的后端, 用于失败后重试下一个后端
+    pub fn get_server_addr_except(
+        &self,
+        client: Option<&SocketAddr>,
+        except: &[SocketAddr],
+    ) -> Option<SocketAddr> {
+        if let Some(addr) = self.get_server_addr(client) {
+            if !except.contains(&addr) {
+                return Some(addr);
+            }
+        }
+        let mut candidate = None;
+        for server in self.servers().iter() {
+            if except.contains(&server.addr) || server.weight == 0 {
+                continue;
+            }
+            if !server.is_fall_down() {
+                return Some(server.addr);
+            }
+            candidate = candidate.or(Some(server.addr));
+        }
+        candidate
+    }
+
+    /// 获取地址对应的后端配置
+    pub fn get_server(&self, addr: &SocketAddr) -> Option<SingleStreamConfig> {
+        self.servers().iter().find(|s| &s.addr == addr).cloned()
+    }
+
+    /// 请求的会话保持cookie对应的后端, 该后端不可用或不再参与负载均衡时返回None, 由负载均衡重新选择
+    pub fn get_sticky_addr(&self, cookie: Option<&str>) -> Option<SocketAddr> {
+        let sticky = self.sticky.as_ref()?;
+        let value = sticky.get_value(cookie?)?;
+        self.servers()
+            .iter()
+            .filter(|s| s.weight > 0 && !s.is_fall_down())
+            .find(|s| sticky.hash_addr(&self.name, &s.addr) == value)
+            .map(|s| s.addr)
+    }
+
+    /// 转发到`addr`后需写入的Set-Cookie, 请求中的cookie已指向该后端时返回None
+    pub fn get_sticky_cookie(&self, cookie: Option<&str>, addr: &SocketAddr) -> Option<String> {
+        let sticky = self.sticky.as_ref()?;
+        self.get_server(addr)?;
+        let value = sticky.hash_addr(&self.name, addr);
+        if cookie.and_then(|c| sticky.get_value(c)) == Some(value.as_str()) {
+            return None;
+        }
+        Some(sticky.build_set_cookie(&value))
+    }
+
+    /// 参与负载均衡的可用后端, 全部不可用时返回所有参与负载均衡的后端
+    fn alive_servers(servers: &[SingleStreamConfig]) -> Vec<&SingleStreamConfig> {
+        let servers = servers.iter().filter(|s| s.weight > 0);
+        let alive = servers
+            .clone()
+            .filter(|s| !s.is_fall_down())
+            .collect::<Vec<_>>();
+        if alive.is_empty() {
+            servers.collect()
+        } else {
+            alive
+        }
+    }
+
+    /// 在可用的后端中按权重轮流选择
+    fn get_server_addr_by_round(&self, servers: &[SingleStreamConfig]) -> SocketAddr {
+        let alive = Self::alive_servers(servers);
+        let sum = alive.iter().map(|s| s.weight as usize).sum::<usize>();
+        let mut index = self.round_robin.fetch_add(1, Ordering::Relaxed) % sum;
+        for server in &alive {
+            if index < server.weight as usize {
+                return server.addr;
+            }
+            index -= server.weight as usize;
+        }
+        alive[0].addr
+    }
+
+    /// 选择`请求数/权重`最小的后端, 相同时按轮询顺序选择
+    fn get_server_addr_by_conns(&self, servers: &[SingleStreamConfig]) -> SocketAddr {
+        let alive = Self::alive_servers(servers);
+        let start = self.round_robin.fetch_add(1, Ordering::Relaxed);
+        let mut best: Option<(&SingleStreamConfig, usize)> = None;
+        for i in 0..alive.len() {
+            let server = alive[(start + i) % alive.len()];
+            let conns = UpstreamConnGuard::get_conns(&server.addr);
+            let better = match best {
+                None => true,
+                // 比较 conns / weight < best_conns / best_weight
+                Some((b, b_conns)) => {
+                    conns * (b.weight as usize) < b_conns * (server.weight as usize)
+                }
+            };
+            if better {
+                best = Some((server, conns));
+            }
+        }
+        best.map(|(s, _)| s.addr).unwrap()
+    }
+
+    /// 按客户端IP哈希选择后端, 不计算端口, 后端不可用时顺延到下一个可用的后端
+    fn get_server_addr_by_hash(servers: &[SingleStreamConfig], client: &SocketAddr) -> SocketAddr {
+        let servers = servers.iter().filter(|s| s.weight > 0).collect::<Vec<_>>();
+        let mut hasher = DefaultHasher::new();
+        client.ip().hash(&mut hasher);
+        let start = (hasher.finish() % servers.len() as u64) as usize;
+        for i in 0..servers.len() {
+            let server = servers[(start + i) % servers.len()];
+            if !server.is_fall_down() {
+                return server.addr;
+            }
+        }
+        // 全部不可用时保留原始的映射
+        servers[start].addr
+    }
+
+    pub fn calc_sum_weight(&self) -> (u32, u32) {
+        Self::sum_weight(&self.servers())
+    }
+
+    fn sum_weight(servers: &[SingleStreamConfig]) -> (u32, u32) {
         let mut sum = 0;
         let mut sum_all = 0;
-        for server in &self.server {
-            if !HealthCheck::is_fall_down(&server.addr) {
-                sum += server.weight;
+        for server in servers {
+            // 与选择后端时的判断保持一致, 否则权重和会包含被跳过的后端
+            if !server.is_fall_down() {
+                sum += server.weight as u32;
             }
-            sum_all += server.weight;
+            sum_all += server.weight as u32;
         }
         return (sum, sum_all);
     }
+
+    /// `hosts`中以`unix:`配置的unix socket路径
+    pub fn unix_paths(&self) -> Vec<&str> {
+        self.hosts
+            .iter()
+            .filter_map(|h| h.strip_prefix(UNIX_PREFIX))
+            .collect()
+    }
+
+    /// 轮流选择一个unix socket, 未配置时返回None
+    pub fn get_unix_path(&self) -> Option<String> {
+        let paths = self.unix_paths();
+        if paths.is_empty() {
+            return None;
+        }
+        let index = self.round_robin.fetch_add(1, Ordering::Relaxed) % paths.len();
+        Some(paths[index].to_string())
+    }
+
+    /// 配置了`ping_interval`时新建连接的PING探测, 为0时不探测
+    pub fn build_h2_ping(&self) -> Option<Arc<H2Ping>> {
+        let interval = self.ping_interval.as_ref()?.0;
+        if interval.is_zero() {
+            return None;
+        }
+        let timeout = self.ping_timeout.clone().unwrap_or_else(default_ping_timeout);
+        Some(Arc::new(H2Ping::new(interval, timeout.0)))
+    }
+
+    /// 检查unix socket的配置, 非unix平台不支持, 且不能与TCP的后端同时配置
+    pub fn check_unix(&self) -> io::Result<()> {
+        let paths = self.unix_paths();
+        if paths.is_empty() {
+            return Ok(());
+        }
+        if cfg!(not(unix)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("upstream:{}配置了unix socket, 当前平台不支持", self.name),
+            ));
+        }
+        if !self.server.is_empty() || paths.len() != self.hosts.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("upstream:{}不能同时配置unix socket及TCP的后端", self.name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 解析`hosts`中的域名, 每个地址作为一个后端, 解析失败或无结果时保留上次的结果
+    pub async fn resolve_hosts(&self) {
+        let mut servers = vec![];
+        for host in self.hosts.iter().filter(|h| !h.starts_with(UNIX_PREFIX)) {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(addrs) => {
+                    let before = servers.len();
+                    for addr in addrs {
+                        if !servers.iter().any(|s: &SingleStreamConfig| s.addr == addr) {
+                            servers.push(SingleStreamConfig::new_simple(addr));
+                        }
+                    }
+                    if servers.len() == before {
+                        log::warn!("upstream:{}解析{}无结果, 保留上次解析的地址", self.name, host);
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("upstream:{}解析{}失败, 保留上次解析的地址:{:?}", self.name, host, e);
+                    return;
+                }
+            }
+        }
+        if let Ok(mut resolved) = self.resolved.write() {
+            let old = resolved.iter().map(|s| s.addr).collect::<Vec<_>>();
+            if old != servers.iter().map(|s| s.addr).collect::<Vec<_>>() {
+                log::info!(
+                    "upstream:{}的后端由{:?}更新为{:?}",
+                    self.name,
+                    old,
+                    servers.iter().map(|s| s.addr).collect::<Vec<_>>()
+                );
+            }
+            *resolved = servers;
+        }
+    }
+
+    /// 启动时解析所有upstream的`hosts`, 之后按`resolve_interval`定时重新解析, 直到收到取消信号
+    pub async fn start_resolve_all<'a>(
+        upstreams: impl Iterator<Item = &'a UpstreamConfig>,
+        cancel: &CancellationToken,
+    ) {
+        let mut already = HashSet::new();
+        for up in upstreams {
+            // 克隆的配置共享解析结果, 只需解析一次
+            let has_tcp = up.hosts.iter().any(|h| !h.starts_with(UNIX_PREFIX));
+            if !has_tcp || !already.insert(Arc::as_ptr(&up.resolved) as usize) {
+                continue;
+            }
+            up.resolve_hosts().await;
+            let interval = up.resolve_interval.0;
+            if interval.is_zero() {
+                continue;
+            }
+            let (up, cancel) = (up.clone(), cancel.clone());
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(interval) => {}
+                        _ = cancel.cancelled() => {
+                            log::trace!("upstream:{}的域名解析, 收到退出信号", up.name);
+                            break;
+                        }
+                    }
+                    up.resolve_hosts().await;
+                }
+            });
+        }
+    }
 }
 
 impl SingleStreamConfig {
     pub fn new_simple(addr: SocketAddr) -> Self {
         Self {
             addr,
-            weight: 100,
+            weight: default_weight(),
             fail_timeout: Duration::from_secs(60),
             fall_times: 3,
             rise_times: 2,
+            max_fails: 0,
             status: None,
         }
     }
+
+    /// 后端是否不可用, 主动健康检查的结果优先, 否则按被动的失败次数判断
+    pub fn is_fall_down(&self) -> bool {
+        if self.max_fails > 0 && HealthCheck::is_passive_down(&self.addr) {
+            return true;
+        }
+        HealthCheck::check_fall_down(
+            &self.addr,
+            &self.fail_timeout,
+            &self.fall_times,
+            &self.rise_times,
+        )
+    }
+
+    /// 记录一次后端导致的失败
+    pub fn add_passive_fail(&self) {
+        HealthCheck::add_passive_fail(self.addr, self.max_fails, self.fail_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{reverse::ReverseHelper, HealthCheck};
+
+    use super::{
+        SingleStreamConfig, StickyConfig, UpstreamBalance, UpstreamConfig, UpstreamConnGuard,
+    };
+
+    #[test]
+    fn test_ip_hash() {
+        let mut upstream = UpstreamConfig::new_single(
+            "ip_hash".to_string(),
+            "127.0.0.1:19001".parse().unwrap(),
+        );
+        upstream.balance = UpstreamBalance::IpHash;
+        for port in 19002..19005 {
+            upstream.server.push(SingleStreamConfig::new_simple(
+                format!("127.0.0.1:{}", port).parse().unwrap(),
+            ));
+        }
+        let client: SocketAddr = "192.168.1.100:5000".parse().unwrap();
+        let other_port: SocketAddr = "192.168.1.100:6000".parse().unwrap();
+        let origin = upstream.get_server_addr(Some(&client)).unwrap();
+        for _ in 0..10 {
+            assert_eq!(upstream.get_server_addr(Some(&client)), Some(origin));
+        }
+        assert_eq!(upstream.get_server_addr(Some(&other_port)), Some(origin));
+
+        // 后端不可用时顺延到下一个
+        for _ in 0..3 {
+            HealthCheck::add_fall_down(origin);
+        }
+        let next = upstream.get_server_addr(Some(&client)).unwrap();
+        assert_ne!(next, origin);
+        assert_eq!(upstream.get_server_addr(Some(&client)), Some(next));
+
+        // 恢复后回到原来的映射
+        for _ in 0..2 {
+            HealthCheck::add_rise_up(origin);
+        }
+        assert_eq!(upstream.get_server_addr(Some(&client)), Some(origin));
+    }
+
+    #[test]
+    fn test_skip_fall_down() {
+        let dead: SocketAddr = "127.0.0.1:19101".parse().unwrap();
+        let alive: SocketAddr = "127.0.0.1:19102".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("skip".to_string(), dead);
+        upstream.server.push(SingleStreamConfig::new_simple(alive));
+
+        // 主动检查标记为不可用的后端不参与负载均衡
+        HealthCheck::set_active_status(dead, true);
+        assert_eq!(upstream.calc_sum_weight(), (1, 2));
+        for _ in 0..50 {
+            assert_eq!(upstream.get_server_addr(None), Some(alive));
+        }
+        assert_eq!(upstream.get_server_addr_except(None, &[alive]), Some(dead));
+
+        // 恢复后重新参与负载均衡
+        HealthCheck::set_active_status(dead, false);
+        assert_eq!(upstream.calc_sum_weight(), (2, 2));
+        let picked = (0..200)
+            .filter(|_| upstream.get_server_addr(None) == Some(dead))
+            .count();
+        assert!(picked > 0);
+    }
+
+    #[test]
+    fn test_passive_fail() {
+        let addr: SocketAddr = "127.0.0.1:19111".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:19112".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("passive".to_string(), addr);
+        upstream.server[0].max_fails = 2;
+        upstream.server[0].fail_timeout = Duration::from_millis(100);
+        upstream.server.push(SingleStreamConfig::new_simple(other));
+        let server = upstream.get_server(&addr).unwrap().clone();
+
+        // 未达到失败次数时仍可用
+        server.add_passive_fail();
+        assert!(!server.is_fall_down());
+        server.add_passive_fail();
+        assert!(server.is_fall_down());
+        for _ in 0..20 {
+            assert_eq!(upstream.get_server_addr(None), Some(other));
+        }
+
+        // 不可用时间结束后只允许一个试探请求, 试探失败时重新标记为不可用
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(!server.is_fall_down());
+        assert!(HealthCheck::passive_begin(&addr));
+        assert!(server.is_fall_down());
+        assert!(!HealthCheck::passive_begin(&addr));
+        server.add_passive_fail();
+        assert!(server.is_fall_down());
+
+        // 试探请求被取消时由后续的请求重新试探, 试探成功后恢复
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(HealthCheck::passive_begin(&addr));
+        HealthCheck::passive_cancel(&addr);
+        assert!(!server.is_fall_down());
+        assert!(HealthCheck::passive_begin(&addr));
+        HealthCheck::add_passive_success(addr);
+        assert!(!server.is_fall_down());
+        assert!(!HealthCheck::passive_begin(&addr));
+    }
+
+    #[test]
+    fn test_sticky() {
+        let first: SocketAddr = "127.0.0.1:19141".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:19142".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("sticky".to_string(), first);
+        upstream.server.push(SingleStreamConfig::new_simple(second));
+        assert_eq!(upstream.get_sticky_cookie(None, &first), None);
+        upstream.sticky = Some(StickyConfig::new("srv".to_string()));
+
+        // cookie为哈希值, 不包含后端地址
+        let set = upstream.get_sticky_cookie(None, &second).unwrap();
+        let value = set.split(';').next().unwrap().to_string();
+        assert!(!value.contains("19142"), "{}", value);
+        assert!(set.ends_with("; Path=/; HttpOnly"), "{}", set);
+        let cookie = format!("a=1; {}", value);
+        assert_eq!(upstream.get_sticky_addr(Some(&cookie)), Some(second));
+        assert_eq!(upstream.get_sticky_cookie(Some(&cookie), &second), None);
+        assert!(upstream.get_sticky_cookie(Some(&cookie), &first).is_some());
+        assert_eq!(upstream.get_sticky_addr(Some("srv=unknown")), None);
+        assert_eq!(upstream.get_sticky_addr(None), None);
+
+        // 固定的后端不可用时重新选择
+        HealthCheck::set_active_status(second, true);
+        assert_eq!(upstream.get_sticky_addr(Some(&cookie)), None);
+        HealthCheck::set_active_status(second, false);
+        assert_eq!(upstream.get_sticky_addr(Some(&cookie)), Some(second));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let addrs: Vec<SocketAddr> = (19121..19124)
+            .map(|p| format!("127.0.0.1:{}", p).parse().unwrap())
+            .collect();
+        let mut upstream = UpstreamConfig::new_single("round".to_string(), addrs[0]);
+        upstream.balance = UpstreamBalance::RoundRobin;
+        upstream.server.push(SingleStreamConfig::new_simple(addrs[1]));
+        upstream.server.push(SingleStreamConfig::new_simple(addrs[2]));
+        let first = upstream.get_server_addr(None).unwrap();
+        let start = addrs.iter().position(|a| *a == first).unwrap();
+        for i in 1..9 {
+            assert_eq!(upstream.get_server_addr(None), Some(addrs[(start + i) % 3]));
+        }
+
+        // 克隆的配置共享计数, 不可用的后端被跳过
+        let clone = upstream.clone();
+        HealthCheck::set_active_status(addrs[1], true);
+        for _ in 0..6 {
+            assert_ne!(clone.get_server_addr(None), Some(addrs[1]));
+        }
+        let picked = (0..6)
+            .filter(|_| upstream.get_server_addr(None) == Some(addrs[0]))
+            .count();
+        assert_eq!(picked, 3);
+        HealthCheck::set_active_status(addrs[1], false);
+    }
+
+    #[test]
+    fn test_least_conn() {
+        let busy: SocketAddr = "127.0.0.1:19131".parse().unwrap();
+        let idle: SocketAddr = "127.0.0.1:19132".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("least".to_string(), busy);
+        upstream.balance = UpstreamBalance::LeastConn;
+        upstream.server.push(SingleStreamConfig::new_simple(idle));
+
+        let guards = vec![UpstreamConnGuard::new(busy), UpstreamConnGuard::new(busy)];
+        assert_eq!(UpstreamConnGuard::get_conns(&busy), 2);
+        for _ in 0..10 {
+            assert_eq!(upstream.get_server_addr(None), Some(idle));
+        }
+
+        // 按权重折算, 权重高的后端可承担更多请求
+        upstream.server[0].weight = 300;
+        let _idle = UpstreamConnGuard::new(idle);
+        assert_eq!(upstream.get_server_addr(None), Some(busy));
+
+        drop(guards);
+        assert_eq!(UpstreamConnGuard::get_conns(&busy), 0);
+    }
+
+    #[test]
+    fn test_balance_names() {
+        #[derive(serde::Deserialize)]
+        struct Wrap {
+            balance: UpstreamBalance,
+        }
+        for (name, balance) in [
+            ("random", UpstreamBalance::Random),
+            ("roundrobin", UpstreamBalance::RoundRobin),
+            ("round_robin", UpstreamBalance::RoundRobin),
+            ("iphash", UpstreamBalance::IpHash),
+            ("ip_hash", UpstreamBalance::IpHash),
+            ("leastconn", UpstreamBalance::LeastConn),
+            ("least_conn", UpstreamBalance::LeastConn),
+        ] {
+            let wrap: Wrap = toml::from_str(&format!("balance = \"{}\"", name)).unwrap();
+            assert_eq!(wrap.balance, balance);
+        }
+    }
+
+    #[test]
+    fn test_weight() {
+        let heavy: SocketAddr = "127.0.0.1:19141".parse().unwrap();
+        let light: SocketAddr = "127.0.0.1:19142".parse().unwrap();
+        let drain: SocketAddr = "127.0.0.1:19143".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("weight".to_string(), heavy);
+        upstream.server[0].weight = 3;
+        upstream.server.push(SingleStreamConfig::new_simple(light));
+        upstream.server.push(SingleStreamConfig::new_simple(drain));
+        upstream.server[2].weight = 0;
+
+        // 轮询时严格按权重比例分配
+        upstream.balance = UpstreamBalance::RoundRobin;
+        let picked = (0..400)
+            .map(|_| upstream.get_server_addr(None).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(picked.iter().filter(|a| **a == heavy).count(), 300);
+        assert_eq!(picked.iter().filter(|a| **a == light).count(), 100);
+
+        // 随机时大致按权重比例分配, 权重为0的后端不参与
+        for balance in [UpstreamBalance::Random, UpstreamBalance::LeastConn] {
+            upstream.balance = balance;
+            let picked = (0..4000)
+                .map(|_| upstream.get_server_addr(None).unwrap())
+                .collect::<Vec<_>>();
+            assert!(!picked.contains(&drain));
+            if balance == UpstreamBalance::Random {
+                let count = picked.iter().filter(|a| **a == heavy).count();
+                assert!(count > 2700 && count < 3300, "{}", count);
+            }
+        }
+        upstream.balance = UpstreamBalance::IpHash;
+        for port in 1..50 {
+            let client: SocketAddr = format!("10.0.0.{}:80", port).parse().unwrap();
+            assert_ne!(upstream.get_server_addr(Some(&client)), Some(drain));
+        }
+        assert_ne!(upstream.get_server_addr_except(None, &[heavy, light]), Some(drain));
+
+        // 全部为0时没有可用的后端
+        upstream.server[0].weight = 0;
+        upstream.server[1].weight = 0;
+        assert_eq!(upstream.get_server_addr(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_max_connecting() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut upstream = UpstreamConfig::new_single("connecting".to_string(), addr);
+        upstream.max_connecting = 2;
+        let upstreams = Arc::new(vec![upstream]);
+        let connecting = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = vec![];
+        for _ in 0..20 {
+            let (upstreams, connecting, max) = (upstreams.clone(), connecting.clone(), max.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = ReverseHelper::get_connect_permit(&upstreams, "connecting").await;
+                let now = connecting.fetch_add(1, Ordering::SeqCst) + 1;
+                max.fetch_max(now, Ordering::SeqCst);
+                let stream = HealthCheck::connect(&addr).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                connecting.fetch_sub(1, Ordering::SeqCst);
+                stream
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(max.load(Ordering::SeqCst), 2);
+
+        // 克隆的配置共享上限, 未配置时不限制
+        let clone = upstreams[0].clone();
+        let _permits = (clone.connect_permit().await, clone.connect_permit().await);
+        let wait = tokio::time::timeout(Duration::from_millis(50), upstreams[0].connect_permit());
+        assert!(wait.await.is_err());
+        let unlimited = UpstreamConfig::new_single("unlimited".to_string(), addr);
+        assert!(unlimited.connect_permit().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_hosts() {
+        let addr: SocketAddr = "127.0.0.1:19311".parse().unwrap();
+        let mut upstream = UpstreamConfig::new_single("dns".to_string(), addr);
+        upstream.hosts = vec!["localhost:19312".to_string()];
+        upstream.resolve_hosts().await;
+        let servers = upstream.servers();
+        assert!(servers.len() > 1);
+        assert!(servers.iter().skip(1).all(|s| s.addr.port() == 19312));
+        assert!(servers
+            .iter()
+            .any(|s| s.addr == "127.0.0.1:19312".parse().unwrap()));
+        let resolved = servers.len();
+
+        // 克隆的配置共享解析结果, 解析失败时保留上次的地址
+        let mut clone = upstream.clone();
+        clone.hosts.push("unknown.invalid:80".to_string());
+        clone.resolve_hosts().await;
+        assert_eq!(upstream.servers().len(), resolved);
+        assert_eq!(clone.servers().len(), resolved);
+        let select = clone.get_server_addr_except(None, &[addr]).unwrap();
+        assert_eq!(select.port(), 19312);
+        assert_eq!(ReverseHelper::get_upstream_tries(&[clone], "dns"), resolved);
+    }
 }
@@ -10,32 +10,127 @@
 // -----
 // Created Date: 2023/10/18 02:32:23
 
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc, Mutex};
 
 use async_trait::async_trait;
 
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{channel, Receiver, Sender},
+};
 
-use webparse::ws::{CloseData, OwnedMessage};
+use webparse::{
+    ws::{CloseData, OwnedMessage},
+    Request, Response,
+};
 use wenmeng::{
     ws::{WsHandshake, WsOption, WsTrait},
-    Client, ProtError, ProtResult,
+    Body, Client, MaybeHttpsStream, ProtError, ProtResult, RecvRequest, RecvResponse,
+};
+
+use crate::{data::AccessStat, Helper};
+
+use super::{
+    http::InnerHttpOper, HttpConfig, LocationConfig, ReverseHelper, ServerConfig, UpstreamConnGuard,
 };
 
-use super::{ReverseHelper, ServerConfig};
+/// 透传的升级连接, 后端原始的返回头将原样发给客户端, 之后双向拷贝数据
+pub struct UpgradeTunnel {
+    /// 后端返回的状态码
+    pub status: u16,
+    /// 后端返回的原始数据, 包含头信息及之后多读取的数据
+    pub head: Vec<u8>,
+    pub stream: MaybeHttpsStream<TcpStream>,
+    /// 连接存续期间计入后端的活跃连接数
+    pub conn: Option<UpstreamConnGuard>,
+}
+
+impl UpgradeTunnel {
+    /// 后端返回头的最大长度
+    pub const MAX_HEAD_LEN: usize = 16 * 1024;
+
+    /// 将后端的返回头发给客户端, 之后双向透传, 一端关闭写入时关闭另一端的写入, 两个方向均结束后返回
+    pub async fn splice<T>(mut self, inbound: &mut T) -> std::io::Result<(u64, u64)>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        inbound.write_all(&self.head).await?;
+        tokio::io::copy_bidirectional(inbound, &mut self.stream).await
+    }
+}
+
+/// 透传升级请求时存放后端的连接, 请求通过与HTTP相同的检查后由匹配的location连接后端
+#[derive(Clone)]
+pub struct UpgradeSlot(pub Arc<Mutex<Option<UpgradeTunnel>>>);
+
+impl UpgradeSlot {
+    /// 连接后端并保存透传的连接, 返回后端的状态码, 连接失败时返回502
+    pub async fn upgrade(
+        &self,
+        location: &LocationConfig,
+        req: &mut Request<Body>,
+    ) -> ProtResult<Response<Body>> {
+        match location.deal_upgrade(req).await {
+            Ok(tunnel) => {
+                let res = Response::builder()
+                    .status(tunnel.status)
+                    .body(Body::empty())?;
+                *self.0.lock().unwrap() = Some(tunnel);
+                Ok(res)
+            }
+            Err(e) => {
+                log::warn!("反向代理：升级请求连接后端失败：{:?}", e);
+                Ok(Response::status502()
+                    .body("bad gateway")
+                    .unwrap()
+                    .into_type())
+            }
+        }
+    }
+}
 
 pub struct ServerWsOperate {
     inner: InnerWsOper,
     sender: Option<Sender<OwnedMessage>>,
+    /// 与HTTP处理共用的连接信息, 升级请求计入该连接的请求数
+    oper: InnerHttpOper,
+    /// 透传到后端的升级连接, 由连接处理方取出后进行双向拷贝
+    tunnel: Arc<Mutex<Option<UpgradeTunnel>>>,
 }
 
 #[async_trait]
 impl WsTrait for ServerWsOperate {
+    /// 握手请求同样记录访问日志及统计
+    async fn on_request(&mut self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+        self.oper.req_num.fetch_add(1, Ordering::Relaxed);
+        let passthrough = ReverseHelper::get_location_by_req(&self.inner.servers, req)
+            .is_some_and(|l| l.is_upgrade_passthrough());
+        if passthrough {
+            return self.deal_passthrough(req).await;
+        }
+        let res = WsHandshake::build_request(req)?;
+        AccessStat::on_request(res.status().as_u16());
+        if let Some(location) = ReverseHelper::get_location_by_req(&self.inner.servers, req) {
+            Helper::log_acess_res(
+                &location.comm.log_format,
+                &location.comm.access_log,
+                req,
+                &res,
+            );
+        }
+        Ok(res)
+    }
+
     /// 握手完成后之后的回调,服务端返回了Response之后就认为握手成功
     async fn on_open(&mut self, shake: WsHandshake) -> ProtResult<Option<WsOption>> {
         if shake.request.is_none() {
             return Err(ProtError::Extension("miss request"));
         }
+        // 已透传到后端的连接不再按websocket处理, 返回后由连接处理方接管
+        if self.tunnel.lock().unwrap().is_some() {
+            return Err(ProtError::Extension("upgrade passthrough"));
+        }
         let mut option = WsOption::new();
         if let Some(location) =
             ReverseHelper::get_location_by_req(&self.inner.servers, shake.request.as_ref().unwrap())
@@ -44,7 +139,12 @@ impl WsTrait for ServerWsOperate {
                 return Err(ProtError::Extension("Not Support Ws"));
             }
             if let Ok((url, domain)) = location.get_reverse_url() {
-                println!("connect url = {}, domain = {:?}", url, domain);
+                log::trace!(
+                    "websocket转发：location:{} 连接后端{}, domain:{:?}",
+                    location.rule,
+                    url,
+                    domain
+                );
                 let mut client = Client::builder()
                     .url(url)?
                     .connect_with_domain(&domain)
@@ -65,9 +165,9 @@ impl WsTrait for ServerWsOperate {
                         .wait_ws_operate_with_req(shake.request.unwrap())
                         .await
                     {
-                        println!("error = {:?}", e);
+                        log::debug!("websocket转发：后端连接发生错误：{:?}", e);
                     };
-                    println!("client close!!!!!!!!!!");
+                    log::trace!("websocket转发：后端连接已关闭");
                 });
             }
             return Ok(Some(option));
@@ -118,11 +218,52 @@ impl InnerWsOper {
 }
 
 impl ServerWsOperate {
-    pub fn new(http: Vec<Arc<ServerConfig>>) -> Self {
+    pub(crate) fn new(oper: InnerHttpOper) -> Self {
         Self {
-            inner: InnerWsOper::new(http),
+            inner: InnerWsOper::new(oper.servers.clone()),
             sender: None,
+            oper,
+            tunnel: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn tunnel(&self) -> Arc<Mutex<Option<UpgradeTunnel>>> {
+        self.tunnel.clone()
+    }
+
+    /// 升级请求经过与HTTP请求相同的检查后透传到后端, 成功时返回101使服务端进入`on_open`,
+    /// 实际返回头由后端提供
+    async fn deal_passthrough(&mut self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+        let mut up = Request::builder().body(Body::empty())?;
+        *up.parts_mut() = req.parts().clone();
+        up.extensions_mut().insert(UpgradeSlot(self.tunnel.clone()));
+        let res = match HttpConfig::inner_operate(&mut up, &mut self.oper).await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("反向代理：处理升级请求失败：{:?}", e);
+                Response::status502()
+                    .body("bad gateway")
+                    .unwrap()
+                    .into_type()
+            }
+        };
+        AccessStat::on_request(res.status().as_u16());
+        if let Some(location) = ReverseHelper::get_location_by_req(&self.inner.servers, &up) {
+            Helper::log_acess_res(
+                &location.comm.log_format,
+                &location.comm.access_log,
+                req,
+                &res,
+            );
+        }
+        if self.tunnel.lock().unwrap().is_some() {
+            // 之后的数据不再有请求头, 不再限制接收请求头的时间
+            if let Some(deadline) = &self.oper.header_deadline {
+                deadline.disable();
+            }
+            return Ok(Response::builder().status(101).body(Body::empty())?);
         }
+        Ok(res)
     }
 }
 
@@ -12,7 +12,7 @@
 
 use std::sync::Arc;
 use std::time::Duration;
-use std::{collections::HashMap, io};
+use std::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::mpsc::Receiver;
 use tokio::{io::split, net::TcpStream, sync::mpsc::channel};
@@ -26,8 +26,8 @@ use webparse::{BinaryMut, Buf};
 
 use crate::proxy::ProxyServer;
 use crate::{
-    HealthCheck, Helper, MappingConfig, ProtClose, ProtCreate, ProtFrame, ProxyConfig, ProxyResult,
-    TransStream, VirtualStream,
+    HealthCheck, Helper, KeepAlive, MappingConfig, ProtCloseCode, ProtCreate, ProtFrame,
+    ProxyConfig, ProxyResult, SockMap, TransStream, VirtualStream,
 };
 
 /// 中心客户端
@@ -133,13 +133,16 @@ impl CenterClient {
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
-        let mut map = HashMap::<u64, Sender<ProtFrame>>::new();
+        let mut map = SockMap::new();
         let mut read_buf = BinaryMut::new();
         let mut write_buf = BinaryMut::new();
         let (mut reader, mut writer) = split(stream);
         let mut vec = Vec::with_capacity(4096);
         vec.resize(4096, 0);
         let is_closed;
+        let mut keep_alive = KeepAlive::new(option.ping_interval.0, option.ping_timeout.0);
+        let max_frame_size = option.max_frame_size.0 as usize;
+        let mut close_code = ProtCloseCode::Normal;
         if option.username.is_some() && option.password.is_some() {
             ProtFrame::new_token(
                 option.username.clone().unwrap(),
@@ -164,9 +167,11 @@ impl CenterClient {
                 // 数据的接收，并将数据写入给远程端
                 r = receiver.recv() => {
                     if let Some(p) = r {
-                        let _ = p.encode(&mut write_buf);
+                        let _ = p.encode_by(&mut write_buf, option.compress);
                     }
                 }
+                // 转发之前因接收端已满而缓存的消息
+                _ = map.flush(), if map.has_pending() => {}
                 // 数据的等待读取，一旦流可读则触发，读到0则关闭主动关闭所有连接
                 r = reader.read(&mut vec) => {
                     match r {
@@ -183,6 +188,20 @@ impl CenterClient {
                         },
                     }
                 }
+                // 定时发送心跳, 超时未回应则关闭所有连接
+                _ = tokio::time::sleep_until(keep_alive.deadline()), if keep_alive.is_enable() => {
+                    match keep_alive.tick() {
+                        Some(p) => {
+                            let _ = p.encode(&mut write_buf);
+                        }
+                        None => {
+                            log::warn!("内网穿透:心跳超时未回应, 断开与服务端的连接");
+                            close_code = ProtCloseCode::Timeout;
+                            is_closed = true;
+                            break;
+                        }
+                    }
+                }
                 // 一旦有写数据，则尝试写入数据，写入成功后扣除相应的数据
                 r = writer.write(write_buf.chunk()), if write_buf.has_remaining() => {
                     match r {
@@ -201,7 +220,7 @@ impl CenterClient {
 
             loop {
                 // 将读出来的数据全部解析成ProtFrame并进行相应的处理，如果是0则是自身消息，其它进行转发
-                match Helper::decode_frame(&mut read_buf)? {
+                match Helper::decode_frame_limit(&mut read_buf, max_frame_size)? {
                     Some(p) => {
                         match p {
                             ProtFrame::Create(p) => {
@@ -215,7 +234,13 @@ impl CenterClient {
                                 if mapping.is_none() {
                                     log::info!("本地地址为空，无法做内网映射");
                                     log::warn!("local addr is none, can't mapping");
-                                    let _ = sender.send(ProtFrame::new_close(p.sock_map())).await;
+                                    let _ = sender
+                                        .send(ProtFrame::new_close_code(
+                                            p.sock_map(),
+                                            ProtCloseCode::PolicyDenied,
+                                            String::new(),
+                                        ))
+                                        .await;
                                     continue;
                                 }
                                 let (virtual_sender, virtual_receiver) = channel::<ProtFrame>(10);
@@ -267,27 +292,32 @@ impl CenterClient {
                                                     e
                                                 );
                                                 let _ = sender
-                                                    .send(ProtFrame::new_close(sock_map))
+                                                    .send(ProtFrame::new_close_code(
+                                                        sock_map,
+                                                        ProtCloseCode::UpstreamError,
+                                                        String::new(),
+                                                    ))
                                                     .await;
                                             }
                                         }
                                     });
                                 }
                             }
-                            ProtFrame::Data(_) => {
-                                if let Some(sender) = map.get(&p.sock_map()) {
-                                    let _ = sender.try_send(p);
-                                }
-                            }
+                            ProtFrame::Data(_) | ProtFrame::WindowUpdate(_) => map.send(p),
                             ProtFrame::Close(p) => {
                                 if p.sock_map() == 0 {
-                                    log::warn!("客户端被服务端关闭:{}", p.reason());
-                                } else if let Some(sender) = map.get(&p.sock_map()) {
-                                    let _ = sender.try_send(ProtFrame::Close(p));
+                                    log::warn!("客户端被服务端关闭:{:?} {}", p.code(), p.reason());
+                                } else {
+                                    map.send(ProtFrame::Close(p));
                                 }
                             }
                             ProtFrame::Mapping(_) => {}
                             ProtFrame::Token(_) => todo!(),
+                            ProtFrame::Ping(p) => {
+                                let _ = ProtFrame::new_pong(p.sock_map(), *p.data())
+                                    .encode(&mut write_buf);
+                            }
+                            ProtFrame::Pong(p) => keep_alive.on_pong(&p),
                         }
                     }
                     None => {
@@ -300,8 +330,8 @@ impl CenterClient {
             }
         }
         if is_closed {
-            for v in map {
-                let _ = v.1.try_send(ProtFrame::Close(ProtClose::new(v.0)));
+            for v in map.drain() {
+                let _ = v.1.try_send(ProtFrame::new_close_code(v.0, close_code, String::new()));
             }
         }
         Ok(())
@@ -10,7 +10,7 @@
 // -----
 // Created Date: 2023/09/25 10:08:56
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 use tokio::{
     io::{split, AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -29,10 +29,11 @@ use webparse::BinaryMut;
 use webparse::Buf;
 
 use crate::{
-    prot::{ProtClose, ProtFrame},
+    prot::{ProtClose, ProtCloseCode, ProtFrame},
     proxy::ProxyServer,
     trans::{TransHttp, TransTcp},
-    Helper, MappingConfig, ProtCreate, ProxyConfig, ProxyResult, VirtualStream,
+    Helper, KeepAlive, MappingConfig, ProtCreate, ProxyConfig, ProxyResult, SockMap,
+    VirtualStream,
 };
 
 /// 中心服务端
@@ -91,6 +92,7 @@ impl CenterServer {
 
     pub async fn inner_serve<T>(
         stream: T,
+        addr: SocketAddr,
         option: ProxyConfig,
         sender: Sender<ProtFrame>,
         mut receiver: Receiver<ProtFrame>,
@@ -100,7 +102,7 @@ impl CenterServer {
     where
         T: AsyncRead + AsyncWrite + Unpin,
     {
-        let mut map = HashMap::<u64, Sender<ProtFrame>>::new();
+        let mut map = SockMap::new();
         let mut read_buf = BinaryMut::new();
         let mut write_buf = BinaryMut::new();
         let mut verify_succ = option.username.is_none() && option.password.is_none();
@@ -109,6 +111,9 @@ impl CenterServer {
         let mut vec = Vec::with_capacity(4096);
         vec.resize(4096, 0);
         let is_closed;
+        let mut keep_alive = KeepAlive::new(option.ping_interval.0, option.ping_timeout.0);
+        let max_frame_size = option.max_frame_size.0 as usize;
+        let mut close_code = ProtCloseCode::Normal;
         let mut is_ready_shutdown = false;
         loop {
             let _ = tokio::select! {
@@ -117,6 +122,15 @@ impl CenterServer {
                 // 新的流建立，这里接收Create并进行绑定
                 r = receiver_work.recv() => {
                     if let Some((create, sender)) = r {
+                        // 未验证通过前不分配映射
+                        if !verify_succ {
+                            let _ = sender.try_send(ProtFrame::new_close_code(
+                                create.sock_map(),
+                                ProtCloseCode::PolicyDenied,
+                                String::new(),
+                            ));
+                            continue;
+                        }
                         map.insert(create.sock_map(), sender);
                         let _ = create.encode(&mut write_buf);
                     }
@@ -124,9 +138,11 @@ impl CenterServer {
                 // 数据的接收，并将数据写入给远程端
                 r = receiver.recv() => {
                     if let Some(p) = r {
-                        let _ = p.encode(&mut write_buf);
+                        let _ = p.encode_by(&mut write_buf, option.compress);
                     }
                 }
+                // 转发之前因接收端已满而缓存的消息
+                _ = map.flush(), if map.has_pending() => {}
                 // 数据的等待读取，一旦流可读则触发，读到0则关闭主动关闭所有连接
                 r = reader.read(&mut vec) => {
                     match r {
@@ -143,6 +159,20 @@ impl CenterServer {
                         },
                     }
                 }
+                // 定时发送心跳, 超时未回应则关闭所有连接
+                _ = tokio::time::sleep_until(keep_alive.deadline()), if keep_alive.is_enable() => {
+                    match keep_alive.tick() {
+                        Some(p) => {
+                            let _ = p.encode(&mut write_buf);
+                        }
+                        None => {
+                            log::warn!("内网穿透:来自{}的连接心跳超时未回应, 断开连接", addr);
+                            close_code = ProtCloseCode::Timeout;
+                            is_closed = true;
+                            break;
+                        }
+                    }
+                }
                 // 一旦有写数据，则尝试写入数据，写入成功后扣除相应的数据
                 r = writer.write(write_buf.chunk()), if write_buf.has_remaining() => {
                     match r {
@@ -152,11 +182,15 @@ impl CenterServer {
                                 write_buf.clear();
 
                                 if is_ready_shutdown {
+                                    let _ = writer.shutdown().await;
                                     return Ok(())
                                 }
                             }
                         }
-                        Err(_) => todo!(),
+                        Err(_) => {
+                            is_closed = true;
+                            break;
+                        }
                     }
                 }
             };
@@ -165,7 +199,7 @@ impl CenterServer {
             }
             loop {
                 // 将读出来的数据全部解析成ProtFrame并进行相应的处理，如果是0则是自身消息，其它进行转发
-                match Helper::decode_frame(&mut read_buf)? {
+                match Helper::decode_frame_limit(&mut read_buf, max_frame_size)? {
                     Some(p) => {
                         match &p {
                             ProtFrame::Token(p) => {
@@ -179,8 +213,22 @@ impl CenterServer {
                             _ => {}
                         }
                         if !verify_succ {
-                            ProtFrame::new_close_reason(0, "not verify so close".to_string())
-                                .encode(&mut write_buf)?;
+                            log::warn!("内网穿透:来自{}的连接验证失败, 关闭连接", addr);
+                            // 释放已分配的映射, 确保不残留sock_map
+                            for (sock_map, sender) in map.drain() {
+                                let _ = sender.try_send(ProtFrame::new_close_code(
+                                    sock_map,
+                                    ProtCloseCode::PolicyDenied,
+                                    String::new(),
+                                ));
+                            }
+                            mappings.write().await.clear();
+                            ProtFrame::new_close_code(
+                                0,
+                                ProtCloseCode::PolicyDenied,
+                                ProtClose::REASON_AUTH_FAILED.to_string(),
+                            )
+                            .encode(&mut write_buf)?;
                             is_ready_shutdown = true;
                             break;
                         }
@@ -206,16 +254,19 @@ impl CenterServer {
                                     let _ = proxy_server.deal_proxy(stream).await;
                                 });
                             }
-                            ProtFrame::Close(_) | ProtFrame::Data(_) => {
-                                if let Some(sender) = map.get(&p.sock_map()) {
-                                    let _ = sender.send(p).await;
-                                }
+                            ProtFrame::Close(_) | ProtFrame::Data(_) | ProtFrame::WindowUpdate(_) => {
+                                map.send(p)
                             }
                             ProtFrame::Mapping(p) => {
                                 let mut guard = mappings.write().await;
                                 *guard = p.into_mappings();
                             }
                             ProtFrame::Token(_t) => {}
+                            ProtFrame::Ping(p) => {
+                                let _ = ProtFrame::new_pong(p.sock_map(), *p.data())
+                                    .encode(&mut write_buf);
+                            }
+                            ProtFrame::Pong(p) => keep_alive.on_pong(&p),
                         }
                     }
                     None => {
@@ -228,14 +279,14 @@ impl CenterServer {
             }
         }
         if is_closed {
-            for v in map {
-                let _ = v.1.try_send(ProtFrame::Close(ProtClose::new(v.0)));
+            for v in map.drain() {
+                let _ = v.1.try_send(ProtFrame::new_close_code(v.0, close_code, String::new()));
             }
         }
         Ok(())
     }
 
-    pub async fn serve<T>(&mut self, stream: T) -> ProxyResult<()>
+    pub async fn serve<T>(&mut self, stream: T, addr: SocketAddr) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
@@ -250,7 +301,7 @@ impl CenterServer {
         let mapping = self.mappings.clone();
         tokio::spawn(async move {
             let _ =
-                Self::inner_serve(stream, option, sender, receiver, receiver_work, mapping).await;
+                Self::inner_serve(stream, addr, option, sender, receiver, receiver_work, mapping).await;
         });
         Ok(())
     }
@@ -333,3 +384,96 @@ impl CenterServer {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+    use webparse::BinaryMut;
+
+    use super::CenterServer;
+    use crate::{prot::ProtFrame, Helper, ProxyConfig};
+
+    #[tokio::test]
+    async fn test_auth_failed_close() {
+        let mut option = ProxyConfig::default();
+        option.username = Some("wmproxy".to_string());
+        option.password = Some("wmproxy".to_string());
+        let mut server = CenterServer::new(option);
+        let mappings = server.mappings.clone();
+        let (mut client, stream) = duplex(4096);
+        server
+            .serve(stream, "127.0.0.1:8090".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut buf = BinaryMut::new();
+        ProtFrame::new_token("wmproxy".to_string(), "error".to_string())
+            .encode(&mut buf)
+            .unwrap();
+        client.write_all(&buf[..]).await.unwrap();
+
+        // 服务端发送关闭原因后主动断开连接
+        let mut data = vec![];
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut data))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut read_buf = BinaryMut::from(data);
+        match Helper::decode_frame(&mut read_buf).unwrap() {
+            Some(ProtFrame::Close(p)) => {
+                assert_eq!(p.sock_map(), 0);
+                assert!(p.is_auth_failed());
+                assert_eq!(p.code(), crate::ProtCloseCode::PolicyDenied);
+            }
+            p => panic!("unexpected frame {:?}", p),
+        }
+        assert!(Helper::decode_frame(&mut read_buf).unwrap().is_none());
+        assert!(mappings.read().await.is_empty());
+        assert!(server.is_close());
+    }
+
+    #[tokio::test]
+    async fn test_ping_pong() {
+        let mut option = ProxyConfig::default();
+        option.ping_interval = "50ms".parse().unwrap();
+        option.ping_timeout = "50ms".parse().unwrap();
+        let mut server = CenterServer::new(option);
+        let (mut client, stream) = duplex(4096);
+        server
+            .serve(stream, "127.0.0.1:8090".parse().unwrap())
+            .await
+            .unwrap();
+
+        // 收到Ping时回应相同内容的Pong
+        let mut buf = BinaryMut::new();
+        ProtFrame::new_ping(0, *b"wmproxy!").encode(&mut buf).unwrap();
+        client.write_all(&buf[..]).await.unwrap();
+        let mut read_buf = BinaryMut::new();
+        let mut pings = 0;
+        let mut vec = vec![0u8; 4096];
+        let mut data = vec![];
+        // 不回应服务端的Ping, 超时后服务端断开连接
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), client.read(&mut vec))
+                .await
+                .unwrap()
+                .unwrap()
+            {
+                0 => break,
+                n => read_buf.put_slice(&vec[..n]),
+            };
+            while let Some(p) = Helper::decode_frame(&mut read_buf).unwrap() {
+                match p {
+                    ProtFrame::Pong(p) => data.push(*p.data()),
+                    ProtFrame::Ping(_) => pings += 1,
+                    p => panic!("unexpected frame {:?}", p),
+                }
+            }
+        }
+        assert_eq!(data, vec![*b"wmproxy!"]);
+        assert_eq!(pings, 1);
+        assert!(server.is_close());
+    }
+}
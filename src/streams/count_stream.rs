@@ -0,0 +1,101 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/08 10:12:45
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// 最多保留的起始数据长度
+const HEAD_LIMIT: usize = 1024;
+
+/// 流的读取记录, 流被移走后仍可获取
+#[derive(Debug, Default)]
+pub struct ReadRecord {
+    bytes: AtomicU64,
+    head: Mutex<Vec<u8>>,
+}
+
+impl ReadRecord {
+    /// 已读取的字节数
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// 最先读取的数据, 最多保留1024字节
+    pub fn head(&self) -> Vec<u8> {
+        self.head.lock().map(|h| h.clone()).unwrap_or_default()
+    }
+}
+
+/// 记录读取数据的流, 用于判断连接关闭时是否收到过数据及数据是否可解析
+pub struct CountStream<T> {
+    stream: T,
+    record: Arc<ReadRecord>,
+}
+
+impl<T> CountStream<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream,
+            record: Arc::new(ReadRecord::default()),
+        }
+    }
+
+    pub fn record(&self) -> Arc<ReadRecord> {
+        self.record.clone()
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        let data = &buf.filled()[before..];
+        let old = self.record.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        if (old as usize) < HEAD_LIMIT {
+            if let Ok(mut head) = self.record.head.lock() {
+                let len = data.len().min(HEAD_LIMIT - old as usize);
+                head.extend_from_slice(&data[..len]);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
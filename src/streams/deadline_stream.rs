@@ -0,0 +1,167 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/27 09:32:18
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// 正在处理请求, 不限制读取
+    Off,
+    /// 等待下一个请求, 收到数据后开始计时
+    Waiting,
+    /// 需在该时间前接收完请求头
+    Until(Instant),
+}
+
+/// 接收请求头的截止时间, 连接建立或收到下一个请求的首个字节时开始计时,
+/// 交由回调处理请求时暂停, 超时后读取将返回`TimedOut`
+#[derive(Debug)]
+pub struct ReadDeadline {
+    timeout: Duration,
+    phase: Mutex<Phase>,
+    expired: AtomicBool,
+    disabled: AtomicBool,
+}
+
+impl ReadDeadline {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            phase: Mutex::new(Phase::Until(Instant::now() + timeout)),
+            expired: AtomicBool::new(false),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// 开始处理请求, 暂停计时
+    pub fn pause(&self) {
+        *self.phase.lock().unwrap() = Phase::Off;
+    }
+
+    /// 请求处理完毕, 等待下一个请求
+    pub fn resume(&self) {
+        if !self.disabled.load(Ordering::Relaxed) {
+            *self.phase.lock().unwrap() = Phase::Waiting;
+        }
+    }
+
+    /// 不再限制, 如HTTP/2的连接多个请求并行处理
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::Relaxed);
+        self.pause();
+    }
+
+    /// 是否因超时而中断读取
+    pub fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    fn current(&self) -> Option<Instant> {
+        match *self.phase.lock().unwrap() {
+            Phase::Until(deadline) => Some(deadline),
+            _ => None,
+        }
+    }
+
+    fn on_data(&self) {
+        let mut phase = self.phase.lock().unwrap();
+        if let Phase::Waiting = *phase {
+            *phase = Phase::Until(Instant::now() + self.timeout);
+        }
+    }
+
+    fn expire(&self) -> io::Error {
+        self.expired.store(true, Ordering::Relaxed);
+        io::Error::new(io::ErrorKind::TimedOut, "client header timeout")
+    }
+}
+
+/// 限制接收请求头时间的流, 用于防止缓慢发送请求头占用连接, `deadline`为None时不限制
+pub struct DeadlineStream<T> {
+    stream: T,
+    deadline: Option<Arc<ReadDeadline>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> DeadlineStream<T> {
+    pub fn new(stream: T, deadline: Option<Arc<ReadDeadline>>) -> Self {
+        Self {
+            stream,
+            deadline,
+            sleep: None,
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for DeadlineStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let deadline = match &this.deadline {
+            Some(deadline) => deadline,
+            None => return Pin::new(&mut this.stream).poll_read(cx, buf),
+        };
+        if let Some(until) = deadline.current() {
+            let sleep = this
+                .sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(until)));
+            if sleep.deadline() != until {
+                sleep.as_mut().reset(until);
+            }
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(deadline.expire()));
+            }
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            deadline.on_data();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for DeadlineStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/27 16:45:10
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use futures::task::AtomicWaker;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// 已从后端读取但尚未发往客户端的字节数, 超出窗口时暂停读取后端, 客户端接收后再继续,
+/// 使返回体按窗口逐段转发而不是全部读入内存, 未启用或客户端已断开时不限制
+#[derive(Debug)]
+pub struct FlowWindow {
+    window: usize,
+    pending: AtomicUsize,
+    armed: AtomicBool,
+    closed: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl FlowWindow {
+    /// 默认最多缓存的字节数
+    pub const DEFAULT_WINDOW: usize = 1024 * 1024;
+
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            pending: AtomicUsize::new(0),
+            armed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// 开始限制, 之前读取的数据不再计入
+    pub fn arm(&self) {
+        self.pending.store(0, Ordering::Relaxed);
+        self.armed.store(true, Ordering::Relaxed);
+    }
+
+    /// 不再限制, 如返回体需完整读取到本地时
+    pub fn release(&self) {
+        self.armed.store(false, Ordering::Relaxed);
+        self.waker.wake();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.waker.wake();
+    }
+
+    fn is_open(&self) -> bool {
+        self.armed.load(Ordering::Relaxed) && !self.closed.load(Ordering::Relaxed)
+    }
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_open() || self.pending.load(Ordering::Relaxed) < self.window {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // 注册后再次检查, 避免错过注册前的唤醒
+        if !self.is_open() || self.pending.load(Ordering::Relaxed) < self.window {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    fn on_read(&self, n: usize) {
+        self.pending.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn on_write(&self, n: usize) {
+        let _ = self
+            .pending
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_sub(n))
+            });
+        self.waker.wake();
+    }
+}
+
+impl Default for FlowWindow {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW)
+    }
+}
+
+/// 后端连接当前转发的客户端窗口, 复用连接时切换为新请求的窗口
+#[derive(Debug, Default)]
+pub struct FlowSlot(Mutex<Option<Arc<FlowWindow>>>);
+
+impl FlowSlot {
+    pub fn attach(&self, window: Option<&Arc<FlowWindow>>) {
+        *self.0.lock().unwrap() = window.cloned();
+    }
+
+    /// 不再限制该连接, 如HTTP/2的连接同时服务多个请求
+    pub fn detach(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    fn get(&self) -> Option<Arc<FlowWindow>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+enum FlowSide {
+    Client(Arc<FlowWindow>),
+    Upstream(Arc<FlowSlot>),
+}
+
+/// 按窗口转发的流, 客户端连接发送时减少窗口中的计数, 后端连接读取时增加并在超出时等待
+pub struct FlowStream<T> {
+    stream: T,
+    side: FlowSide,
+}
+
+impl<T> FlowStream<T> {
+    pub fn client(stream: T, window: Arc<FlowWindow>) -> Self {
+        Self {
+            stream,
+            side: FlowSide::Client(window),
+        }
+    }
+
+    pub fn upstream(stream: T, slot: Arc<FlowSlot>) -> Self {
+        Self {
+            stream,
+            side: FlowSide::Upstream(slot),
+        }
+    }
+}
+
+impl<T> Drop for FlowStream<T> {
+    fn drop(&mut self) {
+        // 客户端已断开, 后端无需再等待发送
+        if let FlowSide::Client(window) = &self.side {
+            window.close();
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FlowStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let window = match &this.side {
+            FlowSide::Upstream(slot) => slot.get(),
+            FlowSide::Client(_) => None,
+        };
+        if let Some(window) = &window {
+            ready!(window.poll_ready(cx));
+        }
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.stream).poll_read(cx, buf))?;
+        if let Some(window) = &window {
+            window.on_read(buf.filled().len() - before);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FlowStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        if let FlowSide::Client(window) = &this.side {
+            window.on_write(n);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{FlowSlot, FlowStream, FlowWindow};
+
+    #[tokio::test]
+    async fn test_flow_window() {
+        let window = Arc::new(FlowWindow::new(8));
+        let slot = Arc::new(FlowSlot::default());
+        let (client, mut peer) = duplex(64);
+        let (upstream, mut backend) = duplex(64);
+        let mut client = FlowStream::client(client, window.clone());
+        let mut upstream = FlowStream::upstream(upstream, slot.clone());
+        slot.attach(Some(&window));
+
+        // 未启用时不限制
+        let mut buf = [0u8; 64];
+        backend.write_all(&[1u8; 16]).await.unwrap();
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 16);
+        backend.write_all(&[1u8; 16]).await.unwrap();
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 16);
+
+        // 超出窗口后暂停读取, 直至发往客户端
+        window.arm();
+        backend.write_all(&[2u8; 16]).await.unwrap();
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 16);
+        backend.write_all(&[3u8; 4]).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_millis(50), upstream.read(&mut buf));
+        assert!(read.await.is_err());
+        client.write_all(&[2u8; 12]).await.unwrap();
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 4);
+        peer.read_exact(&mut buf[..12]).await.unwrap();
+
+        // 未关联窗口的后端连接不限制, 客户端断开后不再限制
+        backend.write_all(&[4u8; 16]).await.unwrap();
+        slot.detach();
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 16);
+        slot.attach(Some(&window));
+        backend.write_all(&[5u8; 16]).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_millis(50), upstream.read(&mut buf));
+        assert!(read.await.is_err());
+        drop(client);
+        assert_eq!(upstream.read(&mut buf).await.unwrap(), 16);
+    }
+}
@@ -0,0 +1,503 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/28 10:12:45
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+};
+
+/// HTTP/2连接的前言
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// 帧头的长度
+const FRAME_HEAD: usize = 9;
+/// PING帧的类型
+const FRAME_PING: u8 = 6;
+/// HTTP/1.1返回头的最大长度, 超出时不再探测
+const MAX_HEAD_LEN: usize = 16 * 1024;
+
+#[derive(Debug, Default)]
+struct PingState {
+    /// 待发送的PING
+    send: Option<[u8; 8]>,
+    /// 等待回应的PING
+    wait: Option<[u8; 8]>,
+    /// 读取连接的任务, 发送PING时唤醒以便写入
+    waker: Option<Waker>,
+    seq: u64,
+}
+
+/// 后端HTTP/2连接的PING探测, 连接在连接池中空闲时每隔`interval`发送PING,
+/// 超过`timeout`未收到PONG则认为连接已断开, 不再复用
+#[derive(Debug)]
+pub struct H2Ping {
+    interval: Duration,
+    timeout: Duration,
+    state: Mutex<PingState>,
+    notify: Notify,
+    idle: AtomicBool,
+    probing: AtomicBool,
+    dead: AtomicBool,
+}
+
+impl H2Ping {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            state: Mutex::new(PingState::default()),
+            notify: Notify::new(),
+            idle: AtomicBool::new(false),
+            probing: AtomicBool::new(false),
+            dead: AtomicBool::new(false),
+        }
+    }
+
+    /// 是否已超时未收到PONG
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Relaxed)
+    }
+
+    /// 连接放入连接池, 空闲期间开始发送PING, 连接关闭后停止
+    pub fn start(self: &Arc<Self>) {
+        self.idle.store(true, Ordering::Relaxed);
+        if self.probing.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let weak = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let interval = match weak.upgrade() {
+                    Some(ping) => ping.interval,
+                    None => return,
+                };
+                tokio::time::sleep(interval).await;
+                let ping = match weak.upgrade() {
+                    Some(ping) => ping,
+                    None => return,
+                };
+                if !ping.idle.load(Ordering::Relaxed) {
+                    ping.probing.store(false, Ordering::Relaxed);
+                    // 停止前已重新放回连接池则继续探测
+                    if ping.idle.load(Ordering::Relaxed)
+                        && !ping.probing.swap(true, Ordering::Relaxed)
+                    {
+                        continue;
+                    }
+                    return;
+                }
+                if !ping.probe().await {
+                    log::trace!("HTTP/2连接超过{:?}未回应PING, 不再复用", ping.timeout);
+                    ping.dead.store(true, Ordering::Relaxed);
+                    return;
+                }
+            }
+        });
+    }
+
+    /// 连接从连接池中取出, 使用期间不发送PING
+    pub fn stop(&self) {
+        self.idle.store(false, Ordering::Relaxed);
+    }
+
+    /// 发送一次PING并等待回应, 超时返回false
+    async fn probe(&self) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.seq = state.seq.wrapping_add(1);
+            let data = state.seq.to_be_bytes();
+            state.send = Some(data);
+            state.wait = Some(data);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+        let wait = async {
+            loop {
+                self.notify.notified().await;
+                if self.state.lock().unwrap().wait.is_none() {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(self.timeout, wait).await.is_ok()
+    }
+
+    fn take_send(&self) -> Option<[u8; 8]> {
+        self.state.lock().unwrap().send.take()
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut state = self.state.lock().unwrap();
+        if !state.waker.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            state.waker = Some(waker.clone());
+        }
+    }
+
+    /// 收到PONG, 内容与等待中的PING一致时结束等待并返回true, 该PONG不再交给连接处理
+    fn on_pong(&self, data: &[u8]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(&state.wait, Some(wait) if wait == data) {
+            return false;
+        }
+        state.wait = None;
+        self.notify.notify_one();
+        true
+    }
+}
+
+/// 读取的数据格式
+enum ReadMode {
+    /// 等待HTTP/1.1的返回头, 为h2c的升级时之后为HTTP/2的帧
+    Head(Vec<u8>),
+    Frame,
+    Off,
+}
+
+/// 写入的数据格式
+enum WriteMode {
+    /// 等待HTTP/2的前言, 记录已写入的长度
+    Preface(usize),
+    Frame,
+    Off,
+}
+
+/// 按帧头记录当前帧剩余的长度
+#[derive(Default)]
+struct FrameTrack {
+    head: Vec<u8>,
+    remain: usize,
+}
+
+impl FrameTrack {
+    fn frame_len(head: &[u8]) -> usize {
+        u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize
+    }
+
+    fn is_boundary(&self) -> bool {
+        self.head.is_empty() && self.remain == 0
+    }
+
+    /// 写入的数据只记录帧的边界
+    fn on_write(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remain > 0 {
+                let n = self.remain.min(data.len());
+                self.remain -= n;
+                data = &data[n..];
+                continue;
+            }
+            let n = (FRAME_HEAD - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.head.len() == FRAME_HEAD {
+                self.remain = Self::frame_len(&self.head);
+                self.head.clear();
+            }
+        }
+    }
+
+    /// 读取的数据中去除等待中的PING的回应, 其余数据写入`out`
+    fn on_read(&mut self, mut data: &[u8], ping: &H2Ping, out: &mut Vec<u8>) {
+        while !data.is_empty() {
+            if self.remain > 0 {
+                let n = self.remain.min(data.len());
+                out.extend_from_slice(&data[..n]);
+                self.remain -= n;
+                data = &data[n..];
+                continue;
+            }
+            // PING的回应为带ACK标记的8字节PING帧, 读取完整后再判断
+            let need = if self.head.len() >= FRAME_HEAD && Self::is_pong(&self.head) {
+                FRAME_HEAD + 8
+            } else {
+                FRAME_HEAD
+            };
+            let n = (need - self.head.len()).min(data.len());
+            self.head.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.head.len() < need {
+                continue;
+            }
+            if need == FRAME_HEAD && Self::is_pong(&self.head) {
+                continue;
+            }
+            if need == FRAME_HEAD {
+                self.remain = Self::frame_len(&self.head);
+                out.extend_from_slice(&self.head);
+            } else if !ping.on_pong(&self.head[FRAME_HEAD..]) {
+                out.extend_from_slice(&self.head);
+            }
+            self.head.clear();
+        }
+    }
+
+    fn is_pong(head: &[u8]) -> bool {
+        Self::frame_len(head) == 8 && head[3] == FRAME_PING && head[4] & 1 == 1
+    }
+}
+
+/// 后端连接的流, 在帧的边界写入探测的PING并去除其回应, 其余数据原样转发,
+/// HTTP/1.1的连接在升级为h2c后开始处理
+pub struct H2PingStream<T> {
+    stream: T,
+    ping: Option<Arc<H2Ping>>,
+    read: ReadMode,
+    write: WriteMode,
+    read_track: FrameTrack,
+    write_track: FrameTrack,
+    /// 已过滤待读取的数据
+    out: Vec<u8>,
+    data: Vec<u8>,
+    /// 未写完的PING帧
+    inject: Vec<u8>,
+    need_flush: bool,
+}
+
+impl<T> H2PingStream<T> {
+    /// `h2`为已协商为HTTP/2的连接, 如ALPN为h2, 未配置`ping`时原样转发
+    pub fn new(stream: T, ping: Option<Arc<H2Ping>>, h2: bool) -> Self {
+        let (read, write) = match (&ping, h2) {
+            (None, _) => (ReadMode::Off, WriteMode::Off),
+            (Some(_), true) => (ReadMode::Frame, WriteMode::Preface(0)),
+            (Some(_), false) => (ReadMode::Head(vec![]), WriteMode::Off),
+        };
+        Self {
+            stream,
+            ping,
+            read,
+            write,
+            read_track: FrameTrack::default(),
+            write_track: FrameTrack::default(),
+            out: vec![],
+            data: vec![],
+            inject: vec![],
+            need_flush: false,
+        }
+    }
+
+    /// 处理读取的数据, 写入待读取的`out`中
+    fn on_read(&mut self, mut data: &[u8]) {
+        if let ReadMode::Head(head) = &mut self.read {
+            let start = head.len().saturating_sub(3);
+            head.extend_from_slice(data);
+            let end = match head[start..].windows(4).position(|w| w == b"\r\n\r\n") {
+                Some(pos) => start + pos + 4,
+                None => {
+                    if head.len() > MAX_HEAD_LEN {
+                        self.read = ReadMode::Off;
+                    }
+                    self.out.extend_from_slice(data);
+                    return;
+                }
+            };
+            let used = data.len() - (head.len() - end);
+            let text = String::from_utf8_lossy(&head[..end]).to_ascii_lowercase();
+            let upgrade = text.starts_with("http/1.1 101") && text.contains("upgrade: h2c");
+            self.out.extend_from_slice(&data[..used]);
+            data = &data[used..];
+            if upgrade {
+                self.read = ReadMode::Frame;
+                self.write = WriteMode::Preface(0);
+            } else {
+                self.read = ReadMode::Off;
+            }
+        }
+        match (&self.read, &self.ping) {
+            (ReadMode::Frame, Some(ping)) => self.read_track.on_read(data, ping, &mut self.out),
+            _ => self.out.extend_from_slice(data),
+        }
+    }
+
+    fn on_write(&mut self, mut data: &[u8]) {
+        if let WriteMode::Preface(len) = &mut self.write {
+            let n = (PREFACE.len() - *len).min(data.len());
+            if data[..n] != PREFACE[*len..*len + n] {
+                self.write = WriteMode::Off;
+                return;
+            }
+            *len += n;
+            if *len < PREFACE.len() {
+                return;
+            }
+            data = &data[n..];
+            self.write = WriteMode::Frame;
+        }
+        if let WriteMode::Frame = self.write {
+            self.write_track.on_write(data);
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> H2PingStream<T> {
+    /// 在帧的边界写入待发送的PING
+    fn poll_inject(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let (true, Some(ping)) = (self.inject.is_empty(), &self.ping) {
+            if matches!(self.write, WriteMode::Frame) && self.write_track.is_boundary() {
+                if let Some(data) = ping.take_send() {
+                    self.inject
+                        .extend_from_slice(&[0, 0, 8, FRAME_PING, 0, 0, 0, 0, 0]);
+                    self.inject.extend_from_slice(&data);
+                }
+            }
+        }
+        while !self.inject.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.inject))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.inject.drain(..n);
+            self.need_flush = true;
+        }
+        if self.need_flush {
+            ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+            self.need_flush = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for H2PingStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let (Some(ping), false) = (&this.ping, matches!(this.write, WriteMode::Off)) {
+            ping.register(cx.waker());
+            // 连接空闲时只有读取在等待, 在此写入PING, 写入等待时由写入唤醒
+            if let Poll::Ready(Err(e)) = this.poll_inject(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        loop {
+            if !this.out.is_empty() {
+                let n = this.out.len().min(buf.remaining());
+                buf.put_slice(&this.out[..n]);
+                this.out.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            if let ReadMode::Off = this.read {
+                return Pin::new(&mut this.stream).poll_read(cx, buf);
+            }
+            let mut data = std::mem::take(&mut this.data);
+            data.resize(buf.remaining().clamp(1, 16 * 1024), 0);
+            let mut read = ReadBuf::new(&mut data);
+            let ret = Pin::new(&mut this.stream).poll_read(cx, &mut read);
+            let filled = read.filled().len();
+            let ret = ret.map_ok(|_| filled);
+            this.data = data;
+            let filled = ready!(ret)?;
+            if filled == 0 {
+                // 连接已关闭, 未完整的帧头一并交出
+                let head = std::mem::take(&mut this.read_track.head);
+                buf.put_slice(&head[..head.len().min(buf.remaining())]);
+                return Poll::Ready(Ok(()));
+            }
+            let data = std::mem::take(&mut this.data);
+            this.on_read(&data[..filled]);
+            this.data = data;
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for H2PingStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_inject(cx))?;
+        let n = ready!(Pin::new(&mut this.stream).poll_write(cx, buf))?;
+        this.on_write(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_inject(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::{H2Ping, H2PingStream, PREFACE};
+
+    #[tokio::test]
+    async fn test_h2_ping() {
+        let ping = Arc::new(H2Ping::new(
+            Duration::from_millis(20),
+            Duration::from_millis(50),
+        ));
+        let (upstream, mut backend) = duplex(256);
+        let mut stream = H2PingStream::new(upstream, Some(ping.clone()), true);
+        let settings = [0u8, 0, 0, 4, 0, 0, 0, 0, 0];
+        stream.write_all(PREFACE).await.unwrap();
+        stream.write_all(&settings).await.unwrap();
+        let mut buf = [0u8; 64];
+        backend.read_exact(&mut buf[..33]).await.unwrap();
+
+        // 空闲时在帧的边界写入PING, 其回应不交给连接处理
+        ping.start();
+        let mut frame = [0u8; 17];
+        tokio::select! {
+            _ = stream.read(&mut buf) => panic!("unexpected read"),
+            r = backend.read_exact(&mut frame) => r.unwrap(),
+        };
+        assert_eq!(&frame[..9], &[0, 0, 8, 6, 0, 0, 0, 0, 0]);
+        frame[4] = 1;
+        backend.write_all(&frame).await.unwrap();
+        // 不匹配的回应原样转发
+        let mut other = frame;
+        other[16] ^= 0xff;
+        backend.write_all(&other).await.unwrap();
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 17);
+        assert_eq!(&buf[..17], &other);
+        assert!(!ping.is_dead());
+
+        // 取出使用期间不发送PING, 放回后不再回应则超时
+        ping.stop();
+        tokio::select! {
+            _ = stream.read(&mut buf) => panic!("unexpected read"),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {},
+        };
+        let read = tokio::time::timeout(Duration::from_millis(10), backend.read(&mut frame));
+        assert!(read.await.is_err());
+        ping.start();
+        tokio::select! {
+            _ = stream.read(&mut buf) => panic!("unexpected read"),
+            _ = tokio::time::sleep(Duration::from_millis(150)) => {},
+        };
+        assert!(ping.is_dead());
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/20 10:35:17
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{ProtFrame, ProtPong};
+
+/// 内网穿透的心跳保活, 每隔`interval`发送Ping, 超过`timeout`未收到Pong则认为连接已断开
+pub struct KeepAlive {
+    interval: Duration,
+    timeout: Duration,
+    /// 下一次发送Ping的时间
+    next: Instant,
+    /// 等待回应的Ping内容及其超时时间
+    wait: Option<([u8; 8], Instant)>,
+    seq: u64,
+}
+
+impl KeepAlive {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            next: Instant::now() + interval,
+            wait: None,
+            seq: 0,
+        }
+    }
+
+    /// 间隔为0时不发送心跳
+    pub fn is_enable(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// 下一次需要处理的时间, 等待回应时为超时时间
+    pub fn deadline(&self) -> Instant {
+        match &self.wait {
+            Some((_, deadline)) => *deadline,
+            None => self.next,
+        }
+    }
+
+    /// 到达处理时间, 返回需要发送的Ping, 等待回应超时时返回None
+    pub fn tick(&mut self) -> Option<ProtFrame> {
+        let now = Instant::now();
+        if let Some((_, deadline)) = &self.wait {
+            if *deadline <= now {
+                return None;
+            }
+        }
+        self.seq = self.seq.wrapping_add(1);
+        let data = self.seq.to_be_bytes();
+        self.wait = Some((data, now + self.timeout));
+        self.next = now + self.interval;
+        Some(ProtFrame::new_ping(0, data))
+    }
+
+    /// 收到回应, 内容与等待中的Ping一致时结束等待
+    pub fn on_pong(&mut self, pong: &ProtPong) {
+        if matches!(&self.wait, Some((data, _)) if data == pong.data()) {
+            self.wait = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::KeepAlive;
+    use crate::{ProtFrame, ProtPong};
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let mut keep = KeepAlive::new(Duration::from_millis(20), Duration::from_millis(10));
+        assert!(keep.is_enable());
+        assert!(!KeepAlive::new(Duration::ZERO, Duration::ZERO).is_enable());
+
+        let data = match keep.tick() {
+            Some(ProtFrame::Ping(p)) => *p.data(),
+            p => panic!("unexpected frame {:?}", p),
+        };
+        // 内容不一致的回应不结束等待, 超时后返回None
+        keep.on_pong(&ProtPong::new(0, [0u8; 8]));
+        tokio::time::sleep_until(keep.deadline()).await;
+        assert!(keep.tick().is_none());
+
+        // 收到匹配的回应后等到下一次的发送时间
+        let mut keep = KeepAlive::new(Duration::from_millis(20), Duration::from_millis(10));
+        let data2 = match keep.tick() {
+            Some(ProtFrame::Ping(p)) => *p.data(),
+            p => panic!("unexpected frame {:?}", p),
+        };
+        assert_eq!(data, data2);
+        keep.on_pong(&ProtPong::new(0, data2));
+        tokio::time::sleep_until(keep.deadline()).await;
+        assert!(keep.tick().is_some());
+    }
+}
@@ -13,11 +13,23 @@
 mod center_client;
 mod center_server;
 mod center_trans;
+mod count_stream;
+mod deadline_stream;
+mod flow_stream;
+mod h2_ping;
+mod keep_alive;
+mod sock_map;
 mod trans_stream;
 mod virtual_stream;
 
 pub use center_client::CenterClient;
 pub use center_server::CenterServer;
 pub use center_trans::CenterTrans;
+pub use count_stream::{CountStream, ReadRecord};
+pub use deadline_stream::{DeadlineStream, ReadDeadline};
+pub use flow_stream::{FlowSlot, FlowStream, FlowWindow};
+pub use h2_ping::{H2Ping, H2PingStream};
+pub use keep_alive::KeepAlive;
+pub use sock_map::SockMap;
 pub use trans_stream::TransStream;
 pub use virtual_stream::VirtualStream;
@@ -0,0 +1,190 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/21 10:05:33
+
+use std::collections::{HashMap, VecDeque};
+
+use futures::{future::select_all, FutureExt};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::ProtFrame;
+
+/// 各sock_map对应的接收端, 接收端暂时无法接收时缓存该消息, 不阻塞其它sock_map的转发
+/// 发送端受流量控制的窗口限制, 每个sock_map缓存的数据不超过其窗口大小
+#[derive(Default)]
+pub struct SockMap {
+    senders: HashMap<u64, Sender<ProtFrame>>,
+    pending: HashMap<u64, VecDeque<ProtFrame>>,
+}
+
+impl SockMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, sock_map: u64, sender: Sender<ProtFrame>) {
+        self.pending.remove(&sock_map);
+        self.senders.insert(sock_map, sender);
+    }
+
+    pub fn contains(&self, sock_map: &u64) -> bool {
+        self.senders.contains_key(sock_map)
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// 移除所有的接收端, 返回其sock_map及发送端
+    pub fn drain(&mut self) -> impl Iterator<Item = (u64, Sender<ProtFrame>)> + '_ {
+        self.pending.clear();
+        self.senders.drain()
+    }
+
+    /// 转发消息给对应的sock_map, 之前有缓存或接收端已满时缓存该消息, 转发关闭消息后移除该sock_map
+    pub fn send(&mut self, frame: ProtFrame) {
+        let sock_map = frame.sock_map();
+        let is_close = frame.is_close();
+        let sender = match self.senders.get(&sock_map) {
+            Some(sender) => sender,
+            None => return,
+        };
+        if let Some(pending) = self.pending.get_mut(&sock_map) {
+            pending.push_back(frame);
+        } else {
+            match sender.try_send(frame) {
+                Ok(_) => {}
+                Err(TrySendError::Full(frame)) => {
+                    self.pending.entry(sock_map).or_default().push_back(frame);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    self.senders.remove(&sock_map);
+                    return;
+                }
+            }
+        }
+        if is_close && !self.pending.contains_key(&sock_map) {
+            self.senders.remove(&sock_map);
+        }
+    }
+
+    /// 等待任一有缓存的接收端可接收, 并转发其缓存的消息, 无缓存时不会返回
+    pub async fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return std::future::pending().await;
+        }
+        let works = self
+            .pending
+            .keys()
+            .filter_map(|sock_map| {
+                let sender = self.senders.get(sock_map)?.clone();
+                let sock_map = *sock_map;
+                Some(async move { (sock_map, sender.reserve_owned().await) }.boxed())
+            })
+            .collect::<Vec<_>>();
+        if works.is_empty() {
+            self.pending.clear();
+            return;
+        }
+        let ((sock_map, permit), _, _) = select_all(works).await;
+        let pending = match self.pending.get_mut(&sock_map) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let (mut is_close, mut is_closed) = (false, false);
+        match permit {
+            Ok(permit) => {
+                let frame = match pending.pop_front() {
+                    Some(frame) => frame,
+                    None => return,
+                };
+                is_close = frame.is_close();
+                let sender = permit.send(frame);
+                // 尽可能多的转发剩余的缓存
+                while !is_close {
+                    let frame = match pending.pop_front() {
+                        Some(frame) => frame,
+                        None => break,
+                    };
+                    let close = frame.is_close();
+                    match sender.try_send(frame) {
+                        Ok(_) => is_close = close,
+                        Err(TrySendError::Full(frame)) => {
+                            pending.push_front(frame);
+                            break;
+                        }
+                        Err(TrySendError::Closed(_)) => {
+                            is_closed = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(_) => is_closed = true,
+        }
+        if is_close || is_closed {
+            self.pending.remove(&sock_map);
+            self.senders.remove(&sock_map);
+        } else if pending.is_empty() {
+            self.pending.remove(&sock_map);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::SockMap;
+    use crate::ProtFrame;
+
+    #[tokio::test]
+    async fn test_blocked_stream() {
+        let mut map = SockMap::new();
+        let (sender_a, mut receiver_a) = channel::<ProtFrame>(1);
+        let (sender_b, mut receiver_b) = channel::<ProtFrame>(1);
+        map.insert(1, sender_a);
+        map.insert(3, sender_b);
+
+        // 交替转发两个流的数据, 流1未读取时不影响流3的接收
+        for i in 0..5u8 {
+            map.send(ProtFrame::new_data(1, vec![i]));
+            map.send(ProtFrame::new_data(3, vec![i]));
+            match receiver_b.recv().await {
+                Some(ProtFrame::Data(d)) => assert_eq!(d.data(), &vec![i]),
+                p => panic!("unexpected frame {:?}", p),
+            }
+        }
+        map.send(ProtFrame::new_close(1));
+        assert!(map.has_pending());
+        assert!(map.contains(&1));
+
+        // 流1开始读取后按顺序收到缓存的消息, 关闭后移除
+        for i in 0..5u8 {
+            if i > 0 {
+                tokio::time::timeout(Duration::from_secs(1), map.flush())
+                    .await
+                    .unwrap();
+            }
+            match receiver_a.recv().await {
+                Some(ProtFrame::Data(d)) => assert_eq!(d.data(), &vec![i]),
+                p => panic!("unexpected frame {:?}", p),
+            }
+        }
+        map.flush().await;
+        assert!(receiver_a.recv().await.unwrap().is_close());
+        assert!(!map.has_pending());
+        assert!(!map.contains(&1));
+        assert!(map.contains(&3));
+    }
+}
@@ -23,7 +23,7 @@ use tokio::{
 };
 use webparse::{BinaryMut, Buf, BufMut};
 
-use crate::ProtFrame;
+use crate::{ProtFrame, ProtWindowUpdate};
 
 /// 转发流量端
 /// 提供与中心端绑定的读出写入功能
@@ -43,6 +43,10 @@ where
     in_sender: Sender<ProtFrame>,
     // 收到中心端的写入请求，转成write
     out_receiver: Receiver<ProtFrame>,
+    // 可发送给对端的字节数, 为0时暂停读取stream直到收到窗口更新
+    send_window: u32,
+    // 已写入stream但未通知对端的字节数
+    consumed: u32,
 }
 
 impl<T> TransStream<T>
@@ -62,6 +66,8 @@ where
             write: BinaryMut::new(),
             in_sender,
             out_receiver,
+            send_window: ProtWindowUpdate::INIT_WINDOW,
+            consumed: 0,
         }
     }
 
@@ -79,14 +85,19 @@ where
         let mut link = LinkedList::<ProtFrame>::new();
         let (mut reader, mut writer) = split(self.stream);
         loop {
-            // 有剩余数据，优先转化成Prot，因为数据可能从外部直接带入
-            if self.read.has_remaining() {
-                link.push_back(ProtFrame::new_data(self.id, self.read.chunk().to_vec()));
-                self.read.clear();
+            // 有剩余数据，优先转化成Prot，因为数据可能从外部直接带入, 超出发送窗口的部分等待窗口更新
+            if self.read.has_remaining() && self.send_window > 0 {
+                let len = self.read.remaining().min(self.send_window as usize);
+                link.push_back(ProtFrame::new_data(self.id, self.read.chunk()[..len].to_vec()));
+                self.read.advance(len);
+                self.send_window -= len as u32;
+                if !self.read.has_remaining() {
+                    self.read.clear();
+                }
             }
 
             tokio::select! {
-                n = reader.read(&mut buf) => {
+                n = reader.read(&mut buf), if !self.read.has_remaining() => {
                     let n = n?;
                     if n == 0 {
                         return Ok(())
@@ -101,6 +112,12 @@ where
                             if !self.write.has_remaining() {
                                 self.write.clear();
                             }
+                            // 写入后通知对端可继续发送, 累计到一定大小后再通知以减少消息数
+                            self.consumed += n as u32;
+                            if self.consumed >= ProtWindowUpdate::INIT_WINDOW / 2 {
+                                link.push_back(ProtFrame::new_window_update(self.id, self.consumed));
+                                self.consumed = 0;
+                            }
                         }
                         Err(e) => return Err(e),
                     }
@@ -109,13 +126,15 @@ where
                     if let Some(v) = r {
                         if v.is_close() || v.is_create() {
                             return Ok(())
-                        } else if v.is_data() {
-                            match v {
-                                ProtFrame::Data(d) => {
-                                    self.write.put_slice(&d.data());
-                                }
-                                _ => unreachable!(),
+                        }
+                        match v {
+                            ProtFrame::Data(d) => {
+                                self.write.put_slice(d.data());
+                            }
+                            ProtFrame::WindowUpdate(w) => {
+                                self.send_window = self.send_window.saturating_add(w.size());
                             }
+                            _ => {}
                         }
                     } else {
                         return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid frame"))
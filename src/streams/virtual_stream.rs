@@ -12,14 +12,14 @@
 
 use std::{
     pin::Pin,
-    task::{ready, Poll},
+    task::{ready, Context, Poll, Waker},
 };
 use tokio_util::sync::PollSender;
 
 use tokio::{io::{AsyncRead, AsyncWrite}, sync::mpsc::{Sender, Receiver}};
 use webparse::{BinaryMut, Buf};
 
-use crate::prot::ProtData;
+use crate::prot::{ProtData, ProtWindowUpdate};
 use crate::{prot::ProtFrame};
 
 /// 虚拟端
@@ -34,8 +34,15 @@ pub struct VirtualStream
     receiver: Receiver<ProtFrame>,
     // 读取的数据缓存，将转发成ProtFrame
     read: BinaryMut,
-    // 写的数据缓存，直接写入到stream下，从ProtFrame转化而来
-    write: BinaryMut,
+    // 可发送给对端的字节数, 为0时写入等待窗口更新
+    send_window: u32,
+    // 已读取但未通知对端的字节数
+    consumed: u32,
+    // 已收到关闭消息
+    closed: bool,
+    // 读写可能在不同的任务中, 收到消息时唤醒等待的一方
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
 }
 
 impl VirtualStream
@@ -46,7 +53,55 @@ impl VirtualStream
             sender: PollSender::new(sender),
             receiver,
             read: BinaryMut::new(),
-            write: BinaryMut::new(),
+            send_window: ProtWindowUpdate::INIT_WINDOW,
+            consumed: 0,
+            closed: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// 接收当前所有可用的消息, 数据存入读缓存, 窗口更新增加可发送的字节数
+    fn poll_receive(&mut self, cx: &mut Context<'_>) {
+        let mut received = false;
+        while !self.closed {
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(ProtFrame::Data(d))) => {
+                    self.read.put_slice(d.data());
+                }
+                Poll::Ready(Some(ProtFrame::WindowUpdate(w))) => {
+                    self.send_window = self.send_window.saturating_add(w.size());
+                }
+                Poll::Ready(Some(v)) => {
+                    if v.is_close() || v.is_create() {
+                        self.closed = true;
+                    }
+                }
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+            received = true;
+        }
+        if received {
+            if let Some(w) = self.read_waker.take() {
+                w.wake();
+            }
+            if let Some(w) = self.write_waker.take() {
+                w.wake();
+            }
+        }
+    }
+
+    /// 读取的数据累计到窗口的一半时通知对端可继续发送
+    fn poll_window_update(&mut self, cx: &mut Context<'_>) {
+        if self.consumed < ProtWindowUpdate::INIT_WINDOW / 2 {
+            return;
+        }
+        if let Poll::Ready(Ok(_)) = self.sender.poll_reserve(cx) {
+            let frame = ProtFrame::new_window_update(self.id, self.consumed);
+            if self.sender.send_item(frame).is_ok() {
+                self.consumed = 0;
+            }
         }
     }
 }
@@ -59,77 +114,66 @@ impl AsyncRead for VirtualStream
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        loop {
-            match self.receiver.poll_recv(cx) {
-                Poll::Ready(value) => {
-                    if let Some(v) = value {
-                        if v.is_close() || v.is_create() {
-                            return Poll::Ready(Ok(()))
-                        } else if v.is_data() {
-                            match v {
-                                ProtFrame::Data(d) => {
-                                    self.read.put_slice(&d.data());
-                                }
-                                _ => unreachable!(),
-                            }
-                        }
-                    } else {
-                        return Poll::Ready(Ok(()))
-                    }
-                },
-                Poll::Pending => {
-                    if !self.read.has_remaining() {
-                        return Poll::Pending;
-                    }
-                },
-            }
-
-            if self.read.has_remaining() {
-                let copy = std::cmp::min(self.read.remaining(), buf.remaining());
-                buf.put_slice(&self.read.chunk()[..copy]);
-                self.read.advance(copy);
-                return Poll::Ready(Ok(()));
+        self.poll_receive(cx);
+        if self.read.has_remaining() {
+            let copy = std::cmp::min(self.read.remaining(), buf.remaining());
+            buf.put_slice(&self.read.chunk()[..copy]);
+            self.read.advance(copy);
+            if !self.read.has_remaining() {
+                self.read.clear();
             }
+            self.consumed += copy as u32;
+            self.poll_window_update(cx);
+            return Poll::Ready(Ok(()));
+        }
+        if self.closed {
+            return Poll::Ready(Ok(()));
         }
-        
+        self.poll_window_update(cx);
+        self.read_waker = Some(cx.waker().clone());
+        Poll::Pending
     }
 }
 
 impl AsyncWrite for VirtualStream
 {
-    /// 将数据直接写入到write缓冲里, 可以设定一个最大值
-    /// 最大的缓冲值不超过这个数值, 防止无限往缓冲区里发送该值
+    /// 将数据直接发送给中心端, 单次发送不超过对端的接收窗口
+    /// 窗口为0时等待对端的窗口更新, 防止无限往缓冲区里发送该值
     fn poll_write(
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
-        self.write.put_slice(buf);
-        if let Err(_) = ready!(self.sender.poll_reserve(cx)) {
+        self.poll_window_update(cx);
+        if self.send_window == 0 {
+            self.poll_receive(cx);
+            if self.send_window == 0 {
+                if self.closed {
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+                }
+                self.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        if ready!(self.sender.poll_reserve(cx)).is_err() {
             return Poll::Pending;
         }
-        let data = self.write.chunk().to_vec();
+        let len = std::cmp::min(buf.len(), self.send_window as usize);
         let id = self.id;
-        if let Ok(_) = self.sender.send_item(ProtFrame::Data(ProtData::new(id, data))) {
-            self.write.clear();
+        if self
+            .sender
+            .send_item(ProtFrame::Data(ProtData::new(id, buf[..len].to_vec())))
+            .is_ok()
+        {
+            self.send_window -= len as u32;
         }
-        Poll::Ready(Ok(buf.len()))
+        Poll::Ready(Ok(len))
     }
 
     fn poll_flush(
-        mut self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), std::io::Error>> {
-        if self.write.has_remaining() {
-            if let Err(_) = ready!(self.sender.poll_reserve(cx)) {
-                return Poll::Pending;
-            }
-            let data = self.write.chunk().to_vec();
-            let id = self.id;
-            if let Ok(_) = self.sender.send_item(ProtFrame::Data(ProtData::new(id, data))) {
-                self.write.clear();
-            }
-        }
         Poll::Ready(Ok(()))
     }
 
@@ -140,3 +184,35 @@ impl AsyncWrite for VirtualStream
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::AsyncWriteExt, sync::mpsc::channel};
+
+    use super::VirtualStream;
+    use crate::{ProtFrame, ProtWindowUpdate};
+
+    #[tokio::test]
+    async fn test_send_window() {
+        let (sender, mut out) = channel::<ProtFrame>(10);
+        let (input, receiver) = channel::<ProtFrame>(10);
+        let mut stream = VirtualStream::new(1, sender, receiver);
+        let size = ProtWindowUpdate::INIT_WINDOW as usize;
+        let n = stream.write(&vec![0u8; size + 10]).await.unwrap();
+        assert_eq!(n, size);
+
+        // 窗口用完后等待对端的窗口更新
+        let write = stream.write(&[1u8; 10]);
+        assert!(tokio::time::timeout(Duration::from_millis(50), write).await.is_err());
+        input.send(ProtFrame::new_window_update(1, 5)).await.unwrap();
+        assert_eq!(stream.write(&[1u8; 10]).await.unwrap(), 5);
+
+        let mut total = 0;
+        while let Ok(ProtFrame::Data(d)) = out.try_recv() {
+            total += d.data().len();
+        }
+        assert_eq!(total, size + 5);
+    }
+}
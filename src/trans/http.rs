@@ -23,7 +23,7 @@ use tokio::{
 use webparse::{Request, Response};
 use wenmeng::{Body, Client, HttpTrait, ProtResult, RecvRequest, RecvResponse, Server};
 
-use crate::{Helper, MappingConfig, ProtCreate, ProtFrame, ProxyError, VirtualStream};
+use crate::{Helper, MappingConfig, ProtCreate, ProtCreateMode, ProtFrame, ProxyError, VirtualStream};
 
 static TIP_NOT_FOUND: &'static str = "当前连接未检测到与之匹配的域名，请检查配置是否正确，或者查看官方网站<a href=\"https://github.com/tickbh/wmproxy\"/>wmproxy</a>。";
 struct Operate {
@@ -109,8 +109,12 @@ impl TransHttp {
                 oper.http_map = config;
             }
 
-            let create =
-                ProtCreate::new(oper.sock_map, Some(req.get_host().unwrap_or(String::new())));
+            let create = ProtCreate::new_target(
+                oper.sock_map,
+                Some(req.get_host().unwrap_or_default()),
+                ProtCreateMode::Http,
+                None,
+            );
             let _ = oper.sender_work.send((create, sender.unwrap())).await;
         }
 
@@ -17,7 +17,7 @@ use tokio::{
     sync::{mpsc::{Sender, channel}, RwLock},
 };
 
-use crate::{ProtFrame, TransStream, ProxyError, ProtCreate, MappingConfig};
+use crate::{ProtFrame, TransStream, ProxyError, ProtCreate, ProtCreateMode, MappingConfig};
 
 pub struct TransTcp {
     sender: Sender<ProtFrame>,
@@ -65,7 +65,8 @@ impl TransTcp {
         };
 
         // 通知客户端数据进行连接的建立，客户端的tcp配置只能存在有且只有一个，要不然无法确定转发源
-        let create = ProtCreate::new(self.sock_map, Some(domain));
+        let create =
+            ProtCreate::new_target(self.sock_map, Some(domain), ProtCreateMode::Tcp, None);
         let (stream_sender, stream_receiver) = channel::<ProtFrame>(10);
         let _ = self.sender_work.send((create, stream_sender)).await;
         
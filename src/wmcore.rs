@@ -28,12 +28,14 @@ use tokio::{
     },
 };
 use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_util::sync::DropGuard;
 
 use crate::{
     option::ConfigOption,
     proxy::ProxyServer,
     reverse::{HttpConfig, ServerConfig, StreamConfig, StreamUdp},
     ActiveHealth, CenterClient, CenterServer, CenterTrans, Helper, OneHealth, ProxyResult,
+    Shutdown, ShutdownState,
 };
 
 /// 核心处理类
@@ -57,6 +59,8 @@ pub struct WMCore {
     pub http_accept: Option<TlsAcceptor>,
     pub http_tlss: Vec<bool>,
     pub http_listeners: Vec<TcpListener>,
+    /// unix socket的监听在独立的任务中处理, 释放时停止接收
+    http_unix_guard: Option<DropGuard>,
 
     pub stream_config: Option<Arc<Mutex<StreamConfig>>>,
     pub stream_listeners: Vec<TcpListener>,
@@ -85,6 +89,7 @@ impl WMCore {
             http_accept: None,
             http_tlss: vec![],
             http_listeners: vec![],
+            http_unix_guard: None,
 
             stream_config: None,
             stream_listeners: vec![],
@@ -97,7 +102,7 @@ impl WMCore {
     async fn deal_center_stream<T>(
         &mut self,
         inbound: T,
-        _addr: SocketAddr,
+        addr: SocketAddr,
         tls_client: Option<Arc<rustls::ClientConfig>>,
     ) -> ProxyResult<()>
     where
@@ -110,7 +115,7 @@ impl WMCore {
             } else {
                 let server = CenterServer::new(option.clone());
                 self.center_servers.push(server);
-                return self.center_servers.last_mut().unwrap().serve(inbound).await;
+                return self.center_servers.last_mut().unwrap().serve(inbound, addr).await;
             }
         }
         Ok(())
@@ -207,6 +212,10 @@ impl WMCore {
     }
 
     pub async fn ready_serve(&mut self) -> ProxyResult<()> {
+        // 绑定前检查配置, 一次报告所有错误
+        if let Some(http) = &self.option.http {
+            http.check_start()?;
+        }
         if let Some(option) = &mut self.option.proxy {
             (
                 self.proxy_accept,
@@ -245,6 +254,16 @@ impl WMCore {
         if let Some(stream) = &mut self.option.stream {
             (self.stream_listeners, self.stream_udp_listeners) = stream.bind().await?;
         }
+
+        #[cfg(unix)]
+        if let Some(http) = &self.option.http {
+            let cancel = tokio_util::sync::CancellationToken::new();
+            for (path, listener) in http.bind_unix().await? {
+                let servers = self.http_servers.clone();
+                tokio::spawn(HttpConfig::serve_unix(servers, path, listener, cancel.clone()));
+            }
+            self.http_unix_guard = Some(cancel.drop_guard());
+        }
         Ok(())
     }
 
@@ -298,32 +317,21 @@ impl WMCore {
                 }
                 (result, index) = Self::multi_tcp_listen_work(&mut self.http_listeners) => {
                     if let Ok((conn, addr)) = result {
-                        let local_port = self.http_listeners[index].local_addr()?.port();
+                        let local_addr = self.http_listeners[index].local_addr()?;
+                        let local_port = local_addr.port();
                         log::trace!("反向代理:{}收到客户端连接: {}->{}", if self.http_tlss[index] { "https" } else { "http" }, addr,self.http_listeners[index].local_addr()?);
                         let mut local_servers = vec![];
                         for s in &self.http_servers {
-                            if !(*s).bind_addr.contains(local_port) && !(*s).bind_ssl.contains(local_port) {
+                            if !s.is_bind_port(local_port) {
                                 continue;
                             }
                             local_servers.push(s.clone());
                         }
                         if self.http_tlss[index] {
                             let tls_accept = self.http_accept.clone().unwrap();
-                            tokio::spawn(async move {
-                                if let Ok(stream) = tls_accept.accept(conn).await {
-                                    let data = stream.get_ref();
-                                    let up_name = data.1.server_name().clone().map(|s| s.to_string());
-                                    for s in &local_servers {
-                                        if up_name.is_some() && &s.up_name == up_name.as_ref().unwrap() {
-                                            let _ = HttpConfig::process(vec![s.clone()], stream, addr).await;
-                                            return;
-                                        }
-                                    }
-                                    let _ = HttpConfig::process(local_servers, stream, addr).await;
-                                }
-                            });
+                            let _ = HttpConfig::process_tls_by(tls_accept, local_servers, conn, addr, Some(local_addr)).await;
                         } else {
-                            let _ = HttpConfig::process(local_servers, conn, addr).await;
+                            let _ = HttpConfig::process_by(local_servers, conn, addr, Some(local_addr)).await;
                         }
                     }
                 }
@@ -354,6 +362,10 @@ impl WMCore {
                 }
                 _ = receiver_close.recv() => {
                     log::info!("反向代理：接收到退出信号,来自配置的变更,退出当前线程");
+                    if let Some(http) = &self.option.http {
+                        http.stop_health_check();
+                    }
+                    self.http_unix_guard = None;
                     return Ok(());
                 }
             }
@@ -368,7 +380,20 @@ impl WMCore {
     ) -> ProxyResult<()> {
         log::trace!("开始启动服务器，正在加载配置中");
         self.ready_serve().await?;
+        self.serve(receiver_close, sender_close).await
+    }
+
+    /// 已完成`ready_serve`后开始服务, 收到关闭信号后返回
+    pub async fn serve(
+        &mut self,
+        receiver_close: Receiver<()>,
+        sender_close: Option<Sender<()>>,
+    ) -> ProxyResult<()> {
         self.run_serve(receiver_close, sender_close).await?;
+        // 关闭流程中已停止接收连接, 等待已有连接排空后再退出
+        if Shutdown::state() >= ShutdownState::Draining {
+            Shutdown::wait_closed().await;
+        }
         Ok(())
     }
 
@@ -0,0 +1,98 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 访问日志及统计相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::{AccessStat, AccessStatSnapshot, ConnCloseReason};
+
+    use super::common;
+
+    const CONFIG: &str = r#"
+        [[server]]
+        bind_addr = "127.0.0.1:0"
+        bind_ssl = ""
+        [[server.location]]
+        rule = "/"
+        static_response = "ok"
+    "#;
+
+    /// 等待连接结束并完成统计
+    async fn wait_closed(before: &AccessStatSnapshot, count: u64) -> AccessStatSnapshot {
+        let now = Instant::now();
+        loop {
+            let stat = AccessStat::snapshot();
+            let closed: u64 = stat.closed.values().sum::<u64>() + stat.served;
+            let old: u64 = before.closed.values().sum::<u64>() + before.served;
+            if closed >= old + count || now.elapsed() > Duration::from_secs(5) {
+                return stat;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// 发送数据, 读取返回直到服务端关闭或收到返回头
+    async fn send(addr: SocketAddr, data: &[u8], shutdown: bool) -> Vec<u8> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(data).await.unwrap();
+        if shutdown {
+            stream.shutdown().await.unwrap();
+        }
+        let mut buf = vec![];
+        let mut tmp = [0u8; 1024];
+        while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut tmp)).await {
+                Ok(Ok(n)) if n > 0 => buf.extend_from_slice(&tmp[..n]),
+                _ => break,
+            }
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_early_close() {
+        let addr = common::run_proxy(CONFIG).await;
+
+        let cases = [
+            (&b"\x16\x03\x01\x00\xff garbage\r\n\r\n"[..], ConnCloseReason::Parse),
+            (&b"GET / HTTP/1.1\r\nHost: loc"[..], ConnCloseReason::Incomplete),
+            (&b""[..], ConnCloseReason::Empty),
+        ];
+        for (data, reason) in cases {
+            let before = AccessStat::snapshot();
+            send(addr, data, true).await;
+            let after = wait_closed(&before, 1).await;
+            assert_eq!(after.accepted, before.accepted + 1);
+            assert_eq!(after.served, before.served);
+            // 每个连接只记录一次关闭原因
+            for r in ConnCloseReason::ALL {
+                let expect = before.get_closed(r) + if r == reason { 1 } else { 0 };
+                assert_eq!(after.get_closed(r), expect, "{:?}", reason);
+            }
+        }
+
+        // 正常的请求记录为已处理的连接及对应的状态码
+        let before = AccessStat::snapshot();
+        let req = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let res = send(addr, req, false).await;
+        assert!(res.starts_with(b"HTTP/1.1 200"));
+        let after = wait_closed(&before, 1).await;
+        assert_eq!(after.accepted, before.accepted + 1);
+        assert_eq!(after.served, before.served + 1);
+        assert_eq!(after.get_requests(200), before.get_requests(200) + 1);
+        assert_eq!(
+            after.accepted,
+            after.served + after.closed.values().sum::<u64>()
+        );
+    }
+}
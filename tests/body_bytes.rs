@@ -0,0 +1,151 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 请求及返回字节数统计相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::Metrics;
+
+    use super::common;
+
+    /// 模拟后端, 读取完整的请求后按路径返回chunked或压缩前的内容
+    async fn handle(mut stream: TcpStream) {
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                if !head.contains("transfer-encoding: chunked")
+                    || body.ends_with("0\r\n\r\n")
+                {
+                    break;
+                }
+            }
+            match stream.read(&mut buf).await {
+                Ok(n) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return,
+            }
+        }
+        if data.starts_with(b"POST /chunked") {
+            let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n";
+            let _ = stream.write_all(head.as_bytes()).await;
+            for i in 0..3 {
+                let mut chunk = format!("3e8\r\n{}\r\n", "b".repeat(1000));
+                if i == 2 {
+                    chunk += "0\r\n\r\n";
+                }
+                let _ = stream.write_all(chunk.as_bytes()).await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        } else {
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 4096\r\nConnection: close\r\n\r\n{}",
+                "a".repeat(4096)
+            );
+            let _ = stream.write_all(res.as_bytes()).await;
+        }
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "bytes.local"
+            gzip = true
+            [[server.location]]
+            rule = "/chunked"
+            proxy_url = "http://{0}/"
+            [[server.location]]
+            rule = "/gzip"
+            proxy_url = "http://{0}/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 发送请求并读取完整的返回, 返回小写的返回头及返回体的大小(不含chunked的分块信息)
+    async fn request(addr: SocketAddr, req: &[u8]) -> (String, usize) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(req).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&data[..pos]).to_ascii_lowercase();
+                let body = &data[pos + 4..];
+                let len = if head.contains("transfer-encoding: chunked") {
+                    decode_chunked(body)
+                } else {
+                    head.lines()
+                        .find_map(|l| l.strip_prefix("content-length: "))
+                        .and_then(|v| v.trim().parse::<usize>().ok())
+                        .filter(|len| body.len() >= *len)
+                };
+                if let Some(len) = len {
+                    return (head, len);
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => panic!("incomplete response: {:?}", String::from_utf8_lossy(&data)),
+            }
+        }
+    }
+
+    /// 解析完整的chunked内容, 未结束时返回None
+    fn decode_chunked(mut body: &[u8]) -> Option<usize> {
+        let mut total = 0;
+        loop {
+            let pos = body.windows(2).position(|w| w == b"\r\n")?;
+            let size = usize::from_str_radix(std::str::from_utf8(&body[..pos]).ok()?, 16).ok()?;
+            if size == 0 {
+                return Some(total);
+            }
+            if body.len() < pos + 2 + size + 2 {
+                return None;
+            }
+            total += size;
+            body = &body[pos + 2 + size + 2..];
+        }
+    }
+
+    #[tokio::test]
+    async fn test_body_bytes() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        // chunked的请求体按实际收到的内容计算
+        let req = format!(
+            "POST /chunked HTTP/1.1\r\nHost: bytes.local\r\nTransfer-Encoding: chunked\r\n\r\n3e8\r\n{}\r\n1f4\r\n{}\r\n0\r\n\r\n",
+            "c".repeat(1000),
+            "c".repeat(500)
+        );
+        let (head, len) = request(addr, req.as_bytes()).await;
+        assert!(head.starts_with("http/1.1 200"));
+        assert_eq!(len, 3000);
+        let metrics = Metrics::location("bytes.local", "/chunked");
+        assert_eq!(metrics.requests(), 1);
+        assert_eq!(metrics.bytes_in(), 1500);
+        assert_eq!(metrics.bytes_out(), 3000);
+        assert!(metrics.header_out() > 0);
+
+        // 压缩的返回按压缩后发送的内容计算
+        let req = "GET /gzip HTTP/1.1\r\nHost: bytes.local\r\nAccept-Encoding: gzip\r\n\r\n";
+        let (head, len) = request(addr, req.as_bytes()).await;
+        assert!(head.contains("content-encoding: gzip"));
+        assert!(len > 0 && len < 4096);
+        let metrics = Metrics::location("bytes.local", "/gzip");
+        assert_eq!(metrics.requests(), 1);
+        assert_eq!(metrics.bytes_in(), 0);
+        assert_eq!(metrics.bytes_out(), len as u64);
+    }
+}
@@ -0,0 +1,171 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 请求体大小限制相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, `done`记录收到完整请求的次数, `aborted`记录请求未结束即断开的次数
+    async fn handle(mut stream: TcpStream, done: Arc<AtomicUsize>, aborted: Arc<AtomicUsize>) {
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let finish = if head.contains("transfer-encoding: chunked") {
+                    body.ends_with("0\r\n\r\n")
+                } else {
+                    let len = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("content-length: "))
+                        .and_then(|v| v.trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    body.len() >= len
+                };
+                if finish {
+                    break;
+                }
+            }
+            match stream.read(&mut buf).await {
+                Ok(n) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => {
+                    aborted.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+        done.fetch_add(1, Ordering::SeqCst);
+        let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    /// 反向代理的配置, `server`为server中额外的配置项
+    fn config(upstream: SocketAddr, server: &str) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            {}
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            max_body_size = "1k"
+            "#,
+            server, upstream
+        )
+    }
+
+    /// 发送请求并返回状态行
+    async fn upload(addr: SocketAddr, head: &str, body: Vec<u8>) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut data = head.as_bytes().to_vec();
+        data.extend(body);
+        stream.write_all(&data).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(2).any(|w| w == b"\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_string();
+        text.lines().next().unwrap_or("").to_string()
+    }
+
+    /// 生成`count`个512字节的chunk
+    fn chunks(count: usize) -> Vec<u8> {
+        let mut data = (0..count)
+            .map(|_| format!("200\r\n{}\r\n", "a".repeat(512)))
+            .collect::<String>();
+        data.push_str("0\r\n\r\n");
+        data.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_max_body_size() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let aborted = Arc::new(AtomicUsize::new(0));
+        let (count, failed) = (done.clone(), aborted.clone());
+        let upstream =
+            common::run_upstream(move |stream| handle(stream, count.clone(), failed.clone())).await;
+        let addr = common::run_proxy(&config(upstream, "")).await;
+        let chunked = "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+        // 声明的大小超出时直接返回413, 不访问后端
+        let head = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2048\r\n\r\n";
+        let status = upload(addr, head, vec![b'a'; 2048]).await;
+        assert!(status.starts_with("HTTP/1.1 413"), "{}", status);
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        assert_eq!(aborted.load(Ordering::SeqCst), 0);
+
+        // 未超出的chunked请求正常转发
+        let status = upload(addr, chunked, chunks(1)).await;
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+
+        // chunked超出时返回413, 后端连接被中断且未收到完整的请求
+        let status = upload(addr, chunked, chunks(8)).await;
+        assert!(status.starts_with("HTTP/1.1 413"), "{}", status);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+        assert_eq!(aborted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_client_timeout() {
+        let done = Arc::new(AtomicUsize::new(0));
+        let aborted = Arc::new(AtomicUsize::new(0));
+        let (count, failed) = (done.clone(), aborted.clone());
+        let upstream =
+            common::run_upstream(move |stream| handle(stream, count.clone(), failed.clone())).await;
+        let server = "client_header_timeout = \"300ms\"\nclient_body_timeout = \"300ms\"";
+        let addr = common::run_proxy(&config(upstream, server)).await;
+
+        // 请求头未在限制时间内接收完整时返回408并关闭连接
+        let status = upload(addr, "GET / HTTP/1.1\r\nHost: local", vec![]).await;
+        assert!(status.starts_with("HTTP/1.1 408"), "{}", status);
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+
+        // 请求体未在限制时间内接收完整时返回408, 后端未收到完整的请求
+        let head = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 100\r\n\r\n";
+        let status = upload(addr, head, vec![b'a'; 10]).await;
+        assert!(status.starts_with("HTTP/1.1 408"), "{}", status);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        assert_eq!(aborted.load(Ordering::SeqCst), 1);
+
+        // 及时发送的请求正常转发, 请求之间的空闲时间不计入请求头的超时
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1024];
+        for _ in 0..2 {
+            let req = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\naaaaaaaaaa";
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let res = String::from_utf8_lossy(&buf[..n]).to_string();
+            assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,132 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 返回缓存相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 返回请求的次数, 按路径返回不可缓存的头
+    async fn handle(mut stream: TcpStream, count: Arc<AtomicUsize>) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let num = count.fetch_add(1, Ordering::SeqCst) + 1;
+        let text = String::from_utf8_lossy(&data).to_string();
+        let extra = if text.starts_with("GET /nostore") {
+            "Cache-Control: no-store\r\n"
+        } else if text.starts_with("GET /cookie") {
+            "Set-Cookie: sid=1\r\n"
+        } else {
+            ""
+        };
+        let body = format!("data-{}", num);
+        let res = format!(
+            "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            extra,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            [server.location.cache]
+            max_size = "1m"
+            ttl = "60s"
+            status = [200]
+            "#,
+            upstream
+        )
+    }
+
+    /// 返回X-Cache的值及返回内容
+    async fn request(addr: SocketAddr, method: &str, path: &str) -> (Option<String>, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+            method, path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let head = head.to_ascii_lowercase();
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    let cache = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("x-cache: "))
+                        .map(|v| v.trim().to_string());
+                    return (cache, body.to_string());
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => panic!("incomplete response: {}", text),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let upstream = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let hit = Some("hit".to_string());
+        let miss = Some("miss".to_string());
+        // 首次访问后端, 之后由缓存返回
+        assert_eq!(request(addr, "GET", "/data").await, (miss.clone(), "data-1".to_string()));
+        assert_eq!(request(addr, "GET", "/data").await, (hit.clone(), "data-1".to_string()));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 查询参数不同时单独缓存
+        assert_eq!(request(addr, "GET", "/data?a=1").await.0, miss);
+        assert_eq!(request(addr, "GET", "/data?a=1").await.0, hit);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // no-store及设置cookie的返回不缓存
+        for path in ["/nostore", "/cookie"] {
+            assert_eq!(request(addr, "GET", path).await.0, miss);
+            assert_eq!(request(addr, "GET", path).await.0, miss);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 6);
+
+        // 非GET请求直接访问后端
+        let (cache, body) = request(addr, "POST", "/data").await;
+        assert_eq!(cache, None);
+        assert_eq!(body, "data-7");
+    }
+}
@@ -0,0 +1,64 @@
+//! 集成测试共用的反向代理及模拟后端
+#![allow(dead_code)]
+
+use std::{future::Future, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+use wmproxy::HttpConfig;
+
+/// 按配置启动反向代理, `config`的格式与配置文件中`[http]`的内容一致, 返回监听的地址
+pub async fn run_proxy(config: &str) -> SocketAddr {
+    let http: HttpConfig = toml::from_str(config).unwrap();
+    run_http(http).await
+}
+
+/// 按已构造的配置启动反向代理, 返回监听的地址
+pub async fn run_http(mut http: HttpConfig) -> SocketAddr {
+    http.after_load_option().unwrap();
+    let servers = http.convert_server_config();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, client)) = listener.accept().await {
+            let servers = servers.clone();
+            tokio::spawn(async move {
+                let _ = HttpConfig::process_by(servers, stream, client, Some(addr)).await;
+            });
+        }
+    });
+    addr
+}
+
+/// 启动模拟后端, 每个连接交由`handler`处理, 返回监听的地址
+pub async fn run_upstream<F, Fut>(handler: F) -> SocketAddr
+where
+    F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(handler(stream));
+        }
+    });
+    addr
+}
+
+/// 读取完整的请求头, 连接在此之前关闭时返回None
+pub async fn read_head(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    let mut buf = [0u8; 1024];
+    while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(n) if n > 0 => data.extend_from_slice(&buf[..n]),
+            _ => return None,
+        }
+    }
+    Some(data)
+}
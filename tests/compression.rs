@@ -0,0 +1,162 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 动态压缩相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 按请求路径返回不同大小及类型的内容
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let text = String::from_utf8_lossy(&data).to_string();
+        // 后端已压缩的返回
+        if text.starts_with("GET /encoded") {
+            let body = [0x1fu8, 0x8b, 0x08, 0x00];
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(&body).await;
+            return;
+        }
+        let (content_type, len) = if text.starts_with("GET /small") {
+            ("application/json", 100)
+        } else if text.starts_with("GET /image") {
+            ("image/png", 4096)
+        } else {
+            ("text/plain", 4096)
+        };
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            len,
+            "a".repeat(len)
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    /// 转发到后端的配置, `server`为server中的其它配置
+    fn config(upstream: SocketAddr, server: &str) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            {}
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            server, upstream
+        )
+    }
+
+    /// 返回小写的返回头
+    async fn request_headers(addr: SocketAddr, path: &str) -> String {
+        request_accept(addr, path, "gzip").await
+    }
+
+    async fn request_accept(addr: SocketAddr, path: &str, accept: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: {}\r\n\r\n",
+            path, accept
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        text.split("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_compression() {
+        let upstream = common::run_upstream(handle).await;
+        let server = r#"
+            compression_min_length = "1k"
+            compression_types = ["text/*", "application/json"]
+        "#;
+        let addr = common::run_proxy(&config(upstream, server)).await;
+
+        let small = request_headers(addr, "/small").await;
+        assert!(small.starts_with("http/1.1 200"));
+        assert!(!small.contains("content-encoding: gzip"));
+        assert!(small.contains("content-length: 100"));
+
+        let large = request_headers(addr, "/large").await;
+        assert!(large.starts_with("http/1.1 200"));
+        assert!(large.contains("content-encoding: gzip"));
+
+        let image = request_headers(addr, "/image").await;
+        assert!(image.starts_with("http/1.1 200"));
+        assert!(!image.contains("content-encoding: gzip"));
+    }
+
+    /// 开启gzip时使用默认的大小及类型
+    #[tokio::test]
+    async fn test_gzip() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream, "gzip = true")).await;
+
+        let small = request_headers(addr, "/small").await;
+        assert!(!small.contains("content-encoding: gzip"));
+        assert!(small.contains("content-length: 100"));
+        assert!(small.contains("vary: accept-encoding"));
+
+        let large = request_headers(addr, "/large").await;
+        assert!(large.contains("content-encoding: gzip"));
+        assert!(large.contains("vary: accept-encoding"));
+
+        let image = request_headers(addr, "/image").await;
+        assert!(!image.contains("content-encoding: gzip"));
+        assert!(!image.contains("vary: accept-encoding"));
+
+        let addr = common::run_proxy(&config(upstream, "gzip = false")).await;
+        let large = request_headers(addr, "/large").await;
+        assert!(!large.contains("content-encoding: gzip"));
+    }
+
+    /// 按配置的压缩方式及优先级选择, 后端已压缩的返回原样转发
+    #[tokio::test]
+    async fn test_compression_methods() {
+        let upstream = common::run_upstream(handle).await;
+        let server = r#"
+            gzip = true
+            compression_methods = ["br", "gzip"]
+        "#;
+        let addr = common::run_proxy(&config(upstream, server)).await;
+
+        let large = request_accept(addr, "/large", "gzip, br").await;
+        assert!(large.contains("content-encoding: br"), "{}", large);
+        assert!(large.contains("vary: accept-encoding"));
+        let large = request_accept(addr, "/large", "gzip").await;
+        assert!(large.contains("content-encoding: gzip"), "{}", large);
+        // 未允许的压缩方式不压缩
+        let large = request_accept(addr, "/large", "deflate").await;
+        assert!(!large.contains("content-encoding: deflate"), "{}", large);
+        assert!(large.contains("content-length: 4096"), "{}", large);
+
+        let encoded = request_accept(addr, "/encoded", "br").await;
+        assert!(encoded.contains("content-encoding: gzip"), "{}", encoded);
+        assert!(encoded.contains("content-length: 4"), "{}", encoded);
+    }
+}
@@ -0,0 +1,90 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 请求并发数公平分配相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpSocket, TcpStream},
+    };
+
+    use super::common;
+
+    /// 模拟较慢的后端, 使请求保持在处理中
+    async fn handle(mut stream: TcpStream) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            limit_concurrency = "max=4 per_ip=2"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 从指定的本地IP发起请求, 返回状态码
+    async fn request(local: &str, addr: SocketAddr) -> u16 {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind(format!("{}:0", local).parse().unwrap()).unwrap();
+        let mut stream = socket.connect(addr).await.unwrap();
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(2).any(|w| w == b"\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_string();
+        text.split_whitespace()
+            .nth(1)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_fair_concurrency() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        // 单个IP大量并发时只占用自己的份额
+        let flood = (0..8)
+            .map(|_| tokio::spawn(request("127.0.0.1", addr)))
+            .collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // 其它IP的请求仍能被接收
+        let other = (0..2)
+            .map(|_| tokio::spawn(request("127.0.0.2", addr)))
+            .collect::<Vec<_>>();
+        for o in other {
+            assert_eq!(o.await.unwrap(), 200);
+        }
+
+        let mut status = vec![];
+        for f in flood {
+            status.push(f.await.unwrap());
+        }
+        assert_eq!(status.iter().filter(|s| **s == 200).count(), 2);
+        assert_eq!(status.iter().filter(|s| **s == 429).count(), 6);
+    }
+}
@@ -0,0 +1,86 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 连接数限制相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 直接返回内容的配置, `server`为server中的其它配置
+    fn config(server: &str) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "conn.test"
+            {}
+            [[server.location]]
+            rule = "/"
+            static_response = "ok"
+            "#,
+            server
+        )
+    }
+
+    /// 在已建立的连接上发送请求, 返回小写的返回头
+    async fn request(stream: &mut TcpStream) -> String {
+        let req = "GET / HTTP/1.1\r\nHost: conn.test\r\n\r\n";
+        let _ = stream.write_all(req.as_bytes()).await;
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        text.split("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    async fn check_limit(addr: SocketAddr) {
+        // 保持两个连接, 第三个连接直接返回503
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert!(request(&mut first).await.starts_with("http/1.1 200"));
+        assert!(request(&mut second).await.starts_with("http/1.1 200"));
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let res = request(&mut third).await;
+        assert!(res.starts_with("http/1.1 503"), "{}", res);
+
+        // 连接关闭后归还连接数
+        drop(first);
+        let mut ok = false;
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            if request(&mut stream).await.starts_with("http/1.1 200") {
+                ok = true;
+                break;
+            }
+        }
+        assert!(ok);
+        assert!(request(&mut second).await.starts_with("http/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() {
+        let addr = common::run_proxy(&config("max_connections = 2")).await;
+        check_limit(addr).await;
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_per_ip() {
+        let addr = common::run_proxy(&config("max_connections_per_ip = 2")).await;
+        check_limit(addr).await;
+    }
+}
@@ -0,0 +1,137 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 跨域处理相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 记录收到的请求数, 返回中携带通配的跨域头
+    async fn handle(mut stream: TcpStream, count: Arc<AtomicUsize>) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        count.fetch_add(1, Ordering::Relaxed);
+        let res = "HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            [server.location.cors]
+            allow_origins = ["https://example.com", "https://*.example.org"]
+            allow_methods = ["GET", "PUT"]
+            allow_headers = ["Content-Type", "X-Token"]
+            allow_credentials = true
+            max_age = "1h"
+            "#,
+            upstream
+        )
+    }
+
+    /// 发送请求并返回完整的返回内容(小写)
+    async fn send(addr: SocketAddr, method: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} /api HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            method, headers
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cors() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let upstream = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        // 预检请求直接返回, 不访问后端
+        let preflight = "Origin: https://a.example.org\r\nAccess-Control-Request-Method: PUT\r\n";
+        let res = send(addr, "OPTIONS", preflight).await;
+        assert!(res.starts_with("http/1.1 204"), "{}", res);
+        assert!(
+            res.contains("access-control-allow-origin: https://a.example.org\r\n"),
+            "{}",
+            res
+        );
+        assert!(
+            res.contains("access-control-allow-methods: get, put\r\n"),
+            "{}",
+            res
+        );
+        assert!(
+            res.contains("access-control-allow-headers: content-type, x-token\r\n"),
+            "{}",
+            res
+        );
+        assert!(
+            res.contains("access-control-allow-credentials: true\r\n"),
+            "{}",
+            res
+        );
+        assert!(res.contains("access-control-max-age: 3600\r\n"), "{}", res);
+        assert!(res.contains("vary: origin\r\n"), "{}", res);
+        let preflight = "Origin: https://evil.com\r\nAccess-Control-Request-Method: PUT\r\n";
+        let res = send(addr, "OPTIONS", preflight).await;
+        assert!(res.starts_with("http/1.1 204"), "{}", res);
+        assert!(!res.contains("access-control-"), "{}", res);
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+
+        // 普通请求按允许列表返回Origin, 不在列表中时不返回跨域头
+        let res = send(addr, "GET", "Origin: https://example.com\r\n").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        assert!(
+            res.contains("access-control-allow-origin: https://example.com\r\n"),
+            "{}",
+            res
+        );
+        assert!(res.contains("vary: origin\r\n"), "{}", res);
+        let res = send(addr, "GET", "Origin: https://evil.com\r\n").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        assert!(!res.contains("access-control-"), "{}", res);
+        assert!(res.contains("vary: origin\r\n"), "{}", res);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+}
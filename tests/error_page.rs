@@ -0,0 +1,126 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 自定义错误页面相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::common;
+
+    /// 模拟后端, 均返回503
+    async fn handle(mut stream: TcpStream) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        let res = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 8\r\nConnection: close\r\n\r\nupstream";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr, pages: &[&str], intercept: bool) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            intercept_errors = {}
+            error_page = {:?}
+            [[server.location]]
+            rule = "/errors"
+            return = [200, "custom page"]
+            [[server.location]]
+            rule = "/api"
+            proxy_url = "http://{}/"
+            "#,
+            intercept, pages, upstream
+        )
+    }
+
+    async fn request(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok());
+                if len.is_some_and(|len| body.len() >= len) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_page() {
+        let upstream = common::run_upstream(handle).await;
+        let file = "target/error_page_503.html";
+        std::fs::write(file, "<h1>busy</h1>").unwrap();
+        let pages = ["404 /errors/404", &format!("503 {}", file)];
+
+        // 代理自身的错误内部跳转至错误页面, 保留原状态码
+        let addr = common::run_proxy(&config(upstream, &pages, false)).await;
+        let res = request(addr, "/missing").await;
+        assert!(res.starts_with("HTTP/1.1 404"), "{}", res);
+        assert!(res.ends_with("custom page"));
+
+        // 未开启intercept_errors时后端的错误原样返回
+        let res = request(addr, "/api").await;
+        assert!(res.starts_with("HTTP/1.1 503"), "{}", res);
+        assert!(res.ends_with("upstream"));
+
+        // 开启后替换为本地的错误页面
+        let addr = common::run_proxy(&config(upstream, &pages, true)).await;
+        let res = request(addr, "/api").await;
+        assert!(res.starts_with("HTTP/1.1 503"), "{}", res);
+        assert!(res.to_ascii_lowercase().contains("content-type: text/html"));
+        assert!(res.ends_with("<h1>busy</h1>"));
+
+        // 错误页面的文件不存在时按原返回处理
+        let pages = ["503 target/error_page_missing.html"];
+        let addr = common::run_proxy(&config(upstream, &pages, true)).await;
+        let res = request(addr, "/api").await;
+        assert!(res.starts_with("HTTP/1.1 503"), "{}", res);
+        assert!(res.ends_with("upstream"));
+        let _ = std::fs::remove_file(file);
+    }
+
+    #[tokio::test]
+    async fn test_error_page_upstream_down() {
+        // 后端无法连接时使用内嵌的错误页面
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        drop(listener);
+        let pages = ["502 504 <h1>Service down</h1>"];
+        let addr = common::run_proxy(&config(upstream, &pages, false)).await;
+        let res = request(addr, "/api").await;
+        assert!(res.starts_with("HTTP/1.1 502"), "{}", res);
+        assert!(res.to_ascii_lowercase().contains("content-type: text/html"));
+        assert!(res.ends_with("<h1>Service down</h1>"));
+
+        // 未配置时仍返回原有的内容
+        let addr = common::run_proxy(&config(upstream, &[], false)).await;
+        let res = request(addr, "/api").await;
+        assert!(res.starts_with("HTTP/1.1 502"), "{}", res);
+        assert!(res.ends_with("bad gateway"));
+    }
+}
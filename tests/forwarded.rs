@@ -0,0 +1,124 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 转发头相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use webparse::Request;
+    use wenmeng::Body;
+    use wmproxy::{LocationConfig, TlsConnection};
+
+    use super::common;
+
+    /// 模拟后端, 将收到的请求头作为返回内容
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            data.len()
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+        let _ = stream.write_all(&data).await;
+    }
+
+    /// 转发到后端的配置, `server`为server中的其它配置
+    fn config(upstream: SocketAddr, server: &str) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            {}
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            server, upstream
+        )
+    }
+
+    /// 发送请求并返回后端收到的请求头(小写)
+    async fn send(addr: SocketAddr, extra: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", extra);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        // 返回头及作为返回内容的请求头均以空行结束
+        while data.windows(4).filter(|w| w == b"\r\n\r\n").count() < 2 {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&data).to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_headers() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream, "")).await;
+
+        let headers = send(addr, "").await;
+        assert!(headers.contains("x-forwarded-for: 127.0.0.1\r\n"));
+        assert!(headers.contains("x-real-ip: 127.0.0.1\r\n"));
+        assert!(headers.contains("x-forwarded-proto: http\r\n"));
+        // 传递接入的监听端口
+        let port = format!("x-forwarded-port: {}\r\n", addr.port());
+        assert!(headers.contains(&port), "{}", headers);
+
+        // 已有的X-Forwarded-For追加客户端地址
+        let headers = send(addr, "X-Forwarded-For: 10.0.0.1\r\n").await;
+        assert!(headers.contains("x-forwarded-for: 10.0.0.1, 127.0.0.1\r\n"));
+
+        // 关闭后不添加
+        let server = "forwarded_headers = false";
+        let addr = common::run_proxy(&config(upstream, server)).await;
+        let headers = send(addr, "").await;
+        assert!(!headers.contains("x-forwarded-for"));
+        assert!(!headers.contains("x-real-ip"));
+        assert!(!headers.contains("x-forwarded-port"));
+    }
+
+    #[test]
+    fn test_real_ip_alias() {
+        let location: LocationConfig = toml::from_str("rule = \"/\"\nreal_ip = false").unwrap();
+        assert_eq!(location.comm.forwarded_headers, Some(false));
+        let location: LocationConfig = toml::from_str("rule = \"/\"").unwrap();
+        assert_eq!(location.comm.forwarded_headers, None);
+    }
+
+    #[test]
+    fn test_forwarded_proto() {
+        let location = LocationConfig::new();
+        let mut req = Request::builder()
+            .url("http://localhost/")
+            .body(Body::empty())
+            .unwrap();
+        let client: SocketAddr = "192.168.1.2:5000".parse().unwrap();
+        req.extensions_mut().insert(client);
+        req.extensions_mut().insert(TlsConnection);
+        location.set_forwarded_headers(&mut req);
+        // 重试时不重复添加
+        location.set_forwarded_headers(&mut req);
+        let headers = req.headers();
+        assert_eq!(
+            headers.get_str_value(&"x-forwarded-for").unwrap(),
+            "192.168.1.2"
+        );
+        assert_eq!(headers.get_str_value(&"x-real-ip").unwrap(), "192.168.1.2");
+        assert_eq!(headers.get_str_value(&"x-forwarded-proto").unwrap(), "https");
+        // 未知监听地址时不传递端口
+        assert!(headers.get_str_value(&"x-forwarded-port").is_none());
+    }
+}
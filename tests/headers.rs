@@ -0,0 +1,126 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 请求头及返回头修改相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 将收到的请求头作为返回内容
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Powered-By: upstream\r\nServer: nginx\r\nConnection: close\r\n\r\n",
+            data.len()
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+        let _ = stream.write_all(&data).await;
+    }
+
+    /// 转发到后端的配置, `location`为location中的其它配置
+    fn config(upstream: SocketAddr, location: &str) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            {}
+            "#,
+            upstream, location
+        )
+    }
+
+    /// 发送请求, 返回小写的返回头及后端收到的请求头
+    async fn send(addr: SocketAddr, req: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        // 返回头及作为返回内容的请求头均以空行结束
+        while data.windows(4).filter(|w| w == b"\r\n\r\n").count() < 2 {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        let (res, upstream_req) = text.split_once("\r\n\r\n").unwrap();
+        (res.to_string(), upstream_req.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_headers() {
+        let upstream = common::run_upstream(handle).await;
+        let location = r#"
+            forwarded_headers = false
+            headers = [
+                "proxy Host internal.example.com",
+                "proxy -Authorization",
+                "proxy +X-Trace '$http_x_client from $remote_addr'",
+                "proxy X-Uri $request_uri",
+                "proxy X-Tenant $cookie_tenant",
+                "proxy X-Request-Id $request_id",
+                "-X-Powered-By",
+                "+ X-Served-Host $host",
+            ]
+        "#;
+        let addr = common::run_proxy(&config(upstream, location)).await;
+
+        let req = "GET /a?b=1 HTTP/1.1\r\nHost: localhost\r\nAuthorization: Basic eA==\r\nX-Client: c1\r\nCookie: tenant=wm\r\n\r\n";
+        let (res, upstream_req) = send(addr, req).await;
+
+        assert!(upstream_req.contains("host: internal.example.com\r\n"));
+        assert!(!upstream_req.contains("host: localhost"));
+        assert!(!upstream_req.contains("authorization"));
+        assert!(upstream_req.contains("x-trace: c1 from 127.0.0.1\r\n"));
+        assert!(upstream_req.contains("x-uri: /a?b=1\r\n"));
+        assert!(upstream_req.contains("x-tenant: wm\r\n"));
+        let request_id = upstream_req
+            .lines()
+            .find_map(|l| l.strip_prefix("x-request-id: "))
+            .unwrap();
+        assert_eq!(request_id.len(), 32);
+
+        assert!(res.starts_with("http/1.1 200"));
+        assert!(!res.contains("x-powered-by"));
+        assert!(res.contains("x-served-host: localhost"));
+    }
+
+    #[tokio::test]
+    async fn test_response_header_rules() {
+        let upstream = common::run_upstream(handle).await;
+        let location = r#"
+            proxy_set_header = ["Strict-Transport-Security max-age=31536000", "X-Served-By ${host}_$remote_addr"]
+            proxy_hide_header = ["Server", "x-powered-by"]
+        "#;
+        let addr = common::run_proxy(&config(upstream, location)).await;
+
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (res, _) = send(addr, req).await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        assert!(res.contains("strict-transport-security: max-age=31536000"));
+        assert!(res.contains("x-served-by: localhost_127.0.0.1"), "{}", res);
+        // 移除server后不再添加默认的server: wmproxy
+        assert!(!res.contains("server:"), "{}", res);
+        assert!(!res.contains("x-powered-by"));
+
+        // 未配置时后端的server被替换成wmproxy
+        let addr = common::run_proxy(&config(upstream, "")).await;
+        let (res, _) = send(addr, req).await;
+        assert!(res.contains("server: wmproxy"), "{}", res);
+    }
+}
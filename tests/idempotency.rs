@@ -0,0 +1,153 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 幂等请求相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use webparse::{Request, Response, Url};
+    use wenmeng::Body;
+    use wmproxy::{Idempotency, LocationConfig, UpstreamConfig};
+
+    use super::common;
+
+    /// 模拟后端, 返回收到的请求次数及body
+    async fn handle(stream: TcpStream, count: Arc<AtomicUsize>) {
+        let _ = deal_upstream(stream, count).await;
+    }
+
+    async fn deal_upstream(mut stream: TcpStream, count: Arc<AtomicUsize>) -> std::io::Result<()> {
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        let pos = loop {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            data.extend_from_slice(&buf[..n]);
+            if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+        };
+        let header = String::from_utf8_lossy(&data[..pos]).to_ascii_lowercase();
+        let len = header
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .map(|l| l.trim().parse::<usize>().unwrap())
+            .unwrap_or(0);
+        while data.len() < pos + len {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        let times = count.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let body = format!(
+            "{}:{}",
+            times,
+            String::from_utf8_lossy(&data[pos..pos + len])
+        );
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(res.as_bytes()).await?;
+        Ok(())
+    }
+
+    fn build_location(upstream: UpstreamConfig) -> LocationConfig {
+        let mut location = LocationConfig::new();
+        location.idempotency = true;
+        let url = format!("http://{}/", upstream.name);
+        location.comm.proxy_url = Some(Url::parse(url.into_bytes()).unwrap());
+        location.upstream.push(upstream);
+        location
+    }
+
+    fn build_req(key: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .url("http://pay/charge")
+            .header("Idempotency-Key", key.to_string())
+            .body(Body::new_text(body.to_string()))
+            .unwrap()
+    }
+
+    async fn send(location: &LocationConfig, key: &str, body: &str) -> (u16, String) {
+        let mut req = build_req(key, body);
+        let (mut res, _, _): (Response<Body>, _, _) =
+            location.deal_request(&mut req).await.unwrap();
+        let (data, _) = Idempotency::read_body(res.body_mut(), 1024).await.unwrap();
+        (res.status().as_u16(), String::from_utf8(data).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_idempotency() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let location = build_location(UpstreamConfig::new_single("pay".to_string(), addr));
+
+        // 处理中的相同请求合并成一次后端访问
+        let (a, b) = tokio::join!(
+            send(&location, "k1", "amount=1"),
+            send(&location, "k1", "amount=1")
+        );
+        assert_eq!(a, (200, "1:amount=1".to_string()));
+        assert_eq!(a, b);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 重新发送时直接返回缓存的返回
+        let c = send(&location, "k1", "amount=1").await;
+        assert_eq!(c, a);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 相同的key不同的内容被拒绝
+        let d = send(&location, "k1", "amount=2").await;
+        assert_eq!(d.0, 422);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 不同的key正常访问后端
+        let e = send(&location, "k2", "amount=2").await;
+        assert_eq!(e, (200, "2:amount=2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_retry() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let dead = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut upstream = UpstreamConfig::new_single("pay".to_string(), dead);
+        upstream
+            .server
+            .extend(UpstreamConfig::new_single("pay".to_string(), addr).server);
+        let location = build_location(upstream);
+
+        // 连接失败时重试下一个后端
+        for i in 0..4 {
+            let key = format!("retry{}", i);
+            let (status, _) = send(&location, &key, "amount=1").await;
+            assert_eq!(status, 200);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+}
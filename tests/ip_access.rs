@@ -0,0 +1,96 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// IP访问控制相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::LocationConfig;
+
+    use super::common;
+
+    const CONFIG: &str = r#"
+        [[server]]
+        bind_addr = "127.0.0.1:0"
+        bind_ssl = ""
+        allow_ip = "127.0.0.0/24 ::1"
+        [[server.location]]
+        rule = "/deny"
+        deny = "127.0.0.1"
+        static_response = "ok"
+        [[server.location]]
+        rule = "/inner"
+        allow = "10.0.0.0/8"
+        deny_status = 404
+        static_response = "ok"
+        [[server.location]]
+        rule = "/order"
+        access = ["deny 127.0.0.2", "allow 127.0.0.0/24", "deny all"]
+        static_response = "ok"
+        [[server.location]]
+        rule = "/rules"
+        access = ["deny 127.0.0.1/32", "allow all"]
+        static_response = "ok"
+        [[server.location]]
+        rule = "/"
+        static_response = "ok"
+    "#;
+
+    /// 返回状态行
+    async fn request_status(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(2).any(|w| w == b"\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_string();
+        text.lines().next().unwrap_or("").to_string()
+    }
+
+    #[tokio::test]
+    async fn test_ip_access() {
+        let addr = common::run_proxy(CONFIG).await;
+
+        // 在server允许的网段内
+        let res = request_status(addr, "/index").await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+
+        // location中单独禁止该IP
+        let res = request_status(addr, "/deny").await;
+        assert!(res.starts_with("HTTP/1.1 403"), "{}", res);
+
+        // location的规则覆盖server, 并使用自定义状态码
+        let res = request_status(addr, "/inner").await;
+        assert!(res.starts_with("HTTP/1.1 404"), "{}", res);
+
+        // 按顺序匹配的规则, 第一个匹配的规则生效
+        let res = request_status(addr, "/order").await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        let res = request_status(addr, "/rules").await;
+        assert!(res.starts_with("HTTP/1.1 403"), "{}", res);
+    }
+
+    #[test]
+    fn test_bad_access() {
+        // 错误的CIDR在加载配置时即报错
+        for config in [
+            "rule = \"/\"\naccess = [\"allow 10.0.0.0/33\"]",
+            "rule = \"/\"\naccess = [\"permit all\"]",
+            "rule = \"/\"\nallow = \"10.0.0.256\"",
+        ] {
+            assert!(toml::from_str::<LocationConfig>(config).is_err(), "{}", config);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 请求频率限制相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 限流的配置, `http`为http中的其它配置
+    fn config(http: &str) -> String {
+        format!(
+            r#"
+            {}
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "limit.test"
+            [[server.location]]
+            rule = "/limit"
+            static_response = "ok"
+            limit_req = "rate=1r/min burst=1"
+            [[server.location]]
+            rule = "/"
+            static_response = "ok"
+            "#,
+            http
+        )
+    }
+
+    /// 返回小写的返回头
+    async fn request_headers(addr: SocketAddr, path: &str) -> String {
+        request_from(addr, path, "").await
+    }
+
+    async fn request_from(addr: SocketAddr, path: &str, extra: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: limit.test\r\n{}\r\n",
+            path, extra
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        text.split("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_limit_req() {
+        let addr = common::run_proxy(&config("")).await;
+
+        // 速率加上burst共两个请求可通过
+        for _ in 0..2 {
+            let res = request_headers(addr, "/limit").await;
+            assert!(res.starts_with("http/1.1 200"), "{}", res);
+        }
+        let res = request_headers(addr, "/limit").await;
+        assert!(res.starts_with("http/1.1 429"), "{}", res);
+        assert!(res.contains("retry-after: "));
+
+        // 其它location不受影响
+        let res = request_headers(addr, "/other").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+    }
+
+    /// 经由可信的代理时按X-Forwarded-For中的客户端地址分别限制
+    #[tokio::test]
+    async fn test_limit_req_forwarded() {
+        let addr = common::run_proxy(&config("trusted_proxies = \"127.0.0.1\"")).await;
+        let client = "X-Forwarded-For: 10.0.0.1\r\n";
+        for _ in 0..2 {
+            let res = request_from(addr, "/limit", client).await;
+            assert!(res.starts_with("http/1.1 200"), "{}", res);
+        }
+        let res = request_from(addr, "/limit", client).await;
+        assert!(res.starts_with("http/1.1 429"), "{}", res);
+
+        let res = request_from(addr, "/limit", "X-Forwarded-For: 10.0.0.2\r\n").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+    }
+}
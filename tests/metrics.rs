@@ -0,0 +1,81 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// Prometheus统计相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use wmproxy::MetricsServer;
+
+    use super::common;
+
+    const CONFIG: &str = r#"
+        [[server]]
+        bind_addr = "127.0.0.1:0"
+        bind_ssl = ""
+        up_name = "metrics.local"
+        [[server.location]]
+        rule = "/static"
+        static_response = "ok"
+    "#;
+
+    /// 发送请求并按Content-Length读取完整的返回
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: metrics.local\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| {
+                        l.to_ascii_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|v| v.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let addr = common::run_proxy(CONFIG).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let metrics_addr = listener.local_addr().unwrap();
+        tokio::spawn(MetricsServer::serve(listener));
+
+        assert!(get(addr, "/static").await.starts_with("HTTP/1.1 200"));
+        assert!(get(addr, "/static").await.starts_with("HTTP/1.1 200"));
+        assert!(get(addr, "/other").await.starts_with("HTTP/1.1 404"));
+
+        let text = get(metrics_addr, "/metrics").await;
+        assert!(text.starts_with("HTTP/1.1 200"));
+        let labels = "server=\"metrics.local\",location=\"/static\"";
+        assert!(text.contains(&format!("wmproxy_http_requests_total{{{labels}}} 2\n")));
+        assert!(text.contains(&format!(
+            "wmproxy_http_responses_total{{{labels},class=\"2xx\"}} 2\n"
+        )));
+        assert!(text.contains("# TYPE wmproxy_upstream_response_seconds histogram\n"));
+        assert!(text.contains("wmproxy_connections_accepted_total "));
+
+        // 只提供/metrics
+        assert!(get(metrics_addr, "/").await.starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,97 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 动态后端地址相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 记录收到的请求次数
+    async fn handle(mut stream: TcpStream, count: Arc<AtomicUsize>) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        count.fetch_add(1, Ordering::SeqCst);
+        let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(port: u16) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_pass = "http://$http_x_backend:{}/"
+            proxy_pass_allow = ["127.0.0.*"]
+            "#,
+            port
+        )
+    }
+
+    /// 在同一连接上发送请求并返回状态行
+    async fn request(stream: &mut TcpStream, backend: &str) -> String {
+        let req = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Backend: {}\r\n\r\n",
+            backend
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return head.lines().next().unwrap_or("").to_string();
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_pass_allow() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let upstream = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream.port())).await;
+
+        // 由请求头计算出的目标在允许列表中
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut stream, "127.0.0.1").await, "HTTP/1.1 200 OK");
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // 不在允许列表中的目标即使可以访问也被拒绝
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let status = request(&mut stream, "localhost").await;
+        assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+        let status = request(&mut stream, "").await;
+        assert!(status.starts_with("HTTP/1.1 502"), "{}", status);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
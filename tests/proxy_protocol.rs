@@ -0,0 +1,229 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 接收PROXY协议相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 将收到的请求头作为返回内容
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            data.len()
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+        let _ = stream.write_all(&data).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            proxy_protocol = true
+            forwarded_headers = true
+            [[server.location]]
+            rule = "/deny"
+            deny = "10.1.1.1"
+            static_response = "ok"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 分两次发送协议头及请求, 返回完整的返回内容(小写)
+    async fn send(addr: SocketAddr, header: &[u8], path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let (first, second) = header.split_at(header.len() / 2);
+        stream.write_all(first).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(second).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.map(|len| body.len() >= len).unwrap_or(true) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    fn v2_header(ip: [u8; 4], port: u16) -> Vec<u8> {
+        let mut data = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        data.extend_from_slice(&ip);
+        data.extend_from_slice(&[127, 0, 0, 1]);
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend_from_slice(&80u16.to_be_bytes());
+        data
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        // 访问控制及转发的头按协议头中的地址处理
+        let header = b"PROXY TCP4 10.1.1.1 127.0.0.1 5000 80\r\n";
+        let res = send(addr, header, "/deny").await;
+        assert!(res.starts_with("http/1.1 403"), "{}", res);
+        let header = b"PROXY TCP4 10.2.2.2 127.0.0.1 5000 80\r\n";
+        let res = send(addr, header, "/deny").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        let res = send(addr, header, "/").await;
+        assert!(res.contains("x-real-ip: 10.2.2.2\r\n"), "{}", res);
+        assert!(res.contains("x-forwarded-for: 10.2.2.2\r\n"), "{}", res);
+
+        let res = send(addr, &v2_header([10, 1, 1, 1], 5000), "/deny").await;
+        assert!(res.starts_with("http/1.1 403"), "{}", res);
+        let res = send(addr, &v2_header([10, 3, 3, 3], 5000), "/").await;
+        assert!(res.contains("x-real-ip: 10.3.3.3\r\n"), "{}", res);
+
+        // UNKNOWN时使用连接的地址
+        let res = send(addr, b"PROXY UNKNOWN\r\n", "/").await;
+        assert!(res.contains("x-real-ip: 127.0.0.1\r\n"), "{}", res);
+
+        // 缺少或错误的协议头直接关闭连接
+        let res = send(addr, b"", "/").await;
+        assert!(res.is_empty(), "{}", res);
+        let res = send(addr, b"PROXY TCP4 10.2.2.2\r\n", "/").await;
+        assert!(res.is_empty(), "{}", res);
+    }
+
+    /// 每个连接收到的PROXY协议头及之后的请求数
+    type Received = Arc<Mutex<Vec<(Vec<u8>, usize)>>>;
+
+    /// 模拟需要PROXY协议v2的后端, 先读取协议头, 之后在同一连接上持续返回请求
+    async fn handle_proxy_protocol(mut stream: TcpStream, received: Received) {
+        let mut header = vec![0u8; 16];
+        if stream.read_exact(&mut header).await.is_err() {
+            return;
+        }
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await.unwrap();
+        header.extend_from_slice(&body);
+        let index = {
+            let mut received = received.lock().unwrap();
+            received.push((header, 0));
+            received.len() - 1
+        };
+        while let Some(data) = common::read_head(&mut stream).await {
+            // 协议头之后即为HTTP请求
+            assert!(data.starts_with(b"GET / HTTP/1.1\r\n"));
+            received.lock().unwrap()[index].1 += 1;
+            let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+            if stream.write_all(res.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn read_response(stream: &mut TcpStream) -> String {
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.ends_with(b"\r\n\r\nok") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        String::from_utf8_lossy(&data).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_send_proxy_protocol() {
+        let received = Received::default();
+        let receiver = received.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle_proxy_protocol(stream, receiver.clone()))
+                .await;
+        let config = format!(
+            r#"
+            [[upstream]]
+            name = "app"
+            proxy_protocol = "v2"
+            server = [{{ addr = "{}" }}]
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://app/"
+            "#,
+            upstream
+        );
+        let addr = common::run_proxy(&config).await;
+
+        // 同一客户端的多个请求复用连接, 只发送一次协议头
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let client = stream.local_addr().unwrap();
+        for _ in 0..3 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let res = read_response(&mut stream).await;
+            assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        }
+        {
+            let received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            let (header, count) = &received[0];
+            assert_eq!(*count, 3);
+            assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+            // 版本2的PROXY命令, TCP/IPv4, 地址长度12
+            assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+            assert_eq!(&header[16..20], &[127, 0, 0, 1]);
+            assert_eq!(&header[20..24], &[127, 0, 0, 1]);
+            assert_eq!(&header[24..26], &client.port().to_be_bytes());
+            assert_eq!(&header[26..28], &addr.port().to_be_bytes());
+        }
+
+        // 其它客户端不复用已声明客户端地址的连接
+        let mut other = TcpStream::connect(addr).await.unwrap();
+        let other_client = other.local_addr().unwrap();
+        other
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let res = read_response(&mut other).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(&received[1].0[24..26], &other_client.port().to_be_bytes());
+        assert_eq!(received[1].1, 1);
+    }
+}
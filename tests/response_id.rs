@@ -0,0 +1,93 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 后端返回ID相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use webparse::{Request, Response, Url};
+    use wenmeng::Body;
+    use wmproxy::{Helper, LocationConfig};
+
+    use super::common;
+
+    /// 模拟后端, 返回头中携带追踪ID
+    async fn handle(mut stream: TcpStream) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        let res = "HTTP/1.1 200 OK\r\nX-Trace-Id: trace-9527\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn build_location(upstream: SocketAddr) -> LocationConfig {
+        let mut location = LocationConfig::new();
+        let url = format!("http://{}/", upstream);
+        location.comm.proxy_url = Some(Url::parse(url.into_bytes()).unwrap());
+        location.response_id = Some("x-trace-id x-request-id".parse().unwrap());
+        location
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            response_id = "x-trace-id x-request-id"
+            "#,
+            upstream
+        )
+    }
+
+    #[tokio::test]
+    async fn test_response_id() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let headers = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        assert!(headers.starts_with("http/1.1 200"));
+        assert!(headers.contains("x-request-id: trace-9527\r\n"));
+    }
+
+    #[test]
+    fn test_response_id_log() {
+        let location = build_location("127.0.0.1:80".parse().unwrap());
+        let req = Request::builder()
+            .url("http://localhost/")
+            .body(Body::empty())
+            .unwrap();
+        let mut res = Response::builder()
+            .header("X-Trace-Id", "trace-9527")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(Helper::format_req_res(&req, Some(&res), "id={up_id}"), "id=-");
+        location.rewrite_response(&req, &mut res);
+        assert_eq!(
+            Helper::format_req_res(&req, Some(&res), "id={up_id}"),
+            "id=trace-9527"
+        );
+    }
+}
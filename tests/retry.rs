@@ -0,0 +1,199 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 失败重试相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::{io::AsyncWriteExt, net::TcpStream, sync::mpsc::channel};
+    use webparse::{Binary, BinaryMut, Request, Url};
+    use wenmeng::Body;
+    use wmproxy::{LocationConfig, UpstreamBalance, UpstreamConfig};
+
+    use super::common;
+
+    /// 模拟后端, 记录收到的请求次数, 以`status`返回
+    async fn handle(mut stream: TcpStream, count: Arc<AtomicUsize>, status: u16) {
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        count.fetch_add(1, Ordering::SeqCst);
+        let res = format!(
+            "HTTP/1.1 {} OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+            status
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn build_location(addr: SocketAddr, retries: usize) -> LocationConfig {
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        build_location_with(dead, addr, retries)
+    }
+
+    fn build_location_with(dead: SocketAddr, addr: SocketAddr, retries: usize) -> LocationConfig {
+        let mut upstream = UpstreamConfig::new_single("retry".to_string(), dead);
+        // 轮询使失败的后端必定被选中
+        upstream.balance = UpstreamBalance::RoundRobin;
+        upstream
+            .server
+            .extend(UpstreamConfig::new_single("retry".to_string(), addr).server);
+        let mut location = LocationConfig::new();
+        location.retries = retries;
+        location.comm.proxy_url = Some(Url::parse(b"http://retry/".to_vec()).unwrap());
+        location.upstream.push(upstream);
+        location
+    }
+
+    async fn send(location: &LocationConfig, method: &str, body: &str) -> Option<u16> {
+        let mut req = Request::builder()
+            .method(method)
+            .url("http://retry/")
+            .body(if body.is_empty() {
+                Body::empty()
+            } else {
+                Body::new_text(body.to_string())
+            })
+            .unwrap();
+        send_req(location, &mut req).await.map(|(status, _)| status)
+    }
+
+    /// 返回状态码及X-Upstream-Addr
+    async fn send_req(
+        location: &LocationConfig,
+        req: &mut Request<Body>,
+    ) -> Option<(u16, Option<String>)> {
+        match location.deal_request(req).await {
+            Ok((res, _, _)) => Some((
+                res.status().as_u16(),
+                res.headers().get_str_value(&"X-Upstream-Addr"),
+            )),
+            Err(_) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_idempotent() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone(), 200)).await;
+        let location = build_location(addr, 1);
+
+        // 幂等的请求连接失败时重试下一个后端
+        for method in ["GET", "HEAD", "GET", "DELETE"] {
+            assert_eq!(send(&location, method, "").await, Some(200));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+
+        // 请求体已完整接收的请求可重新发送
+        for (method, body) in [("POST", "a=1"), ("POST", "a=1"), ("PUT", "a=1"), ("PUT", "a=1")] {
+            assert_eq!(send(&location, method, body).await, Some(200));
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry_streaming() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone(), 200)).await;
+        let location = build_location(addr, 1);
+        // 流式的请求体发送后无法再次发送, 失败时直接返回
+        let mut fails = 0;
+        let mut senders = vec![];
+        for _ in 0..4 {
+            let (sender, receiver) = channel::<(bool, Binary)>(1);
+            let mut body = Body::new(receiver, BinaryMut::new(), false);
+            body.set_chunked(true);
+            let mut req = Request::builder()
+                .method("POST")
+                .url("http://retry/")
+                .header("Transfer-Encoding", "chunked")
+                .body(body)
+                .unwrap();
+            senders.push(sender);
+            if send_req(&location, &mut req).await.is_none() {
+                fails += 1;
+            }
+        }
+        assert!(fails > 0);
+        assert_eq!(count.load(Ordering::SeqCst), 4 - fails);
+    }
+
+    #[tokio::test]
+    async fn test_retry_status() {
+        let bad_count = Arc::new(AtomicUsize::new(0));
+        let counter = bad_count.clone();
+        let bad = common::run_upstream(move |stream| handle(stream, counter.clone(), 503)).await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone(), 200)).await;
+
+        // 默认只在连接失败及超时时重试, 503直接返回
+        let mut location = build_location_with(bad, addr, 1);
+        location.upstream_addr_header = true;
+        let mut statuses = vec![];
+        for _ in 0..4 {
+            statuses.push(send(&location, "GET", "").await);
+        }
+        assert!(statuses.contains(&Some(503)));
+
+        // 配置后503时重试下一个后端, 并返回最终处理的后端
+        location.proxy_next_upstream = vec!["error".to_string(), "http_503".to_string()];
+        for _ in 0..4 {
+            let mut req = Request::builder()
+                .url("http://retry/")
+                .body(Body::empty())
+                .unwrap();
+            let (status, upstream) = send_req(&location, &mut req).await.unwrap();
+            assert_eq!(status, 200);
+            assert_eq!(upstream, Some(addr.to_string()));
+        }
+        assert!(bad_count.load(Ordering::SeqCst) >= 4);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        let bad_count = Arc::new(AtomicUsize::new(0));
+        let counter = bad_count.clone();
+        let bad = common::run_upstream(move |stream| handle(stream, counter.clone(), 503)).await;
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone(), 200)).await;
+        let mut location = build_location_with(bad, addr, 1);
+        location.proxy_next_upstream = vec!["http_503".to_string()];
+        // 超出重试的总耗时后不再重试
+        location.proxy_next_upstream_timeout = Some("0ms".parse().unwrap());
+        let mut statuses = vec![];
+        for _ in 0..4 {
+            statuses.push(send(&location, "GET", "").await);
+        }
+        assert!(statuses.contains(&Some(503)));
+        assert_eq!(count.load(Ordering::SeqCst) + bad_count.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_no_retry() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let addr = common::run_upstream(move |stream| handle(stream, counter.clone(), 200)).await;
+        let location = build_location(addr, 0);
+        let mut fails = 0;
+        for _ in 0..4 {
+            if send(&location, "GET", "").await.is_none() {
+                fails += 1;
+            }
+        }
+        assert!(fails > 0);
+    }
+}
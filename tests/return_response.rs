@@ -0,0 +1,75 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// location直接返回相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    const CONFIG: &str = r#"
+        [[server]]
+        bind_addr = "127.0.0.1:0"
+        bind_ssl = ""
+        [[server.location]]
+        rule = "/old"
+        return = [301, "https://www.$host/new$path?$query"]
+        [[server.location]]
+        rule = "/deny"
+        return = [403, "forbidden"]
+        [[server.location]]
+        rule = "/empty"
+        return = 204
+    "#;
+
+    async fn request(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_return() {
+        let addr = common::run_proxy(CONFIG).await;
+
+        let res = request(addr, "/old/page?a=1").await;
+        assert!(res.starts_with("HTTP/1.1 301"), "{}", res);
+        assert!(res
+            .to_ascii_lowercase()
+            .contains("location: https://www.example.com/new/old/page?a=1\r\n"));
+
+        let res = request(addr, "/deny").await;
+        assert!(res.starts_with("HTTP/1.1 403"), "{}", res);
+        assert!(res.ends_with("\r\n\r\nforbidden"), "{}", res);
+
+        let res = request(addr, "/empty").await;
+        assert!(res.starts_with("HTTP/1.1 204"), "{}", res);
+    }
+}
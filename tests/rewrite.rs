@@ -0,0 +1,144 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 路径重写相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 将收到的请求行作为返回内容, 并返回跳转至`/login`的Location
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let text = String::from_utf8_lossy(&data).to_string();
+        let line = text.split("\r\n").next().unwrap_or_default().to_string();
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nLocation: /login\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            line.len(),
+            line
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/api/v1/"
+            proxy_url = "http://{0}/"
+            rewrite = "/api/v1/ /"
+            [[server.location]]
+            rule = "/user/"
+            proxy_url = "http://{0}/"
+            rewrite = "^/user/(\\d+)$ /users?id=$1"
+            [[server.location]]
+            rule = "/old/"
+            proxy_url = "http://{0}/"
+            rewrite = "^/old/(.*)$ /new/$1"
+            [[server.location]]
+            rule = "/api/"
+            proxy_url = "http://{0}/"
+            strip_prefix = "/api"
+            [[server.location]]
+            rule = "/grafana/"
+            proxy_url = "http://{0}/"
+            strip_prefix = true
+            strip_prefix_redirect = true
+            [[server.location]]
+            rule = "/app"
+            proxy_url = "http://{0}/"
+            strip_prefix = true
+            "#,
+            upstream
+        )
+    }
+
+    async fn request(addr: SocketAddr, path: &str) -> String {
+        send(addr, path).await.1
+    }
+
+    /// 返回头(小写)及返回内容
+    async fn send(addr: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| {
+                        l.to_ascii_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(|v| v.to_string())
+                    })
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return (head.to_ascii_lowercase(), body.to_string());
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return (String::new(), String::new()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rewrite() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let cases = [
+            ("/api/v1/users?a=1&b=2", "GET /users?a=1&b=2 HTTP/1.1"),
+            ("/api/v1/", "GET / HTTP/1.1"),
+            ("/user/15?x=y", "GET /users?id=15&x=y HTTP/1.1"),
+            ("/user/abc", "GET /user/abc HTTP/1.1"),
+            ("/old/a/b?c=1", "GET /new/a/b?c=1 HTTP/1.1"),
+            ("/api/", "GET / HTTP/1.1"),
+            ("/api/orders/1", "GET /orders/1 HTTP/1.1"),
+        ];
+        for (path, expect) in cases {
+            assert_eq!(request(addr, path).await, expect, "path `{}`", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strip_rule_prefix() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let cases = [
+            ("/grafana/", "GET / HTTP/1.1"),
+            ("/grafana/api/x?a=1", "GET /api/x?a=1 HTTP/1.1"),
+            ("/app", "GET / HTTP/1.1"),
+            ("/app/api/x", "GET /api/x HTTP/1.1"),
+            ("/apps/x", "GET /apps/x HTTP/1.1"),
+        ];
+        for (path, expect) in cases {
+            assert_eq!(request(addr, path).await, expect, "path `{}`", path);
+        }
+
+        // 跳转地址重新加上前缀, 未开启时保持后端的跳转地址
+        let (head, _) = send(addr, "/grafana/api/x").await;
+        assert!(head.contains("\r\nlocation: /grafana/login"), "{}", head);
+        let (head, _) = send(addr, "/app/x").await;
+        assert!(head.contains("\r\nlocation: /login"), "{}", head);
+    }
+}
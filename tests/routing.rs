@@ -0,0 +1,119 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// location按方法, 请求头及参数匹配相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    const CONFIG: &str = r#"
+        [[server]]
+        bind_addr = "127.0.0.1:0"
+        bind_ssl = ""
+        [[server.location]]
+        rule = "/"
+        return = [200, "root"]
+        [[server.location]]
+        rule = "/api/"
+        if_header = ["X-Canary", "1"]
+        return = [200, "canary"]
+        [[server.location]]
+        rule = "/api/"
+        if_query = ["debug"]
+        return = [200, "debug"]
+        [[server.location]]
+        rule = "/api/"
+        methods = ["GET", "HEAD"]
+        return = [200, "read"]
+        [[server.location]]
+        rule = "/api/"
+        methods = ["post"]
+        return = [200, "write"]
+        [[server.location]]
+        rule = "/api/v1/"
+        return = [200, "v1"]
+        [[server.location]]
+        rule = "^/api/v\\d+/"
+        return = [200, "regex"]
+        [[server.location]]
+        rule = "/admin/"
+        methods = ["GET"]
+        return = [200, "admin"]
+    "#;
+
+    async fn request(addr: SocketAddr, method: &str, path: &str, headers: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: example.com\r\n{}Content-Length: 0\r\n\r\n",
+            method, path, headers
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    fn body(res: &str) -> &str {
+        res.split_once("\r\n\r\n").map(|(_, b)| b).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let addr = common::run_proxy(CONFIG).await;
+
+        // 前缀最长的优先, 长度相同时按配置顺序, 正则匹配排在前缀匹配之后
+        let cases = [
+            ("GET", "/other", "", "root"),
+            ("GET", "/api/x", "", "read"),
+            ("HEAD", "/api/x", "", "read"),
+            ("POST", "/api/x", "", "write"),
+            ("GET", "/api/x", "X-Canary: 1\r\n", "canary"),
+            ("GET", "/api/x", "X-Canary: 2\r\n", "read"),
+            ("GET", "/api/x?debug", "", "debug"),
+            ("DELETE", "/api/x?a=1&debug=0", "", "debug"),
+            ("GET", "/api/x?debugging=1", "", "read"),
+            ("GET", "/api/v1/x", "", "v1"),
+            ("DELETE", "/api/v1/x", "", "v1"),
+            ("GET", "/api/v2/x", "", "read"),
+            ("GET", "/admin/", "", "admin"),
+        ];
+        for (method, path, headers, expect) in cases {
+            let res = request(addr, method, path, headers).await;
+            assert!(res.starts_with("HTTP/1.1 200"), "{} {}: {}", method, path, res);
+            assert_eq!(body(&res), expect, "{} {}: {}", method, path, res);
+        }
+
+        // 路径匹配但方法不允许时返回405, 不再匹配其它location
+        let res = request(addr, "DELETE", "/api/x", "").await;
+        assert!(res.starts_with("HTTP/1.1 405"), "{}", res);
+        assert!(res.to_ascii_lowercase().contains("\r\nallow: get, head, post\r\n"), "{}", res);
+        let res = request(addr, "POST", "/admin/", "").await;
+        assert!(res.starts_with("HTTP/1.1 405"), "{}", res);
+        assert!(res.to_ascii_lowercase().contains("\r\nallow: get\r\n"), "{}", res);
+    }
+}
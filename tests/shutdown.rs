@@ -0,0 +1,110 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 关闭时排空连接相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::{HttpConfig, Shutdown, ShutdownState};
+
+    use super::common;
+
+    /// 模拟后端, 延迟返回以模拟处理中的请求
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        if data.starts_with(b"GET /slow") {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+        let _ = stream.write_all(res.as_bytes()).await;
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            up_name = "shutdown.test"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 在已建立的连接上发送请求, 返回小写的返回头, 连接被关闭时返回空
+    async fn request(stream: &mut TcpStream, path: &str) -> String {
+        let req = format!("GET {} HTTP/1.1\r\nHost: shutdown.test\r\n\r\n", path);
+        let _ = stream.write_all(req.as_bytes()).await;
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        text.split("\r\n\r\n").next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        // 保持一个空闲的keep-alive连接
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        assert!(request(&mut idle, "/fast").await.starts_with("http/1.1 200"));
+
+        let mut slow = TcpStream::connect(addr).await.unwrap();
+        let pending = tokio::spawn(async move {
+            let res = request(&mut slow, "/slow").await;
+            // 处理完当前请求后关闭连接
+            let mut buf = [0u8; 64];
+            let closed = matches!(
+                tokio::time::timeout(Duration::from_secs(5), slow.read(&mut buf)).await,
+                Ok(Ok(0)) | Ok(Err(_))
+            );
+            (res, closed)
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let start = Instant::now();
+        let shutdown = tokio::spawn(HttpConfig::shutdown(Duration::from_millis(800)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(Shutdown::state(), ShutdownState::Draining);
+
+        // 处理中的请求正常返回
+        let (res, closed) = pending.await.unwrap();
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        assert!(res.contains("connection: close"), "{}", res);
+        assert!(closed);
+
+        // 不再接收新连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(request(&mut stream, "/fast").await, "");
+
+        // 超时后关闭剩余的空闲连接
+        shutdown.await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(Shutdown::state(), ShutdownState::Closed);
+        let mut buf = [0u8; 64];
+        let ret = tokio::time::timeout(Duration::from_secs(2), idle.read(&mut buf)).await;
+        assert!(matches!(ret, Ok(Ok(0)) | Ok(Err(_))));
+    }
+}
@@ -0,0 +1,173 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 按比例分流到多个upstream相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::{HttpConfig, LocationConfig, ServerConfig, SplitConfig, WrapVecAddr};
+
+    use super::common;
+
+    /// 模拟后端, 返回内容为后端的名称
+    async fn handle(mut stream: TcpStream, name: &'static str) {
+        while common::read_head(&mut stream).await.is_some() {
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                name.len(),
+                name
+            );
+            if stream.write_all(res.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// 启动stable及canary两个后端, 返回按`split`分流的配置
+    async fn config(split: &str) -> String {
+        let mut upstreams = String::new();
+        for name in ["stable", "canary"] {
+            let addr = common::run_upstream(move |stream| handle(stream, name)).await;
+            upstreams += &format!(
+                "[[upstream]]\nname = \"{}\"\nserver = [{{ addr = \"{}\" }}]\n",
+                name, addr
+            );
+        }
+        let config = format!(
+            r#"
+            {}
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            upstream_addr_header = true
+            {}
+            "#,
+            upstreams, split
+        );
+        let mut http: HttpConfig = toml::from_str(&config).unwrap();
+        http.after_load_option().unwrap();
+        assert!(http.validate().is_ok());
+        config
+    }
+
+    /// 发送请求, 返回后端名称的返回头及返回内容
+    async fn request(addr: SocketAddr, cookie: Option<&str>) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let cookie = cookie
+            .map(|c| format!("Cookie: {}\r\n", c))
+            .unwrap_or_default();
+        let req = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            cookie
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let head = head.to_ascii_lowercase();
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.map(|len| body.len() >= len).unwrap_or(false) {
+                    let name = head
+                        .lines()
+                        .find_map(|l| l.strip_prefix("x-upstream-name: "))
+                        .unwrap_or_default();
+                    return (name.to_string(), body.to_string());
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => panic!("{}", text),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_split_cookie() {
+        let split = r#"
+            proxy_url = "http://stable/"
+            split = { key = "cookie:uid", upstreams = [{ name = "stable", percent = 80 }, { name = "canary", percent = 20 }] }
+        "#;
+        let addr = common::run_proxy(&config(split).await).await;
+        let split: LocationConfig = toml::from_str(&format!("rule = \"/\"\n{}", split)).unwrap();
+        let split = split.split.unwrap();
+
+        let mut canary = 0;
+        for i in 0..50 {
+            let uid = format!("user{}", i);
+            let expect = split.choose_by(SplitConfig::hash_point(&uid)).unwrap();
+            // 同一cookie固定访问同一后端, 返回头中标记所选的后端
+            for _ in 0..2 {
+                let (name, body) = request(addr, Some(&format!("uid={}", uid))).await;
+                assert_eq!(name, expect.name);
+                assert_eq!(body, expect.name);
+            }
+            if expect.name == "canary" {
+                canary += 1;
+            }
+        }
+        assert!(canary > 0 && canary < 25, "{}", canary);
+    }
+
+    #[tokio::test]
+    async fn test_split_random() {
+        // 百分比为0的后端不会被选择
+        let split = r#"
+            split = { upstreams = [{ name = "stable", percent = 0 }, { name = "canary", percent = 100 }] }
+        "#;
+        let addr = common::run_proxy(&config(split).await).await;
+        for _ in 0..10 {
+            assert_eq!(
+                request(addr, None).await,
+                ("canary".to_string(), "canary".to_string())
+            );
+        }
+
+        let split = r#"
+            split = { upstreams = [{ name = "stable", percent = 50 }, { name = "canary", percent = 50 }] }
+        "#;
+        let addr = common::run_proxy(&config(split).await).await;
+        let mut names = vec![];
+        for _ in 0..40 {
+            let (name, body) = request(addr, None).await;
+            assert_eq!(name, body);
+            names.push(name);
+        }
+        assert!(names.iter().any(|n| n == "stable"));
+        assert!(names.iter().any(|n| n == "canary"));
+    }
+
+    #[test]
+    fn test_split_validate() {
+        let mut server = ServerConfig::new("127.0.0.1:0".parse::<WrapVecAddr>().unwrap());
+        let location: LocationConfig = toml::from_str(
+            r#"
+            rule = "/"
+            split = { upstreams = [{ name = "stable", percent = 90 }, { name = "missing", percent = 5 }] }
+            "#,
+        )
+        .unwrap();
+        server.location.push(location);
+        let mut http = HttpConfig::new();
+        http.server.push(server.clone());
+        assert!(http.after_load_option().is_err());
+        let errors = http.validate().unwrap_err();
+        let text = wmproxy::ConfigError::join(&errors);
+        assert_eq!(errors.len(), 3, "{}", text);
+        assert!(text.contains("百分比之和需为100, 当前为95"), "{}", text);
+        assert!(text.contains("split的upstreamstable不存在"), "{}", text);
+        assert!(text.contains("split的upstreammissing不存在"), "{}", text);
+    }
+}
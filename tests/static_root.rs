@@ -0,0 +1,171 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// location的root静态文件相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    fn build_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("wmproxy_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(root.join("empty")).unwrap();
+        std::fs::write(root.join("hello.txt"), "hello").unwrap();
+        std::fs::write(root.join("sub").join("home.html"), "<h1>home</h1>").unwrap();
+        root
+    }
+
+    fn config(root: &Path, autoindex: bool) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            root = {:?}
+            index = ["home.html"]
+            autoindex = {}
+            "#,
+            root.to_string_lossy(),
+            autoindex
+        )
+    }
+
+    /// 返回小写的返回头及body
+    async fn request(addr: SocketAddr, method: &str, path: &str) -> (String, String) {
+        request_with(addr, method, path, "").await
+    }
+
+    async fn request_with(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        extra: &str,
+    ) -> (String, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            method, path, extra
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            // 读取到头后仅需短暂等待剩余的body
+            let wait = if data.windows(4).any(|w| w == b"\r\n\r\n") {
+                Duration::from_millis(200)
+            } else {
+                Duration::from_secs(5)
+            };
+            match tokio::time::timeout(wait, stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_string();
+        let (head, body) = text.split_once("\r\n\r\n").unwrap_or((&text, ""));
+        (head.to_ascii_lowercase(), body.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_static_root() {
+        let root = build_root("static");
+        let addr = common::run_proxy(&config(&root, false)).await;
+
+        let (head, body) = request(addr, "GET", "/hello.txt?v=1").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-type: text/plain"));
+        assert!(body.contains("hello"));
+
+        let (head, body) = request(addr, "HEAD", "/hello.txt").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-length: 5"));
+        assert!(body.is_empty(), "{}", body);
+
+        let (head, body) = request(addr, "GET", "/sub/").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-type: text/html"));
+        assert!(body.contains("<h1>home</h1>"));
+
+        let (head, _) = request(addr, "GET", "/missing.txt").await;
+        assert!(head.starts_with("http/1.1 404"), "{}", head);
+
+        let (head, _) = request(addr, "GET", "/empty/").await;
+        assert!(head.starts_with("http/1.1 403"), "{}", head);
+
+        let (head, _) = request(addr, "GET", "/sub/../../etc/passwd").await;
+        assert!(head.starts_with("http/1.1 403"), "{}", head);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_autoindex() {
+        let root = build_root("autoindex");
+        std::fs::write(root.join("a b.txt"), "ab").unwrap();
+        std::fs::write(root.join("中文.md"), "md").unwrap();
+        std::fs::write(root.join(".secret"), "secret").unwrap();
+        std::fs::write(root.join("empty").join("<x>.txt"), "x").unwrap();
+        let addr = common::run_proxy(&config(&root, true)).await;
+
+        let (head, body) = request(addr, "GET", "/").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-type: text/html; charset=utf-8"));
+        assert!(body.contains("href=\"/a%20b.txt\""), "{}", body);
+        assert!(body.contains("href=\"/%E4%B8%AD%E6%96%87.md\""), "{}", body);
+        assert!(body.contains("href=\"/sub/\""), "{}", body);
+        assert!(body.contains("href=\"/hello.txt\""), "{}", body);
+        assert!(!body.contains("secret"), "{}", body);
+
+        // 名字中的html字符需转义
+        let (head, body) = request(addr, "GET", "/empty/").await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(body.contains("href=\"/empty/%3Cx%3E.txt\">&lt;x&gt;.txt</a>"), "{}", body);
+
+        // 有index的目录仍返回index
+        let (_, body) = request(addr, "GET", "/sub/").await;
+        assert!(body.contains("<h1>home</h1>"));
+
+        for path in ["/sub/../../", "/%2e%2e/"] {
+            let (head, _) = request(addr, "GET", path).await;
+            assert!(head.starts_with("http/1.1 403"), "{} {}", path, head);
+        }
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_static_range() {
+        let root = build_root("range");
+        std::fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        let addr = common::run_proxy(&config(&root, false)).await;
+
+        let (head, body) = request_with(addr, "GET", "/digits.txt", "Range: bytes=2-5\r\n").await;
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert!(head.contains("content-range: bytes 2-5/10"), "{}", head);
+        assert_eq!(body, "2345");
+
+        let (head, body) = request_with(addr, "GET", "/digits.txt", "Range: bytes=7-\r\n").await;
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert_eq!(body, "789");
+
+        let (head, _) = request_with(addr, "GET", "/digits.txt", "Range: bytes=20-30\r\n").await;
+        assert!(head.starts_with("http/1.1 416"), "{}", head);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
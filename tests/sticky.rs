@@ -0,0 +1,129 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 按cookie保持会话相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 同一连接上持续返回自身的名称
+    async fn handle(mut stream: TcpStream, name: &'static str) {
+        while common::read_head(&mut stream).await.is_some() {
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                name.len(),
+                name
+            );
+            if stream.write_all(res.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn config(upstream: &str) -> String {
+        format!(
+            r#"
+            [[upstream]]
+            {}
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://app/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 发送请求并返回完整的返回内容
+    async fn send(addr: SocketAddr, cookie: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let cookie = cookie
+            .map(|c| format!("Cookie: {}\r\n", c))
+            .unwrap_or_default();
+        let req = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            cookie
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.is_some_and(|len| body.len() >= len) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    fn set_cookie(res: &str) -> Option<String> {
+        res.lines().find_map(|l| {
+            let (k, v) = l.split_once(": ")?;
+            k.eq_ignore_ascii_case("set-cookie")
+                .then(|| v.split(';').next().unwrap().to_string())
+        })
+    }
+
+    fn body(res: &str) -> &str {
+        res.split_once("\r\n\r\n").map(|(_, b)| b).unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_sticky_cookie() {
+        let first = common::run_upstream(|stream| handle(stream, "first")).await;
+        let second = common::run_upstream(|stream| handle(stream, "second")).await;
+        let upstream = format!(
+            r#"
+            name = "app"
+            balance = "round_robin"
+            sticky = {{ cookie = "srv", max_age = "1h" }}
+            server = [{{ addr = "{}" }}, {{ addr = "{}" }}]
+            "#,
+            first, second
+        );
+        let addr = common::run_proxy(&config(&upstream)).await;
+
+        // 首次返回时写入cookie, 之后的请求均转发到同一后端
+        let res = send(addr, None).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.contains("; Path=/; HttpOnly; Max-Age=3600"), "{}", res);
+        let cookie = set_cookie(&res).unwrap();
+        assert!(cookie.starts_with("srv="), "{}", cookie);
+        assert!(!cookie.contains(&first.port().to_string()), "{}", cookie);
+        let pinned = body(&res).to_string();
+        for _ in 0..6 {
+            let res = send(addr, Some(&format!("a=1; {}", cookie))).await;
+            assert_eq!(body(&res), pinned, "{}", res);
+            assert_eq!(set_cookie(&res), None, "{}", res);
+        }
+
+        // 无法识别的cookie按负载均衡重新选择并重新写入
+        let res = send(addr, Some("srv=unknown")).await;
+        let cookie = set_cookie(&res).unwrap();
+        let pinned = body(&res).to_string();
+        for _ in 0..4 {
+            let res = send(addr, Some(&cookie)).await;
+            assert_eq!(body(&res), pinned, "{}", res);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 返回体的流式转发相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::Notify,
+    };
+    use wmproxy::UpstreamTiming;
+
+    use super::common;
+
+    /// 传输的内容远大于代理中的缓冲
+    const TOTAL: usize = 64 * 1024 * 1024;
+    /// 客户端停止读取时, 后端可写入的内容不超过该值, 包含各连接的内核缓冲
+    const STALL_LIMIT: usize = 24 * 1024 * 1024;
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            upstream
+        )
+    }
+
+    /// 读取到头部结束, 返回小写的头部及已读取的内容长度
+    async fn read_head(stream: &mut TcpStream) -> (String, usize) {
+        let mut data = vec![];
+        let mut buf = [0u8; 4096];
+        loop {
+            if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&data[..pos]).to_ascii_lowercase();
+                return (head, data.len() - pos - 4);
+            }
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed before head");
+            data.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// 持续写入直到写满对端的缓冲, 返回写入的字节数
+    async fn write_until_stall(stream: &mut TcpStream, total: usize) -> usize {
+        let chunk = vec![b'a'; 64 * 1024];
+        let mut written = 0;
+        while written < total {
+            let len = chunk.len().min(total - written);
+            let write = stream.write(&chunk[..len]);
+            match tokio::time::timeout(Duration::from_millis(500), write).await {
+                Ok(Ok(n)) => written += n,
+                Ok(Err(e)) => panic!("write failed: {:?}", e),
+                Err(_) => break,
+            }
+        }
+        written
+    }
+
+    async fn write_rest(stream: &mut TcpStream, mut written: usize, total: usize) {
+        let chunk = vec![b'a'; 64 * 1024];
+        while written < total {
+            let len = chunk.len().min(total - written);
+            stream.write_all(&chunk[..len]).await.unwrap();
+            written += len;
+        }
+    }
+
+    async fn read_exact_len(stream: &mut TcpStream, mut read: usize, total: usize) -> usize {
+        let mut buf = vec![0u8; 64 * 1024];
+        while read < total {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => read += n,
+            }
+        }
+        read
+    }
+
+    #[tokio::test]
+    async fn test_stream_response_body() {
+        let written = Arc::new(AtomicUsize::new(0));
+        let drain = Arc::new(Notify::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        let (count, notify) = (written.clone(), drain.clone());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", TOTAL);
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let n = write_until_stall(&mut stream, TOTAL).await;
+            count.store(n, Ordering::SeqCst);
+            notify.notify_one();
+            write_rest(&mut stream, n, TOTAL).await;
+        });
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /download HTTP/1.1\r\nHost: a.test\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, read) = read_head(&mut stream).await;
+        assert!(
+            head.contains(&format!("content-length: {}", TOTAL)),
+            "{}",
+            head
+        );
+        // 客户端暂停读取时后端的写入被阻塞
+        tokio::time::timeout(Duration::from_secs(30), drain.notified())
+            .await
+            .unwrap();
+        let stalled = written.load(Ordering::SeqCst);
+        assert!(stalled < STALL_LIMIT, "proxy buffered {} bytes", stalled);
+
+        let read = tokio::time::timeout(
+            Duration::from_secs(30),
+            read_exact_len(&mut stream, read, TOTAL),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read, TOTAL);
+    }
+
+    /// 返回体传输缓慢时, 耗时计入返回体的传输阶段
+    #[tokio::test]
+    async fn test_upstream_body_timing() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            read_head(&mut stream).await;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nhello")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            stream.write_all(b"world").await.unwrap();
+            let _ = stream.read(&mut [0u8; 1]).await;
+        });
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: a.test\r\n\r\n")
+            .await
+            .unwrap();
+        let (_, read) = read_head(&mut stream).await;
+        let read = tokio::time::timeout(
+            Duration::from_secs(5),
+            read_exact_len(&mut stream, read, 10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(read, 10);
+
+        let mut stat = None;
+        for _ in 0..50 {
+            stat = UpstreamTiming::get_stat(&upstream).filter(|s| s.body.count() > 0);
+            if stat.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(stat.unwrap().body.count(), 1);
+        let timing = UpstreamTiming::last_attempt(&upstream).unwrap();
+        assert!(timing.body >= Duration::from_millis(250), "{:?}", timing);
+        assert!(timing.header < Duration::from_millis(250), "{:?}", timing);
+        assert!(timing.response_time() >= timing.body);
+    }
+}
@@ -0,0 +1,143 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 后端超时相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟卡死的后端, 每个连接只返回第一个请求, `/hang`则从不返回
+    async fn handle(mut stream: TcpStream, conns: Arc<AtomicUsize>) {
+        conns.fetch_add(1, Ordering::Relaxed);
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let mut buf = [0u8; 1024];
+        if !data.starts_with(b"GET /hang") {
+            let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+            let _ = stream.write_all(res.as_bytes()).await;
+        }
+        // 之后的请求不再返回
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                return;
+            }
+        }
+    }
+
+    fn config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            connect_timeout = "1s"
+            read_timeout = "300ms"
+            write_timeout = "300ms"
+            "#,
+            upstream
+        )
+    }
+
+    /// 超时配置在upstream中, location使用默认值
+    fn upstream_config(upstream: SocketAddr) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://slow/"
+            [[server.location.upstream]]
+            name = "slow"
+            server = [{{ addr = "{}" }}]
+            read_timeout = "300ms"
+            write_timeout = "300ms"
+            "#,
+            upstream
+        )
+    }
+
+    /// 在同一连接上发送请求并读取完整的返回
+    async fn request(stream: &mut TcpStream, path: &str) -> String {
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_timeout() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream = common::run_upstream(move |stream| handle(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let now = Instant::now();
+        let res = request(&mut stream, "/hang").await;
+        assert!(res.starts_with("HTTP/1.1 504"), "{}", res);
+        assert!(now.elapsed() < Duration::from_secs(3));
+
+        // 复用连接卡死时返回504, 并丢弃该连接, 之后的请求重新建立连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let res = request(&mut stream, "/a").await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        let res = request(&mut stream, "/a").await;
+        assert!(res.starts_with("HTTP/1.1 504"), "{}", res);
+        let res = request(&mut stream, "/a").await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert_eq!(conns.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_group_timeout() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let upstream = common::run_upstream(move |stream| handle(stream, conns.clone())).await;
+        let addr = common::run_proxy(&upstream_config(upstream)).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let now = Instant::now();
+        let res = request(&mut stream, "/hang").await;
+        assert!(res.starts_with("HTTP/1.1 504"), "{}", res);
+        assert!(now.elapsed() < Duration::from_secs(3));
+    }
+}
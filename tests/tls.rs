@@ -0,0 +1,1118 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 关于证书相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    };
+    use base64::{
+        engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+        Engine,
+    };
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use webparse::{Request, Url};
+    use wenmeng::Body;
+    use wmproxy::{
+        AcmeConfig, HttpConfig, LocationConfig, ReturnResponse, ServerConfig, TlsVersion,
+        UpstreamTiming, VerifyClient, WrapVecAddr,
+    };
+
+    use super::common;
+
+    static CERT: &str = "tests/certs/localhost.pem";
+    static KEY: &str = "tests/certs/localhost.key";
+    static ECDSA_CERT: &str = "tests/certs/localhost_ecdsa.pem";
+    static ECDSA_KEY: &str = "tests/certs/localhost_ecdsa.key";
+    /// 叶子证书, 两级中间证书组成的证书链
+    static CHAIN_CERT: &str = "tests/certs/chain.pem";
+    static CHAIN_KEY: &str = "tests/certs/chain.key";
+
+    /// 不校验证书, 记录最后一次收到的证书
+    #[derive(Debug, Default)]
+    struct NoVerifier {
+        last: Mutex<Option<Vec<u8>>>,
+        /// 最后一次收到的证书链, 包含叶子证书及中间证书
+        chain: Mutex<Vec<Vec<u8>>>,
+        /// 客户端声明支持的签名算法, 为空时支持所有
+        schemes: Vec<SignatureScheme>,
+    }
+
+    impl ServerCertVerifier for NoVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            *self.last.lock().unwrap() = Some(end_entity.to_vec());
+            let chain = std::iter::once(end_entity).chain(intermediates.iter());
+            *self.chain.lock().unwrap() = chain.map(|c| c.to_vec()).collect();
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            if !self.schemes.is_empty() {
+                return self.schemes.clone();
+            }
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    fn build_server(name: &str) -> ServerConfig {
+        let mut server = ServerConfig::new_ssl("127.0.0.1:0".parse::<WrapVecAddr>().unwrap());
+        server.up_name = name.to_string();
+        server.cert = Some(CERT.to_string());
+        server.key = Some(KEY.to_string());
+        server
+    }
+
+    /// 以IP连接, 不发送SNI
+    async fn handshake_without_sni(http: &mut HttpConfig) -> bool {
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await {
+                let _ = accept.accept(stream).await;
+            }
+        });
+
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier::default()))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::IpAddress(addr.ip().into());
+        assert!(matches!(addr.ip(), IpAddr::V4(_)));
+        connector.connect(name, stream).await.is_ok()
+    }
+
+    #[tokio::test]
+    async fn test_default_cert() {
+        let mut http = HttpConfig::new();
+        http.server.push(build_server("soft.wm-proxy.com"));
+        http.server.push(build_server("localhost"));
+        assert!(!handshake_without_sni(&mut http).await);
+
+        let mut http = HttpConfig::new();
+        http.server.push(build_server("soft.wm-proxy.com"));
+        http.server.push(build_server("localhost"));
+        http.default_cert = Some(CERT.to_string());
+        http.default_key = Some(KEY.to_string());
+        assert!(handshake_without_sni(&mut http).await);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_slow_tls_timing() {
+        let mut http = HttpConfig::new();
+        http.server.push(build_server("localhost"));
+        let (accept, _, _) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+
+        // 模拟握手缓慢的后端
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let _ = accept.accept(stream).await;
+            }
+        });
+
+        let location = LocationConfig::new();
+        let url = Url::parse(format!("https://{}/", addr).into_bytes()).unwrap();
+        let mut req = Request::builder()
+            .url(&*format!("https://{}/", addr))
+            .body(Body::empty())
+            .unwrap();
+        // 自签名证书无法通过校验, 但握手阶段的耗时依然会被记录
+        let _ = location.deal_reverse_proxy(&mut req, &url).await;
+
+        let timing = UpstreamTiming::last_attempt(&addr).unwrap();
+        assert!(!timing.reused);
+        assert!(timing.dns_cached);
+        assert!(timing.tls >= Duration::from_millis(300));
+        assert!(timing.connect < Duration::from_millis(100));
+        assert_eq!(timing.header, Duration::ZERO);
+        let stat = UpstreamTiming::get_stat(&addr).unwrap();
+        assert_eq!(stat.tls.count(), 1);
+    }
+
+    /// 握手并返回服务端的证书
+    async fn handshake_cert(addr: SocketAddr) -> Option<Vec<u8>> {
+        handshake_cert_with(addr, vec![]).await
+    }
+
+    /// 以指定的签名算法握手并返回服务端的证书
+    async fn handshake_cert_with(
+        addr: SocketAddr,
+        schemes: Vec<SignatureScheme>,
+    ) -> Option<Vec<u8>> {
+        handshake_cert_by(addr, "localhost", schemes).await
+    }
+
+    /// 以指定的SNI及签名算法握手并返回服务端的证书
+    async fn handshake_cert_by(
+        addr: SocketAddr,
+        sni: &'static str,
+        schemes: Vec<SignatureScheme>,
+    ) -> Option<Vec<u8>> {
+        let verifier = Arc::new(NoVerifier {
+            schemes,
+            ..Default::default()
+        });
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from(sni).unwrap();
+        connector.connect(name, stream).await.ok()?;
+        let cert = verifier.last.lock().unwrap().clone();
+        cert
+    }
+
+    #[tokio::test]
+    async fn test_reload_certs() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_reload_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("cert.key");
+        std::fs::copy(CERT, &cert).unwrap();
+        std::fs::copy(KEY, &key).unwrap();
+
+        let mut server = build_server("localhost");
+        server.cert = Some(cert.to_string_lossy().to_string());
+        server.key = Some(key.to_string_lossy().to_string());
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accept = accept.clone();
+                tokio::spawn(async move {
+                    let _ = accept.accept(stream).await;
+                });
+            }
+        });
+
+        let origin = handshake_cert(addr).await.unwrap();
+
+        // 证书损坏时返回错误, 并维持原有证书
+        std::fs::write(&cert, "broken").unwrap();
+        let err = http.reload_certs().unwrap_err();
+        assert!(format!("{:?}", err).contains("cert.pem"));
+        assert_eq!(handshake_cert(addr).await.unwrap(), origin);
+
+        // 替换成新证书后, 新的握手使用新证书
+        std::fs::copy("tests/certs/other.pem", &cert).unwrap();
+        std::fs::copy("tests/certs/other.key", &key).unwrap();
+        http.reload_certs().unwrap();
+        let new = handshake_cert(addr).await.unwrap();
+        assert_ne!(new, origin);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_cert_watch() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_watch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert = dir.join("cert.pem");
+        let key = dir.join("cert.key");
+        std::fs::copy(CERT, &cert).unwrap();
+        std::fs::copy(KEY, &key).unwrap();
+
+        let mut server = build_server("localhost");
+        server.cert = Some(cert.to_string_lossy().to_string());
+        server.key = Some(key.to_string_lossy().to_string());
+        let mut http = HttpConfig::new();
+        http.cert_watch_interval = "50ms".parse().unwrap();
+        http.server.push(server);
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accept = accept.clone();
+                tokio::spawn(async move {
+                    let _ = accept.accept(stream).await;
+                });
+            }
+        });
+        let origin = handshake_cert(addr).await.unwrap();
+
+        // 新的证书损坏时继续使用原证书
+        std::fs::write(&cert, "broken").unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(handshake_cert(addr).await.unwrap(), origin);
+
+        // 证书文件变更后无需调用reload_certs即使用新证书
+        std::fs::copy("tests/certs/other.pem", &cert).unwrap();
+        std::fs::copy("tests/certs/other.key", &key).unwrap();
+        let mut new = origin.clone();
+        for _ in 0..40 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            new = handshake_cert(addr).await.unwrap();
+            if new != origin {
+                break;
+            }
+        }
+        assert_eq!(new, read_cert("tests/certs/other.pem"));
+
+        http.stop_health_check();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_cert() {
+        let mut wildcard = build_server("*.wm-proxy.com");
+        wildcard.cert = Some("tests/certs/wildcard.pem".to_string());
+        wildcard.key = Some("tests/certs/wildcard.key".to_string());
+        let mut http = HttpConfig::new();
+        http.server.push(wildcard);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accept = accept.clone();
+                tokio::spawn(async move {
+                    let _ = accept.accept(stream).await;
+                });
+            }
+        });
+        // 完全匹配优先于通配名, 通配名仅匹配一级子域名
+        let cert = handshake_cert_by(addr, "a.wm-proxy.com", vec![]).await;
+        assert_eq!(cert.unwrap(), read_cert("tests/certs/wildcard.pem"));
+        let cert = handshake_cert_by(addr, "soft.wm-proxy.com", vec![]).await;
+        assert_eq!(cert.unwrap(), read_cert(CERT));
+        assert!(handshake_cert_by(addr, "a.b.wm-proxy.com", vec![]).await.is_none());
+    }
+
+    fn read_cert(path: &str) -> Vec<u8> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path).unwrap());
+        let cert = rustls_pemfile::certs(&mut reader).next().unwrap().unwrap();
+        cert.to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_cert_chain() {
+        let mut server = build_server("localhost");
+        server.cert = Some(CHAIN_CERT.to_string());
+        server.key = Some(CHAIN_KEY.to_string());
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Ok((stream, _)) = listener.accept().await {
+                let _ = accept.accept(stream).await;
+            }
+        });
+
+        // 叶子证书及两个中间证书均按顺序发送
+        let verifier = Arc::new(NoVerifier::default());
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        connector.connect(name, stream).await.unwrap();
+        let mut reader = std::io::BufReader::new(std::fs::File::open(CHAIN_CERT).unwrap());
+        let expect = rustls_pemfile::certs(&mut reader)
+            .map(|c| c.unwrap().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(expect.len(), 3);
+        assert_eq!(*verifier.chain.lock().unwrap(), expect);
+
+        // 叶子证书位于中间证书之后
+        http.server[0].cert = Some("tests/certs/chain_disorder.pem".to_string());
+        let err = format!("{:?}", http.bind().await.err().unwrap());
+        assert!(err.contains("位于第2个"), "{}", err);
+        // 私钥与证书不匹配
+        http.server[0].cert = Some(CHAIN_CERT.to_string());
+        http.server[0].key = Some(KEY.to_string());
+        let err = format!("{:?}", http.bind().await.err().unwrap());
+        assert!(err.contains("与私钥不匹配"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_cert_by_signature_schemes() {
+        let mut server = build_server("localhost");
+        server.alt_cert = Some(ECDSA_CERT.to_string());
+        server.alt_key = Some(ECDSA_KEY.to_string());
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accept = accept.clone();
+                tokio::spawn(async move {
+                    let _ = accept.accept(stream).await;
+                });
+            }
+        });
+
+        let rsa = read_cert(CERT);
+        let ecdsa = read_cert(ECDSA_CERT);
+        // 仅支持RSA的客户端使用RSA证书
+        let schemes = vec![
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ];
+        assert_eq!(handshake_cert_with(addr, schemes).await.unwrap(), rsa);
+        // 仅支持ECDSA的客户端使用ECDSA证书
+        let schemes = vec![SignatureScheme::ECDSA_NISTP256_SHA256];
+        assert_eq!(handshake_cert_with(addr, schemes).await.unwrap(), ecdsa);
+        // 均支持时优先使用ECDSA证书
+        assert_eq!(handshake_cert(addr).await.unwrap(), ecdsa);
+    }
+
+    #[tokio::test]
+    async fn test_shared_port() {
+        // 同一端口的多个server均按SNI使用各自的证书
+        let mut other = build_server("localhost");
+        other.cert = Some("tests/certs/other.pem".to_string());
+        other.key = Some("tests/certs/other.key".to_string());
+        let mut no_cert = build_server("no-cert.wm-proxy.com");
+        no_cert.cert = None;
+        no_cert.key = None;
+        let mut http = HttpConfig::new();
+        http.server.push(build_server("soft.wm-proxy.com"));
+        http.server.push(other);
+        http.server.push(no_cert);
+        let (accept, tlss, listeners) = http.bind().await.unwrap();
+        assert_eq!(tlss, vec![true]);
+        let accept: TlsAcceptor = accept.unwrap();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let accept = accept.clone();
+                tokio::spawn(async move {
+                    let _ = accept.accept(stream).await;
+                });
+            }
+        });
+        let soft = handshake_cert_by(addr, "soft.wm-proxy.com", vec![]).await;
+        assert_eq!(soft.unwrap(), read_cert(CERT));
+        let local = handshake_cert_by(addr, "localhost", vec![]).await;
+        assert_eq!(local.unwrap(), read_cert("tests/certs/other.pem"));
+
+        // 同一地址不能同时为HTTP及HTTPS
+        let mut http = HttpConfig::new();
+        http.server.push(build_server("localhost"));
+        http.server.push(ServerConfig::new(
+            "127.0.0.1:0".parse::<WrapVecAddr>().unwrap(),
+        ));
+        let err = http.bind().await.err().unwrap();
+        assert!(format!("{:?}", err).contains("127.0.0.1:0"));
+
+        // 该端口的server均未配置证书
+        let mut server = build_server("localhost");
+        server.cert = None;
+        server.key = None;
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        assert!(http.bind().await.is_err());
+    }
+
+    /// 模拟后端, 以收到的请求头作为返回内容
+    async fn handle_echo(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            data.len()
+        );
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&data).await;
+    }
+
+    /// 以指定的SNI发送请求, 可选携带客户端证书, 握手或读取失败时返回None
+    async fn request_tls(addr: SocketAddr, sni: &'static str, with_cert: bool) -> Option<String> {
+        let builder = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier::default()));
+        let config = if with_cert {
+            let certs = vec![read_cert("tests/certs/client.pem").into()];
+            let file = std::fs::File::open("tests/certs/client.key").unwrap();
+            let key = rustls_pemfile::private_key(&mut std::io::BufReader::new(file))
+                .unwrap()
+                .unwrap();
+            builder.with_client_auth_cert(certs, key).unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from(sni).unwrap();
+        let mut stream = connector.connect(name, stream).await.ok()?;
+        let req = format!(
+            "GET / HTTP/1.1\r\nHost: {}\r\nX-Client-Cert-Subject: CN=fake\r\nConnection: close\r\n\r\n",
+            sni
+        );
+        stream.write_all(req.as_bytes()).await.ok()?;
+        Some(read_http(&mut stream).await).filter(|t| !t.is_empty())
+    }
+
+    /// 读取请求或返回, 直到读完Content-Length的内容或连接关闭
+    async fn read_http<S: AsyncRead + Unpin>(stream: &mut S) -> String {
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.trim().to_string()))
+                    .and_then(|v| v.parse::<usize>().ok());
+                if len.unwrap_or(0) <= body.len() {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_client() {
+        let upstream = common::run_upstream(handle_echo).await;
+        let url = Url::parse(format!("http://{}/", upstream).into_bytes()).unwrap();
+        let mut location = LocationConfig::new();
+        location.comm.proxy_url = Some(url);
+        let mut verify = build_server("localhost");
+        verify.verify_client = Some(VerifyClient::On);
+        verify.client_ca = Some("tests/certs/client_ca.pem".to_string());
+        verify.location.push(location.clone());
+        let mut optional = build_server("optional.wm-proxy.com");
+        optional.verify_client = Some(VerifyClient::Optional);
+        optional.client_ca = Some("tests/certs/client_ca.pem".to_string());
+        optional.location.push(location.clone());
+        optional.cert = None;
+        optional.key = None;
+        let mut ca_only = build_server("ca.wm-proxy.com");
+        ca_only.client_ca = Some("tests/certs/client_ca.pem".to_string());
+        ca_only.location.push(location.clone());
+        ca_only.cert = None;
+        ca_only.key = None;
+        // 明确关闭时即使配置了CA也不校验
+        let mut off = build_server("off.wm-proxy.com");
+        off.verify_client = Some(VerifyClient::Off);
+        off.client_ca = Some("tests/certs/client_ca.pem".to_string());
+        off.location.push(location.clone());
+        off.cert = None;
+        off.key = None;
+        let mut plain = build_server("soft.wm-proxy.com");
+        plain.location.push(location);
+        let mut http = HttpConfig::new();
+        http.server.push(verify);
+        http.server.push(optional);
+        http.server.push(ca_only);
+        http.server.push(off);
+        http.server.push(plain);
+        http.default_cert = Some(CERT.to_string());
+        http.default_key = Some(KEY.to_string());
+        http.after_load_option().unwrap();
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ = HttpConfig::process_tls(accept.clone(), servers.clone(), stream, addr).await;
+            }
+        });
+
+        // 未提供证书时握手失败
+        assert!(request_tls(addr, "localhost", false).await.is_none());
+        // 提供证书后向后端传递证书信息, 并忽略客户端自行传入的内容
+        let res = request_tls(addr, "localhost", true).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.contains("O=wmproxy,CN=client"), "{}", res);
+        assert!(res.contains("1A2B3C"), "{}", res);
+        assert!(!res.contains("CN=fake"), "{}", res);
+
+        // 可选时未提供证书也可访问
+        let res = request_tls(addr, "optional.wm-proxy.com", false).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(!res.contains("CN=fake"), "{}", res);
+        let res = request_tls(addr, "optional.wm-proxy.com", true).await.unwrap();
+        assert!(res.contains("O=wmproxy,CN=client"), "{}", res);
+
+        // 仅配置CA时同样要求提供证书
+        assert!(request_tls(addr, "ca.wm-proxy.com", false).await.is_none());
+        let res = request_tls(addr, "ca.wm-proxy.com", true).await.unwrap();
+        assert!(res.contains("O=wmproxy,CN=client"), "{}", res);
+
+        let res = request_tls(addr, "off.wm-proxy.com", false).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+
+        // 未校验的server不受影响
+        let res = request_tls(addr, "soft.wm-proxy.com", false).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+
+        // 开启校验但未配置CA时绑定失败
+        let mut server = build_server("localhost");
+        server.verify_client = Some(VerifyClient::On);
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        assert!(http.bind().await.is_err());
+    }
+
+    /// 以指定的TLS版本及ALPN握手, 返回协商的ALPN协议
+    async fn handshake_alpn(
+        addr: SocketAddr,
+        sni: &'static str,
+        version: &'static rustls::SupportedProtocolVersion,
+    ) -> Option<Option<Vec<u8>>> {
+        let mut config = rustls::ClientConfig::builder_with_protocol_versions(&[version])
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier::default()))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from(sni).unwrap();
+        let stream = connector.connect(name, stream).await.ok()?;
+        Some(stream.get_ref().1.alpn_protocol().map(|p| p.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn test_tls_option() {
+        let mut server = build_server("localhost");
+        server.alpn = Some(vec!["http/1.1".to_string()]);
+        server.min_tls_version = Some(TlsVersion::Tls13);
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        http.after_load_option().unwrap();
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ = HttpConfig::process_tls(accept.clone(), servers.clone(), stream, addr).await;
+            }
+        });
+
+        let tls13 = &rustls::version::TLS13;
+        let tls12 = &rustls::version::TLS12;
+        // 按server的配置协商ALPN及限制TLS版本
+        let alpn = handshake_alpn(addr, "localhost", tls13).await.unwrap();
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+        assert!(handshake_alpn(addr, "localhost", tls12).await.is_none());
+        // 其它server使用默认配置
+        let alpn = handshake_alpn(addr, "soft.wm-proxy.com", tls12).await.unwrap();
+        assert_eq!(alpn, Some(b"h2".to_vec()));
+
+        // 最低版本大于最高版本或加密套件不可用时加载配置失败
+        let mut server = build_server("localhost");
+        server.min_tls_version = Some(TlsVersion::Tls13);
+        server.max_tls_version = Some(TlsVersion::Tls12);
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        assert!(http.after_load_option().is_err());
+        let mut server = build_server("localhost");
+        server.max_tls_version = Some(TlsVersion::Tls12);
+        server.ciphers = vec!["TLS13_AES_128_GCM_SHA256".to_string()];
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        assert!(http.after_load_option().is_err());
+    }
+
+    async fn run_alpn_proxy(http: &mut HttpConfig) -> SocketAddr {
+        http.after_load_option().unwrap();
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ = HttpConfig::process_tls(accept.clone(), servers.clone(), stream, addr).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_http_alpn() {
+        let tls13 = &rustls::version::TLS13;
+        // server未配置时使用http中的alpn, 按服务端的顺序优先
+        let mut server = build_server("localhost");
+        server.alpn = Some(vec!["h2".to_string()]);
+        let mut http = HttpConfig::new();
+        http.alpn = Some(vec!["http/1.1".to_string(), "h2".to_string()]);
+        http.server.push(server);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        let addr = run_alpn_proxy(&mut http).await;
+        let alpn = handshake_alpn(addr, "soft.wm-proxy.com", tls13).await.unwrap();
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+        let alpn = handshake_alpn(addr, "localhost", tls13).await.unwrap();
+        assert_eq!(alpn, Some(b"h2".to_vec()));
+
+        // 关闭http2后即使配置了h2也不协商
+        let mut server = build_server("localhost");
+        server.alpn = Some(vec!["h2".to_string()]);
+        let mut http = HttpConfig::new();
+        http.http2 = false;
+        http.server.push(server);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        let addr = run_alpn_proxy(&mut http).await;
+        let alpn = handshake_alpn(addr, "soft.wm-proxy.com", tls13).await.unwrap();
+        assert_eq!(alpn, Some(b"http/1.1".to_vec()));
+        let alpn = handshake_alpn(addr, "localhost", tls13).await.unwrap();
+        assert_eq!(alpn, None);
+
+        let mut http = HttpConfig::new();
+        http.alpn = Some(vec![String::new()]);
+        assert!(http.after_load_option().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_tls_version() {
+        let tls12 = &rustls::version::TLS12;
+        let tls13 = &rustls::version::TLS13;
+        // server未配置时使用http中的TLS版本
+        let mut server = build_server("localhost");
+        server.min_tls_version = Some(TlsVersion::Tls12);
+        let mut http = HttpConfig::new();
+        http.min_tls_version = Some(TlsVersion::Tls13);
+        http.server.push(server);
+        http.server.push(build_server("soft.wm-proxy.com"));
+        let addr = run_alpn_proxy(&mut http).await;
+        assert!(handshake_alpn(addr, "soft.wm-proxy.com", tls12).await.is_none());
+        assert!(handshake_alpn(addr, "soft.wm-proxy.com", tls13).await.is_some());
+        assert!(handshake_alpn(addr, "localhost", tls12).await.is_some());
+
+        // 最低版本大于最高版本时绑定失败
+        let mut http = HttpConfig::new();
+        http.min_tls_version = Some(TlsVersion::Tls13);
+        http.max_tls_version = Some(TlsVersion::Tls12);
+        http.server.push(build_server("localhost"));
+        http.after_load_option().unwrap();
+        assert!(http.bind().await.is_err());
+        // 与server的配置组合后不可用时同样失败
+        let mut server = build_server("localhost");
+        server.max_tls_version = Some(TlsVersion::Tls12);
+        let mut http = HttpConfig::new();
+        http.min_tls_version = Some(TlsVersion::Tls13);
+        http.server.push(server);
+        http.after_load_option().unwrap();
+        assert!(http.bind().await.is_err());
+    }
+
+    /// 读取一个DER元素, 返回完整的元素, 内容及剩余的数据
+    fn der_read(data: &[u8]) -> (&[u8], &[u8], &[u8]) {
+        let (len, head) = match data[1] {
+            n if n < 0x80 => (n as usize, 2),
+            n => {
+                let size = (n & 0x7F) as usize;
+                let len = data[2..2 + size].iter().fold(0, |a, b| a << 8 | *b as usize);
+                (len, 2 + size)
+            }
+        };
+        (&data[..head + len], &data[head..head + len], &data[head + len..])
+    }
+
+    fn der_write(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut data = vec![tag];
+        match value.len() {
+            n if n < 0x80 => data.push(n as u8),
+            n if n < 0x100 => data.extend([0x81, n as u8]),
+            n => data.extend([0x82, (n >> 8) as u8, n as u8]),
+        }
+        data.extend_from_slice(value);
+        data
+    }
+
+    /// 以CSR中的名称及公钥签发证书
+    fn sign_csr(csr: &[u8]) -> Vec<u8> {
+        let (_, req, _) = der_read(csr);
+        let (_, info, _) = der_read(req);
+        let (_, _, rest) = der_read(info);
+        let (subject, _, rest) = der_read(rest);
+        let (spki, _, _) = der_read(rest);
+        // ecdsa-with-SHA256
+        let oid = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+        let alg = der_write(0x30, &der_write(0x06, &oid));
+        let san = der_write(0x30, &der_write(0x82, b"localhost"));
+        let san = [der_write(0x06, &[0x55, 0x1D, 0x11]), der_write(0x04, &san)].concat();
+        let validity = [der_write(0x17, b"240101000000Z"), der_write(0x17, b"491231000000Z")];
+        let tbs = [
+            der_write(0xA0, &der_write(0x02, &[2])),
+            der_write(0x02, &[1]),
+            alg.clone(),
+            subject.to_vec(),
+            der_write(0x30, &validity.concat()),
+            subject.to_vec(),
+            spki.to_vec(),
+            // subjectAltName
+            der_write(0xA3, &der_write(0x30, &der_write(0x30, &san))),
+        ];
+        let tbs = der_write(0x30, &tbs.concat());
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let sign = [&[0u8][..], key.sign(&rng, &tbs).unwrap().as_ref()].concat();
+        der_write(0x30, &[tbs, alg, der_write(0x03, &sign)].concat())
+    }
+
+    /// 从finalize请求中取出CSR
+    fn read_csr(req: &str) -> Option<Vec<u8>> {
+        let (_, body) = req.split_once("\r\n\r\n")?;
+        let jws: serde_json::Value = serde_json::from_str(body).ok()?;
+        let payload = URL_SAFE_NO_PAD.decode(jws["payload"].as_str()?).ok()?;
+        let payload: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+        URL_SAFE_NO_PAD.decode(payload["csr"].as_str()?).ok()
+    }
+
+    /// 签发的证书
+    type Issued = Arc<Mutex<Option<Vec<u8>>>>;
+
+    /// 模拟ACME服务, 校验时访问代理的HTTP服务获取token对应的内容, 按提交的CSR签发证书
+    async fn run_acme_mock(proxy: Arc<Mutex<Option<SocketAddr>>>, issued: Issued) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 是否已完成校验, 是否已提交CSR
+        let state = Arc::new(Mutex::new((false, false)));
+        tokio::spawn(async move {
+            let mut nonce = 0;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                nonce += 1;
+                let (proxy, state, issued) = (proxy.clone(), state.clone(), issued.clone());
+                tokio::spawn(async move {
+                    let base = format!("http://{}", addr);
+                    let req = read_http(&mut stream).await;
+                    let path = req.split(' ').nth(1).unwrap_or_default().to_string();
+                    let (validated, finalized) = *state.lock().unwrap();
+                    let (location, body) = match &*path {
+                        "/directory" => (
+                            None,
+                            format!(
+                                r#"{{"newNonce":"{0}/nonce","newAccount":"{0}/account","newOrder":"{0}/order"}}"#,
+                                base
+                            ),
+                        ),
+                        "/nonce" => (None, String::new()),
+                        "/account" => (Some(format!("{}/acct/1", base)), r#"{"status":"valid"}"#.to_string()),
+                        "/order" => (
+                            Some(format!("{}/order/1", base)),
+                            format!(
+                                r#"{{"status":"pending","authorizations":["{0}/authz/1"],"finalize":"{0}/finalize/1"}}"#,
+                                base
+                            ),
+                        ),
+                        "/authz/1" => (
+                            None,
+                            format!(
+                                r#"{{"status":"{1}","challenges":[{{"type":"http-01","url":"{0}/chall/1","token":"test-token"}}]}}"#,
+                                base,
+                                if validated { "valid" } else { "pending" }
+                            ),
+                        ),
+                        "/chall/1" => {
+                            let proxy = proxy.lock().unwrap().unwrap();
+                            let mut stream = TcpStream::connect(proxy).await.unwrap();
+                            let req = "GET /.well-known/acme-challenge/test-token HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+                            stream.write_all(req.as_bytes()).await.unwrap();
+                            let res = read_http(&mut stream).await;
+                            state.lock().unwrap().0 = res.contains("\r\n\r\ntest-token.");
+                            (None, r#"{"status":"processing"}"#.to_string())
+                        }
+                        "/finalize/1" => {
+                            if let (true, Some(csr)) = (validated, read_csr(&req)) {
+                                *issued.lock().unwrap() = Some(sign_csr(&csr));
+                                state.lock().unwrap().1 = true;
+                            }
+                            (None, r#"{"status":"processing"}"#.to_string())
+                        }
+                        "/order/1" => (
+                            None,
+                            format!(
+                                r#"{{"status":"{1}","certificate":"{0}/cert/1"}}"#,
+                                base,
+                                if finalized { "valid" } else { "invalid" }
+                            ),
+                        ),
+                        "/cert/1" => {
+                            let cert = issued.lock().unwrap().clone().unwrap_or_default();
+                            let data = STANDARD.encode(cert);
+                            let lines = data.as_bytes().chunks(64).map(String::from_utf8_lossy);
+                            let lines = lines.collect::<Vec<_>>().join("\n");
+                            let (begin, end) = ("-----BEGIN CERTIFICATE-----", "-----END CERTIFICATE-----");
+                            (None, format!("{}\n{}\n{}\n", begin, lines, end))
+                        }
+                        _ => (None, "{}".to_string()),
+                    };
+                    let mut res = format!(
+                        "HTTP/1.1 200 OK\r\nReplay-Nonce: nonce-{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        nonce,
+                        body.len()
+                    );
+                    if let Some(location) = location {
+                        res.push_str(&format!("Location: {}\r\n", location));
+                    }
+                    res.push_str("\r\n");
+                    res.push_str(&body);
+                    let _ = stream.write_all(res.as_bytes()).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_acme_issue() {
+        let dir = std::env::temp_dir().join(format!("wmproxy_acme_issue_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let proxy = Arc::new(Mutex::new(None));
+        let issued = Issued::default();
+        let acme_addr = run_acme_mock(proxy.clone(), issued.clone()).await;
+
+        let mut server = build_server("localhost");
+        // 监听地址为端口0时无法区分HTTP及HTTPS, 先获取空闲的端口
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        server.bind_addr = port.to_string().parse::<WrapVecAddr>().unwrap();
+        server.cert = None;
+        server.key = None;
+        server.acme = Some(AcmeConfig::new(
+            format!("http://{}/directory", acme_addr),
+            dir.to_string_lossy().to_string(),
+        ));
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        http.after_load_option().unwrap();
+        // 证书尚未申请时依然可以启动
+        let (accept, tlss, listeners) = http.bind().await.unwrap();
+        assert_eq!(tlss, vec![false, true]);
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+        let mut addrs = vec![];
+        for (listener, is_tls) in listeners.into_iter().zip(tlss) {
+            addrs.push(listener.local_addr().unwrap());
+            let (accept, servers) = (accept.clone(), servers.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, addr)) = listener.accept().await {
+                    let (accept, servers) = (accept.clone(), servers.clone());
+                    tokio::spawn(async move {
+                        if is_tls {
+                            let _ = HttpConfig::process_tls(accept, servers, stream, addr).await;
+                        } else {
+                            let _ = HttpConfig::process(servers, stream, addr).await;
+                        }
+                    });
+                }
+            });
+        }
+        *proxy.lock().unwrap() = Some(addrs[0]);
+
+        // 申请成功后无需重启即使用新证书
+        let mut cert = None;
+        for _ in 0..50 {
+            cert = handshake_cert_by(addrs[1], "localhost", vec![]).await;
+            if cert.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(cert, issued.lock().unwrap().clone());
+        assert!(dir.join("localhost.pem").exists());
+        assert!(dir.join("localhost.key").exists());
+        assert!(dir.join("account.key").exists());
+
+        http.stop_health_check();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_add_headers() {
+        // 后端已设置X-Content-Type-Options
+        let upstream = common::run_upstream(|mut stream| async move {
+            read_http(&mut stream).await;
+            let res = "HTTP/1.1 200 OK\r\nX-Content-Type-Options: upstream\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+            let _ = stream.write_all(res.as_bytes()).await;
+        })
+        .await;
+        let run = |force: bool| async move {
+            let mut location = LocationConfig::new();
+            location.comm.proxy_url =
+                Some(Url::parse(format!("http://{}/", upstream).into_bytes()).unwrap());
+            let mut server = build_server("localhost");
+            server.location.push(location);
+            server.hsts = Some("max-age=63072000; includeSubDomains".to_string());
+            server.add_headers = vec![
+                "X-Content-Type-Options: nosniff".parse().unwrap(),
+                "X-Frame-Options: DENY".parse().unwrap(),
+            ];
+            server.force_headers = force;
+            let mut http = HttpConfig::new();
+            http.server.push(server);
+            http.after_load_option().unwrap();
+            let (accept, _, listeners) = http.bind().await.unwrap();
+            let accept: TlsAcceptor = accept.unwrap();
+            let servers = http.convert_server_config();
+            let listener = listeners.into_iter().next().unwrap();
+            let tls_addr = listener.local_addr().unwrap();
+            let tls_servers = servers.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, addr)) = listener.accept().await {
+                    let _ = HttpConfig::process_tls(accept.clone(), tls_servers.clone(), stream, addr)
+                        .await;
+                }
+            });
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let plain_addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((stream, addr)) = listener.accept().await {
+                    let _ = HttpConfig::process(servers.clone(), stream, addr).await;
+                }
+            });
+            (tls_addr, plain_addr)
+        };
+
+        let (tls_addr, plain_addr) = run(false).await;
+        let res = request_tls(tls_addr, "localhost", false).await.unwrap();
+        let res = res.to_ascii_lowercase();
+        assert!(res.contains("strict-transport-security: max-age=63072000; includesubdomains"), "{}", res);
+        assert!(res.contains("x-frame-options: deny"), "{}", res);
+        assert!(res.contains("x-content-type-options: upstream"), "{}", res);
+
+        // 明文接入时不添加HSTS
+        let mut stream = TcpStream::connect(plain_addr).await.unwrap();
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let res = read_http(&mut stream).await.to_ascii_lowercase();
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        assert!(!res.contains("strict-transport-security"), "{}", res);
+        assert!(res.contains("x-frame-options: deny"), "{}", res);
+
+        // force时覆盖后端设置的值
+        let (tls_addr, _) = run(true).await;
+        let res = request_tls(tls_addr, "localhost", false).await.unwrap();
+        let res = res.to_ascii_lowercase();
+        assert!(res.contains("x-content-type-options: nosniff"), "{}", res);
+        assert!(!res.contains("x-content-type-options: upstream"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_https() {
+        let mut location = LocationConfig::new();
+        location.return_response = Some(ReturnResponse::new(200, "tls".to_string()));
+        let mut server = build_server("localhost");
+        server.location.push(location);
+        server.redirect_https = true;
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        http.after_load_option().unwrap();
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+        let listener = listeners.into_iter().next().unwrap();
+        let tls_addr = listener.local_addr().unwrap();
+        let tls_servers = servers.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ = HttpConfig::process_tls(accept.clone(), tls_servers.clone(), stream, addr).await;
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let plain_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ = HttpConfig::process(servers.clone(), stream, addr).await;
+            }
+        });
+
+        // 经由TLS接入时正常处理
+        let res = request_tls(tls_addr, "localhost", false).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200") && res.ends_with("tls"), "{}", res);
+
+        // 明文接入时跳转, 去除端口并保留路径及参数, 非GET请求返回308
+        for (method, status) in [("GET", "301"), ("POST", "308")] {
+            let mut stream = TcpStream::connect(plain_addr).await.unwrap();
+            let req = format!(
+                "{} /a/b?c=1 HTTP/1.1\r\nHost: localhost:80\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                method
+            );
+            stream.write_all(req.as_bytes()).await.unwrap();
+            let res = read_http(&mut stream).await;
+            assert!(res.starts_with(&format!("HTTP/1.1 {}", status)), "{}", res);
+            assert!(
+                res.to_ascii_lowercase().contains("location: https://localhost/a/b?c=1\r\n"),
+                "{}",
+                res
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 可信代理相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 将收到的请求头作为返回内容
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let res = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            data.len()
+        );
+        let _ = stream.write_all(res.as_bytes()).await;
+        let _ = stream.write_all(&data).await;
+    }
+
+    fn config(upstream: SocketAddr, trusted: &str) -> String {
+        format!(
+            r#"
+            trusted_proxies = "{}"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            forwarded_headers = true
+            [[server.location]]
+            rule = "/deny"
+            deny = "10.1.1.1"
+            static_response = "ok"
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://{}/"
+            "#,
+            trusted, upstream
+        )
+    }
+
+    /// 发送请求并返回完整的返回内容(小写)
+    async fn send(addr: SocketAddr, path: &str, forwarded: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-For: {}\r\n\r\n",
+            path, forwarded
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.map(|len| body.len() >= len).unwrap_or(true) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trusted_proxies() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = common::run_proxy(&config(upstream, "127.0.0.1")).await;
+
+        // 直连的地址可信时, 访问控制按解析出的客户端地址判断
+        let res = send(addr, "/deny", "10.1.1.1").await;
+        assert!(res.starts_with("http/1.1 403"), "{}", res);
+        let res = send(addr, "/deny", "10.2.2.2").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        // 跳过右侧可信的代理
+        let res = send(addr, "/deny", "10.1.1.1, 127.0.0.1").await;
+        assert!(res.starts_with("http/1.1 403"), "{}", res);
+        // 伪造的地址在不可信的地址左侧, 不生效
+        let res = send(addr, "/deny", "10.1.1.1, 10.2.2.2").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+
+        // 转发时X-Real-IP为客户端地址, X-Forwarded-For追加直连的代理地址
+        let res = send(addr, "/", "10.2.2.2").await;
+        assert!(res.contains("x-real-ip: 10.2.2.2\r\n"), "{}", res);
+        assert!(
+            res.contains("x-forwarded-for: 10.2.2.2, 127.0.0.1\r\n"),
+            "{}",
+            res
+        );
+
+        // 直连的地址不可信时忽略X-Forwarded-For
+        let addr = common::run_proxy(&config(upstream, "10.0.0.0/8")).await;
+        let res = send(addr, "/deny", "10.1.1.1").await;
+        assert!(res.starts_with("http/1.1 200"), "{}", res);
+        let res = send(addr, "/", "10.1.1.1").await;
+        assert!(res.contains("x-real-ip: 127.0.0.1\r\n"), "{}", res);
+    }
+}
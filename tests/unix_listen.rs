@@ -0,0 +1,107 @@
+#![deny(rust_2018_idioms)]
+
+/// 监听unix socket及socket选项相关
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+    use tokio_util::sync::CancellationToken;
+    use wmproxy::{
+        Helper, HttpConfig, ListenAddr, LocationConfig, ServerConfig, SocketOptions, WrapVecAddr,
+    };
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "wmproxy-listen-{}-{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_unix_listen() {
+        let path = socket_path("serve");
+        let mut server = ServerConfig::new(WrapVecAddr::empty());
+        server.listen.push(ListenAddr::Unix(path.clone()));
+        let root = std::env::temp_dir().join(format!("wmproxy_listen_{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("hello.txt"), "hello").unwrap();
+        let mut location = LocationConfig::new();
+        location.rule = "/".parse().unwrap();
+        location.root = Some(root.to_string_lossy().to_string());
+        server.location.push(location);
+        let mut http = HttpConfig::new();
+        http.server.push(server);
+        http.after_load_option().unwrap();
+        let servers = http.convert_server_config();
+
+        // 残留的socket文件将被移除
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let mut listeners = http.bind_unix().await.unwrap();
+        assert_eq!(listeners.len(), 1);
+        let (bind_path, listener) = listeners.pop().unwrap();
+        assert_eq!(bind_path, path);
+
+        // 其它程序监听中时返回错误
+        let other = socket_path("other");
+        let _other = std::os::unix::net::UnixListener::bind(&other).unwrap();
+        let err = Helper::bind_unix(&other, &SocketOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        let _ = std::fs::remove_file(&other);
+
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(HttpConfig::serve_unix(
+            servers,
+            path.clone(),
+            listener,
+            cancel.clone(),
+        ));
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /hello.txt HTTP/1.1\r\nHost: a.test\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(
+            buf[..n].starts_with(b"HTTP/1.1 200"),
+            "{}",
+            String::from_utf8_lossy(&buf[..n])
+        );
+
+        // 停止后移除socket文件
+        cancel.cancel();
+        task.await.unwrap();
+        assert!(!path.exists());
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[tokio::test]
+    async fn test_socket_options() {
+        let options: SocketOptions = toml::from_str(
+            "tcp_nodelay = true\nkeepalive = \"60s\"\nkeepalive_interval = \"10s\"\nbacklog = 16",
+        )
+        .unwrap();
+        assert_eq!(options.backlog(), 16);
+        assert_eq!(SocketOptions::default().backlog(), 128);
+        let listener = Helper::bind_with("127.0.0.1:0", &options).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        // linux下接收的连接继承监听的选项
+        if cfg!(target_os = "linux") {
+            assert!(stream.nodelay().unwrap());
+            let socket = socket2::SockRef::from(&stream);
+            assert!(socket.keepalive().unwrap());
+        }
+    }
+}
@@ -0,0 +1,133 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 代理到unix socket的后端相关
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpStream, UnixListener},
+    };
+    use wmproxy::{LocationConfig, UpstreamConfig};
+
+    use super::common;
+
+    /// 模拟unix socket的后端, 返回请求行及Host
+    fn run_unix_upstream(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("wmproxy-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut data = vec![];
+                    let mut buf = [0u8; 1024];
+                    while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(n) if n > 0 => data.extend_from_slice(&buf[..n]),
+                            _ => return,
+                        }
+                    }
+                    let text = String::from_utf8_lossy(&data).to_string();
+                    let line = text.lines().next().unwrap_or("").to_string();
+                    let host = text
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("host: ")
+                                .map(|v| v.to_string())
+                        })
+                        .unwrap_or_default();
+                    let body = format!("{} {}", line, host);
+                    let res = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.write_all(res.as_bytes()).await;
+                });
+            }
+        });
+        path
+    }
+
+    /// 反向代理的配置, `upstream`为http中的upstream配置, `location`为location的配置
+    fn config(upstream: &str, location: &str) -> String {
+        format!(
+            r#"
+            {}
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            {}
+            "#,
+            upstream, location
+        )
+    }
+
+    async fn request(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!(
+            "GET {} HTTP/1.1\r\nHost: a.test\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok());
+                if len.is_some_and(|len| body.len() >= len) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unix_proxy_url() {
+        let path = run_unix_upstream("url");
+        let location = format!("proxy_url = \"unix:{}\"", path.display());
+        let addr = common::run_proxy(&config("", &location)).await;
+        // 请求原样转发, 未配置proxy_host时Host为localhost
+        let res = request(addr, "/api?a=1").await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert!(res.ends_with("GET /api?a=1 HTTP/1.1 localhost"), "{}", res);
+
+        // 路径须为绝对路径
+        let config = "rule = \"/\"\nproxy_url = \"unix:app.sock\"";
+        assert!(toml::from_str::<LocationConfig>(config).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_unix_upstream() {
+        let path = run_unix_upstream("upstream");
+        let upstream = format!("name = \"app\"\nhosts = [\"unix:{}\"]", path.display());
+        let http = format!("[[upstream]]\n{}", upstream);
+        let addr = common::run_proxy(&config(&http, "proxy_url = \"http://app/\"")).await;
+        let res = request(addr, "/index").await;
+        assert!(res.ends_with("GET /index HTTP/1.1 localhost"), "{}", res);
+
+        // 不能同时配置unix socket及TCP的后端
+        let mut mixed: UpstreamConfig = toml::from_str(&upstream).unwrap();
+        mixed.hosts.push("127.0.0.1:80".to_string());
+        assert!(mixed.check_unix().is_err());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,277 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 复用后端连接相关
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::common;
+
+    /// 模拟后端, 每个连接返回第一个请求后, `close`为true时声明`Connection: close`并关闭,
+    /// 否则保持连接, 收到第二个请求时不返回直接关闭
+    async fn handle(mut stream: TcpStream, conns: Arc<AtomicUsize>, close: bool) {
+        conns.fetch_add(1, Ordering::Relaxed);
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        let res = if close {
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+        } else {
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+        };
+        let _ = stream.write_all(res.as_bytes()).await;
+        if close {
+            return;
+        }
+        common::read_head(&mut stream).await;
+    }
+
+    /// 模拟后端, 同一连接上持续返回请求
+    async fn handle_keepalive(mut stream: TcpStream, conns: Arc<AtomicUsize>) {
+        conns.fetch_add(1, Ordering::Relaxed);
+        while common::read_head(&mut stream).await.is_some() {
+            let res = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+            if stream.write_all(res.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// h2c的模拟后端, 升级后同一连接上持续返回请求, `pong`为false时不再回应PING
+    async fn handle_h2c(mut stream: TcpStream, conns: Arc<AtomicUsize>, pong: Arc<AtomicBool>) {
+        conns.fetch_add(1, Ordering::Relaxed);
+        if common::read_head(&mut stream).await.is_none() {
+            return;
+        }
+        let upgrade =
+            "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+        let mut data = upgrade.as_bytes().to_vec();
+        // 空的SETTINGS及升级请求(stream 1)的返回, 0x88为`:status: 200`
+        data.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&[0, 0, 1, 1, 5, 0, 0, 0, 1, 0x88]);
+        if stream.write_all(&data).await.is_err() {
+            return;
+        }
+        let mut preface = [0u8; 24];
+        if stream.read_exact(&mut preface).await.is_err() {
+            return;
+        }
+        let mut head = [0u8; 9];
+        while stream.read_exact(&mut head).await.is_ok() {
+            let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+            let mut payload = vec![0u8; len];
+            if stream.read_exact(&mut payload).await.is_err() {
+                return;
+            }
+            let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+            let res = match (head[3], head[4] & 1) {
+                (4, 0) => vec![0, 0, 0, 4, 1, 0, 0, 0, 0],
+                (6, 0) if pong.load(Ordering::Relaxed) => {
+                    let mut res = vec![0, 0, 8, 6, 1, 0, 0, 0, 0];
+                    res.extend_from_slice(&payload);
+                    res
+                }
+                (1, _) => {
+                    let mut res = vec![0, 0, 1, 1, 5];
+                    res.extend_from_slice(&id.to_be_bytes());
+                    res.push(0x88);
+                    res
+                }
+                _ => continue,
+            };
+            if stream.write_all(&res).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn config(upstream: SocketAddr, keepalive: usize, retries: usize) -> String {
+        format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            keepalive = {}
+            [[server.location]]
+            rule = "/"
+            retries = {}
+            proxy_url = "http://{}/"
+            "#,
+            keepalive, retries, upstream
+        )
+    }
+
+    /// 在同一连接上发送请求, 返回状态行
+    async fn request(stream: &mut TcpStream) -> String {
+        let req = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: ").map(|v| v.to_string()))
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                if body.len() >= len {
+                    return head.lines().next().unwrap_or_default().to_string();
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reuse_after_upstream_close() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle(stream, counter.clone(), false)).await;
+        let addr = common::run_proxy(&config(upstream, 32, 0)).await;
+
+        // 复用的连接被后端关闭时该请求失败并丢弃该连接, 之后的请求重新建立连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+        let res = request(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 5"), "{}", res);
+        let res = request(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert_eq!(conns.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_reused_connection() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle(stream, counter.clone(), false)).await;
+        let addr = common::run_proxy(&config(upstream, 32, 1)).await;
+
+        // 复用的连接失败时与新建的连接一样按`retries`重试, 重新连接后端
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+        let res = request(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert_eq!(conns.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_no_reuse_connection_close() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle(stream, counter.clone(), true)).await;
+        let addr = common::run_proxy(&config(upstream, 32, 0)).await;
+
+        // 后端声明关闭的连接不再复用, 每个请求都重新建立连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            let res = request(&mut stream).await;
+            assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        }
+        assert_eq!(conns.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_pool_shared_between_clients() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle_keepalive(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream, 32, 0)).await;
+
+        // 不同的客户端连接共享连接池中的后端连接
+        for _ in 0..3 {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let res = request(&mut stream).await;
+            assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        }
+        assert_eq!(conns.load(Ordering::Relaxed), 1);
+
+        // 同时处理的请求各自使用后端连接, 结束后均放回连接池
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let (a, b) = tokio::join!(request(&mut first), request(&mut second));
+        assert!(a.starts_with("HTTP/1.1 200") && b.starts_with("HTTP/1.1 200"));
+        let opened = conns.load(Ordering::Relaxed);
+        assert!(opened <= 2, "{}", opened);
+        for stream in [&mut first, &mut second] {
+            assert!(request(stream).await.starts_with("HTTP/1.1 200"));
+        }
+        assert_eq!(conns.load(Ordering::Relaxed), opened);
+
+        // 为0时不复用后端连接
+        let conns = Arc::new(AtomicUsize::new(0));
+        let counter = conns.clone();
+        let upstream =
+            common::run_upstream(move |stream| handle_keepalive(stream, counter.clone())).await;
+        let addr = common::run_proxy(&config(upstream, 0, 0)).await;
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        for _ in 0..3 {
+            assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+        }
+        assert_eq!(conns.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_h2_ping_timeout() {
+        let conns = Arc::new(AtomicUsize::new(0));
+        let pong = Arc::new(AtomicBool::new(true));
+        let (counter, answer) = (conns.clone(), pong.clone());
+        let upstream =
+            common::run_upstream(move |stream| handle_h2c(stream, counter.clone(), answer.clone()))
+                .await;
+        let config = format!(
+            r#"
+            [[server]]
+            bind_addr = "127.0.0.1:0"
+            bind_ssl = ""
+            [[server.location]]
+            rule = "/"
+            proxy_url = "http://h2/"
+            [[server.location.upstream]]
+            name = "h2"
+            server = [{{ addr = "{}" }}]
+            ping_interval = "50ms"
+            ping_timeout = "100ms"
+            "#,
+            upstream
+        );
+        let addr = common::run_proxy(&config).await;
+
+        // 回应PING的HTTP/2连接空闲时继续复用
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        assert!(request(&mut stream).await.starts_with("HTTP/1.1 200"));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let res = request(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert_eq!(conns.load(Ordering::Relaxed), 1);
+
+        // 后端不再回应PING, 超时后该连接不再复用
+        pong.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let res = request(&mut stream).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+        assert_eq!(conns.load(Ordering::Relaxed), 2);
+    }
+}
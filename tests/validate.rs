@@ -0,0 +1,84 @@
+#![deny(rust_2018_idioms)]
+
+/// 启动前的配置检查相关
+#[cfg(test)]
+mod tests {
+    use wmproxy::{
+        ConfigError, HttpConfig, LocationConfig, ServerConfig, UpstreamConfig, WrapVecAddr,
+    };
+
+    fn build_server(addr: &str, name: &str, location: &str) -> ServerConfig {
+        let mut server = ServerConfig::new(addr.parse::<WrapVecAddr>().unwrap());
+        server.up_name = name.to_string();
+        let location: LocationConfig = toml::from_str(location).unwrap();
+        server.location.push(location);
+        server
+    }
+
+    fn paths(errors: &[ConfigError]) -> Vec<&str> {
+        errors.iter().map(|e| e.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_validate_all_errors() {
+        let mut http = HttpConfig::new();
+        let mut server = build_server("127.0.0.1:18801", "a.com", "rule = \"/\"");
+        server.cert = Some("tests/certs/missing.pem".to_string());
+        server.key = Some("tests/certs/missing.key".to_string());
+        http.server.push(server);
+        let location = "rule = \"/\"\nproxy_url = \"http://empty/\"";
+        http.server
+            .push(build_server("127.0.0.1:18801", "A.com", location));
+        let location = "rule = \"/\"\nstatic_response = \"ok\"";
+        http.server
+            .push(build_server("192.0.2.1:18802", "b.com", location));
+        let upstream: UpstreamConfig = toml::from_str("name = \"empty\"").unwrap();
+        http.upstream.push(upstream);
+        http.after_load_option().unwrap();
+
+        let errors = http.validate().unwrap_err();
+        let text = ConfigError::join(&errors);
+        assert_eq!(
+            paths(&errors),
+            vec![
+                "server[a.com]",
+                "upstream[empty]",
+                "server[a.com].location[/]",
+                "server[A.com]",
+                "server[b.com]",
+            ],
+            "{}",
+            text
+        );
+        assert!(
+            errors[0].message.contains("tests/certs/missing.pem"),
+            "{}",
+            text
+        );
+        assert!(errors[3].message.contains("端口18801"), "{}", text);
+        assert!(errors[4].message.contains("192.0.2.1:18802"), "{}", text);
+    }
+
+    #[test]
+    fn test_validate_default_server() {
+        let mut http = HttpConfig::new();
+        let location = "rule = \"/\"\nstatic_response = \"ok\"";
+        for name in ["a.com", "b.com"] {
+            let mut server = build_server("127.0.0.1:18803", name, location);
+            server.default_server = true;
+            http.server.push(server);
+        }
+        http.server[1].cert = Some("tests/certs/localhost.pem".to_string());
+        http.server[1].key = Some("tests/certs/localhost.key".to_string());
+        // 加载时只返回首个错误, 检查时返回全部
+        assert!(http.after_load_option().is_err());
+        let errors = http.validate().unwrap_err();
+        assert_eq!(paths(&errors), vec!["default_server"]);
+        assert!(errors[0].message.contains("端口18803"));
+
+        http.server[1].default_server = false;
+        http.server[1].bind_addr = "127.0.0.1:18804".parse().unwrap();
+        http.after_load_option().unwrap();
+        assert!(http.validate().is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// 按Host选择server相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use wmproxy::{HttpConfig, LocationConfig, ReturnResponse, ServerConfig, WrapVecAddr};
+
+    use super::common;
+
+    fn build_server(name: &str) -> ServerConfig {
+        let mut server = ServerConfig::new("127.0.0.1:0".parse::<WrapVecAddr>().unwrap());
+        server.up_name = name.to_string();
+        let mut location = LocationConfig::new();
+        location.rule = "/".parse().unwrap();
+        location.return_response = Some(ReturnResponse::new(200, name.to_string()));
+        server.location.push(location);
+        server
+    }
+
+    fn build_http(servers: Vec<ServerConfig>) -> HttpConfig {
+        let mut http = HttpConfig::new();
+        http.server = servers;
+        http
+    }
+
+    /// 发送请求, host为None时以HTTP/1.0发送且不携带Host
+    async fn request(addr: SocketAddr, host: Option<&str>) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = match host {
+            Some(host) => format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                host
+            ),
+            None => "GET / HTTP/1.0\r\n\r\n".to_string(),
+        };
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        loop {
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let len = head
+                    .to_ascii_lowercase()
+                    .lines()
+                    .find_map(|l| {
+                        l.strip_prefix("content-length: ")
+                            .map(|v| v.trim().to_string())
+                    })
+                    .and_then(|v| v.parse::<usize>().ok());
+                if len.is_some_and(|len| body.len() >= len) {
+                    return text;
+                }
+            }
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => return text,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_server() {
+        // 未配置默认的server时, 未匹配的Host不再交由最后一个server处理
+        let http = build_http(vec![build_server("a.test"), build_server("b.test")]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("b.test")).await;
+        assert!(res.ends_with("b.test"), "{}", res);
+        let res = request(addr, Some("c.test")).await;
+        assert!(res.starts_with("HTTP/1.1 421"), "{}", res);
+        let res = request(addr, None).await;
+        assert!(res.contains(" 421"), "{}", res);
+
+        // 配置默认的server后, 未匹配及未携带Host的请求均由其处理
+        let mut default = build_server("a.test");
+        default.default_server = true;
+        let http = build_http(vec![default, build_server("b.test")]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("c.test")).await;
+        assert!(res.ends_with("a.test"), "{}", res);
+        let res = request(addr, None).await;
+        assert!(res.ends_with("a.test"), "{}", res);
+        let res = request(addr, Some("b.test")).await;
+        assert!(res.ends_with("b.test"), "{}", res);
+
+        // 默认的server不受配置顺序影响, 未匹配的Host不交由最后一个server处理
+        let mut default = build_server("a.test");
+        default.default_server = true;
+        let http = build_http(vec![
+            default,
+            build_server("b.test"),
+            build_server("c.test"),
+        ]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("d.test")).await;
+        assert!(res.ends_with("a.test"), "{}", res);
+
+        // 同一端口配置多个默认的server时加载失败
+        let mut http = HttpConfig::new();
+        for name in ["a.test", "b.test"] {
+            let mut server = build_server(name);
+            server.default_server = true;
+            http.server.push(server);
+        }
+        assert!(http.after_load_option().is_err());
+        let text = "bind_addr = \"127.0.0.1:80\"\nbind_ssl = \"127.0.0.1:443\"\ndefault = true";
+        let server: ServerConfig = toml::from_str(text).unwrap();
+        assert!(server.default_server);
+
+        // 未配置up_name的server可处理任意的Host
+        let http = build_http(vec![build_server("a.test"), build_server("")]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("c.test")).await;
+        assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_host_normalize() {
+        // Host忽略大小写及端口后与up_name比较
+        let http = build_http(vec![build_server("a.test"), build_server("B.test")]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("A.Test")).await;
+        assert!(res.ends_with("a.test"), "{}", res);
+        let res = request(addr, Some("a.test:8080")).await;
+        assert!(res.ends_with("a.test"), "{}", res);
+        let res = request(addr, Some("b.TEST:443")).await;
+        assert!(res.ends_with("B.test"), "{}", res);
+        let res = request(addr, Some("c.test:8080")).await;
+        assert!(res.starts_with("HTTP/1.1 421"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_server() {
+        // 完全匹配优先于通配名, 通配名仅匹配一级子域名
+        let http = build_http(vec![build_server("*.a.test"), build_server("www.a.test")]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("img.a.test")).await;
+        assert!(res.ends_with("*.a.test"), "{}", res);
+        let res = request(addr, Some("IMG.a.test:8080")).await;
+        assert!(res.ends_with("*.a.test"), "{}", res);
+        let res = request(addr, Some("www.a.test")).await;
+        assert!(res.ends_with("www.a.test"), "{}", res);
+        let res = request(addr, Some("x.img.a.test")).await;
+        assert!(res.starts_with("HTTP/1.1 421"), "{}", res);
+        let res = request(addr, Some("a.test")).await;
+        assert!(res.starts_with("HTTP/1.1 421"), "{}", res);
+    }
+
+    #[tokio::test]
+    async fn test_reuseport_workers() {
+        let mut http = HttpConfig::new();
+        http.server = vec![build_server("a.test")];
+        http.workers = 2;
+        http.after_load_option().unwrap();
+        let (_, tlss, listeners) = http.bind().await.unwrap();
+        let servers = http.convert_server_config();
+        let addr = listeners[0].local_addr().unwrap();
+        if cfg!(target_os = "linux") {
+            // 同一端口绑定多个监听
+            assert_eq!(listeners.len(), 2);
+            assert_eq!(listeners[1].local_addr().unwrap(), addr);
+        } else {
+            assert_eq!(listeners.len(), 1);
+        }
+        assert_eq!(tlss, vec![false; listeners.len()]);
+        for listener in listeners {
+            let servers = servers.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, addr)) = listener.accept().await {
+                    let _ = HttpConfig::process(servers.clone(), stream, addr).await;
+                }
+            });
+        }
+        for _ in 0..8 {
+            let res = request(addr, Some("a.test")).await;
+            assert!(res.ends_with("a.test"), "{}", res);
+        }
+        http.stop_health_check();
+    }
+
+    #[tokio::test]
+    async fn test_redirect_https() {
+        // 仅用于跳转的server无需配置location
+        let mut server = ServerConfig::new("127.0.0.1:0".parse::<WrapVecAddr>().unwrap());
+        server.up_name = "a.test".to_string();
+        server.redirect_https = true;
+        server.default_server = true;
+        let http = build_http(vec![server]);
+        let addr = common::run_http(http).await;
+        let res = request(addr, Some("A.test:80")).await;
+        assert!(res.starts_with("HTTP/1.1 301"), "{}", res);
+        assert!(res.contains("https://A.test/\r\n"), "{}", res);
+        // 未携带Host时使用up_name
+        let res = request(addr, None).await;
+        assert!(res.contains(" 301"), "{}", res);
+        assert!(res.contains("https://a.test/\r\n"), "{}", res);
+
+        // ACME的校验路径不跳转
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = "GET /.well-known/acme-challenge/unknown HTTP/1.1\r\nHost: a.test\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await.unwrap();
+        let res = String::from_utf8_lossy(&buf[..n]);
+        assert!(res.starts_with("HTTP/1.1 ") && !res.contains(" 301"), "{}", res);
+    }
+}
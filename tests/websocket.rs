@@ -0,0 +1,283 @@
+#![deny(rust_2018_idioms)]
+
+mod common;
+
+/// websocket升级透传相关
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        DigitallySignedStruct, SignatureScheme,
+    };
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpStream,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use webparse::Url;
+    use wmproxy::{HttpConfig, LocationConfig, ServerConfig, WrapVecAddr};
+
+    use super::common;
+
+    static CERT: &str = "tests/certs/localhost.pem";
+    static KEY: &str = "tests/certs/localhost.key";
+
+    /// 不校验证书
+    #[derive(Debug)]
+    struct NoVerifier;
+
+    impl ServerCertVerifier for NoVerifier {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            rustls::crypto::ring::default_provider()
+                .signature_verification_algorithms
+                .supported_schemes()
+        }
+    }
+
+    /// 模拟后端, 返回带自定义头的101, 并将收到的转发头及证书头带回, 之后原样回显数据
+    async fn handle(mut stream: TcpStream) {
+        let data = match common::read_head(&mut stream).await {
+            Some(data) => data,
+            None => return,
+        };
+        let text = String::from_utf8_lossy(&data).to_ascii_lowercase();
+        let seen = |name: &str| {
+            text.lines()
+                .find_map(|l| l.strip_prefix(name))
+                .unwrap_or("none")
+                .trim()
+                .to_string()
+        };
+        let res = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\nSec-WebSocket-Protocol: chat\r\nX-Seen-Proto: {}\r\nX-Seen-Cert: {} {}\r\n\r\n",
+            seen("x-forwarded-proto: "),
+            seen("x-client-cert-subject: "),
+            seen("x-client-cert-serial: ")
+        );
+        if stream.write_all(res.as_bytes()).await.is_err() {
+            return;
+        }
+        let (mut reader, mut writer) = stream.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    }
+
+    fn build_server(upstream: SocketAddr, ssl: bool) -> ServerConfig {
+        let addr = "127.0.0.1:0".parse::<WrapVecAddr>().unwrap();
+        let mut server = if ssl {
+            let mut server = ServerConfig::new_ssl(addr);
+            server.up_name = "localhost".to_string();
+            server.cert = Some(CERT.to_string());
+            server.key = Some(KEY.to_string());
+            server
+        } else {
+            ServerConfig::new(addr)
+        };
+        let mut location = LocationConfig::new();
+        let url = format!("http://{}/", upstream);
+        location.comm.proxy_url = Some(Url::parse(url.into_bytes()).unwrap());
+        server.location.push(location);
+        server
+    }
+
+    fn build_http(server: ServerConfig, trusted: Option<&str>) -> HttpConfig {
+        let mut http = HttpConfig::new();
+        http.trusted_proxies = trusted.map(|t| t.parse().unwrap());
+        http.server.push(server);
+        http
+    }
+
+    async fn run_tls_proxy(upstream: SocketAddr) -> SocketAddr {
+        let mut http = build_http(build_server(upstream, true), None);
+        http.after_load_option().unwrap();
+        let (accept, _, listeners) = http.bind().await.unwrap();
+        let accept: TlsAcceptor = accept.unwrap();
+        let servers = http.convert_server_config();
+
+        let listener = listeners.into_iter().next().unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let _ =
+                    HttpConfig::process_tls(accept.clone(), servers.clone(), stream, addr).await;
+            }
+        });
+        addr
+    }
+
+    /// 发起升级请求并返回小写的返回头, 之后验证数据的双向透传
+    async fn upgrade<S>(stream: &mut S) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        upgrade_with(stream, "").await
+    }
+
+    /// 发起携带额外请求头的升级请求
+    async fn upgrade_with<S>(stream: &mut S, extra: &str) -> String
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let req = format!("GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Protocol: chat\r\n{}\r\n", extra);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut data = vec![];
+        let mut buf = [0u8; 1024];
+        while !data.windows(4).any(|w| w == b"\r\n\r\n") {
+            match tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => data.extend_from_slice(&buf[..n]),
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&data).to_string();
+        let (head, rest) = text.split_once("\r\n\r\n").unwrap();
+        if !head.starts_with("HTTP/1.1 101") {
+            return head.to_ascii_lowercase();
+        }
+        assert!(rest.is_empty());
+
+        // 升级后的数据不做任何解析, 原样透传
+        for msg in [&b"\x81\x05hello"[..], b"raw bytes"] {
+            stream.write_all(msg).await.unwrap();
+            let mut echo = vec![0u8; msg.len()];
+            tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut echo))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&echo[..], msg);
+        }
+        head.to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_passthrough() {
+        let upstream = common::run_upstream(handle).await;
+        let http = build_http(build_server(upstream, false), None);
+        let addr = common::run_http(http).await;
+
+        let head = upgrade(&mut TcpStream::connect(addr).await.unwrap()).await;
+        assert!(head.starts_with("http/1.1 101 switching protocols"), "{}", head);
+        assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+        assert!(head.contains("sec-websocket-protocol: chat"));
+        assert!(head.contains("x-seen-proto: http"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_passthrough_tls() {
+        let upstream = common::run_upstream(handle).await;
+        let addr = run_tls_proxy(upstream).await;
+
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, stream).await.unwrap();
+
+        // TLS在代理处终止, 后端收到的是明文的升级请求
+        let head = upgrade(&mut stream).await;
+        assert!(head.starts_with("http/1.1 101 switching protocols"), "{}", head);
+        assert!(head.contains("sec-websocket-protocol: chat"));
+        assert!(head.contains("x-seen-proto: https"));
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_spoof_cert() {
+        let upstream = common::run_upstream(handle).await;
+        let http = build_http(build_server(upstream, false), None);
+        let addr = common::run_http(http).await;
+
+        // 未经握手校验的证书信息不转发到后端
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = upgrade_with(
+            &mut stream,
+            "X-Client-Cert-Subject: CN=fake\r\nX-Client-Cert-Serial: 01\r\n",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(head.contains("x-seen-cert: none none"), "{}", head);
+    }
+
+    /// 升级请求同样经过访问控制, 频率限制及跳转等检查, 通过后才连接后端
+    #[tokio::test]
+    async fn test_upgrade_checks() {
+        let upstream = common::run_upstream(handle).await;
+        let mut server = build_server(upstream, false);
+        server.location[0].comm.deny_ip = Some("10.0.0.9".parse().unwrap());
+        server.location[0].comm.limit_req = Some("rate=1r/min burst=1".parse().unwrap());
+        let addr = common::run_http(build_http(server, Some("127.0.0.1"))).await;
+
+        // 按可信代理解析出的客户端地址判断
+        let denied = "X-Forwarded-For: 10.0.0.9\r\n";
+        let head = upgrade_with(&mut TcpStream::connect(addr).await.unwrap(), denied).await;
+        assert!(head.starts_with("http/1.1 403"), "{}", head);
+
+        let client = "X-Forwarded-For: 10.0.0.1\r\n";
+        for _ in 0..2 {
+            let head = upgrade_with(&mut TcpStream::connect(addr).await.unwrap(), client).await;
+            assert!(head.starts_with("http/1.1 101"), "{}", head);
+        }
+        let head = upgrade_with(&mut TcpStream::connect(addr).await.unwrap(), client).await;
+        assert!(head.starts_with("http/1.1 429"), "{}", head);
+
+        let mut server = build_server(upstream, false);
+        server.redirect_https = true;
+        let addr = common::run_http(build_http(server, None)).await;
+        let head = upgrade(&mut TcpStream::connect(addr).await.unwrap()).await;
+        assert!(head.starts_with("http/1.1 301"), "{}", head);
+        assert!(head.contains("location: https://localhost/chat"), "{}", head);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_half_close() {
+        let upstream = common::run_upstream(handle).await;
+        let http = build_http(build_server(upstream, false), None);
+        let addr = common::run_http(http).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let head = upgrade(&mut stream).await;
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+
+        // 客户端关闭写端后仍能收到后端剩余的数据, 后端关闭后客户端读取结束
+        stream.write_all(b"last words").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut rest = vec![];
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&rest[..], b"last words");
+    }
+}